@@ -0,0 +1,202 @@
+//! Messages of the host for the players, shown in the lobby and in the running game.
+//!
+//! WC3 only shows chat of players that are in the game, so the messages appear as chat of the
+//! player at the game host. They are added to what the game host sends through each tunnel.
+
+use std::{
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use binrw::NullString;
+use tokio::sync::{
+    mpsc::{self, UnboundedReceiver, UnboundedSender},
+    watch,
+};
+use tracing::debug;
+
+use crate::{
+    events::{GamePhase, LobbyInfo},
+    packets::{GPS_HEADER, QueryForGamesResponse},
+    tap::PacketRewriter,
+    utils::truncate,
+    w3gs::{ChatBody, SlotTable, W3gsPacket, chat_from_host},
+};
+
+/// Longest message WC3 shows, in bytes.
+const MAX_MESSAGE_LEN: usize = 254;
+
+/// Sends messages to the players of all tunnels.
+#[derive(Debug, Clone)]
+pub(crate) struct Announcer {
+    outlets: Arc<Mutex<Vec<UnboundedSender<String>>>>,
+    lobby: watch::Receiver<Option<QueryForGamesResponse>>,
+}
+
+impl Announcer {
+    pub fn new(lobby: watch::Receiver<Option<QueryForGamesResponse>>) -> Self {
+        Announcer {
+            outlets: Arc::default(),
+            lobby,
+        }
+    }
+
+    /// Shows a message to every player that joined through a tunnel.
+    pub fn say(&self, message: &str) {
+        self.outlets
+            .lock()
+            .unwrap()
+            .retain(|outlet| outlet.send(message.to_string()).is_ok());
+    }
+
+    /// An injector for a new forwarded connection.
+    pub fn injector(&self) -> ChatInjector {
+        let (outlet, messages) = mpsc::unbounded_channel();
+        self.outlets.lock().unwrap().push(outlet);
+        //GProxy numbers the packets of the game host, extra ones would break rejoins
+        let gproxy = self
+            .lobby
+            .borrow()
+            .as_ref()
+            .is_some_and(|lobby| LobbyInfo::from(lobby).gproxy);
+        ChatInjector {
+            messages,
+            own_id: None,
+            sender: None,
+            phase: None,
+            gproxy,
+        }
+    }
+}
+
+/// Adds the messages of an [`Announcer`] to the packets the game host sends to one player.
+pub(crate) struct ChatInjector {
+    messages: UnboundedReceiver<String>,
+    own_id: Option<u8>,
+    /// The player at the game host, who the messages come from.
+    sender: Option<u8>,
+    phase: Option<GamePhase>,
+    gproxy: bool,
+}
+
+impl ChatInjector {
+    fn packet(&self, message: &str) -> Option<Vec<u8>> {
+        if self.gproxy {
+            return None;
+        }
+        let (own_id, sender) = (self.own_id?, self.sender?);
+        let message = NullString::from(truncate(message, MAX_MESSAGE_LEN));
+        let body = match self.phase {
+            Some(GamePhase::InGame) => ChatBody::GameMessage { flags: 0, message },
+            _ => ChatBody::Message(message),
+        };
+        chat_from_host(&[own_id], sender, body)
+    }
+}
+
+impl PacketRewriter for ChatInjector {
+    fn rewrite(&mut self, packet: &mut Vec<u8>) {
+        if packet.first() == Some(&GPS_HEADER) {
+            self.gproxy = true;
+            return;
+        }
+        match W3gsPacket::detect(packet) {
+            Some(W3gsPacket::SlotInfoJoin(join)) => {
+                self.own_id = Some(join.player_id);
+                self.sender = host_player(&join.slots);
+                self.phase = Some(GamePhase::Lobby);
+            }
+            Some(W3gsPacket::SlotInfo(info)) => self.sender = host_player(&info.slots),
+            Some(W3gsPacket::CountdownEnd) => self.phase = Some(GamePhase::Loading),
+            Some(W3gsPacket::IncomingAction(_)) if self.phase == Some(GamePhase::Loading) => {
+                self.phase = Some(GamePhase::InGame);
+            }
+            _ => {}
+        }
+    }
+
+    fn poll_inject(&mut self, cx: &mut Context<'_>) -> Poll<Option<Vec<u8>>> {
+        //Chat while the game loads would be lost, the messages wait for the game
+        if self.phase == Some(GamePhase::Loading) {
+            return Poll::Pending;
+        }
+        while let Poll::Ready(message) = self.messages.poll_recv(cx) {
+            let Some(message) = message else {
+                return Poll::Ready(None);
+            };
+            match self.packet(&message) {
+                Some(packet) => return Poll::Ready(Some(packet)),
+                None => debug!("Not showing \"{message}\" on a game connection without a player"),
+            }
+        }
+        Poll::Pending
+    }
+}
+
+/// The lowest id of the human players, the game host gives it to its own player.
+fn host_player(slots: &SlotTable) -> Option<u8> {
+    slots
+        .slots
+        .iter()
+        .filter(|slot| slot.is_occupied() && slot.computer == 0)
+        .map(|slot| slot.player_id)
+        .min()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt, duplex},
+        sync::watch,
+    };
+
+    use super::Announcer;
+    use crate::{
+        tap::Rewritten,
+        test_utils::w3gs_frames::{frame, slot_info_join},
+        w3gs::{ChatBody, W3gsPacket},
+    };
+
+    #[tokio::test]
+    async fn shows_messages_between_packets_of_the_game_host() {
+        let announcer = Announcer::new(watch::channel(None).1);
+        let (mut game, local) = duplex(1024);
+        let mut stream = Rewritten::new(local, announcer.injector());
+
+        game.write_all(&slot_info_join(2)).await.unwrap();
+        let mut joined = vec![0; slot_info_join(2).len()];
+        stream.read_exact(&mut joined).await.unwrap();
+        announcer.say("Host will start in 30s");
+        game.write_all(&frame(0x0A, &[])).await.unwrap();
+        drop(game);
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+
+        let countdown = frame(0x0A, &[]);
+        let (chat, after) = rest.split_at(rest.len() - countdown.len());
+        assert_eq!(after, countdown.as_slice());
+        match W3gsPacket::detect(chat) {
+            Some(W3gsPacket::ChatFromHost(chat)) => {
+                assert_eq!(chat.packet_size as usize, rest.len() - countdown.len());
+                assert_eq!(chat.receivers, vec![2]);
+                assert_eq!(chat.sender, 1);
+                assert!(matches!(chat.body, ChatBody::Message(_)));
+                assert_eq!(
+                    chat.body.message().as_deref(),
+                    Some("Host will start in 30s")
+                );
+            }
+            other => panic!("Expected ChatFromHost, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn drops_messages_before_the_join() {
+        let announcer = Announcer::new(watch::channel(None).1);
+        let mut stream = Rewritten::new(&b""[..], announcer.injector());
+        announcer.say("Nobody is there");
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+}
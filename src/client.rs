@@ -1,153 +1,805 @@
-use std::sync::Arc;
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
 
 use binrw::NullString;
-use iroh::{Endpoint, EndpointAddr, endpoint::Connection};
+use iroh::{
+    Endpoint, EndpointAddr, PublicKey,
+    endpoint::{Connection, ConnectionError, WriteError},
+};
 use tokio::{
-    io::copy_bidirectional,
-    net::{TcpListener, UdpSocket},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{
+        broadcast::{self, Receiver, Sender},
+        watch,
+    },
+    task::JoinHandle,
+    time::{self, sleep, timeout_at},
 };
+use tracing::{debug, info, warn};
 
 use crate::{
-    handle_error_displayed,
-    packets::Wc3UdpMessageType,
-    utils::{ALPN, APP_NAME, LOCALHOST_WC3_ADDR, ZERO_SOCKET_ADDR, try_serialize},
+    error::{CLOSE_HOST_SHUTDOWN, CLOSE_PROTOCOL_ERROR, Error, ProtocolError},
+    events::{ClientEvent, LobbyEnd, LobbyInfo},
+    game::{GameTracker, Side, TrackerEvent},
+    gproxy::{self, GproxySessions, GproxyStream},
+    logging::{PacketLogger, log_udp_packet},
+    map_transfer::MapFetcher,
+    maps::{MapCheck, MapStatus, found_map, map_status},
+    packets::{GenerableWc3UdpMessageType, ServerClosed, Wc3UdpMessageType, take_packet},
+    path::{PathEvent, current_path, monitor_path},
+    resume::{Hello, RESUME_TIMEOUT, Transport, TunnelLinks, open_stream, resumable},
+    stats::TunnelRegistry,
+    status::{ClientStatus, PeerStatus},
+    tap::Tapped,
+    utils::{ALPN, APP_NAME, LOCALHOST_WC3_ADDR, ZERO_SOCKET_ADDR, truncate, try_serialize},
 };
 
-pub async fn run_client(address: EndpointAddr) {
-    let endpoint = handle_error_displayed!(Endpoint::bind().await, "Can't create endpoint: {}");
+/// Settings for a [`Client`].
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// The host to connect to.
+    pub host: EndpointAddr,
+    /// Where the local WC3 instance listens for lobby packets.
+    pub game_addr: SocketAddr,
+    /// The password of the host, if it has one.
+    pub password: Option<String>,
+    /// Follow the game in the forwarded game traffic and report it as [`ClientEvent::Game`].
+    pub decode_game_traffic: bool,
+    /// Rejoin a running game with GProxy reconnects when the game host supports them (GHost++).
+    pub gproxy_reconnect: bool,
+    /// The `Maps` folder of the local WC3. The map of every lobby is looked up in it and reported as
+    /// [`ClientEvent::MapChecked`].
+    pub maps_dir: Option<PathBuf>,
+    /// Download missing maps from hosts that offer them, see [`crate::HostConfig::maps_dir`].
+    pub download_maps: bool,
+    /// Keep downloaded maps in this folder, so the same map is not downloaded again.
+    pub map_cache_dir: Option<PathBuf>,
+}
 
-    let connection = handle_error_displayed!(
-        endpoint.connect(address, ALPN).await,
-        "Can't connect to host: {}"
-    );
+impl ClientConfig {
+    pub fn new(host: EndpointAddr) -> Self {
+        ClientConfig {
+            host,
+            game_addr: LOCALHOST_WC3_ADDR,
+            password: None,
+            decode_game_traffic: true,
+            gproxy_reconnect: true,
+            maps_dir: None,
+            download_maps: true,
+            map_cache_dir: None,
+        }
+    }
+}
+
+/// Longest wait between two attempts to reach the host again.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// A client connected to a host. Forwards the host's lobby to the local game until stopped.
+pub struct Client {
+    endpoint: Endpoint,
+    /// The current connection to the host. Replaced when the client reconnects.
+    connection: watch::Receiver<Connection>,
+    /// Why the connection ended for good, once it did.
+    closed: watch::Receiver<Option<ConnectionError>>,
+    events: Sender<ClientEvent>,
+    /// The error that made the client close the connection, if any.
+    failure: Arc<Mutex<Option<Error>>>,
+    lobby: CurrentLobby,
+    map: CurrentMap,
+    tunnels: TunnelRegistry,
+    connected_at: Instant,
+    game: Option<Arc<GameTracker>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+/// The lobby the host announced last.
+type CurrentLobby = Arc<Mutex<Option<LobbyInfo>>>;
+/// The map of the current lobby, once it was looked up.
+type CurrentMap = Arc<Mutex<Option<MapStatus>>>;
+
+impl Client {
+    /// Binds a new endpoint and connects to the host with it.
+    pub async fn connect(config: ClientConfig) -> Result<Client, Error> {
+        let endpoint = Endpoint::bind().await.map_err(Error::EndpointBind)?;
+        Self::connect_on(endpoint, config).await
+    }
+
+    /// Connects to the host with an existing endpoint.
+    pub async fn connect_on(endpoint: Endpoint, config: ClientConfig) -> Result<Client, Error> {
+        let connection = endpoint
+            .connect(config.host.clone(), ALPN)
+            .await
+            .map_err(Error::Connect)?;
+        let connected_at = Instant::now();
+        let password = config.password.unwrap_or_default();
+        send_password(&connection, &password).await?;
+
+        let tcp_client = TcpListener::bind(ZERO_SOCKET_ADDR)
+            .await
+            .map_err(Error::Socket)?;
+        let random_port = tcp_client.local_addr().map_err(Error::Socket)?.port();
+
+        let local_udp_sender = Arc::new(
+            UdpSocket::bind(ZERO_SOCKET_ADDR)
+                .await
+                .map_err(Error::Socket)?,
+        );
+        local_udp_sender
+            .connect(config.game_addr)
+            .await
+            .map_err(Error::Socket)?;
+
+        let (events, _) = broadcast::channel(64);
+        let failure = Arc::new(Mutex::new(None));
+        let lobby = CurrentLobby::default();
+        let map = CurrentMap::default();
+        let tunnels = TunnelRegistry::default();
+        let links = TunnelLinks::default();
+        let gproxy_sessions = GproxySessions::default();
+        let game = config.decode_game_traffic.then(|| {
+            let game_events = events.clone();
+            GameTracker::new(move |event| {
+                if let TrackerEvent::Game(event) = event {
+                    let _ = game_events.send(ClientEvent::Game(event));
+                }
+            })
+        });
+        let (current_connection, connection_updates) = watch::channel(connection.clone());
+        let (closed_sender, closed) = watch::channel(None);
+        let fetcher = config.download_maps.then(|| MapFetcher {
+            endpoint: endpoint.clone(),
+            host: config.host.clone(),
+            password: password.clone(),
+            cache_dir: config.map_cache_dir,
+        });
+        let session = Session {
+            events: events.clone(),
+            failure: failure.clone(),
+            lobby: lobby.clone(),
+            maps_dir: config.maps_dir,
+            map: map.clone(),
+            checking_map: Arc::default(),
+            fetcher,
+            local_udp_sender,
+            tcp_port: random_port,
+            game: game.clone(),
+        };
+        let tasks = vec![
+            tokio::spawn(connect_tcp_port_to_iroh(
+                tcp_client,
+                Forwarding {
+                    connection: connection_updates.clone(),
+                    tunnels: tunnels.clone(),
+                    links: links.clone(),
+                    next_tunnel: Arc::default(),
+                    lobby: lobby.clone(),
+                    gproxy: config.gproxy_reconnect.then(|| gproxy_sessions.clone()),
+                    game: game.clone(),
+                },
+            )),
+            tokio::spawn(keep_connected(
+                endpoint.clone(),
+                Reconnect {
+                    host: config.host,
+                    password,
+                    connection: current_connection,
+                    links,
+                    gproxy: gproxy_sessions,
+                },
+                session,
+                tunnels.clone(),
+                connected_at,
+                closed_sender,
+            )),
+        ];
+
+        Ok(Client {
+            endpoint,
+            connection: connection_updates,
+            closed,
+            events,
+            failure,
+            lobby,
+            map,
+            tunnels,
+            connected_at,
+            game,
+            tasks,
+        })
+    }
+
+    /// The id of this client, as seen by the host.
+    pub fn id(&self) -> PublicKey {
+        self.endpoint.id()
+    }
+
+    /// Subscribes to the events of this client. Only events sent after subscribing are received.
+    pub fn subscribe(&self) -> Receiver<ClientEvent> {
+        self.events.subscribe()
+    }
 
-    println!("Connection established");
+    /// What the client is doing right now.
+    pub fn status(&self) -> ClientStatus {
+        let connection = self.connection.borrow().clone();
+        ClientStatus {
+            lobby: self.lobby.lock().unwrap().clone(),
+            map: self.map.lock().unwrap().clone(),
+            host: PeerStatus {
+                id: connection.remote_id(),
+                path: current_path(&connection),
+                connected_at: self.connected_at,
+                traffic: self.tunnels.traffic(connection.remote_id()),
+                player: None,
+            },
+            tunnels: self.tunnels.snapshot(),
+            game: self.game.as_ref().and_then(|game| game.phase()),
+        }
+    }
 
-    let tcp_client = handle_error_displayed!(
-        TcpListener::bind(ZERO_SOCKET_ADDR).await,
-        "Can't create TCP client: {}"
+    /// Waits until the connection to the host is closed and won't come back.
+    /// Returns an error if the connection was not closed on purpose by either side.
+    pub async fn closed(&self) -> Result<(), Error> {
+        let mut closed = self.closed.clone();
+        let reason = match closed.wait_for(Option::is_some).await {
+            Ok(reason) => reason.clone(),
+            Err(_) => None,
+        };
+        let reason = match reason {
+            Some(reason) => reason,
+            None => self.connection.borrow().clone().closed().await,
+        };
+        if let Some(failure) = self.failure.lock().unwrap().take() {
+            return Err(failure);
+        }
+        Error::from_close(reason)
+    }
+
+    /// Disconnects from the host.
+    pub async fn stop(self) {
+        self.connection
+            .borrow()
+            .close(0u32.into(), b"client stopped");
+        self.endpoint.close().await;
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// The host expects the password as the first stream of every connection.
+pub(crate) async fn send_password(connection: &Connection, password: &str) -> Result<(), Error> {
+    let mut send = connection
+        .open_uni()
+        .await
+        .map_err(ProtocolError::OpenStream)?;
+    send.write_all(password.as_bytes())
+        .await
+        .map_err(ProtocolError::Write)?;
+    send.finish()
+        .map_err(|_| ProtocolError::Write(WriteError::ClosedStream))?;
+    Ok(())
+}
+
+/// What the tasks of every connection to the host share.
+#[derive(Clone)]
+struct Session {
+    events: Sender<ClientEvent>,
+    failure: Arc<Mutex<Option<Error>>>,
+    lobby: CurrentLobby,
+    maps_dir: Option<PathBuf>,
+    map: CurrentMap,
+    /// The map that is being looked for in the Maps folder.
+    checking_map: Arc<Mutex<Option<String>>>,
+    fetcher: Option<MapFetcher>,
+    local_udp_sender: Arc<UdpSocket>,
+    tcp_port: u16,
+    game: Option<Arc<GameTracker>>,
+}
+
+impl Session {
+    /// Starts reporting the path of `connection` and forwarding its lobby packets.
+    fn serve(&self, connection: &Connection) -> ConnectionTasks {
+        let path_events = self.events.clone();
+        let session = self.clone();
+        let udp_connection = connection.clone();
+        ConnectionTasks(vec![
+            tokio::spawn(monitor_path(
+                connection.clone(),
+                "host".to_string(),
+                move |event| {
+                    let _ = path_events.send(match event {
+                        PathEvent::Changed(path) => ClientEvent::PathChanged(path),
+                        PathEvent::StillRelayed => ClientEvent::StillRelayed,
+                    });
+                },
+            )),
+            tokio::spawn(async move {
+                let result = forward_udp_packets_to_game(&udp_connection, &session).await;
+                if let Err(e) = result {
+                    //Without lobby packets the client is useless, give up
+                    *session.failure.lock().unwrap() = Some(e);
+                    udp_connection.close(CLOSE_PROTOCOL_ERROR, b"protocol error");
+                }
+            }),
+        ])
+    }
+}
+
+/// The tasks of one connection. Stopped when dropped.
+struct ConnectionTasks(Vec<JoinHandle<()>>);
+
+impl Drop for ConnectionTasks {
+    fn drop(&mut self) {
+        for task in &self.0 {
+            task.abort();
+        }
+    }
+}
+
+/// What it takes to get a broken connection back.
+struct Reconnect {
+    host: EndpointAddr,
+    password: String,
+    connection: watch::Sender<Connection>,
+    links: TunnelLinks,
+    gproxy: GproxySessions,
+}
+
+impl Reconnect {
+    /// Whether the connection is worth getting back, because game connections wait for it.
+    fn is_needed(&self, host: PublicKey) -> bool {
+        self.gproxy.is_active() || !self.links.of_peer(host).is_empty()
+    }
+
+    /// Connects to the host again until it works or the game connections stop waiting.
+    async fn connect(&self, endpoint: &Endpoint) -> Option<Connection> {
+        let wait = if self.gproxy.is_active() {
+            gproxy::RECONNECT_TIMEOUT
+        } else {
+            RESUME_TIMEOUT
+        };
+        let deadline = time::Instant::now() + wait;
+        let mut delay = Duration::from_millis(500);
+        loop {
+            let attempt = timeout_at(deadline, async {
+                let connection = endpoint.connect(self.host.clone(), ALPN).await;
+                let connection = connection.inspect_err(|e| debug!("Reconnecting failed: {e}"));
+                let connection = connection.ok()?;
+                send_password(&connection, &self.password).await.ok()?;
+                Some(connection)
+            })
+            .await;
+            match attempt {
+                Ok(Some(connection)) => return Some(connection),
+                Ok(None) if time::Instant::now() + delay < deadline => {}
+                _ => return None,
+            }
+            sleep(delay).await;
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    }
+
+    /// Continues the game connections on a new connection to the host.
+    async fn resume_tunnels(&self, connection: &Connection) {
+        let host = connection.remote_id();
+        for (id, link) in self.links.of_peer(host) {
+            let hello = Hello::Resume {
+                tunnel: id,
+                received: link.received(),
+            };
+            match open_stream(connection, hello).await {
+                Ok(transport) => {
+                    link.attach(transport).await;
+                }
+                Err(ProtocolError::UnknownTunnel) => {
+                    debug!("The host no longer knows TCP connection {id}");
+                    self.links.remove(host, id);
+                }
+                Err(e) => warn!("Can't resume TCP connection {id}: {e}"),
+            }
+        }
+    }
+}
+
+/// Serves the connection to the host. If it breaks while game connections are open, connects
+/// again and continues them. Reports the end once the connection is gone for good.
+async fn keep_connected(
+    endpoint: Endpoint,
+    reconnect: Reconnect,
+    session: Session,
+    tunnels: TunnelRegistry,
+    connected_at: Instant,
+    closed: watch::Sender<Option<ConnectionError>>,
+) {
+    let mut connection = reconnect.connection.borrow().clone();
+    let host = connection.remote_id();
+    let mut tasks = session.serve(&connection);
+    let reason = loop {
+        let reason = connection.closed().await;
+        if !resumable(&reason) || !reconnect.is_needed(host) {
+            break reason;
+        }
+        warn!("Lost connection to host, reconnecting: {reason}");
+        let _ = session.events.send(ClientEvent::ConnectionLost);
+        let Some(new_connection) = reconnect.connect(&endpoint).await else {
+            warn!("Could not reach the host again");
+            break reason;
+        };
+        info!("Reconnected to host");
+        reconnect.resume_tunnels(&new_connection).await;
+        tasks = session.serve(&new_connection);
+        reconnect.connection.send_replace(new_connection.clone());
+        connection = new_connection;
+        let _ = session.events.send(ClientEvent::Reconnected);
+    };
+    drop(tasks);
+    reconnect.links.remove_peer(host);
+
+    let traffic = tunnels.traffic(host);
+    info!(
+        "Disconnected from host after {:.1?}, {} game connections, {traffic}",
+        connected_at.elapsed(),
+        traffic.streams
     );
+    if matches!(&reason, ConnectionError::ApplicationClosed(close) if close.error_code == CLOSE_HOST_SHUTDOWN)
+    {
+        info!("The host shut down");
+        let _ = session.events.send(ClientEvent::HostShutDown);
+    }
+    //The host might not have been able to close the lobby, don't leave a dead one in the game
+    let open_lobby = session.lobby.lock().unwrap().take();
+    session.map.lock().unwrap().take();
+    if let Some(open_lobby) = open_lobby {
+        let closed = GenerableWc3UdpMessageType::ServerClosed(ServerClosed {
+            game_id: open_lobby.game_id,
+        });
+        if let Some(packet) = try_serialize(&closed) {
+            log_udp_packet("client -> game", &packet);
+            let _ = session.local_udp_sender.send(&packet).await;
+        }
+        let _ = session
+            .events
+            .send(ClientEvent::LobbyClosed(lobby_end(session.game.as_deref())));
+    }
+    closed.send_replace(Some(reason));
+    let _ = session.events.send(ClientEvent::Disconnected);
+}
 
-    let random_port = handle_error_displayed!(
-        tcp_client.local_addr(),
-        "Can't determine local TCP port: {}"
-    )
-    .port();
+fn lobby_end(game: Option<&GameTracker>) -> LobbyEnd {
+    game.map_or(LobbyEnd::Unknown, GameTracker::lobby_end)
+}
 
-    tokio::spawn(connect_tcp_port_to_iroh(tcp_client, connection.clone()));
-    if let Err(_) = start_forwarding_udp_packets_to_game(connection.clone(), random_port).await {
-        return;
+/// What the game connections of the local game share.
+#[derive(Clone)]
+struct Forwarding {
+    connection: watch::Receiver<Connection>,
+    tunnels: TunnelRegistry,
+    links: TunnelLinks,
+    next_tunnel: Arc<AtomicU64>,
+    lobby: CurrentLobby,
+    /// `None` if GProxy reconnects are turned off.
+    gproxy: Option<GproxySessions>,
+    game: Option<Arc<GameTracker>>,
+}
+
+impl Forwarding {
+    fn next_tunnel(&self) -> u64 {
+        self.next_tunnel.fetch_add(1, Ordering::Relaxed) + 1
     }
 
-    connection.closed().await;
-    println!("The server has closed the connection");
+    /// Opens a tunnel to the reconnect port of the game host, once there is a connection to the host.
+    async fn rejoin(&self, id: u64) -> Option<Transport> {
+        let deadline = time::Instant::now() + gproxy::RECONNECT_TIMEOUT;
+        let mut connection = self.connection.clone();
+        loop {
+            let current = connection.borrow_and_update().clone();
+            if current.close_reason().is_none() {
+                match open_stream(&current, Hello::Rejoin(id)).await {
+                    Ok(transport) => return Some(transport),
+                    Err(ProtocolError::UnknownTunnel) => {
+                        warn!("The host can't forward the reconnect to the game host");
+                        return None;
+                    }
+                    Err(e) => debug!("Can't rejoin the game yet: {e}"),
+                }
+            }
+            //Wait for the next connection to the host
+            match timeout_at(deadline, connection.changed()).await {
+                Ok(Ok(())) => {}
+                _ => return None,
+            }
+        }
+    }
 }
 
-async fn connect_tcp_port_to_iroh(local_socket: TcpListener, web_connection: Connection) {
+async fn connect_tcp_port_to_iroh(local_socket: TcpListener, forwarding: Forwarding) {
     loop {
         match local_socket.accept().await {
-            Ok((mut local_tcp_stream, _)) => {
-                let cloned_conn = web_connection.clone();
+            Ok((local_tcp_stream, _)) => {
+                let forwarding = forwarding.clone();
                 tokio::spawn(async move {
-                    let mut web_stream = {
-                        let (send_stream, recv_stream) = handle_error_displayed!(
-                            cloned_conn.open_bi().await,
-                            "Failed to open stream to host: {}"
-                        );
-                        tokio::io::join(recv_stream, send_stream)
-                    };
-
-                    if let Err(e) = copy_bidirectional(&mut web_stream, &mut local_tcp_stream).await
-                    {
-                        eprintln!("TCP port forwarding stopped with error: {}", e);
+                    if let Err(e) = forward_tcp_stream(local_tcp_stream, &forwarding).await {
+                        warn!("{e}");
                     }
                 });
             }
             Err(e) => {
-                eprintln!("Can't accept TCP stream: {}", e);
+                warn!("Can't accept TCP stream: {}", e);
             }
         }
     }
 }
 
-async fn start_forwarding_udp_packets_to_game(
-    connection: Connection,
-    tcp_port: u16,
-) -> Result<(), ()> {
-    //No loop needed, as this is a single stream per connection
-    let mut udp_web_recv = connection
-        .accept_uni()
-        .await
-        .map_err(|e| eprintln!("Can't accept UDP stream from host: {}", e))?;
+async fn forward_tcp_stream(
+    local_tcp_stream: TcpStream,
+    forwarding: &Forwarding,
+) -> Result<(), Error> {
+    debug!("Forwarding new TCP connection of the local game");
+    let web_connection = forwarding.connection.borrow().clone();
+    let host = web_connection.remote_id();
+    let tunnel = forwarding.tunnels.open(host);
+    let game = forwarding
+        .game
+        .as_ref()
+        .map(|game| game.tap(Side::Player, host));
+    let local_tcp_stream = Tapped::new(
+        local_tcp_stream,
+        ((PacketLogger::new("host"), tunnel.byte_counter()), game),
+    );
+    let supports_gproxy = forwarding
+        .lobby
+        .lock()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|lobby| lobby.gproxy);
+    let mut local_tcp_stream = GproxyStream::new(
+        local_tcp_stream,
+        supports_gproxy && forwarding.gproxy.is_some(),
+        forwarding.gproxy.clone().unwrap_or_default(),
+    );
 
-    let local_udp_sender = Arc::from(
-        UdpSocket::bind(ZERO_SOCKET_ADDR)
-            .await
-            .map_err(|e| eprintln!("Can't create UDP sender: {}", e))?,
+    let mut id = forwarding.next_tunnel();
+    let resumable = forwarding
+        .links
+        .register(host, id)
+        .ok_or(ProtocolError::TunnelInUse)?;
+    let transport = open_stream(&web_connection, Hello::Open(id)).await?;
+    let mut result = resumable.run(&mut local_tcp_stream, transport).await;
+    //The tunnel is gone for good, but the game host may still take the player back
+    while let Err(e) = &result
+        && local_tcp_stream.can_reconnect()
+    {
+        info!("TCP connection {id} to the host broke ({e}), rejoining the game");
+        id = forwarding.next_tunnel();
+        let Some(transport) = forwarding.rejoin(id).await else {
+            break;
+        };
+        let Some(resumable) = forwarding.links.register(host, id) else {
+            break;
+        };
+        local_tcp_stream.reconnect();
+        result = resumable.run(&mut local_tcp_stream, transport).await;
+    }
+    let (to_game, from_game) = result?;
+    debug!(
+        "TCP connection of the local game ended: {to_game} bytes to game, {from_game} bytes from game"
     );
-    local_udp_sender
-        .connect(LOCALHOST_WC3_ADDR)
-        .await
-        .map_err(|e| eprintln!("Can't connect local UDP socket to local game: {}", e))?;
+    Ok(())
+}
 
+/// Looks for the map of the lobby in the Maps folder and compares it with `checksum`, once per map.
+/// Runs in the background, reading the map can take a while.
+fn check_lobby_map(session: &Session, map_name: &str, checksum: Option<u32>) {
+    let Some(maps_dir) = session.maps_dir.clone() else {
+        return;
+    };
+    let known = session
+        .map
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|map| map.map_name.clone());
+    if known.as_deref() == Some(map_name) {
+        return;
+    }
+    //The lobby is announced every second, while the last check may still run
+    let checking = session
+        .checking_map
+        .lock()
+        .unwrap()
+        .replace(map_name.to_string());
+    if checking.as_deref() == Some(map_name) {
+        return;
+    }
+    let session = session.clone();
+    let map_name = map_name.to_string();
     tokio::spawn(async move {
-        let forward_package = async |packet: &[u8]| {
-            let _ = local_udp_sender.send(packet).await; //Ignore errors, as the game might not be running and the error behavior is unpredictable
+        let name = map_name.clone();
+        let status =
+            tokio::task::spawn_blocking(move || map_status(&maps_dir, &name, checksum)).await;
+        {
+            let mut checking = session.checking_map.lock().unwrap();
+            if checking.as_deref() == Some(map_name.as_str()) {
+                checking.take();
+            }
+        }
+        let status = match status {
+            Ok(Ok(status)) => status,
+            Ok(Err(e)) => {
+                warn!("Can't look for the map in the Maps folder: {e}");
+                return;
+            }
+            Err(_) => return,
         };
+        //The lobby may have closed or changed its map in the meantime
+        let lobby_map = session
+            .lobby
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|lobby| lobby.map_name.clone());
+        if lobby_map.as_deref() != Some(map_name.as_str()) {
+            return;
+        }
+        info!("{status}");
+        let missing = status.check == MapCheck::Missing;
+        *session.map.lock().unwrap() = Some(status.clone());
+        let _ = session.events.send(ClientEvent::MapChecked(status));
+        if missing && let Some(fetcher) = session.fetcher.clone() {
+            tokio::spawn(download_map(session, fetcher, map_name, checksum));
+        }
+    });
+}
 
-        let mut server_detected = false;
-
-        let mut handle_packet = async |data: &[u8]| {
-            match Wc3UdpMessageType::detect(data) {
-                Some(Wc3UdpMessageType::QueryForGamesResponse(mut response)) => {
-                    if !server_detected {
-                        println!(
-                            "Found game on host: {} {:?}[V1.{}]",
-                            response.game_name, response.game_type, response.game_version
-                        );
-                        server_detected = true;
+async fn download_map(
+    session: Session,
+    fetcher: MapFetcher,
+    map_name: String,
+    checksum: Option<u32>,
+) {
+    let Some(maps_dir) = session.maps_dir.clone() else {
+        return;
+    };
+    info!("Asking the host for map {map_name}");
+    let path = match fetcher.fetch(&maps_dir, &map_name).await {
+        Ok(Some(path)) => path,
+        Ok(None) => {
+            info!("The host does not offer map {map_name}");
+            return;
+        }
+        Err(e) => {
+            warn!("Can't download map {map_name}: {e}");
+            return;
+        }
+    };
+    let status =
+        match tokio::task::spawn_blocking(move || found_map(&maps_dir, &map_name, path, checksum))
+            .await
+        {
+            Ok(status) => status,
+            Err(_) => return,
+        };
+    let mut map = session.map.lock().unwrap();
+    //Only if it is still the map of the lobby
+    if map
+        .as_ref()
+        .is_some_and(|map| map.map_name == status.map_name)
+    {
+        info!("Downloaded: {status}");
+        *map = Some(status.clone());
+        drop(map);
+        let _ = session.events.send(ClientEvent::MapDownloaded(status));
+    } else {
+        debug!(
+            "Downloaded map {}, but the lobby has another one now",
+            status.map_name
+        );
+    }
+}
+
+async fn forward_udp_packets_to_game(
+    connection: &Connection,
+    session: &Session,
+) -> Result<(), Error> {
+    let Session {
+        events,
+        lobby,
+        local_udp_sender,
+        tcp_port,
+        game,
+        ..
+    } = session;
+    let game = game.as_deref();
+    //No loop needed, as this is a single stream per connection
+    let mut udp_web_recv = match connection.accept_uni().await {
+        Ok(stream) => stream,
+        //The connection was closed before the host sent anything
+        Err(_) if connection.close_reason().is_some() => return Ok(()),
+        Err(e) => return Err(ProtocolError::AcceptStream(e).into()),
+    };
+
+    let forward_package = async |packet: &[u8]| {
+        log_udp_packet("client -> game", packet);
+        let _ = local_udp_sender.send(packet).await; //Ignore errors, as the game might not be running and the error behavior is unpredictable
+    };
+
+    let handle_packet = async |data: &[u8]| -> Result<(), Error> {
+        log_udp_packet("host -> client", data);
+        match Wc3UdpMessageType::detect(data) {
+            Some(Wc3UdpMessageType::QueryForGamesResponse(mut response)) => {
+                let info = LobbyInfo::from(&response);
+                let (map_name, map_checksum) = (info.map_name.clone(), info.map_checksum);
+                let previous = lobby.lock().unwrap().replace(info.clone());
+                match previous {
+                    None => {
+                        if let Some(game) = game {
+                            game.lobby_opened();
+                        }
+                        info!("Found game on host: {info}");
+                        let _ = events.send(ClientEvent::LobbyFound(info));
                     }
-                    response.tcp_port = tcp_port;
-                    let mut new_name = format!("[{}] {}", APP_NAME, response.game_name);
-                    new_name.truncate(31); //Trim to max 31 chars for WC3 size limit
-                    response.packet_size -= response.game_name.len() as u16;
-                    response.packet_size += new_name.len() as u16;
-                    response.game_name = NullString::from(new_name);
-                    if let Some(serialized) = &try_serialize(&response) {
-                        forward_package(serialized).await;
-                    } else {
-                        eprintln!("Failed to serialize modified QueryForGamesResponse packet");
+                    Some(previous) if previous != info => {
+                        debug!("Lobby changed: {info}");
+                        let _ = events.send(ClientEvent::LobbyUpdated(info));
                     }
+                    Some(_) => {}
                 }
-                Some(Wc3UdpMessageType::NewServerHosted) => forward_package(data).await,
-                Some(Wc3UdpMessageType::ServerCanceled) => {
-                    println!(
-                        "The lobby is no longer available. The game was started or canceled by the host."
-                    );
-                    server_detected = false;
-                    forward_package(data).await;
+                response.tcp_port = *tcp_port;
+                let new_name = format!("[{}] {}", APP_NAME, response.game_name);
+                let new_name = truncate(&new_name, 31).to_string(); //Trim to max 31 bytes for WC3 size limit
+                response.packet_size -= response.game_name.len() as u16;
+                response.packet_size += new_name.len() as u16;
+                response.game_name = NullString::from(new_name);
+                let serialized = try_serialize(&response)
+                    .ok_or(ProtocolError::Serialize("QueryForGamesResponse"))?;
+                forward_package(&serialized).await;
+                if let Some(map_name) = map_name {
+                    check_lobby_map(session, &map_name, map_checksum);
                 }
-                _ => {}
-            };
+            }
+            Some(Wc3UdpMessageType::NewServerHosted) => forward_package(data).await,
+            Some(Wc3UdpMessageType::ServerCanceled) => {
+                let end = lobby_end(game);
+                info!("The lobby is no longer available: {end:?}");
+                lobby.lock().unwrap().take();
+                session.map.lock().unwrap().take();
+                let _ = events.send(ClientEvent::LobbyClosed(end));
+                forward_package(data).await;
+            }
+            _ => {}
         };
+        Ok(())
+    };
 
-        let mut buf = [0; 1024];
-        loop {
-            match udp_web_recv.read(&mut buf).await {
-                Ok(Some(len)) => {
-                    let data = &buf[0..len];
-                    handle_packet(data).await;
-                }
-                Ok(None) => {
-                    //Stream closed
-                    break;
-                }
-                Err(e) => {
-                    eprintln!("Can't read from UDP web tunnel: {}", e);
-                    break;
+    let mut buf = [0; 1024];
+    let mut pending = Vec::new();
+    loop {
+        match udp_web_recv.read(&mut buf).await {
+            Ok(Some(len)) => {
+                //A read can contain several packets or only a part of one
+                pending.extend_from_slice(&buf[0..len]);
+                while let Some(packet) = take_packet(&mut pending)? {
+                    handle_packet(&packet).await?;
                 }
             }
+            Ok(None) => {
+                //Stream closed
+                return Ok(());
+            }
+            Err(_) if connection.close_reason().is_some() => return Ok(()),
+            Err(e) => return Err(ProtocolError::Read(e).into()),
         }
-    });
-    Ok(())
+    }
 }
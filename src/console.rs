@@ -0,0 +1,233 @@
+use std::{
+    io::{self, BufRead},
+    str::FromStr,
+    thread,
+};
+
+use iroh::PublicKey;
+use simple_wc3::{Host, status::PeerStatus};
+use tokio::sync::mpsc;
+
+const HELP: &str = "\
+Commands:
+  clients           List the connected clients
+  kick <id>         Disconnect a client, it may connect again
+  ban <id>          Disconnect a client and refuse it from now on
+  lobby             Show the lobby and the phase of the local game
+  stats             Show the traffic per client and game connection
+  password [<new>]  Set the password for new clients, none to remove it
+  say <message>     Show a message to the tunneled players in the game
+  pings             Show the real pings of the players, in the lobby as well
+  start             Start the game of the virtual host
+  quit              Shut down the host
+<id> can be the start of a client id or the name of its player, as long as only one client matches.";
+
+/// Reads host commands from stdin until `quit` is entered.
+/// Never returns if stdin is closed, the host then runs until Ctrl+C.
+pub async fn run(host: &Host) -> io::Result<()> {
+    let mut lines = read_lines();
+    while let Some(line) = lines.recv().await {
+        let line = line?;
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            continue;
+        };
+        let argument = words.next();
+        match (command, argument) {
+            ("quit" | "exit", _) => return Ok(()),
+            ("help", _) => println!("{HELP}"),
+            ("clients", _) => print_clients(host),
+            ("kick", Some(id)) => match find_client(host, id) {
+                Ok(id) => {
+                    let label = client_label(host, &id);
+                    if host.kick(id) {
+                        println!("Kicked {label}");
+                    } else {
+                        println!("{label} is not connected");
+                    }
+                }
+                Err(e) => println!("{e}"),
+            },
+            ("ban", Some(id)) => match find_client(host, id) {
+                Ok(id) => {
+                    let label = client_label(host, &id);
+                    host.ban(id);
+                    println!("Banned {label}");
+                }
+                Err(e) => println!("{e}"),
+            },
+            ("lobby", _) => {
+                let status = host.status();
+                match status.lobby {
+                    Some(lobby) => {
+                        println!("{lobby}");
+                        println!("Map: {}", lobby.map_file_name().unwrap_or("unknown"));
+                        println!("Players: {}/{}", lobby.players, lobby.player_slots);
+                    }
+                    None => println!("No lobby open"),
+                }
+                if let Some(phase) = status.game {
+                    println!("Game: {phase}");
+                }
+            }
+            ("stats", _) => print_stats(host),
+            ("password", password) => {
+                host.set_password(password.map(str::to_string));
+                match password {
+                    Some(_) => println!("Password changed, connected clients stay"),
+                    None => println!("Password removed"),
+                }
+            }
+            ("say", Some(_)) => {
+                let message = line
+                    .trim()
+                    .split_once(char::is_whitespace)
+                    .map(|(_, message)| message.trim());
+                host.say(message.unwrap_or_default());
+            }
+            ("pings", _) => match host.pings() {
+                Some(pings) => {
+                    println!("{pings}");
+                    host.say(&pings);
+                }
+                None => println!("No player joined through the tunnel yet"),
+            },
+            ("start", _) => {
+                if host.start_game().await {
+                    println!("Starting the game");
+                } else {
+                    println!(
+                        "Can't start. Only a virtual game can be started, once a player with the map is in its lobby."
+                    );
+                }
+            }
+            _ => println!("Unknown command. Type `help` to see all commands."),
+        }
+    }
+    //Stdin is closed, keep hosting
+    std::future::pending().await
+}
+
+/// Reading stdin blocks, so it gets its own thread.
+fn read_lines() -> mpsc::UnboundedReceiver<io::Result<String>> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+fn print_clients(host: &Host) {
+    let clients = host.status().clients;
+    if clients.is_empty() {
+        println!("No clients connected");
+    }
+    for client in clients {
+        println!("{}", describe_client(&client));
+    }
+}
+
+fn describe_client(client: &PeerStatus) -> String {
+    let player = match &client.player {
+        Some(player) => format!(" {player}"),
+        None => String::new(),
+    };
+    match client.path.rtt {
+        Some(rtt) => format!(
+            "{}{player} {} {} ms",
+            client.id,
+            client.path.kind,
+            rtt.as_millis()
+        ),
+        None => format!("{}{player} {}", client.id, client.path.kind),
+    }
+}
+
+/// The client id, followed by the player name if it is known.
+fn client_label(host: &Host, id: &PublicKey) -> String {
+    let status = host.status();
+    let player = status
+        .clients
+        .iter()
+        .find(|client| client.id == *id)
+        .and_then(|client| client.player.as_ref());
+    match player {
+        Some(player) => format!("{id} ({})", player.name),
+        None => id.to_string(),
+    }
+}
+
+fn player_name(client: &PeerStatus) -> &str {
+    client
+        .player
+        .as_ref()
+        .map_or("unknown player", |player| &player.name)
+}
+
+fn print_stats(host: &Host) {
+    let status = host.status();
+    println!(
+        "{} clients, {} open game connections",
+        status.clients.len(),
+        status.tunnels.len()
+    );
+    for client in &status.clients {
+        println!(
+            "Client {} ({}): online for {}s, {} game connections, {}",
+            client.id.fmt_short(),
+            player_name(client),
+            client.connected_at.elapsed().as_secs(),
+            client.traffic.streams,
+            client.traffic
+        );
+    }
+    for tunnel in status.tunnels {
+        let name = status
+            .clients
+            .iter()
+            .find(|client| client.id == tunnel.peer)
+            .map_or("unknown player", player_name);
+        println!(
+            "#{} {} ({name}): open for {}s, {}",
+            tunnel.id,
+            tunnel.peer.fmt_short(),
+            tunnel.opened_at.elapsed().as_secs(),
+            tunnel.traffic()
+        );
+    }
+}
+
+/// Resolves a complete id, the start of the id or the player name of a connected client.
+fn find_client(host: &Host, id: &str) -> Result<PublicKey, String> {
+    if let Ok(id) = PublicKey::from_str(id) {
+        return Ok(id);
+    }
+    let clients = host.status().clients;
+    //Player names win, they are what people know each other by
+    let by_name: Vec<_> = clients
+        .iter()
+        .filter(|client| {
+            client
+                .player
+                .as_ref()
+                .is_some_and(|player| player.name.eq_ignore_ascii_case(id))
+        })
+        .collect();
+    if let [client] = by_name.as_slice() {
+        return Ok(client.id);
+    }
+    let prefix = id.to_lowercase();
+    let matches: Vec<_> = clients
+        .iter()
+        .filter(|client| client.id.to_string().starts_with(&prefix))
+        .collect();
+    match matches.as_slice() {
+        [client] => Ok(client.id),
+        [] => Err(format!("No connected client matches {id}")),
+        _ => Err(format!("{id} matches several clients, type more of the id")),
+    }
+}
@@ -0,0 +1,419 @@
+use std::{
+    collections::VecDeque,
+    io, thread,
+    time::{Duration, Instant},
+};
+
+use iroh::PublicKey;
+use ratatui::{
+    DefaultTerminal, Frame,
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Modifier, Style},
+    text::Line,
+    widgets::{Block, Cell, List, Paragraph, Row, Table},
+};
+use simple_wc3::{
+    Client, ClientEvent, GamePhase, Host, LobbyInfo,
+    maps::{MapCheck, MapStatus},
+    path::{ConnectionKind, PathStatus},
+    status::{PeerStatus, TunnelStatus},
+    utils::{APP_NAME, APP_VERSION},
+};
+use tokio::{
+    sync::{broadcast::error::RecvError, mpsc},
+    time::interval,
+};
+
+use crate::{PlayerNames, client_event_message, host_event_message};
+
+/// How often the status is polled and redrawn.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(250);
+/// Number of events kept for the event list.
+const MAX_EVENTS: usize = 50;
+
+/// Why the client dashboard was closed.
+pub enum ClientExit {
+    /// The user quit, the client is still connected.
+    Quit,
+    /// The connection to the host was closed.
+    Disconnected,
+}
+
+/// Shows the host dashboard until the user quits.
+pub async fn run_host(host: &Host) -> io::Result<()> {
+    let mut events = host.subscribe();
+    let mut names = PlayerNames::default();
+    let mut dashboard = Dashboard::start()?;
+    let mut refresh = interval(REFRESH_INTERVAL);
+    let result = loop {
+        let status = host.status();
+        let draw = dashboard.terminal.draw(|frame| {
+            let [header, lobby, clients, tunnels, log] = Layout::vertical([
+                Constraint::Length(4),
+                Constraint::Length(7),
+                Constraint::Min(4),
+                Constraint::Min(4),
+                Constraint::Min(6),
+            ])
+            .areas(frame.area());
+            render_header(frame, header, "Host", &format!("Address: {}", host.id()));
+            render_lobby(frame, lobby, status.lobby.as_ref(), None, status.game);
+            render_peers(frame, clients, "Clients", &status.clients);
+            render_tunnels(frame, tunnels, "Client", &status.tunnels);
+            dashboard.log.render(frame, log);
+        });
+        if let Err(e) = draw {
+            break Err(e);
+        }
+
+        tokio::select! {
+            key = dashboard.keys.recv() => match key {
+                Some(Ok(event)) if is_quit(&event) => break Ok(()),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => break Err(e),
+                None => break Ok(()),
+            },
+            event = events.recv() => match event {
+                Ok(event) => dashboard.log.push(host_event_message(&event, &mut names)),
+                Err(RecvError::Lagged(missed)) => {
+                    dashboard.log.push(format!("{missed} events skipped"))
+                }
+                Err(RecvError::Closed) => break Ok(()),
+            },
+            _ = refresh.tick() => {}
+        }
+    };
+    dashboard.stop();
+    result
+}
+
+/// Shows the client dashboard until the user quits or the connection is closed.
+pub async fn run_client(client: &Client) -> io::Result<ClientExit> {
+    let mut events = client.subscribe();
+    let mut dashboard = Dashboard::start()?;
+    let mut refresh = interval(REFRESH_INTERVAL);
+    let result = loop {
+        let status = client.status();
+        let draw = dashboard.terminal.draw(|frame| {
+            let [header, lobby, host, tunnels, log] = Layout::vertical([
+                Constraint::Length(4),
+                Constraint::Length(8),
+                Constraint::Length(4),
+                Constraint::Min(4),
+                Constraint::Min(6),
+            ])
+            .areas(frame.area());
+            render_header(
+                frame,
+                header,
+                "Client",
+                &format!("Host: {}", status.host.id),
+            );
+            render_lobby(
+                frame,
+                lobby,
+                status.lobby.as_ref(),
+                status.map.as_ref(),
+                status.game,
+            );
+            render_peers(frame, host, "Host", std::slice::from_ref(&status.host));
+            render_tunnels(frame, tunnels, "Host", &status.tunnels);
+            dashboard.log.render(frame, log);
+        });
+        if let Err(e) = draw {
+            break Err(e);
+        }
+
+        tokio::select! {
+            key = dashboard.keys.recv() => match key {
+                Some(Ok(event)) if is_quit(&event) => break Ok(ClientExit::Quit),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => break Err(e),
+                None => break Ok(ClientExit::Quit),
+            },
+            event = events.recv() => match event {
+                Ok(ClientEvent::Disconnected) | Err(RecvError::Closed) => {
+                    break Ok(ClientExit::Disconnected)
+                }
+                Ok(event) => {
+                    if let Some(message) = client_event_message(&event) {
+                        dashboard.log.push(message);
+                    }
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    dashboard.log.push(format!("{missed} events skipped"))
+                }
+            },
+            _ = refresh.tick() => {}
+        }
+    };
+    dashboard.stop();
+    result
+}
+
+/// The terminal in raw mode and the keys pressed in it.
+struct Dashboard {
+    terminal: DefaultTerminal,
+    keys: mpsc::UnboundedReceiver<io::Result<Event>>,
+    log: EventLog,
+}
+
+impl Dashboard {
+    fn start() -> io::Result<Dashboard> {
+        let terminal = ratatui::try_init()?;
+        let (tx, keys) = mpsc::unbounded_channel();
+        //Reading terminal events blocks, so it gets its own thread.
+        //It stops once the dashboard is gone.
+        thread::spawn(move || {
+            while !tx.is_closed() {
+                match event::poll(REFRESH_INTERVAL) {
+                    Ok(true) => {
+                        if tx.send(event::read()).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(false) => {}
+                    Err(e) => {
+                        let _ = tx.send(Err(e));
+                        break;
+                    }
+                }
+            }
+        });
+        Ok(Dashboard {
+            terminal,
+            keys,
+            log: EventLog::default(),
+        })
+    }
+
+    fn stop(self) {
+        ratatui::restore();
+    }
+}
+
+fn is_quit(event: &Event) -> bool {
+    match event {
+        Event::Key(key) if key.kind == KeyEventKind::Press => {
+            key.code == KeyCode::Char('q')
+                || key.code == KeyCode::Esc
+                || (key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL))
+        }
+        _ => false,
+    }
+}
+
+/// The most recent events, newest last.
+#[derive(Default)]
+struct EventLog {
+    entries: VecDeque<(Instant, String)>,
+}
+
+impl EventLog {
+    fn push(&mut self, message: String) {
+        if self.entries.len() == MAX_EVENTS {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((Instant::now(), message));
+    }
+
+    fn render(&self, frame: &mut Frame, area: Rect) {
+        //Only the newest entries that fit, without the border
+        let visible = (area.height as usize).saturating_sub(2);
+        let skip = self.entries.len().saturating_sub(visible);
+        let items =
+            self.entries.iter().skip(skip).map(|(at, message)| {
+                format!("{:>6} ago  {message}", format_duration(at.elapsed()))
+            });
+        frame.render_widget(
+            List::new(items).block(Block::bordered().title("Events")),
+            area,
+        );
+    }
+}
+
+fn render_header(frame: &mut Frame, area: Rect, role: &str, detail: &str) {
+    let title = format!("{APP_NAME} v{APP_VERSION} - {role}");
+    let text = vec![
+        Line::from(detail.to_string()),
+        Line::from("Press q to quit").style(Style::new().add_modifier(Modifier::DIM)),
+    ];
+    frame.render_widget(
+        Paragraph::new(text).block(Block::bordered().title(title)),
+        area,
+    );
+}
+
+fn render_lobby(
+    frame: &mut Frame,
+    area: Rect,
+    lobby: Option<&LobbyInfo>,
+    map: Option<&MapStatus>,
+    game: Option<GamePhase>,
+) {
+    let availability = match map.map(|map| &map.check) {
+        Some(MapCheck::Found(_)) => " (found)",
+        Some(MapCheck::Missing) => " (missing, WC3 will download it)",
+        Some(MapCheck::Mismatch(_)) => " (other version, WC3 will download it)",
+        None => "",
+    };
+    let mut text = match lobby {
+        Some(lobby) => vec![
+            Line::from(format!("Name:    {}", lobby.game_name)),
+            Line::from(format!(
+                "Map:     {}{availability}",
+                lobby.map_file_name().unwrap_or("unknown")
+            )),
+            Line::from(format!("Players: {}/{}", lobby.players, lobby.player_slots)),
+            Line::from(format!(
+                "Version: {:?} 1.{}",
+                lobby.game_type, lobby.game_version
+            )),
+        ],
+        None => vec![Line::from("No lobby open")],
+    };
+    if lobby.is_some()
+        && let Some(info) = map.and_then(|map| map.info.as_ref())
+    {
+        text.insert(2, Line::from(format!("Details: {info}")));
+    }
+    if let Some(phase) = game {
+        text.push(Line::from(format!("Game:    {phase}")));
+    }
+    frame.render_widget(
+        Paragraph::new(text).block(Block::bordered().title("Lobby")),
+        area,
+    );
+}
+
+fn render_peers(frame: &mut Frame, area: Rect, title: &str, peers: &[PeerStatus]) {
+    let rows = peers.iter().map(|peer| {
+        Row::new([
+            Cell::from(short_id(&peer.id)),
+            Cell::from(
+                peer.player
+                    .as_ref()
+                    .map_or("-".to_string(), |player| player.to_string()),
+            ),
+            Cell::from(peer.path.kind.to_string()).style(path_style(&peer.path)),
+            Cell::from(format_rtt(&peer.path)),
+            Cell::from(format_duration(peer.connected_at.elapsed())),
+            Cell::from(peer.traffic.streams.to_string()),
+            Cell::from(format_bytes(peer.traffic.bytes_to_game)),
+            Cell::from(format_bytes(peer.traffic.bytes_from_game)),
+        ])
+    });
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Length(12),
+            Constraint::Length(24),
+            Constraint::Length(10),
+            Constraint::Length(10),
+            Constraint::Length(10),
+            Constraint::Length(8),
+            Constraint::Length(12),
+            Constraint::Length(12),
+        ],
+    )
+    .header(
+        Row::new([
+            "Id",
+            "Player",
+            "Path",
+            "RTT",
+            "Online",
+            "Streams",
+            "To game",
+            "From game",
+        ])
+        .style(Style::new().add_modifier(Modifier::BOLD)),
+    )
+    .block(Block::bordered().title(format!("{title} ({})", peers.len())));
+    frame.render_widget(table, area);
+}
+
+fn render_tunnels(frame: &mut Frame, area: Rect, peer_title: &str, tunnels: &[TunnelStatus]) {
+    let rows = tunnels.iter().map(|tunnel| {
+        Row::new([
+            Cell::from(tunnel.id.to_string()),
+            Cell::from(short_id(&tunnel.peer)),
+            Cell::from(format_duration(tunnel.opened_at.elapsed())),
+            Cell::from(format_traffic(tunnel.bytes_to_game, tunnel.packets_to_game)),
+            Cell::from(format_traffic(
+                tunnel.bytes_from_game,
+                tunnel.packets_from_game,
+            )),
+        ])
+    });
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Length(6),
+            Constraint::Length(12),
+            Constraint::Length(8),
+            Constraint::Length(22),
+            Constraint::Length(22),
+        ],
+    )
+    .header(
+        Row::new(["#", peer_title, "Open", "To game", "From game"])
+            .style(Style::new().add_modifier(Modifier::BOLD)),
+    )
+    .block(Block::bordered().title(format!("TCP tunnels ({})", tunnels.len())));
+    frame.render_widget(table, area);
+}
+
+fn short_id(id: &PublicKey) -> String {
+    id.fmt_short().to_string()
+}
+
+/// Relayed connections are highlighted, they add latency.
+fn path_style(path: &PathStatus) -> Style {
+    match path.kind {
+        ConnectionKind::Relayed => Style::new().fg(Color::Yellow),
+        ConnectionKind::Direct => Style::new().fg(Color::Green),
+        ConnectionKind::Unknown => Style::new(),
+    }
+}
+
+fn format_rtt(path: &PathStatus) -> String {
+    match path.rtt {
+        Some(rtt) => format!("{} ms", rtt.as_millis()),
+        None => "-".to_string(),
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    if seconds < 3600 {
+        format!("{}:{:02}", seconds / 60, seconds % 60)
+    } else {
+        format!(
+            "{}:{:02}:{:02}",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        )
+    }
+}
+
+fn format_traffic(bytes: u64, packets: u64) -> String {
+    format!("{} / {packets} pkt", format_bytes(bytes))
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
@@ -0,0 +1,193 @@
+use std::{fmt, io, net::SocketAddr};
+
+use iroh::endpoint::{BindError, ConnectError, ConnectionError, ReadError, VarInt, WriteError};
+
+pub use crate::mpq::MpqError;
+
+/// Close code sent to the other side when giving up because of a [`ProtocolError`].
+pub(crate) const CLOSE_PROTOCOL_ERROR: VarInt = VarInt::from_u32(1);
+/// Close code sent by a host that shuts down on purpose.
+pub(crate) const CLOSE_HOST_SHUTDOWN: VarInt = VarInt::from_u32(5);
+
+/// Errors of a [`crate::Host`] or a [`crate::Client`].
+#[derive(Debug)]
+pub enum Error {
+    /// The iroh endpoint could not be created.
+    EndpointBind(BindError),
+    /// The connection to the host could not be established.
+    Connect(ConnectError),
+    /// The game scanner could not open its UDP socket.
+    ScannerStart(io::Error),
+    /// A local TCP or UDP socket could not be created.
+    Socket(io::Error),
+    /// The local WC3 game at this address could not be reached.
+    GameConnect(SocketAddr, io::Error),
+    /// An established connection broke down without being closed by either side.
+    ConnectionLost(ConnectionError),
+    /// The tunnel protocol between host and client failed.
+    Protocol(ProtocolError),
+    /// The host closed the connection and does not want this client in the session.
+    Rejected(RejectReason),
+    /// A map file could not be read or stored.
+    MapFile(io::Error),
+    /// The map of the virtual game can't be hosted.
+    Map(MpqError),
+}
+
+/// Why a host turned a client away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    Kicked,
+    Banned,
+    WrongPassword,
+}
+
+impl Error {
+    /// Whether the connection to the host ended on purpose, judged by its close reason.
+    pub(crate) fn from_close(reason: ConnectionError) -> Result<(), Error> {
+        match reason {
+            ConnectionError::ApplicationClosed(close) => {
+                match RejectReason::from_close_code(close.error_code) {
+                    Some(reason) => Err(Error::Rejected(reason)),
+                    None => Ok(()),
+                }
+            }
+            ConnectionError::LocallyClosed => Ok(()),
+            e => Err(Error::ConnectionLost(e)),
+        }
+    }
+}
+
+impl RejectReason {
+    /// The close code the host sends with this reason.
+    pub(crate) fn close_code(self) -> VarInt {
+        match self {
+            RejectReason::Kicked => VarInt::from_u32(2),
+            RejectReason::Banned => VarInt::from_u32(3),
+            RejectReason::WrongPassword => VarInt::from_u32(4),
+        }
+    }
+
+    pub(crate) fn from_close_code(code: VarInt) -> Option<Self> {
+        [
+            RejectReason::Kicked,
+            RejectReason::Banned,
+            RejectReason::WrongPassword,
+        ]
+        .into_iter()
+        .find(|reason| reason.close_code() == code)
+    }
+}
+
+/// Failures of the streams between host and client.
+#[derive(Debug)]
+pub enum ProtocolError {
+    OpenStream(ConnectionError),
+    AcceptStream(ConnectionError),
+    Read(ReadError),
+    Write(WriteError),
+    /// Copying between a forwarded TCP connection and its stream failed.
+    Forwarding(io::Error),
+    /// Data on the UDP stream that is not a WC3 packet.
+    MalformedPacket,
+    /// A packet that can't be serialized. Contains the packet name.
+    Serialize(&'static str),
+    /// The first messages of a tunnel stream could not be exchanged.
+    Handshake(io::Error),
+    /// The other side does not know the tunnel that should be resumed.
+    UnknownTunnel,
+    /// A tunnel with this id is still open.
+    TunnelInUse,
+    /// The other side gave up the tunnel.
+    TunnelAborted,
+    /// The stream of a tunnel broke and no new one came in time.
+    ResumeTimedOut,
+    /// A downloaded map does not match what the host offered.
+    BadMap,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::EndpointBind(e) => write!(f, "Can't create endpoint: {e}"),
+            Error::Connect(e) => write!(f, "Can't connect to host: {e}"),
+            Error::ScannerStart(e) => write!(f, "Can't start game scanner: {e}"),
+            Error::Socket(e) => write!(f, "Can't create local socket: {e}"),
+            Error::GameConnect(addr, e) => write!(f, "Can't reach the WC3 game at {addr}: {e}"),
+            Error::ConnectionLost(e) => write!(f, "Connection lost: {e}"),
+            Error::Protocol(e) => write!(f, "Tunnel protocol error: {e}"),
+            Error::Rejected(reason) => write!(f, "Rejected by host: {reason}"),
+            Error::MapFile(e) => write!(f, "Can't read or store the map: {e}"),
+            Error::Map(e) => write!(f, "Can't host the map: {e}"),
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::OpenStream(e) => write!(f, "can't open stream: {e}"),
+            ProtocolError::AcceptStream(e) => write!(f, "can't accept stream: {e}"),
+            ProtocolError::Read(e) => write!(f, "can't read from stream: {e}"),
+            ProtocolError::Write(e) => write!(f, "can't write to stream: {e}"),
+            ProtocolError::Forwarding(e) => write!(f, "TCP port forwarding stopped: {e}"),
+            ProtocolError::MalformedPacket => write!(f, "received malformed packet"),
+            ProtocolError::Serialize(packet) => write!(f, "can't serialize {packet} packet"),
+            ProtocolError::Handshake(e) => write!(f, "can't set up tunnel stream: {e}"),
+            ProtocolError::UnknownTunnel => write!(f, "the other side does not know the tunnel"),
+            ProtocolError::TunnelInUse => write!(f, "a tunnel with this id is still open"),
+            ProtocolError::TunnelAborted => write!(f, "the other side gave up the tunnel"),
+            ProtocolError::ResumeTimedOut => write!(f, "the tunnel could not be resumed in time"),
+            ProtocolError::BadMap => write!(f, "the map does not match the offer of the host"),
+        }
+    }
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectReason::Kicked => write!(f, "kicked"),
+            RejectReason::Banned => write!(f, "banned"),
+            RejectReason::WrongPassword => write!(f, "wrong password"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::EndpointBind(e) => Some(e),
+            Error::Connect(e) => Some(e),
+            Error::ScannerStart(e) | Error::Socket(e) | Error::MapFile(e) => Some(e),
+            Error::GameConnect(_, e) => Some(e),
+            Error::ConnectionLost(e) => Some(e),
+            Error::Protocol(e) => Some(e),
+            Error::Map(e) => Some(e),
+            Error::Rejected(_) => None,
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProtocolError::OpenStream(e) | ProtocolError::AcceptStream(e) => Some(e),
+            ProtocolError::Read(e) => Some(e),
+            ProtocolError::Write(e) => Some(e),
+            ProtocolError::Forwarding(e) | ProtocolError::Handshake(e) => Some(e),
+            ProtocolError::MalformedPacket
+            | ProtocolError::Serialize(_)
+            | ProtocolError::UnknownTunnel
+            | ProtocolError::TunnelInUse
+            | ProtocolError::TunnelAborted
+            | ProtocolError::ResumeTimedOut
+            | ProtocolError::BadMap => None,
+        }
+    }
+}
+
+impl From<ProtocolError> for Error {
+    fn from(e: ProtocolError) -> Self {
+        Error::Protocol(e)
+    }
+}
@@ -0,0 +1,243 @@
+use std::{fmt, path::PathBuf};
+
+use iroh::PublicKey;
+
+use crate::{
+    error::RejectReason,
+    gproxy::RELIABLE_MAP_SIZE,
+    maps::{self, MapStatus},
+    packets::{GameType, QueryForGamesResponse},
+    path::PathStatus,
+    status::InGamePlayer,
+};
+
+/// The parts of a lobby that are interesting to a user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LobbyInfo {
+    pub game_id: u32,
+    pub game_name: String,
+    pub game_type: GameType,
+    pub game_version: u32,
+    /// Path of the map inside the WC3 folder, like `Maps\FrozenThrone\(4)TwistedMeadows.w3x`.
+    /// `None` if the stat string can't be decoded.
+    pub map_name: Option<String>,
+    /// Checksum of the map, compared with the local map by WC3. See [`crate::map_info::MapInfo::checksum`].
+    /// `None` if the lobby has none, like a simulated lobby.
+    pub map_checksum: Option<u32>,
+    pub host_name: Option<String>,
+    pub players: u32,
+    pub player_slots: u32,
+    /// The game host lets players rejoin with GProxy after their connection broke (GHost++).
+    pub gproxy: bool,
+}
+
+impl LobbyInfo {
+    /// The file name of the map without its folders.
+    pub fn map_file_name(&self) -> Option<&str> {
+        self.map_name.as_deref().map(maps::file_name)
+    }
+}
+
+impl From<&QueryForGamesResponse> for LobbyInfo {
+    fn from(response: &QueryForGamesResponse) -> Self {
+        let stat_string = response.stat_string();
+        LobbyInfo {
+            game_id: response.game_id,
+            game_name: response.game_name.to_string(),
+            game_type: response.game_type,
+            game_version: response.game_version,
+            map_name: stat_string.as_ref().map(|s| s.map_name.to_string()),
+            //0 is not the checksum of any map
+            map_checksum: stat_string
+                .as_ref()
+                .map(|s| s.map_checksum)
+                .filter(|checksum| *checksum != 0),
+            host_name: stat_string.as_ref().map(|s| s.host_username.to_string()),
+            players: response.number_of_players,
+            player_slots: response.number_of_player_slots,
+            gproxy: stat_string.as_ref().is_some_and(|s| {
+                s.map_width == RELIABLE_MAP_SIZE && s.map_height == RELIABLE_MAP_SIZE
+            }),
+        }
+    }
+}
+
+impl fmt::Display for LobbyInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:?}[1.{}]",
+            self.game_name, self.game_type, self.game_version
+        )
+    }
+}
+
+/// Things that happen in the game, decoded from the forwarded game traffic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GameEvent {
+    /// A player took a slot in the lobby. Slots are counted from 0.
+    PlayerJoined {
+        player_id: u8,
+        name: String,
+        slot: u8,
+    },
+    PlayerLeft {
+        player_id: u8,
+        name: String,
+    },
+    /// A chat message. The name is `None` if the sender is not known yet.
+    Chat {
+        player_id: u8,
+        name: Option<String>,
+        message: String,
+    },
+    /// The game moved on to the next phase.
+    PhaseChanged(GamePhase),
+}
+
+impl fmt::Display for GameEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GameEvent::PlayerJoined { name, slot, .. } => {
+                write!(f, "{name} joined slot {}", slot + 1)
+            }
+            GameEvent::PlayerLeft { name, .. } => write!(f, "{name} left the game"),
+            GameEvent::Chat {
+                name: Some(name),
+                message,
+                ..
+            } => write!(f, "{name}: {message}"),
+            GameEvent::Chat {
+                player_id, message, ..
+            } => write!(f, "Player {player_id}: {message}"),
+            GameEvent::PhaseChanged(GamePhase::Lobby) => write!(f, "Players are in the lobby"),
+            GameEvent::PhaseChanged(GamePhase::Countdown) => write!(f, "The countdown started"),
+            GameEvent::PhaseChanged(GamePhase::Loading) => write!(f, "The game is loading"),
+            GameEvent::PhaseChanged(GamePhase::InGame) => write!(f, "The game started"),
+            GameEvent::PhaseChanged(GamePhase::Ended) => write!(f, "The game ended"),
+        }
+    }
+}
+
+/// Where a game is in its life, as seen in the forwarded game traffic.
+/// A game only moves forward through the phases.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum GamePhase {
+    /// Players joined the lobby.
+    Lobby,
+    Countdown,
+    /// The countdown is over, the players load the map.
+    Loading,
+    /// All players loaded the map and play.
+    InGame,
+    /// All game connections are closed after the game started.
+    Ended,
+}
+
+impl GamePhase {
+    /// The lobby is no longer open to new players.
+    pub fn is_started(self) -> bool {
+        self >= GamePhase::Countdown
+    }
+}
+
+impl fmt::Display for GamePhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GamePhase::Lobby => write!(f, "lobby"),
+            GamePhase::Countdown => write!(f, "countdown"),
+            GamePhase::Loading => write!(f, "loading"),
+            GamePhase::InGame => write!(f, "in game"),
+            GamePhase::Ended => write!(f, "ended"),
+        }
+    }
+}
+
+/// Why a [`ClientEvent::LobbyClosed`] lobby is gone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LobbyEnd {
+    /// The countdown ran, the game goes on without the lobby.
+    Started,
+    /// The lobby closed while the local player was in it and no countdown ran.
+    Canceled,
+    /// The local player was not in the lobby, so the game traffic can't tell.
+    Unknown,
+}
+
+/// Things that happen while a [`crate::Host`] is running.
+#[derive(Debug, Clone)]
+pub enum HostEvent {
+    /// Game queries can be sent to the local game (again).
+    GameReachable,
+    /// The local game can't be queried. Usually WC3 is not running.
+    GameUnreachable(String),
+    LobbyOpened(LobbyInfo),
+    /// Something in the open lobby changed, usually the number of players.
+    LobbyUpdated(LobbyInfo),
+    LobbyClosed(LobbyInfo),
+    ClientConnected(PublicKey),
+    /// The connection to a client broke while it had game connections open.
+    /// They wait for the client to come back, otherwise [`HostEvent::ClientDisconnected`] follows.
+    ClientConnectionLost(PublicKey),
+    /// A client came back after [`HostEvent::ClientConnectionLost`].
+    ClientReconnected(PublicKey),
+    ClientDisconnected(PublicKey),
+    /// A client was turned away before it could join.
+    ClientRejected(PublicKey, RejectReason),
+    /// The connection to a client switched between direct and relayed.
+    ClientPathChanged(PublicKey, PathStatus),
+    /// The connection to a client could not become direct for a while.
+    ClientStillRelayed(PublicKey),
+    /// A client joined the game as this player.
+    ClientIdentified(PublicKey, InGamePlayer),
+    Game(GameEvent),
+    /// The replay of a game that ended was saved to this file.
+    ReplaySaved(PathBuf),
+    SpectatorConnected(PublicKey),
+    SpectatorDisconnected(PublicKey),
+    /// The map of the lobby was sent to a client. Contains the map path.
+    MapSent(PublicKey, String),
+}
+
+/// Things that happen while a [`crate::Spectator`] is connected.
+#[derive(Debug, Clone)]
+pub enum SpectatorEvent {
+    /// A game runs on the host and is followed from now on. Contains the game name.
+    Watching(String),
+    /// Chat and players that leave the watched game, and its end.
+    Game(GameEvent),
+    /// The replay of the watched game is written to this file while the game runs.
+    /// WC3 can open it at any time to watch the game up to then.
+    Recording(PathBuf),
+    /// The replay of the watched game was saved to this file.
+    ReplaySaved(PathBuf),
+}
+
+/// Things that happen while a [`crate::Client`] is connected.
+#[derive(Debug, Clone)]
+pub enum ClientEvent {
+    /// A lobby was announced by the host. Repeated announcements of the same lobby are not reported.
+    LobbyFound(LobbyInfo),
+    /// Something in the lobby changed, usually the number of players.
+    LobbyUpdated(LobbyInfo),
+    /// The lobby is gone.
+    LobbyClosed(LobbyEnd),
+    /// The map of the lobby was looked up in [`crate::ClientConfig::maps_dir`].
+    MapChecked(MapStatus),
+    /// The missing map of the lobby was downloaded from the host.
+    MapDownloaded(MapStatus),
+    /// The connection to the host switched between direct and relayed.
+    PathChanged(PathStatus),
+    /// The connection to the host could not become direct for a while.
+    StillRelayed,
+    Game(GameEvent),
+    /// The connection to the host broke while game connections were open. The client tries to
+    /// connect again, the game connections wait for it.
+    ConnectionLost,
+    /// The connection to the host is back after [`ClientEvent::ConnectionLost`].
+    Reconnected,
+    /// The host shut down on purpose. Followed by [`ClientEvent::Disconnected`].
+    HostShutDown,
+    /// The connection to the host was closed.
+    Disconnected,
+}
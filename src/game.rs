@@ -0,0 +1,551 @@
+//! Follows the game through the W3GS packets of the forwarded TCP connections.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use iroh::PublicKey;
+use tracing::{debug, info};
+
+use crate::{
+    events::{GameEvent, GamePhase, LobbyEnd},
+    packets::take_packets,
+    status::InGamePlayer,
+    tap::StreamObserver,
+    w3gs::{SlotTable, W3gsPacket},
+};
+
+/// Which part the local game plays in the W3GS connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Side {
+    /// The local game hosts the WC3 game, the peers are players.
+    Host,
+    /// The local game is a player, the peer hosts the WC3 game.
+    Player,
+}
+
+/// What a [`GameTracker`] reports.
+#[derive(Debug, Clone)]
+pub(crate) enum TrackerEvent {
+    Game(GameEvent),
+    /// The peer of a forwarded connection joined the game as this player. Only reported on the host side.
+    Identified(PublicKey, InGamePlayer),
+}
+
+/// The game as seen through all forwarded connections of one [`crate::Host`] or [`crate::Client`].
+/// Every change is reported once, no matter how many connections carry it.
+pub(crate) struct GameTracker {
+    state: Mutex<GameState>,
+    report: Box<dyn Fn(TrackerEvent) + Send + Sync>,
+}
+
+#[derive(Default)]
+struct GameState {
+    players: BTreeMap<u8, Player>,
+    slots: Option<SlotTable>,
+    /// Players that joined through a forwarded connection.
+    tapped: BTreeSet<u8>,
+    /// `None` until a join was accepted.
+    phase: Option<GamePhase>,
+    /// Open connections. The state is forgotten when the last one closes.
+    taps: usize,
+    /// How the lobby ended for the local player, kept after the connections closed.
+    /// WC3 closes them before the lobby is gone from the LAN list.
+    last_end: Option<LobbyEnd>,
+}
+
+struct Player {
+    name: String,
+    /// Joins are reported once the slot of the player is known.
+    announced: bool,
+}
+
+impl fmt::Debug for GameTracker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        f.debug_struct("GameTracker")
+            .field("players", &state.players.len())
+            .field("taps", &state.taps)
+            .finish()
+    }
+}
+
+impl GameTracker {
+    pub fn new(report: impl Fn(TrackerEvent) + Send + Sync + 'static) -> Arc<Self> {
+        Arc::new(GameTracker {
+            state: Mutex::new(GameState::default()),
+            report: Box::new(report),
+        })
+    }
+
+    /// A tap for a new forwarded connection with `peer`.
+    pub fn tap(self: &Arc<Self>, side: Side, peer: PublicKey) -> GameTap {
+        self.state.lock().unwrap().taps += 1;
+        GameTap {
+            tracker: self.clone(),
+            side,
+            peer,
+            own: TapState::default(),
+            from_game: Vec::new(),
+            to_game: Vec::new(),
+        }
+    }
+
+    /// The phase of the followed game, `None` if no game connection is open.
+    pub fn phase(&self) -> Option<GamePhase> {
+        self.state.lock().unwrap().phase
+    }
+
+    /// Why the lobby the local game was offered is gone. Reported once for every lobby.
+    pub fn lobby_end(&self) -> LobbyEnd {
+        let mut state = self.state.lock().unwrap();
+        let last_end = state.last_end.take();
+        state.end().or(last_end).unwrap_or(LobbyEnd::Unknown)
+    }
+
+    /// A new lobby is offered, the end of an earlier one no longer applies.
+    pub fn lobby_opened(&self) {
+        self.state.lock().unwrap().last_end = None;
+    }
+
+    fn emit(&self, event: TrackerEvent) {
+        match &event {
+            TrackerEvent::Game(event) => info!("{event}"),
+            TrackerEvent::Identified(peer, player) => info!("Client {peer} is player {player}"),
+        }
+        (self.report)(event);
+    }
+
+    fn handle(
+        &self,
+        tap: &mut TapState,
+        side: Side,
+        peer: PublicKey,
+        to_host: bool,
+        packet: W3gsPacket,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        let mut events = Vec::new();
+        let mut identified = None;
+        match (to_host, packet) {
+            (true, W3gsPacket::ReqJoin(join)) => {
+                tap.own_name = Some(join.player_name.to_string());
+            }
+            (true, W3gsPacket::LeaveGame(_)) => {
+                if let Some(id) = tap.own_id.take() {
+                    state.tapped.remove(&id);
+                    events.extend(state.remove_player(id));
+                }
+            }
+            //Chat is taken from the packets of the sender, so every message is seen once
+            (true, W3gsPacket::ChatToHost(chat)) if side == Side::Host => {
+                if let Some(message) = chat.body.message() {
+                    events.push(state.chat(chat.sender, message));
+                }
+            }
+            (false, W3gsPacket::ChatFromHost(chat)) if side == Side::Player => {
+                if let Some(message) = chat.body.message() {
+                    events.push(state.chat(chat.sender, message));
+                }
+            }
+            //Players at the game host only show up in what it sends on, taken from the first receiver
+            (false, W3gsPacket::ChatFromHost(chat))
+                if !state.tapped.contains(&chat.sender)
+                    && tap.own_id.is_some()
+                    && tap.own_id == state.first_tapped(&chat.receivers) =>
+            {
+                if let Some(message) = chat.body.message() {
+                    events.push(state.chat(chat.sender, message));
+                }
+            }
+            (false, W3gsPacket::SlotInfoJoin(join)) => {
+                tap.own_id = Some(join.player_id);
+                state.tapped.insert(join.player_id);
+                if let Some(name) = &tap.own_name {
+                    state.add_player(join.player_id, name);
+                    if side == Side::Host {
+                        identified = Some(InGamePlayer {
+                            player_id: join.player_id,
+                            name: name.clone(),
+                            slot: join.slots.slot_of(join.player_id),
+                        });
+                    }
+                }
+                state.slots = Some(join.slots);
+                events.extend(state.advance(GamePhase::Lobby));
+                events.extend(state.announce_players());
+            }
+            (false, W3gsPacket::SlotInfo(info)) => {
+                state.slots = Some(info.slots);
+                events.extend(state.announce_players());
+            }
+            (false, W3gsPacket::PlayerInfo(info)) => {
+                state.add_player(info.player_id, &info.player_name.to_string());
+                events.extend(state.announce_players());
+            }
+            (false, W3gsPacket::PlayerLeft(left)) => {
+                events.extend(state.remove_player(left.player_id));
+            }
+            (false, W3gsPacket::RejectJoin(reject)) => {
+                debug!("The game rejected a join, reason {}", reject.reason);
+            }
+            (false, W3gsPacket::CountdownStart) => {
+                events.extend(state.advance(GamePhase::Countdown));
+            }
+            (false, W3gsPacket::CountdownEnd) => {
+                events.extend(state.advance(GamePhase::Loading));
+            }
+            //The game host sends actions once every player has loaded
+            (false, W3gsPacket::IncomingAction(_)) if state.phase == Some(GamePhase::Loading) => {
+                events.extend(state.advance(GamePhase::InGame));
+            }
+            _ => {}
+        }
+        drop(state);
+        if let Some(player) = identified {
+            self.emit(TrackerEvent::Identified(peer, player));
+        }
+        for event in events {
+            self.emit(TrackerEvent::Game(event));
+        }
+    }
+
+    fn close_tap(&self, tap: &TapState) {
+        let mut state = self.state.lock().unwrap();
+        //A player whose connection ends without a goodbye is gone as well
+        if let Some(id) = tap.own_id {
+            state.tapped.remove(&id);
+        }
+        let mut events: Vec<_> = tap
+            .own_id
+            .and_then(|id| state.remove_player(id))
+            .into_iter()
+            .collect();
+        state.taps -= 1;
+        if state.taps == 0 {
+            //A player who left the lobby was not in it when it ended
+            let end = tap.own_id.and(state.end()).or(state.last_end);
+            if state.phase.is_some_and(GamePhase::is_started) {
+                events.extend(state.advance(GamePhase::Ended));
+            }
+            *state = GameState {
+                last_end: end,
+                ..GameState::default()
+            };
+        }
+        drop(state);
+        for event in events {
+            self.emit(TrackerEvent::Game(event));
+        }
+    }
+}
+
+impl GameState {
+    fn end(&self) -> Option<LobbyEnd> {
+        self.phase.map(|phase| {
+            if phase.is_started() {
+                LobbyEnd::Started
+            } else {
+                LobbyEnd::Canceled
+            }
+        })
+    }
+
+    /// Moves to a later phase. Repeated or late packets don't move the game back.
+    fn advance(&mut self, phase: GamePhase) -> Option<GameEvent> {
+        if self.phase.is_some_and(|current| current >= phase) {
+            return None;
+        }
+        self.phase = Some(phase);
+        Some(GameEvent::PhaseChanged(phase))
+    }
+
+    fn add_player(&mut self, id: u8, name: &str) {
+        if self
+            .players
+            .get(&id)
+            .is_some_and(|player| player.name == name)
+        {
+            return;
+        }
+        self.players.insert(
+            id,
+            Player {
+                name: name.to_string(),
+                announced: false,
+            },
+        );
+    }
+
+    fn announce_players(&mut self) -> Vec<GameEvent> {
+        let Some(slots) = &self.slots else {
+            return Vec::new();
+        };
+        let mut events = Vec::new();
+        for (id, player) in &mut self.players {
+            if player.announced {
+                continue;
+            }
+            if let Some(slot) = slots.slot_of(*id) {
+                player.announced = true;
+                events.push(GameEvent::PlayerJoined {
+                    player_id: *id,
+                    name: player.name.clone(),
+                    slot,
+                });
+            }
+        }
+        events
+    }
+
+    fn remove_player(&mut self, id: u8) -> Option<GameEvent> {
+        let player = self.players.remove(&id)?;
+        player.announced.then_some(GameEvent::PlayerLeft {
+            player_id: id,
+            name: player.name,
+        })
+    }
+
+    fn first_tapped(&self, players: &[u8]) -> Option<u8> {
+        players
+            .iter()
+            .copied()
+            .filter(|id| self.tapped.contains(id))
+            .min()
+    }
+
+    fn chat(&self, sender: u8, message: String) -> GameEvent {
+        GameEvent::Chat {
+            player_id: sender,
+            name: self.players.get(&sender).map(|player| player.name.clone()),
+            message,
+        }
+    }
+}
+
+/// What one forwarded connection knows about its own player.
+#[derive(Default)]
+struct TapState {
+    own_name: Option<String>,
+    own_id: Option<u8>,
+}
+
+/// Decodes the W3GS packets of one forwarded connection for a [`GameTracker`]. Does not change any data.
+/// Reads are data from the local game, writes are data to it.
+pub(crate) struct GameTap {
+    tracker: Arc<GameTracker>,
+    side: Side,
+    peer: PublicKey,
+    own: TapState,
+    from_game: Vec<u8>,
+    to_game: Vec<u8>,
+}
+
+impl GameTap {
+    fn decode(&mut self, from_game: bool, data: &[u8]) {
+        //Packets from the local host or to the local player come from the WC3 host
+        let to_host = from_game == (self.side == Side::Player);
+        let buffer = if from_game {
+            &mut self.from_game
+        } else {
+            &mut self.to_game
+        };
+        buffer.extend_from_slice(data);
+        for packet in take_packets(buffer)
+            .iter()
+            .filter_map(|frame| W3gsPacket::detect(frame))
+        {
+            self.tracker
+                .handle(&mut self.own, self.side, self.peer, to_host, packet);
+        }
+    }
+}
+
+impl StreamObserver for GameTap {
+    fn on_read(&mut self, data: &[u8]) {
+        self.decode(true, data);
+    }
+
+    fn on_write(&mut self, data: &[u8]) {
+        self.decode(false, data);
+    }
+}
+
+impl Drop for GameTap {
+    fn drop(&mut self) {
+        self.tracker.close_tap(&self.own);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use iroh::SecretKey;
+
+    use super::{GameTracker, Side, TrackerEvent};
+    use crate::{
+        events::{GameEvent, GamePhase, LobbyEnd},
+        status::InGamePlayer,
+        tap::StreamObserver,
+        test_utils::w3gs_frames::{frame, req_join, slot_info_join},
+    };
+
+    #[test]
+    fn follows_a_player_through_the_lobby() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let identified = Arc::new(Mutex::new(Vec::new()));
+        let (reported, reported_identity) = (events.clone(), identified.clone());
+        let tracker = GameTracker::new(move |event| match event {
+            TrackerEvent::Game(event) => reported.lock().unwrap().push(event),
+            TrackerEvent::Identified(peer, player) => {
+                reported_identity.lock().unwrap().push((peer, player))
+            }
+        });
+        let peer = SecretKey::from_bytes(&[7; 32]).public();
+        let mut tap = tracker.tap(Side::Host, peer);
+
+        let join = req_join("Grubby");
+        //Packets may arrive in pieces
+        tap.on_write(&join[..5]);
+        tap.on_write(&join[5..]);
+        tap.on_read(&slot_info_join(2));
+        tap.on_write(&frame(0x28, b"\x01\x01\x02\x10gl hf\0"));
+        tap.on_read(&frame(0x0A, &[]));
+        tap.on_read(&frame(0x0A, &[]));
+        drop(tap);
+
+        assert_eq!(
+            *identified.lock().unwrap(),
+            vec![(
+                peer,
+                InGamePlayer {
+                    player_id: 2,
+                    name: "Grubby".to_string(),
+                    slot: Some(1)
+                }
+            )]
+        );
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                GameEvent::PhaseChanged(GamePhase::Lobby),
+                GameEvent::PlayerJoined {
+                    player_id: 2,
+                    name: "Grubby".to_string(),
+                    slot: 1
+                },
+                GameEvent::Chat {
+                    player_id: 2,
+                    name: Some("Grubby".to_string()),
+                    message: "gl hf".to_string()
+                },
+                GameEvent::PhaseChanged(GamePhase::Countdown),
+                GameEvent::PlayerLeft {
+                    player_id: 2,
+                    name: "Grubby".to_string()
+                },
+                GameEvent::PhaseChanged(GamePhase::Ended),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_chat_of_the_host_player_once() {
+        let chat = Arc::new(Mutex::new(Vec::new()));
+        let reported = chat.clone();
+        let tracker = GameTracker::new(move |event| {
+            if let TrackerEvent::Game(event @ GameEvent::Chat { .. }) = event {
+                reported.lock().unwrap().push(event);
+            }
+        });
+        let mut taps: Vec<_> = [(2, [9; 32]), (3, [10; 32])]
+            .into_iter()
+            .map(|(player_id, key)| {
+                let mut tap = tracker.tap(Side::Host, SecretKey::from_bytes(&key).public());
+                tap.on_write(&req_join(&format!("Player{player_id}")));
+                tap.on_read(&slot_info_join(player_id));
+                tap
+            })
+            .collect();
+        //The game host sends the message of its player to both
+        let message = frame(0x0F, b"\x02\x02\x03\x01\x10hi\0");
+        for tap in &mut taps {
+            tap.on_read(&message);
+        }
+
+        assert_eq!(
+            *chat.lock().unwrap(),
+            vec![GameEvent::Chat {
+                player_id: 1,
+                name: None,
+                message: "hi".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn moves_through_the_game_phases() {
+        let phases = Arc::new(Mutex::new(Vec::new()));
+        let reported = phases.clone();
+        let tracker = GameTracker::new(move |event| {
+            if let TrackerEvent::Game(GameEvent::PhaseChanged(phase)) = event {
+                reported.lock().unwrap().push(phase);
+            }
+        });
+        let host = SecretKey::from_bytes(&[8; 32]).public();
+        assert_eq!(tracker.lobby_end(), LobbyEnd::Unknown);
+
+        let mut tap = tracker.tap(Side::Player, host);
+        tap.on_write(&slot_info_join(2));
+        assert_eq!(tracker.lobby_end(), LobbyEnd::Canceled);
+        let action = frame(0x0C, &[0x64, 0x00]);
+        //Actions before the countdown ended don't start the game
+        tap.on_write(&action);
+        tap.on_write(&frame(0x0A, &[]));
+        tap.on_write(&frame(0x0B, &[]));
+        assert_eq!(tracker.lobby_end(), LobbyEnd::Started);
+        tap.on_write(&action);
+        tap.on_write(&action);
+        assert_eq!(tracker.phase(), Some(GamePhase::InGame));
+        drop(tap);
+        assert_eq!(tracker.phase(), None);
+
+        assert_eq!(
+            *phases.lock().unwrap(),
+            vec![
+                GamePhase::Lobby,
+                GamePhase::Countdown,
+                GamePhase::Loading,
+                GamePhase::InGame,
+                GamePhase::Ended
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_the_lobby_end_after_the_connection_closed() {
+        let tracker = GameTracker::new(|_| {});
+        let host = SecretKey::from_bytes(&[8; 32]).public();
+        let mut tap = tracker.tap(Side::Player, host);
+        tap.on_write(&slot_info_join(2));
+        drop(tap);
+        assert_eq!(tracker.lobby_end(), LobbyEnd::Canceled);
+        //Only reported for the lobby it belongs to
+        assert_eq!(tracker.lobby_end(), LobbyEnd::Unknown);
+
+        let mut tap = tracker.tap(Side::Player, host);
+        tap.on_write(&slot_info_join(2));
+        drop(tap);
+        tracker.lobby_opened();
+        assert_eq!(tracker.lobby_end(), LobbyEnd::Unknown);
+
+        let mut tap = tracker.tap(Side::Player, host);
+        tap.on_write(&slot_info_join(2));
+        tap.on_read(&frame(0x21, &[0; 4]));
+        drop(tap);
+        assert_eq!(tracker.lobby_end(), LobbyEnd::Unknown);
+    }
+}
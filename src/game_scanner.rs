@@ -1,38 +1,98 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::BTreeSet,
+    net::SocketAddr,
+    sync::{self, Arc},
+    time::Duration,
+};
 use tokio::{
     io,
     net::UdpSocket,
     sync::{
         Mutex,
         broadcast::{self, Sender},
+        watch,
     },
+    task::JoinHandle,
 };
+use tracing::{debug, error, info};
 
 use crate::{
+    events::{HostEvent, LobbyInfo},
+    logging::log_udp_packet,
     packets::{
         GenerableWc3UdpMessageType, NewServerHosted, QueryForGamesRequest, QueryForGamesResponse,
         ServerClosed, Wc3UdpMessageType,
     },
-    utils::{
-        LOCALHOST_WC3_ADDR, SUPPORTED_GAME_TYPES, SUPPORTED_GAME_VERSIONS, ZERO_SOCKET_ADDR,
-        try_serialize,
-    },
+    utils::{SUPPORTED_GAME_TYPES, SUPPORTED_GAME_VERSIONS, ZERO_SOCKET_ADDR, try_serialize},
 };
 
-pub async fn run_game_scanner() -> io::Result<Sender<GenerableWc3UdpMessageType>> {
+/// Polls the local game for lobbies. The background tasks stop when this is dropped.
+pub struct GameScanner {
+    tx: Sender<GenerableWc3UdpMessageType>,
+    lobby: watch::Receiver<Option<QueryForGamesResponse>>,
+    game_ids: Arc<sync::Mutex<BTreeSet<u32>>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl GameScanner {
+    /// Packets to send to clients to mimic the broadcasts of the local game.
+    pub fn sender(&self) -> Sender<GenerableWc3UdpMessageType> {
+        self.tx.clone()
+    }
+
+    /// The lobby that is currently open in the local game.
+    pub fn lobby(&self) -> Option<LobbyInfo> {
+        self.lobby.borrow().as_ref().map(LobbyInfo::from)
+    }
+
+    /// The last answer of the local game to the game queries, `None` while no lobby is open.
+    pub fn advertised(&self) -> watch::Receiver<Option<QueryForGamesResponse>> {
+        self.lobby.clone()
+    }
+
+    /// The game ids of all lobbies clients were told about and not told that they closed.
+    pub fn advertised_game_ids(&self) -> Vec<u32> {
+        self.game_ids.lock().unwrap().iter().copied().collect()
+    }
+}
+
+impl Drop for GameScanner {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+pub async fn run_game_scanner(
+    game_addr: SocketAddr,
+    events: Sender<HostEvent>,
+) -> io::Result<GameScanner> {
     let listen_socket: Arc<_> = UdpSocket::bind(ZERO_SOCKET_ADDR).await?.into();
-    listen_socket.connect(LOCALHOST_WC3_ADDR).await?; //Limit socket to only communicate with local server
+    listen_socket.connect(game_addr).await?; //Limit socket to only communicate with local server
 
     let send_socket = listen_socket.clone();
 
-    let (tx, _rx) = broadcast::channel::<GenerableWc3UdpMessageType>(1);
+    let (tx, _rx) = broadcast::channel::<GenerableWc3UdpMessageType>(16); //Room for the NewServerHosted + QueryForGamesResponse burst
     let tx_external = tx.clone();
 
     let last_known_state = Arc::new(Mutex::new(Option::<QueryForGamesResponse>::None));
     let last_known_state_set = last_known_state.clone();
+    let (lobby_tx, lobby_rx) = watch::channel(Option::<QueryForGamesResponse>::None);
+    let game_ids = Arc::new(sync::Mutex::new(BTreeSet::new()));
+    let advertised_ids = game_ids.clone();
 
-    tokio::spawn(async move {
+    let poll_task = tokio::spawn(async move {
         let broadcast_packet = |packet: GenerableWc3UdpMessageType| {
+            match &packet {
+                GenerableWc3UdpMessageType::QueryForGamesResponse(response) => {
+                    advertised_ids.lock().unwrap().insert(response.game_id);
+                }
+                GenerableWc3UdpMessageType::ServerClosed(closed) => {
+                    advertised_ids.lock().unwrap().remove(&closed.game_id);
+                }
+                _ => {}
+            }
             //This error can be ignored, it only happens if there are no listeners
             let _ = tx.send(packet);
         };
@@ -45,7 +105,7 @@ pub async fn run_game_scanner() -> io::Result<Sender<GenerableWc3UdpMessageType>
                 let mut state = last_known_state.lock().await;
                 state.take()
             };
-            send_game_query(&send_socket, &mut last_send_successful).await;
+            send_game_query(&send_socket, &mut last_send_successful, &events).await;
             tokio::time::sleep(Duration::from_secs(1)).await;
             let new_state = {
                 let state = last_known_state.lock().await;
@@ -53,10 +113,10 @@ pub async fn run_game_scanner() -> io::Result<Sender<GenerableWc3UdpMessageType>
             };
             match (old_state, new_state) {
                 (None, Some(state)) => {
-                    println!(
-                        "Discovered new game server: {} {:?}[1.{}]",
-                        state.game_name, state.game_type, state.game_version
-                    );
+                    let lobby = LobbyInfo::from(&state);
+                    info!("Discovered new game server: {lobby}");
+                    lobby_tx.send_replace(Some(state.clone()));
+                    let _ = events.send(HostEvent::LobbyOpened(lobby));
                     broadcast_packet(GenerableWc3UdpMessageType::NewServerHosted(
                         NewServerHosted {
                             game_id: state.game_id,
@@ -67,13 +127,18 @@ pub async fn run_game_scanner() -> io::Result<Sender<GenerableWc3UdpMessageType>
                     broadcast_packet(GenerableWc3UdpMessageType::QueryForGamesResponse(state));
                 }
                 (Some(_), Some(state)) => {
+                    let lobby = LobbyInfo::from(&state);
+                    let old_lobby = lobby_tx.send_replace(Some(state.clone()));
+                    if old_lobby.as_ref().map(LobbyInfo::from).as_ref() != Some(&lobby) {
+                        debug!("Lobby changed: {lobby}");
+                        let _ = events.send(HostEvent::LobbyUpdated(lobby));
+                    }
                     broadcast_packet(GenerableWc3UdpMessageType::QueryForGamesResponse(state));
                 }
                 (Some(old_state), None) => {
-                    println!(
-                        "Server closed: {} {:?}[1.{}]",
-                        old_state.game_name, old_state.game_type, old_state.game_version
-                    );
+                    info!("Server closed: {}", LobbyInfo::from(&old_state));
+                    lobby_tx.send_replace(None);
+                    let _ = events.send(HostEvent::LobbyClosed(LobbyInfo::from(&old_state)));
                     broadcast_packet(GenerableWc3UdpMessageType::ServerClosed(ServerClosed {
                         game_id: old_state.game_id,
                     }));
@@ -83,9 +148,15 @@ pub async fn run_game_scanner() -> io::Result<Sender<GenerableWc3UdpMessageType>
         }
     });
 
-    tokio::spawn(async move { run_port_listener(listen_socket, last_known_state_set).await });
+    let listen_task =
+        tokio::spawn(async move { run_port_listener(listen_socket, last_known_state_set).await });
 
-    io::Result::Ok(tx_external)
+    io::Result::Ok(GameScanner {
+        tx: tx_external,
+        lobby: lobby_rx,
+        game_ids,
+        tasks: vec![poll_task, listen_task],
+    })
 }
 
 async fn run_port_listener(
@@ -96,43 +167,47 @@ async fn run_port_listener(
     loop {
         if let Result::Ok(len) = listen_socket.recv(&mut buffer).await {
             let data = &buffer[..len];
+            log_udp_packet("game -> scanner", data);
 
             match Wc3UdpMessageType::detect(data) {
                 Some(Wc3UdpMessageType::QueryForGamesResponse(response)) => {
                     let mut state = last_known_state_set.lock().await;
                     *state = Some(response.clone());
                 }
-                Some(packet) => eprintln!("Received UDP packet: {:?}", packet),
-                None => eprintln!("Received unknown UDP packet of length {}", len),
+                Some(packet) => debug!("Received UDP packet: {:?}", packet),
+                None => debug!("Received unknown UDP packet of length {}", len),
             };
         };
     }
 }
 
-async fn send_game_query(send_socket: &UdpSocket, last_successful: &mut Option<bool>) {
+async fn send_game_query(
+    send_socket: &UdpSocket,
+    last_successful: &mut Option<bool>,
+    events: &Sender<HostEvent>,
+) {
     for game_version in SUPPORTED_GAME_VERSIONS {
         for game_type in SUPPORTED_GAME_TYPES {
             let request = QueryForGamesRequest::new(game_type, game_version);
             let bytes = if let Some(bytes) = try_serialize(&request) {
                 bytes
             } else {
-                eprintln!("Failed to serialize QueryForGamesRequest packet");
+                error!("Failed to serialize QueryForGamesRequest packet");
                 continue;
             };
 
             match send_socket.send(&bytes).await {
                 Ok(_) => {
                     if *last_successful != Some(true) {
-                        println!("Successfully sent game query to WC3");
+                        info!("Successfully sent game query to WC3");
+                        let _ = events.send(HostEvent::GameReachable);
                     }
                     *last_successful = Some(true);
                 }
                 Err(e) => {
                     if *last_successful != Some(false) {
-                        eprintln!(
-                            "Can't send game query to WC3. Is the game running? Error: {}",
-                            e
-                        );
+                        info!("Can't send game query to WC3: {e}");
+                        let _ = events.send(HostEvent::GameUnreachable(e.to_string()));
                     }
                     *last_successful = Some(false);
                 }
@@ -140,3 +215,148 @@ async fn send_game_query(send_socket: &UdpSocket, last_successful: &mut Option<b
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        net::UdpSocket,
+        sync::broadcast::{self, Receiver},
+        time::timeout,
+    };
+
+    use super::run_game_scanner;
+    use crate::{
+        events::HostEvent,
+        packets::GenerableWc3UdpMessageType,
+        test_utils::fake_wc3_server::{FakeGame, FakeWc3Server},
+    };
+
+    async fn next_message(
+        rx: &mut Receiver<GenerableWc3UdpMessageType>,
+    ) -> GenerableWc3UdpMessageType {
+        timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .expect("scanner message in time")
+            .expect("open scanner channel")
+    }
+
+    #[tokio::test]
+    async fn reports_hosted_and_closed_lobby() {
+        let lan = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut server = FakeWc3Server::start().await;
+        server.add_broadcast_target(lan.local_addr().unwrap());
+        let (events, mut host_events) = broadcast::channel(16);
+        let scanner = run_game_scanner(server.addr(), events).await.unwrap();
+        let mut rx = scanner.sender().subscribe();
+
+        let game = FakeGame {
+            game_name: "Scanner Test".to_string(),
+            game_id: 42,
+            ..FakeGame::default()
+        };
+        server.host_game(game.clone()).await;
+
+        match next_message(&mut rx).await {
+            GenerableWc3UdpMessageType::NewServerHosted(hosted) => {
+                assert_eq!(hosted.game_id, 42);
+                assert_eq!(hosted.game_version, game.game_version);
+            }
+            other => panic!("Expected NewServerHosted, got {other:?}"),
+        }
+        match next_message(&mut rx).await {
+            GenerableWc3UdpMessageType::QueryForGamesResponse(response) => {
+                assert_eq!(response.game_name.to_string(), "Scanner Test");
+                assert_eq!(response.tcp_port, server.addr().port());
+            }
+            other => panic!("Expected QueryForGamesResponse, got {other:?}"),
+        }
+        assert!(server.answered_queries() > 0);
+        assert!(matches!(
+            host_events.recv().await.unwrap(),
+            HostEvent::GameReachable
+        ));
+        match host_events.recv().await.unwrap() {
+            HostEvent::LobbyOpened(lobby) => assert_eq!(lobby.game_name, "Scanner Test"),
+            other => panic!("Expected LobbyOpened, got {other:?}"),
+        }
+
+        let mut buffer = [0u8; 64];
+        let len = lan.recv(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..2], b"\xF7\x31");
+        assert_eq!(len, 16);
+
+        server.close_game().await;
+        loop {
+            match next_message(&mut rx).await {
+                GenerableWc3UdpMessageType::QueryForGamesResponse(_) => continue,
+                GenerableWc3UdpMessageType::ServerClosed(closed) => {
+                    assert_eq!(closed.game_id, 42);
+                    break;
+                }
+                other => panic!("Expected ServerClosed, got {other:?}"),
+            }
+        }
+        let len = lan.recv(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..len], b"\xF7\x33\x08\x00\x2A\x00\x00\x00");
+    }
+
+    #[tokio::test]
+    async fn remembers_advertised_lobbies_until_closed() {
+        let server = FakeWc3Server::start().await;
+        let (events, _) = broadcast::channel(16);
+        let scanner = run_game_scanner(server.addr(), events).await.unwrap();
+        let mut rx = scanner.sender().subscribe();
+
+        for game_id in [7, 8] {
+            server
+                .host_game(FakeGame {
+                    game_id,
+                    ..FakeGame::default()
+                })
+                .await;
+            loop {
+                match next_message(&mut rx).await {
+                    GenerableWc3UdpMessageType::QueryForGamesResponse(response)
+                        if response.game_id == game_id =>
+                    {
+                        break;
+                    }
+                    _ => continue,
+                }
+            }
+        }
+        //The lobby was replaced without closing, clients still know both
+        assert_eq!(scanner.advertised_game_ids(), vec![7, 8]);
+
+        server.close_game().await;
+        while !matches!(
+            next_message(&mut rx).await,
+            GenerableWc3UdpMessageType::ServerClosed(_)
+        ) {}
+        assert_eq!(scanner.advertised_game_ids(), vec![7]);
+    }
+
+    #[tokio::test]
+    async fn ignores_lobby_with_unsupported_version() {
+        let server = FakeWc3Server::start().await;
+        let (events, _) = broadcast::channel(16);
+        let scanner = run_game_scanner(server.addr(), events).await.unwrap();
+        let mut rx = scanner.sender().subscribe();
+
+        server
+            .host_game(FakeGame {
+                game_version: 99,
+                ..FakeGame::default()
+            })
+            .await;
+
+        assert!(
+            timeout(Duration::from_millis(2500), rx.recv())
+                .await
+                .is_err()
+        );
+        assert_eq!(server.answered_queries(), 0);
+    }
+}
@@ -0,0 +1,604 @@
+//! GProxy++ compatible reconnects.
+//!
+//! GHost++ game hosts let players rejoin a running game after their connection broke, if they play
+//! through GProxy++. GProxy sits between the local game and the game host. It numbers the packets of
+//! both directions, keeps what it sent until the game host confirms it, and when the connection
+//! breaks it connects to the reconnect port of the game host and both sides send again what got lost.
+//!
+//! The client side of the tunnel does the same as GProxy, so a player can rejoin over a new iroh
+//! connection once the tunnel itself can't be resumed anymore. The host side learns the reconnect
+//! port from the game host and forwards the new stream there. GHost++ marks games that support
+//! reconnects with a map size of 1984x1984 in the lobby.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io, mem,
+    pin::Pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    task::{Context, Poll, Waker, ready},
+    time::Duration,
+};
+
+use binrw::{BinRead, BinWrite};
+use iroh::PublicKey;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::{debug, info, warn};
+
+pub(crate) use crate::packets::GPS_HEADER;
+use crate::{
+    packets::take_packet,
+    tap::StreamObserver,
+    utils::{try_parse, try_serialize},
+};
+
+const GPS_INIT: u8 = 0x01;
+const GPS_RECONNECT: u8 = 0x02;
+const GPS_ACK: u8 = 0x03;
+const GPS_REJECT: u8 = 0x04;
+const GPS_VERSION: u32 = 1;
+
+/// The map width and height GHost++ announces for games that support reconnects.
+pub(crate) const RELIABLE_MAP_SIZE: u16 = 1984;
+/// How long GHost++ waits for a player to reconnect by default.
+pub(crate) const RECONNECT_TIMEOUT: Duration = Duration::from_secs(3 * 60);
+/// The game host is told about received packets after this many.
+const ACK_INTERVAL: u32 = 10;
+/// How much is read from the local game at once.
+const READ_CHUNK: usize = 8192;
+
+const W3GS_SLOTINFOJOIN: u8 = 0x04;
+const W3GS_LEAVEGAME: u8 = 0x21;
+
+/// Offers reconnects to the game host, sent after it accepted the join.
+#[derive(BinRead, BinWrite, Debug, Clone)]
+#[brw(little)]
+#[brw(magic = b"\xF8\x01")]
+struct InitRequest {
+    packet_size: u16,
+    version: u32,
+}
+
+/// The game host accepts reconnects for this player.
+#[derive(BinRead, BinWrite, Debug, Clone)]
+#[brw(little)]
+#[brw(magic = b"\xF8\x01")]
+struct Init {
+    packet_size: u16,
+    reconnect_port: u16,
+    player_id: u8,
+    reconnect_key: u32,
+    /// Empty actions the game host adds to the game for the lag screen.
+    empty_actions: u8,
+}
+
+/// The first packet on the reconnect port, with the number of packets received from the game host.
+#[derive(BinRead, BinWrite, Debug, Clone)]
+#[brw(little)]
+#[brw(magic = b"\xF8\x02")]
+struct ReconnectRequest {
+    packet_size: u16,
+    player_id: u8,
+    reconnect_key: u32,
+    last_packet: u32,
+}
+
+/// The answer of the game host, with the number of packets it received from the player.
+#[derive(BinRead, BinWrite, Debug, Clone)]
+#[brw(little)]
+#[brw(magic = b"\xF8\x02")]
+struct Reconnect {
+    packet_size: u16,
+    last_packet: u32,
+}
+
+/// Both sides confirm the number of packets they received.
+#[derive(BinRead, BinWrite, Debug, Clone)]
+#[brw(little)]
+#[brw(magic = b"\xF8\x03")]
+struct Ack {
+    packet_size: u16,
+    last_packet: u32,
+}
+
+/// The game host refuses a reconnect.
+#[derive(BinRead, BinWrite, Debug, Clone)]
+#[brw(little)]
+#[brw(magic = b"\xF8\x04")]
+struct Reject {
+    packet_size: u16,
+    reason: u32,
+}
+
+/// The reconnect ports the game host announced, by client.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ReconnectPorts(Arc<Mutex<HashMap<PublicKey, u16>>>);
+
+impl ReconnectPorts {
+    pub fn get(&self, peer: PublicKey) -> Option<u16> {
+        self.0.lock().unwrap().get(&peer).copied()
+    }
+
+    pub fn remove(&self, peer: PublicKey) {
+        self.0.lock().unwrap().remove(&peer);
+    }
+
+    /// Watches the game connection of a client for the reconnect port.
+    pub fn observer(&self, peer: PublicKey) -> ReconnectPortObserver {
+        ReconnectPortObserver {
+            ports: self.clone(),
+            peer,
+            buffer: Vec::new(),
+            done: false,
+        }
+    }
+}
+
+/// Learns the reconnect port of a client from the data of the game host.
+pub(crate) struct ReconnectPortObserver {
+    ports: ReconnectPorts,
+    peer: PublicKey,
+    buffer: Vec<u8>,
+    /// The stream is not W3GS or the port is known.
+    done: bool,
+}
+
+impl StreamObserver for ReconnectPortObserver {
+    fn on_read(&mut self, data: &[u8]) {
+        if self.done {
+            return;
+        }
+        self.buffer.extend_from_slice(data);
+        loop {
+            match take_packet(&mut self.buffer) {
+                Ok(Some(packet)) if packet[..2] == [GPS_HEADER, GPS_INIT] => {
+                    if let Some(init) = try_parse::<Init>(&packet) {
+                        debug!(
+                            "Client {} may rejoin through port {}",
+                            self.peer, init.reconnect_port
+                        );
+                        let mut ports = self.ports.0.lock().unwrap();
+                        ports.insert(self.peer, init.reconnect_port);
+                        self.done = true;
+                        self.buffer = Vec::new();
+                        return;
+                    }
+                }
+                Ok(Some(_)) => {}
+                Ok(None) => return,
+                Err(_) => {
+                    self.done = true;
+                    self.buffer = Vec::new();
+                    return;
+                }
+            }
+        }
+    }
+
+    fn on_write(&mut self, _data: &[u8]) {}
+}
+
+/// Counts the game connections the game host accepts reconnects for.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct GproxySessions(Arc<AtomicUsize>);
+
+impl GproxySessions {
+    pub fn is_active(&self) -> bool {
+        self.0.load(Ordering::Acquire) > 0
+    }
+
+    fn start(&self) -> SessionGuard {
+        self.0.fetch_add(1, Ordering::AcqRel);
+        SessionGuard(self.0.clone())
+    }
+}
+
+struct SessionGuard(Arc<AtomicUsize>);
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// What a player needs to rejoin.
+struct Session {
+    player_id: u8,
+    reconnect_key: u32,
+    _active: SessionGuard,
+}
+
+/// Wraps the game connection of the local game like GProxy++ does.
+///
+/// Reading gets the packets for the game host, writing takes the packets of the game host.
+/// Without support of the game host, or if turned off, the data is passed on as it is.
+pub(crate) struct GproxyStream<S> {
+    inner: S,
+    enabled: bool,
+    sessions: GproxySessions,
+    session: Option<Session>,
+    /// Packets are counted once the game host accepted the join, like GHost++ does.
+    counting: bool,
+    /// The stream is not W3GS, it is passed on as it is from now on.
+    passthrough: bool,
+    /// The local game left on purpose.
+    left: bool,
+    /// Waiting for the answer of the game host to a reconnect.
+    reconnecting: bool,
+    /// Data of the game host that does not make up a complete packet yet.
+    from_host: Vec<u8>,
+    /// Complete packets for the local game that were not written yet.
+    to_game: Vec<u8>,
+    to_game_written: usize,
+    /// Data of the local game that does not make up a complete packet yet.
+    from_game: Vec<u8>,
+    /// Packets for the game host that were not read yet.
+    to_host: Vec<u8>,
+    to_host_read: usize,
+    /// Packets sent to the game host that it did not confirm yet, the oldest first.
+    unconfirmed: VecDeque<Vec<u8>>,
+    packets_to_host: u32,
+    packets_from_host: u32,
+    /// Wakes the reader when GProxy packets for the game host are queued.
+    read_waker: Option<Waker>,
+}
+
+impl<S> GproxyStream<S> {
+    /// `enabled` if the game host announced reconnect support.
+    pub fn new(inner: S, enabled: bool, sessions: GproxySessions) -> Self {
+        GproxyStream {
+            inner,
+            enabled,
+            sessions,
+            session: None,
+            counting: false,
+            passthrough: false,
+            left: false,
+            reconnecting: false,
+            from_host: Vec::new(),
+            to_game: Vec::new(),
+            to_game_written: 0,
+            from_game: Vec::new(),
+            to_host: Vec::new(),
+            to_host_read: 0,
+            unconfirmed: VecDeque::new(),
+            packets_to_host: 0,
+            packets_from_host: 0,
+            read_waker: None,
+        }
+    }
+
+    /// The game host accepts a reconnect of this player.
+    pub fn can_reconnect(&self) -> bool {
+        self.session.is_some() && !self.left
+    }
+
+    /// Starts over on a stream to the reconnect port of the game host. Everything that was not
+    /// confirmed is sent again once the game host answered.
+    pub fn reconnect(&mut self) {
+        let Some(session) = &self.session else {
+            return;
+        };
+        let request = ReconnectRequest {
+            packet_size: 13,
+            player_id: session.player_id,
+            reconnect_key: session.reconnect_key,
+            last_packet: self.packets_from_host,
+        };
+        //Whatever was on the way is sent again by both sides
+        self.to_host.clear();
+        self.to_host_read = 0;
+        self.from_host.clear();
+        self.reconnecting = true;
+        self.send_to_host(try_serialize(&request));
+    }
+
+    fn send_to_host(&mut self, packet: Option<Vec<u8>>) {
+        if let Some(packet) = packet {
+            self.to_host.extend_from_slice(&packet);
+            if let Some(waker) = self.read_waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    fn on_game_packet(&mut self, packet: Vec<u8>) {
+        if packet[0] != GPS_HEADER && packet[1] == W3GS_LEAVEGAME {
+            self.left = true;
+        }
+        if self.counting {
+            self.packets_to_host += 1;
+            self.unconfirmed.push_back(packet.clone());
+        }
+        self.to_host.extend_from_slice(&packet);
+    }
+
+    fn on_host_packet(&mut self, packet: Vec<u8>) {
+        match (packet[0], packet[1]) {
+            (GPS_HEADER, GPS_INIT) => {
+                if let Some(init) = try_parse::<Init>(&packet) {
+                    info!(
+                        "The game host accepts reconnects of player {}",
+                        init.player_id
+                    );
+                    self.session = Some(Session {
+                        player_id: init.player_id,
+                        reconnect_key: init.reconnect_key,
+                        _active: self.sessions.start(),
+                    });
+                }
+            }
+            (GPS_HEADER, GPS_ACK) => {
+                if let Some(ack) = try_parse::<Ack>(&packet) {
+                    let keep = self.packets_to_host.saturating_sub(ack.last_packet) as usize;
+                    while self.unconfirmed.len() > keep {
+                        self.unconfirmed.pop_front();
+                    }
+                }
+            }
+            (GPS_HEADER, GPS_RECONNECT) if self.reconnecting => {
+                if let Some(reconnect) = try_parse::<Reconnect>(&packet) {
+                    self.resend(reconnect.last_packet);
+                }
+            }
+            (GPS_HEADER, GPS_REJECT) => {
+                let reason = try_parse::<Reject>(&packet).map_or(0, |reject| reject.reason);
+                warn!("The game host refused the reconnect, reason {reason}");
+                self.session = None;
+            }
+            //Other GProxy packets are not for the local game
+            (GPS_HEADER, _) => {}
+            (_, id) => {
+                if id == W3GS_SLOTINFOJOIN && !self.counting {
+                    self.counting = true;
+                    let request = InitRequest {
+                        packet_size: 8,
+                        version: GPS_VERSION,
+                    };
+                    self.send_to_host(try_serialize(&request));
+                }
+                if self.counting {
+                    self.packets_from_host += 1;
+                    if self.session.is_some() && self.packets_from_host.is_multiple_of(ACK_INTERVAL)
+                    {
+                        let ack = Ack {
+                            packet_size: 8,
+                            last_packet: self.packets_from_host,
+                        };
+                        self.send_to_host(try_serialize(&ack));
+                    }
+                }
+                self.to_game.extend_from_slice(&packet);
+            }
+        }
+    }
+
+    /// The game host received `last_packet` packets, the rest is sent again.
+    fn resend(&mut self, last_packet: u32) {
+        let forgotten = self.packets_to_host - self.unconfirmed.len() as u32;
+        let received = (last_packet.saturating_sub(forgotten) as usize).min(self.unconfirmed.len());
+        self.unconfirmed.drain(..received);
+        debug!(
+            "Rejoined the game, sending {} packets again",
+            self.unconfirmed.len()
+        );
+        let resent: Vec<u8> = self.unconfirmed.iter().flatten().copied().collect();
+        self.reconnecting = false;
+        self.send_to_host(Some(resent));
+    }
+}
+
+impl<S: AsyncWrite + Unpin> GproxyStream<S> {
+    /// Writes the pending packets to the local game.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.to_game_written < self.to_game.len() {
+            let pending = &self.to_game[self.to_game_written..];
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, pending))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.to_game_written += written;
+        }
+        self.to_game.clear();
+        self.to_game_written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for GproxyStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.enabled {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        loop {
+            if this.to_host_read < this.to_host.len() {
+                let len = buf.remaining().min(this.to_host.len() - this.to_host_read);
+                buf.put_slice(&this.to_host[this.to_host_read..this.to_host_read + len]);
+                this.to_host_read += len;
+                if this.to_host_read == this.to_host.len() {
+                    this.to_host.clear();
+                    this.to_host_read = 0;
+                }
+                return Poll::Ready(Ok(()));
+            }
+            this.read_waker = Some(cx.waker().clone());
+            if this.reconnecting {
+                //What the game sends now has to wait for the lost packets
+                return Poll::Pending;
+            }
+
+            let mut chunk = [0; READ_CHUNK];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            let read = chunk.filled();
+            if read.is_empty() {
+                if this.from_game.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                this.to_host = mem::take(&mut this.from_game);
+                continue;
+            }
+            if this.passthrough {
+                this.to_host.extend_from_slice(read);
+                continue;
+            }
+            this.from_game.extend_from_slice(read);
+            loop {
+                match take_packet(&mut this.from_game) {
+                    Ok(Some(packet)) => this.on_game_packet(packet),
+                    Ok(None) => break,
+                    Err(_) => {
+                        this.passthrough = true;
+                        this.to_host.append(&mut this.from_game);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for GproxyStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if !this.enabled {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        ready!(this.poll_drain(cx))?;
+        if this.passthrough {
+            this.to_game.extend_from_slice(buf);
+        } else {
+            this.from_host.extend_from_slice(buf);
+            loop {
+                match take_packet(&mut this.from_host) {
+                    Ok(Some(packet)) => this.on_host_packet(packet),
+                    Ok(None) => break,
+                    Err(_) => {
+                        this.passthrough = true;
+                        this.to_game.append(&mut this.from_host);
+                        break;
+                    }
+                }
+            }
+        }
+        //The data is taken, the local game gets it with the next write or flush
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh::SecretKey;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+
+    use super::{GproxySessions, GproxyStream, ReconnectPorts};
+    use crate::{
+        tap::StreamObserver,
+        test_utils::w3gs_frames::{frame, gps_frame, gps_init, slot_info_join},
+    };
+
+    /// GPS_INIT of GHost++: port 6114, player 2, reconnect key 0xC0FFEE.
+    fn init() -> Vec<u8> {
+        gps_init(6114, 2, 0xC0FFEE)
+    }
+
+    #[tokio::test]
+    async fn rejoins_and_sends_lost_packets_again() {
+        let sessions = GproxySessions::default();
+        let (mut game, local) = duplex(4096);
+        let mut stream = GproxyStream::new(local, true, sessions.clone());
+
+        let join = slot_info_join(2);
+        stream.write_all(&join).await.unwrap();
+        stream.write_all(&init()).await.unwrap();
+        let mut received = vec![0; join.len()];
+        game.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, join);
+        let mut request = [0; 8];
+        stream.read_exact(&mut request).await.unwrap();
+        assert_eq!(request, *gps_frame(0x01, &1u32.to_le_bytes()));
+        assert!(sessions.is_active());
+
+        let actions: Vec<_> = (1..=3).map(|n| frame(0x26, &[n; 4])).collect();
+        game.write_all(&actions.concat()).await.unwrap();
+        let mut sent = vec![0; actions.concat().len()];
+        stream.read_exact(&mut sent).await.unwrap();
+        assert_eq!(sent, actions.concat());
+
+        //The game host got the first action, but not the others
+        assert!(stream.can_reconnect());
+        stream.reconnect();
+        let mut request = [0; 13];
+        stream.read_exact(&mut request).await.unwrap();
+        let mut expected = vec![2];
+        expected.extend_from_slice(&0xC0FFEEu32.to_le_bytes());
+        expected.extend_from_slice(&1u32.to_le_bytes());
+        assert_eq!(request, *gps_frame(0x02, &expected));
+        stream
+            .write_all(&gps_frame(0x02, &1u32.to_le_bytes()))
+            .await
+            .unwrap();
+        let mut resent = vec![0; actions[1..].concat().len()];
+        stream.read_exact(&mut resent).await.unwrap();
+        assert_eq!(resent, actions[1..].concat());
+
+        drop(stream);
+        assert!(!sessions.is_active());
+    }
+
+    #[tokio::test]
+    async fn passes_data_on_when_turned_off() {
+        let (mut game, local) = duplex(4096);
+        let mut stream = GproxyStream::new(local, false, GproxySessions::default());
+        let join = slot_info_join(2);
+        stream.write_all(&join).await.unwrap();
+        let mut received = vec![0; join.len()];
+        game.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, join);
+
+        game.write_all(b"ping").await.unwrap();
+        let mut sent = [0; 4];
+        stream.read_exact(&mut sent).await.unwrap();
+        assert_eq!(&sent, b"ping");
+        assert!(!stream.can_reconnect());
+    }
+
+    #[test]
+    fn learns_reconnect_port_of_client() {
+        let ports = ReconnectPorts::default();
+        let peer = SecretKey::from_bytes(&[7; 32]).public();
+        let mut observer = ports.observer(peer);
+        let init = init();
+        observer.on_read(&slot_info_join(2));
+        observer.on_read(&init[..5]);
+        assert_eq!(ports.get(peer), None);
+        observer.on_read(&init[5..]);
+        assert_eq!(ports.get(peer), Some(6114));
+    }
+}
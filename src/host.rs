@@ -1,91 +1,753 @@
+use std::{
+    collections::{HashMap, HashSet, hash_map::Entry},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
 use iroh::{
-    Endpoint, PublicKey,
+    Endpoint, EndpointAddr, PublicKey,
     endpoint::{Connection, RecvStream, SendStream, WriteError},
     protocol::{AcceptError, ProtocolHandler, Router},
 };
 use tokio::{
     net::TcpStream,
-    sync::broadcast::{Receiver, Sender},
+    sync::{
+        broadcast::{self, Receiver, Sender, error::RecvError},
+        watch,
+    },
+    task::JoinHandle,
+    time::{Instant, sleep, timeout, timeout_at},
 };
+use tracing::{debug, error, info, warn};
 
 use crate::{
-    game_scanner, handle_error_displayed,
-    packets::GenerableWc3UdpMessageType,
-    utils::{ALPN, LOCALHOST_WC3_ADDR, try_serialize},
+    chat::Announcer,
+    error::{CLOSE_HOST_SHUTDOWN, CLOSE_PROTOCOL_ERROR, Error, ProtocolError, RejectReason},
+    events::HostEvent,
+    game::{GameTracker, Side, TrackerEvent},
+    game_scanner::{self, GameScanner},
+    gproxy::{self, ReconnectPorts},
+    logging::{PacketLogger, log_udp_packet},
+    map_transfer::MapServer,
+    p2p::PeerAddressFilter,
+    packets::{GenerableWc3UdpMessageType, ServerClosed},
+    path::{ConnectionKind, PathEvent, PathStatus, current_path, monitor_path},
+    replay::{ReplayRecorder, encode_message},
+    resume::{
+        Hello, RESUME_TIMEOUT, TunnelLinks, accept_stream, read_hello, refuse_stream, resumable,
+    },
+    simulation::{SimulatedLobby, Simulation},
+    stats::TunnelRegistry,
+    status::{HostStatus, InGamePlayer, PeerStatus},
+    tap::{Rewritten, Tapped},
+    utils::{ALPN, LOCALHOST_WC3_ADDR, MAP_ALPN, SPECTATOR_ALPN, try_serialize},
+    virtual_game::{VirtualGame, VirtualGameConfig},
 };
 
-pub async fn run_host() {
-    let ep = handle_error_displayed!(
-        Endpoint::builder().bind().await,
-        "Can't create endpoint: {}"
-    );
+/// Settings for a [`Host`].
+#[derive(Debug, Clone)]
+pub struct HostConfig {
+    /// Where the local WC3 instance listens for UDP queries and TCP game connections.
+    pub game_addr: SocketAddr,
+    /// Clients have to know this password to join. `None` lets everyone in.
+    pub password: Option<String>,
+    /// Follow the game in the forwarded game traffic and report it as [`HostEvent::Game`].
+    pub decode_game_traffic: bool,
+    /// Save a .w3g replay of every game into this directory, rebuilt from the forwarded game traffic.
+    pub replay_dir: Option<PathBuf>,
+    /// Let [`crate::Spectator`]s watch the running game. They need the password as well.
+    pub spectators: bool,
+    /// Tell the players in the lobby how fast the clients are connected: once when a client joins,
+    /// then every [`PING_INTERVAL`]. WC3 shows a ping close to zero for everyone behind the tunnel.
+    pub announce_pings: bool,
+    /// The `Maps` folder of the local WC3. The map of the lobby is offered from it to the clients,
+    /// which download it over iroh instead of through the much slower game connection.
+    pub maps_dir: Option<PathBuf>,
+    /// Host a game without WC3. `game_addr` is ignored, the map is offered from its folder if
+    /// `maps_dir` is not set.
+    pub virtual_game: Option<VirtualGameConfig>,
+    /// Advertise a made-up lobby that can't be joined instead of polling WC3, to test clients on
+    /// machines without WC3. `game_addr` is ignored. Has no effect with a virtual game.
+    pub simulate: Option<SimulatedLobby>,
+}
 
-    let game_scanner_tx = handle_error_displayed!(
-        game_scanner::run_game_scanner().await,
-        "Can't start game scanner: {}"
-    );
+impl Default for HostConfig {
+    fn default() -> Self {
+        HostConfig {
+            game_addr: LOCALHOST_WC3_ADDR,
+            password: None,
+            decode_game_traffic: true,
+            replay_dir: None,
+            spectators: false,
+            announce_pings: false,
+            maps_dir: None,
+            virtual_game: None,
+            simulate: None,
+        }
+    }
+}
 
-    let handler = ClientHandler {
-        scanner: game_scanner_tx,
-    };
-    //Do not drop the router. It runs the protocol handler in the background.
-    let _router = Router::builder(ep.clone()).accept(ALPN, handler).spawn();
-    ep.online().await;
-    println!("Host is running with address:");
-    println!("{}", ep.addr().id);
-    println!();
-    println!(
-        "Copy this address (by selecting it and right-clicking) and share it with all players to let them connect"
-    );
-    println!("Press Ctrl+C or close the window to shut down");
-    println!();
+/// Longest password a client may send.
+pub(crate) const MAX_PASSWORD_LEN: usize = 256;
+/// How long a new client has to send its password.
+const PASSWORD_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a stopping host waits for clients to receive the last packets and for game connections to end.
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(2);
+/// How often the pings of the clients are announced in the lobby.
+pub const PING_INTERVAL: Duration = Duration::from_secs(30);
 
-    handle_error_displayed!(
-        tokio::signal::ctrl_c().await,
-        "failed to listen for event: {}"
-    );
-    println!("Shutting down host...");
+/// A running host. Serves the lobby of the local game to all connecting clients until stopped.
+pub struct Host {
+    endpoint: Endpoint,
+    router: Router,
+    events: Sender<HostEvent>,
+    scanner: GameScanner,
+    clients: ConnectedClients,
+    tunnels: TunnelRegistry,
+    links: TunnelLinks,
+    game: Option<Arc<GameTracker>>,
+    announcer: Announcer,
+    access: Access,
+    shutdown: watch::Sender<bool>,
+    virtual_game: Option<VirtualGame>,
+    _simulation: Option<Simulation>,
+}
+
+type ConnectedClients = Arc<Mutex<HashMap<PublicKey, ConnectedClient>>>;
+
+#[derive(Debug)]
+struct ConnectedClient {
+    connection: Connection,
+    connected_at: Instant,
+    /// The last player that joined the game from this client.
+    player: Option<InGamePlayer>,
+    /// Sends the lobby packets. Finishes after the last packet was received when shutting down.
+    lobby_task: Option<JoinHandle<()>>,
+    /// The `stable_id` of the latest connection of the client.
+    connection_id: watch::Sender<usize>,
+}
+
+/// Who may join. Shared between the [`Host`] and its [`ClientHandler`].
+#[derive(Debug, Clone, Default)]
+struct Access {
+    password: Arc<Mutex<Option<String>>>,
+    banned: Arc<Mutex<HashSet<PublicKey>>>,
+}
+
+impl Host {
+    /// Binds a new endpoint and starts hosting on it.
+    pub async fn start(config: HostConfig) -> Result<Host, Error> {
+        let endpoint = Endpoint::builder()
+            .bind()
+            .await
+            .map_err(Error::EndpointBind)?;
+        Self::start_on(endpoint, config).await
+    }
+
+    /// Starts hosting on an existing endpoint.
+    pub async fn start_on(endpoint: Endpoint, mut config: HostConfig) -> Result<Host, Error> {
+        let virtual_game = match config.virtual_game.take() {
+            Some(virtual_config) => {
+                if config.maps_dir.is_none() {
+                    config.maps_dir = virtual_config.map.parent().map(PathBuf::from);
+                }
+                let virtual_game = VirtualGame::start(virtual_config).await?;
+                config.game_addr = virtual_game.addr();
+                Some(virtual_game)
+            }
+            None => None,
+        };
+        let simulation = match config.simulate.take() {
+            Some(lobby) if virtual_game.is_none() => {
+                let simulation = Simulation::start(lobby).await?;
+                config.game_addr = simulation.addr();
+                Some(simulation)
+            }
+            _ => None,
+        };
+        let (events, _) = broadcast::channel(64);
+        let scanner = game_scanner::run_game_scanner(config.game_addr, events.clone())
+            .await
+            .map_err(Error::ScannerStart)?;
+
+        let clients = ConnectedClients::default();
+        let tunnels = TunnelRegistry::default();
+        let links = TunnelLinks::default();
+        let access = Access::default();
+        *access.password.lock().unwrap() = config.password;
+        let (shutdown, _) = watch::channel(false);
+        let announcer = Announcer::new(scanner.advertised());
+        let game = config.decode_game_traffic.then(|| {
+            let game_events = events.clone();
+            let players = clients.clone();
+            let announcer = announcer.clone();
+            let announce_pings = config.announce_pings;
+            GameTracker::new(move |event| {
+                let event = match event {
+                    TrackerEvent::Game(event) => HostEvent::Game(event),
+                    TrackerEvent::Identified(client_id, player) => {
+                        let path = players.lock().unwrap().get_mut(&client_id).map(|client| {
+                            client.player = Some(player.clone());
+                            current_path(&client.connection)
+                        });
+                        if let Some(path) = path.filter(|_| announce_pings) {
+                            announcer.say(&join_announcement(&player.name, path));
+                        }
+                        HostEvent::ClientIdentified(client_id, player)
+                    }
+                };
+                let _ = game_events.send(event);
+            })
+        });
+        let replay = (config.replay_dir.is_some() || config.spectators).then(|| {
+            let replay_events = events.clone();
+            ReplayRecorder::new(config.replay_dir, scanner.advertised(), move |path| {
+                let _ = replay_events.send(HostEvent::ReplaySaved(path));
+            })
+        });
+        let spectators = config.spectators.then(|| SpectatorHandler {
+            recorder: replay.clone().expect("recorder for spectators"),
+            events: events.clone(),
+            access: access.clone(),
+            shutdown: shutdown.subscribe(),
+        });
+        let maps = config.maps_dir.map(|maps_dir| MapHandler {
+            server: Arc::new(MapServer::new(maps_dir, scanner.advertised())),
+            events: events.clone(),
+            access: access.clone(),
+        });
+        let handler = ClientHandler {
+            scanner: scanner.sender(),
+            forwarding: Forwarding {
+                game_addr: config.game_addr,
+                tunnels: tunnels.clone(),
+                links: links.clone(),
+                reconnect_ports: ReconnectPorts::default(),
+                game: game.clone(),
+                replay,
+                announcer: announcer.clone(),
+            },
+            events: events.clone(),
+            clients: clients.clone(),
+            access: access.clone(),
+            shutdown: shutdown.subscribe(),
+        };
+        let mut router = Router::builder(endpoint.clone()).accept(ALPN, handler);
+        if let Some(spectators) = spectators {
+            router = router.accept(SPECTATOR_ALPN, spectators);
+        }
+        if let Some(maps) = maps {
+            router = router.accept(MAP_ALPN, maps);
+        }
+        let router = router.spawn();
+        if config.announce_pings
+            && let Some(game) = &game
+        {
+            tokio::spawn(announce_pings(
+                clients.clone(),
+                game.clone(),
+                announcer.clone(),
+                shutdown.subscribe(),
+            ));
+        }
+
+        Ok(Host {
+            endpoint,
+            router,
+            events,
+            scanner,
+            clients,
+            tunnels,
+            links,
+            game,
+            announcer,
+            access,
+            shutdown,
+            virtual_game,
+            _simulation: simulation,
+        })
+    }
+
+    /// The id clients need to connect to this host.
+    pub fn id(&self) -> PublicKey {
+        self.endpoint.id()
+    }
+
+    /// The full address of this host, including all currently known direct addresses and relays.
+    pub fn addr(&self) -> EndpointAddr {
+        self.endpoint.addr()
+    }
+
+    /// Waits until the endpoint is reachable through a relay.
+    pub async fn online(&self) {
+        self.endpoint.online().await;
+    }
+
+    /// Subscribes to the events of this host. Only events sent after subscribing are received.
+    pub fn subscribe(&self) -> Receiver<HostEvent> {
+        self.events.subscribe()
+    }
+
+    /// What the host is doing right now.
+    pub fn status(&self) -> HostStatus {
+        let mut clients: Vec<_> = self
+            .clients
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, client)| PeerStatus {
+                id: *id,
+                path: current_path(&client.connection),
+                connected_at: client.connected_at.into_std(),
+                traffic: self.tunnels.traffic(*id),
+                player: client.player.clone(),
+            })
+            .collect();
+        clients.sort_by_key(|client| client.id);
+        HostStatus {
+            lobby: self.scanner.lobby(),
+            clients,
+            tunnels: self.tunnels.snapshot(),
+            game: self.game.as_ref().and_then(|game| game.phase()),
+        }
+    }
+
+    /// Disconnects a client. It may connect again.
+    /// Returns `false` if no client with this id is connected.
+    pub fn kick(&self, id: PublicKey) -> bool {
+        self.reject(id, RejectReason::Kicked)
+    }
+
+    /// Disconnects a client and refuses all further connections from it.
+    /// Returns `false` if no client with this id is connected, it is banned anyway.
+    pub fn ban(&self, id: PublicKey) -> bool {
+        self.access.banned.lock().unwrap().insert(id);
+        self.reject(id, RejectReason::Banned)
+    }
+
+    /// Changes the password new clients need. Connected clients stay.
+    pub fn set_password(&self, password: Option<String>) {
+        *self.access.password.lock().unwrap() = password;
+    }
+
+    /// The pings of all clients that joined the game, like `Ping: Alice 45ms, Bob 130ms`.
+    /// `None` if no client joined yet.
+    pub fn pings(&self) -> Option<String> {
+        ping_summary(&self.clients)
+    }
+
+    /// Shows a message in the lobby or the running game to every player that joined through the tunnel.
+    /// It appears as chat of the player at the game host. Games with GProxy reconnects don't get it.
+    pub fn say(&self, message: &str) {
+        info!("Saying to the players: {message}");
+        self.announcer.say(message);
+    }
+
+    /// Starts the game of the virtual host. Returns `false` without a virtual game, if nobody with the
+    /// map is in its lobby or if it is already running.
+    pub async fn start_game(&self) -> bool {
+        match &self.virtual_game {
+            Some(virtual_game) => virtual_game.start_game().await,
+            None => false,
+        }
+    }
+
+    fn reject(&self, id: PublicKey, reason: RejectReason) -> bool {
+        match self.clients.lock().unwrap().get(&id) {
+            Some(client) => {
+                info!(
+                    "Removing {}: {reason}",
+                    client_label(id, client.player.as_ref())
+                );
+                client
+                    .connection
+                    .close(reason.close_code(), reason.to_string().as_bytes());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Tells all clients that every lobby they saw is gone, gives open game connections a moment to end,
+    /// then disconnects all clients and stops hosting.
+    pub async fn stop(self) {
+        info!("Shutting down host");
+        let deadline = Instant::now() + SHUTDOWN_GRACE_PERIOD;
+        //Sent before the shutdown signal, so every client gets them as its last packets
+        for game_id in self.scanner.advertised_game_ids() {
+            let _ = self
+                .scanner
+                .sender()
+                .send(GenerableWc3UdpMessageType::ServerClosed(ServerClosed {
+                    game_id,
+                }));
+        }
+        let _ = self.shutdown.send(true);
+        //Game connections of clients that are gone won't come back
+        self.links.clear();
+
+        let lobby_tasks: Vec<_> = self
+            .clients
+            .lock()
+            .unwrap()
+            .values_mut()
+            .filter_map(|client| client.lobby_task.take())
+            .collect();
+        for task in lobby_tasks {
+            let _ = timeout_at(deadline, task).await;
+        }
+        while !self.tunnels.snapshot().is_empty() && Instant::now() < deadline {
+            sleep(Duration::from_millis(50)).await;
+        }
+        let open_tunnels = self.tunnels.snapshot().len();
+        if open_tunnels > 0 {
+            debug!("Closing {open_tunnels} game connections that are still open");
+        }
+
+        for client in self.clients.lock().unwrap().values() {
+            client
+                .connection
+                .close(CLOSE_HOST_SHUTDOWN, b"host shut down");
+        }
+        //Errors only mean the router was already stopped
+        let _ = self.router.shutdown().await;
+        self.endpoint.close().await;
+    }
 }
 
 #[derive(Debug, Clone)]
 struct ClientHandler {
     pub scanner: Sender<GenerableWc3UdpMessageType>,
+    pub forwarding: Forwarding,
+    pub events: Sender<HostEvent>,
+    pub clients: ConnectedClients,
+    pub access: Access,
+    pub shutdown: watch::Receiver<bool>,
+}
+
+impl Access {
+    /// Checks the ban list and the password. Closes the connection if the peer may not join.
+    /// `peer` says what the peer is in the log, like "client" or "spectator".
+    async fn admit(&self, connection: &Connection, peer: &str, events: &Sender<HostEvent>) -> bool {
+        let client_id = connection.remote_id();
+        if self.banned.lock().unwrap().contains(&client_id) {
+            turn_away(connection, peer, RejectReason::Banned, events);
+            return false;
+        }
+
+        //Every client sends a password, even if none is needed
+        let password = timeout(PASSWORD_TIMEOUT, async {
+            let mut recv = connection.accept_uni().await.ok()?;
+            recv.read_to_end(MAX_PASSWORD_LEN).await.ok()
+        })
+        .await;
+        let password = match password {
+            Ok(Some(password)) => password,
+            _ => {
+                warn!("The {peer} {client_id} did not send a password");
+                connection.close(CLOSE_PROTOCOL_ERROR, b"protocol error");
+                return false;
+            }
+        };
+        let expected = self.password.lock().unwrap().clone();
+        match expected {
+            Some(expected) if expected.as_bytes() != password => {
+                turn_away(connection, peer, RejectReason::WrongPassword, events);
+                false
+            }
+            _ => true,
+        }
+    }
+}
+
+fn turn_away(
+    connection: &Connection,
+    peer: &str,
+    reason: RejectReason,
+    events: &Sender<HostEvent>,
+) {
+    let client_id = connection.remote_id();
+    info!("Rejected {peer} {client_id}: {reason}");
+    connection.close(reason.close_code(), reason.to_string().as_bytes());
+    let _ = events.send(HostEvent::ClientRejected(client_id, reason));
 }
 
 impl ProtocolHandler for ClientHandler {
     async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
         let client_id = connection.remote_id();
-        println!("New client connected: {client_id}");
-
+        if !self.access.admit(&connection, "client", &self.events).await {
+            return Ok(());
+        }
         let scanner = self.scanner.subscribe();
-        tokio::spawn(send_udp_packets_to_client(connection.clone(), scanner));
-        tokio::spawn(accept_tcp_forwarding(connection.clone()));
+        let shutdown = self.shutdown.clone();
+        let udp_connection = connection.clone();
+        let lobby_task = tokio::spawn(async move {
+            if let Err(e) = send_udp_packets_to_client(&udp_connection, scanner, shutdown).await {
+                warn!("Stopped sending lobby packets to client {client_id}: {e}");
+            }
+        });
+        let reconnected = match self.clients.lock().unwrap().entry(client_id) {
+            //The client comes back after its connection broke
+            Entry::Occupied(mut client) => {
+                let client = client.get_mut();
+                client.connection = connection.clone();
+                client.lobby_task = Some(lobby_task);
+                client.connection_id.send_replace(connection.stable_id());
+                true
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(ConnectedClient {
+                    connection: connection.clone(),
+                    connected_at: Instant::now(),
+                    player: None,
+                    lobby_task: Some(lobby_task),
+                    connection_id: watch::Sender::new(connection.stable_id()),
+                });
+                false
+            }
+        };
+        if reconnected {
+            info!("Client {client_id} reconnected");
+            let _ = self.events.send(HostEvent::ClientReconnected(client_id));
+        } else {
+            info!("New client connected: {client_id}");
+            let _ = self.events.send(HostEvent::ClientConnected(client_id));
+        }
+        let path_events = self.events.clone();
+        tokio::spawn(monitor_path(
+            connection.clone(),
+            format!("client {client_id}"),
+            move |event| {
+                let _ = path_events.send(match event {
+                    PathEvent::Changed(path) => HostEvent::ClientPathChanged(client_id, path),
+                    PathEvent::StillRelayed => HostEvent::ClientStillRelayed(client_id),
+                });
+            },
+        ));
+        tokio::spawn(accept_tcp_forwarding(
+            connection.clone(),
+            self.forwarding.clone(),
+        ));
+
+        let reason = connection.closed().await;
+        //The game host may wait longer for the player than the tunnels
+        let forwarding = &self.forwarding;
+        let rejoin = forwarding.reconnect_ports.get(client_id).is_some();
+        if resumable(&reason) && (rejoin || !forwarding.links.of_peer(client_id).is_empty()) {
+            info!("Lost connection to client {client_id}, waiting for it to come back: {reason}");
+            let _ = self.events.send(HostEvent::ClientConnectionLost(client_id));
+            let mut connection_id = self
+                .clients
+                .lock()
+                .unwrap()
+                .get(&client_id)
+                .map(|client| client.connection_id.subscribe());
+            let taken_over = async {
+                match &mut connection_id {
+                    Some(id) => {
+                        let _ = id.wait_for(|id| *id != connection.stable_id()).await;
+                    }
+                    None => std::future::pending().await,
+                }
+            };
+            //A client that can rejoin has no tunnels left while the game waits for it
+            let drained = async {
+                if rejoin {
+                    std::future::pending().await
+                } else {
+                    forwarding.links.drained(client_id).await
+                }
+            };
+            tokio::select! {
+                _ = taken_over => {}
+                _ = drained => {}
+                _ = sleep(if rejoin {
+                    gproxy::RECONNECT_TIMEOUT
+                } else {
+                    RESUME_TIMEOUT
+                }) => {}
+            }
+        }
+        let client = {
+            let mut clients = self.clients.lock().unwrap();
+            match clients.get(&client_id) {
+                //A newer connection of the client took over
+                Some(client) if client.connection.stable_id() != connection.stable_id() => {
+                    return Ok(());
+                }
+                _ => clients.remove(&client_id),
+            }
+        };
+        forwarding.links.remove_peer(client_id);
+        forwarding.reconnect_ports.remove(client_id);
+        let connected_at = client.as_ref().map(|client| client.connected_at);
+        let player = client.and_then(|client| client.player);
+        let traffic = forwarding.tunnels.forget(client_id);
+        info!(
+            "Disconnected {}, connected for {:.1?}, {} game connections, {traffic}",
+            client_label(client_id, player.as_ref()),
+            connected_at.map(|at| at.elapsed()).unwrap_or_default(),
+            traffic.streams
+        );
+        let _ = self.events.send(HostEvent::ClientDisconnected(client_id));
+
+        Ok(())
+    }
+}
 
+/// Sends the running game to spectators.
+#[derive(Debug, Clone)]
+struct SpectatorHandler {
+    recorder: Arc<ReplayRecorder>,
+    events: Sender<HostEvent>,
+    access: Access,
+    shutdown: watch::Receiver<bool>,
+}
+
+impl ProtocolHandler for SpectatorHandler {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        let spectator_id = connection.remote_id();
+        if !self
+            .access
+            .admit(&connection, "spectator", &self.events)
+            .await
+        {
+            return Ok(());
+        }
+        info!("New spectator connected: {spectator_id}");
+        let _ = self
+            .events
+            .send(HostEvent::SpectatorConnected(spectator_id));
+        if let Err(e) = send_feed(&connection, &self.recorder, self.shutdown.clone()).await {
+            warn!("Stopped sending the game to spectator {spectator_id}: {e}");
+        }
         connection.closed().await;
-        println!("Client disconnected: {client_id}");
+        info!("Spectator {spectator_id} disconnected");
+        let _ = self
+            .events
+            .send(HostEvent::SpectatorDisconnected(spectator_id));
+        Ok(())
+    }
+}
+
+/// Sends the map of the lobby to clients that don't have it.
+#[derive(Debug, Clone)]
+struct MapHandler {
+    server: Arc<MapServer>,
+    events: Sender<HostEvent>,
+    access: Access,
+}
 
+impl ProtocolHandler for MapHandler {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        let client_id = connection.remote_id();
+        if !self.access.admit(&connection, "client", &self.events).await {
+            return Ok(());
+        }
+        while let Ok((send, recv)) = connection.accept_bi().await {
+            match self.server.serve(send, recv).await {
+                Ok(Some(map_name)) => {
+                    info!("Sent map {map_name} to client {client_id}");
+                    let _ = self.events.send(HostEvent::MapSent(client_id, map_name));
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("Can't send the map to client {client_id}: {e}");
+                    break;
+                }
+            }
+        }
         Ok(())
     }
 }
 
+async fn send_feed(
+    connection: &Connection,
+    recorder: &ReplayRecorder,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<(), Error> {
+    let mut send = match connection.open_uni().await {
+        Ok(stream) => stream,
+        Err(_) if connection.close_reason().is_some() => return Ok(()),
+        Err(e) => return Err(ProtocolError::OpenStream(e).into()),
+    };
+
+    let (mut start, mut feed) = recorder.subscribe();
+    loop {
+        let message = match start.take() {
+            Some(start) => start,
+            None => {
+                let message = tokio::select! {
+                    message = feed.recv() => message,
+                    _ = connection.closed() => return Ok(()),
+                    _ = async { shutdown.wait_for(|stopping| *stopping).await.is_ok() } => {
+                        return Ok(());
+                    }
+                };
+                match message {
+                    Ok(message) => message,
+                    Err(RecvError::Lagged(_)) => {
+                        debug!(
+                            "Spectator {} fell behind, sending the whole game again",
+                            connection.remote_id()
+                        );
+                        (start, feed) = recorder.subscribe();
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                }
+            }
+        };
+        let encoded = encode_message(&message).ok_or(ProtocolError::Serialize("FeedMessage"))?;
+        match send.write_all(&encoded).await {
+            Err(
+                WriteError::ConnectionLost(_) | WriteError::ClosedStream | WriteError::Stopped(_),
+            ) => return Ok(()),
+            Err(e) => return Err(ProtocolError::Write(e).into()),
+            Ok(()) => {}
+        }
+    }
+}
+
 async fn send_udp_packets_to_client(
-    connection: Connection,
+    connection: &Connection,
     mut scanner: Receiver<GenerableWc3UdpMessageType>,
-) {
-    let mut udp_send_stream = handle_error_displayed!(
-        connection.open_uni().await,
-        "Can't open UDP stream to client: {}"
-    );
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<(), Error> {
+    let client_id = connection.remote_id();
+    let mut udp_send_stream = match connection.open_uni().await {
+        Ok(stream) => stream,
+        Err(_) if connection.close_reason().is_some() => return Ok(()),
+        Err(e) => return Err(ProtocolError::OpenStream(e).into()),
+    };
 
     loop {
-        if let Ok(message) = scanner.recv().await {
+        let message = tokio::select! {
+            //Packets that were queued before the shutdown still go out
+            biased;
+            message = scanner.recv() => message,
+            _ = async { shutdown.wait_for(|stopping| *stopping).await.is_ok() } => {
+                //Wait until the client has everything, closing the connection would drop the rest
+                if udp_send_stream.finish().is_ok() {
+                    let _ = udp_send_stream.stopped().await;
+                }
+                return Ok(());
+            }
+        };
+        if let Ok(message) = message {
             let serialized_packet = if let Some(serialized_packet) = try_serialize(&message) {
                 serialized_packet
             } else {
-                eprintln!("Failed to serialize UDP packet");
+                error!("Failed to serialize UDP packet");
                 continue;
             };
 
+            log_udp_packet(
+                &format!("scanner -> client {client_id}"),
+                &serialized_packet,
+            );
             match udp_send_stream.write_all(&serialized_packet).await {
                 Err(
                     WriteError::ConnectionLost(_)
@@ -93,52 +755,257 @@ async fn send_udp_packets_to_client(
                     | WriteError::Stopped(_),
                 ) => {
                     //Connection or stream closed, stop sending packets
-                    break;
-                }
-                Err(e) => {
-                    eprintln!("Error sending UDP packet to client: {e}");
+                    return Ok(());
                 }
+                Err(e) => return Err(ProtocolError::Write(e).into()),
                 Ok(_) => {}
             }
         }
     }
 }
 
-async fn accept_tcp_forwarding(connection: Connection) {
+/// Announces the pings of the clients while the game is in the lobby.
+async fn announce_pings(
+    clients: ConnectedClients,
+    game: Arc<GameTracker>,
+    announcer: Announcer,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    loop {
+        tokio::select! {
+            _ = sleep(PING_INTERVAL) => {}
+            _ = async { shutdown.wait_for(|stopping| *stopping).await.is_ok() } => return,
+        }
+        let in_lobby = game.phase().is_some_and(|phase| !phase.is_started());
+        if let Some(pings) = ping_summary(&clients).filter(|_| in_lobby) {
+            announcer.say(&pings);
+        }
+    }
+}
+
+fn ping_summary(clients: &ConnectedClients) -> Option<String> {
+    let mut players: Vec<_> = clients
+        .lock()
+        .unwrap()
+        .values()
+        .filter_map(|client| {
+            let player = client.player.as_ref()?;
+            let rtt = current_path(&client.connection).rtt?;
+            Some((player.player_id, player.name.clone(), rtt))
+        })
+        .collect();
+    if players.is_empty() {
+        return None;
+    }
+    players.sort();
+    let pings: Vec<_> = players
+        .iter()
+        .map(|(_, name, rtt)| format!("{name} {}ms", rtt.as_millis()))
+        .collect();
+    Some(format!("Ping: {}", pings.join(", ")))
+}
+
+/// Like `Alice is connected via relay, 180 ms`.
+fn join_announcement(name: &str, path: PathStatus) -> String {
+    let kind = match path.kind {
+        ConnectionKind::Direct => " directly",
+        ConnectionKind::Relayed => " via relay",
+        ConnectionKind::Unknown => "",
+    };
+    match path.rtt {
+        Some(rtt) => format!("{name} is connected{kind}, {} ms", rtt.as_millis()),
+        None => format!("{name} is connected{kind}"),
+    }
+}
+
+/// `client <id>`, with the player name once it is known.
+fn client_label(id: PublicKey, player: Option<&InGamePlayer>) -> String {
+    match player {
+        Some(player) => format!("client {id} ({})", player.name),
+        None => format!("client {id}"),
+    }
+}
+
+/// What the game connections of all clients share.
+#[derive(Debug, Clone)]
+struct Forwarding {
+    game_addr: SocketAddr,
+    tunnels: TunnelRegistry,
+    links: TunnelLinks,
+    reconnect_ports: ReconnectPorts,
+    game: Option<Arc<GameTracker>>,
+    replay: Option<Arc<ReplayRecorder>>,
+    announcer: Announcer,
+}
+
+async fn accept_tcp_forwarding(connection: Connection, forwarding: Forwarding) {
     let client_id = connection.remote_id();
 
     loop {
         match connection.accept_bi().await {
             Ok((send, recv)) => {
+                let forwarding = forwarding.clone();
                 tokio::spawn(async move {
-                    let _ = handle_tcp_forwarding_connection(send, recv, client_id).await;
+                    if let Err(e) =
+                        handle_tcp_forwarding_connection(send, recv, client_id, &forwarding).await
+                    {
+                        warn!("TCP port forwarding for client {client_id} failed: {e}");
+                    }
                 });
             }
             Err(e) => {
                 if connection.close_reason().is_some() {
                     break;
                 } else {
-                    eprintln!("Error accepting incoming TCP stream from client {client_id}: {e}");
+                    warn!("Error accepting incoming TCP stream from client {client_id}: {e}");
                 }
             }
         };
     }
 }
 
+/// Takes a stream of a client. It either starts a new game connection, continues one after the
+/// connection of the client broke, or rejoins the game through the reconnect port of the game host.
 async fn handle_tcp_forwarding_connection(
-    mut send: SendStream,
+    send: SendStream,
     mut recv: RecvStream,
     client_id: PublicKey,
-) -> Result<(), ()> {
-    let mut local_stream = TcpStream::connect(LOCALHOST_WC3_ADDR)
-        .await
-        .map_err(|e| eprintln!("Error connecting to local TCP port for client {client_id}: {e}"))?;
+    forwarding: &Forwarding,
+) -> Result<(), Error> {
+    let Forwarding {
+        game_addr,
+        tunnels,
+        links,
+        reconnect_ports,
+        game,
+        replay,
+        announcer,
+    } = forwarding;
+    let hello = read_hello(&mut recv).await?;
+    if let Hello::Resume { tunnel, .. } = hello {
+        match links.get(client_id, tunnel) {
+            Some(link) => {
+                let transport = accept_stream(send, recv, hello, link.received()).await?;
+                if link.attach(transport).await {
+                    debug!("Resuming TCP connection {tunnel} of client {client_id}");
+                }
+            }
+            None => {
+                debug!("Client {client_id} wants to resume unknown TCP connection {tunnel}");
+                refuse_stream(send).await;
+            }
+        }
+        return Ok(());
+    }
 
-    let mut web_connection = tokio::io::join(&mut recv, &mut send);
-    tokio::io::copy_bidirectional(&mut web_connection, &mut local_stream)
+    let game_addr = match hello {
+        Hello::Rejoin(_) => match reconnect_ports.get(client_id) {
+            Some(port) => SocketAddr::new(game_addr.ip(), port),
+            None => {
+                debug!("Client {client_id} wants to rejoin a game that does not allow it");
+                refuse_stream(send).await;
+                return Ok(());
+            }
+        },
+        _ => *game_addr,
+    };
+    let Some(resumable) = links.register(client_id, hello.tunnel()) else {
+        debug!(
+            "Client {client_id} opens TCP connection {} again while it is still open",
+            hello.tunnel()
+        );
+        refuse_stream(send).await;
+        return Ok(());
+    };
+    let local_stream = TcpStream::connect(game_addr)
         .await
-        .map_err(|e| {
-            eprintln!("TCP port forwarding for client {client_id} stopped with error: {e}")
-        })?;
+        .map_err(|e| Error::GameConnect(game_addr, e))?;
+    debug!("Forwarding new TCP connection of client {client_id} to {game_addr}");
+    let game = game.as_ref().map(|game| game.tap(Side::Host, client_id));
+    let replay = replay.as_ref().map(|replay| replay.tap());
+    let tunnel = tunnels.open(client_id);
+    //The clients must not try to reach each other around the tunnel
+    let local_stream = Rewritten::new(local_stream, PeerAddressFilter);
+    let local_stream = Tapped::new(
+        local_stream,
+        (
+            (
+                PacketLogger::new(format!("client {client_id}")),
+                tunnel.byte_counter(),
+            ),
+            ((game, replay), reconnect_ports.observer(client_id)),
+        ),
+    );
+    //Outside of the taps, they only see what the game sent
+    let local_stream = Rewritten::new(local_stream, announcer.injector());
+
+    let transport = accept_stream(send, recv, hello, 0).await?;
+    let (to_game, from_game) = resumable.run(local_stream, transport).await?;
+    debug!(
+        "TCP connection of client {client_id} ended: {to_game} bytes to game, {from_game} bytes from game"
+    );
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt, duplex},
+        sync::watch,
+        time::timeout,
+    };
+
+    use super::{Forwarding, handle_tcp_forwarding_connection};
+    use crate::chat::Announcer;
+    use crate::gproxy::ReconnectPorts;
+    use crate::resume::{Hello, TunnelLinks, open_stream};
+    use crate::stats::TunnelRegistry;
+    use crate::test_utils::{fake_wc3_server::FakeWc3Server, loopback::connected_pair};
+
+    #[tokio::test]
+    async fn forwards_tcp_stream_to_game() {
+        let server = FakeWc3Server::start_with_greeting(b"hello".to_vec()).await;
+        let pair = connected_pair().await;
+
+        let client_id = pair.host.remote_id();
+        let forwarding = Forwarding {
+            game_addr: server.addr(),
+            tunnels: TunnelRegistry::default(),
+            links: TunnelLinks::default(),
+            reconnect_ports: ReconnectPorts::default(),
+            game: None,
+            replay: None,
+            announcer: Announcer::new(watch::channel(None).1),
+        };
+        let (tunnels, links) = (forwarding.tunnels.clone(), forwarding.links.clone());
+        let host_connection = pair.host.clone();
+        tokio::spawn(async move {
+            let (send, recv) = host_connection.accept_bi().await.unwrap();
+            handle_tcp_forwarding_connection(send, recv, client_id, &forwarding).await
+        });
+
+        //The client side of the tunnel, with a local game connection
+        let transport = open_stream(&pair.client, Hello::Open(1)).await.unwrap();
+        let (mut game, local) = duplex(1024);
+        let client_links = TunnelLinks::default();
+        let tunnel = client_links.register(pair.client.remote_id(), 1).unwrap();
+        tokio::spawn(tunnel.run(local, transport));
+        game.write_all(b"ping").await.unwrap();
+
+        let mut received = [0u8; 9];
+        timeout(Duration::from_secs(5), game.read_exact(&mut received))
+            .await
+            .expect("echo in time")
+            .unwrap();
+        assert_eq!(&received, b"helloping");
+        assert_eq!(server.accepted_connections(), 1);
+        assert!(links.get(client_id, 1).is_some());
+
+        let tunnel = &tunnels.snapshot()[0];
+        assert_eq!(tunnel.peer, client_id);
+        assert_eq!(tunnel.bytes_to_game, 4);
+        assert_eq!(tunnel.bytes_from_game, 9);
+    }
+}
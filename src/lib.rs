@@ -0,0 +1,44 @@
+//! Play Warcraft 3 LAN games over the internet.
+//!
+//! A [`Host`] polls the local WC3 instance for lobbies and serves them to every connected
+//! [`Client`] over [iroh](https://www.iroh.computer/). The client makes the lobby show up in the
+//! LAN game list of its local WC3 instance and tunnels the game connection back to the host.
+
+mod chat;
+pub mod client;
+pub mod error;
+pub mod events;
+mod game;
+mod game_scanner;
+mod gproxy;
+pub mod host;
+pub mod logging;
+pub mod map_info;
+mod map_transfer;
+pub mod maps;
+mod mpq;
+mod p2p;
+pub mod packets;
+pub mod path;
+mod replay;
+mod resume;
+pub mod simulation;
+pub mod spectator;
+mod stats;
+pub mod status;
+mod tap;
+#[cfg(test)]
+mod test_utils;
+#[cfg(test)]
+mod tests;
+pub mod utils;
+pub mod virtual_game;
+pub mod w3gs;
+
+pub use client::{Client, ClientConfig};
+pub use error::Error;
+pub use events::{
+    ClientEvent, GameEvent, GamePhase, HostEvent, LobbyEnd, LobbyInfo, SpectatorEvent,
+};
+pub use host::{Host, HostConfig};
+pub use spectator::{Spectator, SpectatorConfig};
@@ -0,0 +1,280 @@
+use std::{fmt, path::PathBuf};
+
+use tracing::{Level, level_filters::LevelFilter};
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{self, Rotation},
+};
+use tracing_subscriber::{
+    EnvFilter, Layer, filter::ParseError, layer::SubscriberExt, util::SubscriberInitExt,
+};
+
+use crate::{
+    packets::{GPS_HEADER, Wc3UdpMessageType, take_packet},
+    tap::StreamObserver,
+};
+
+/// Target of the packet dumps. Enable it on trace level to see every packet crossing the tunnel.
+pub const PACKETS_TARGET: &str = "simple_wc3::packets";
+
+/// Short module names that can be used in [`LogOptions::filter`].
+const MODULE_ALIASES: [(&str, &str); 4] = [
+    ("scanner", "simple_wc3::game_scanner"),
+    ("host", "simple_wc3::host"),
+    ("client", "simple_wc3::client"),
+    ("packets", PACKETS_TARGET),
+];
+
+/// How many rotated log files are kept.
+const MAX_LOG_FILES: usize = 7;
+
+#[derive(Debug, Clone)]
+pub struct LogOptions {
+    /// Level of the console output. `OFF` leaves the console completely quiet.
+    pub console_level: LevelFilter,
+    /// Additional per-module directives like `scanner=debug,host=trace`.
+    /// Module names are `scanner`, `host`, `client`, `packets` or any full tracing target.
+    pub filter: Option<String>,
+    /// Directory for a daily rotated log file. The file always logs on debug level or finer.
+    pub log_dir: Option<PathBuf>,
+    /// Hex-dump and decode every WC3 packet crossing the tunnel.
+    pub debug_packets: bool,
+}
+
+impl Default for LogOptions {
+    fn default() -> Self {
+        LogOptions {
+            console_level: LevelFilter::WARN,
+            filter: None,
+            log_dir: None,
+            debug_packets: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LoggingError {
+    Filter(ParseError),
+    Directory(std::io::Error),
+    File(rolling::InitError),
+}
+
+impl fmt::Display for LoggingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoggingError::Filter(e) => write!(f, "Invalid log filter: {e}"),
+            LoggingError::Directory(e) => write!(f, "Can't create log directory: {e}"),
+            LoggingError::File(e) => write!(f, "Can't create log file: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for LoggingError {}
+
+/// Installs the global logger. Keep the returned guard alive until the program ends,
+/// otherwise the last lines of the log file might get lost.
+pub fn init_logging(options: &LogOptions) -> Result<Option<WorkerGuard>, LoggingError> {
+    let filter = build_filter(options, options.console_level, LevelFilter::ERROR)?;
+    //Without a console layer, the other crates and the extra directives can't print either
+    let console_layer = if options.console_level == LevelFilter::OFF {
+        None
+    } else {
+        Some(
+            tracing_subscriber::fmt::layer()
+                .with_writer(std::io::stderr)
+                .with_filter(filter),
+        )
+    };
+
+    let (file_layer, guard) = match &options.log_dir {
+        Some(dir) => {
+            std::fs::create_dir_all(dir).map_err(LoggingError::Directory)?;
+            let appender = rolling::Builder::new()
+                .rotation(Rotation::DAILY)
+                .filename_prefix("simple-wc3")
+                .filename_suffix("log")
+                .max_log_files(MAX_LOG_FILES)
+                .build(dir)
+                .map_err(LoggingError::File)?;
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            let file_level = options.console_level.max(LevelFilter::DEBUG);
+            let layer = tracing_subscriber::fmt::layer()
+                .with_writer(writer)
+                .with_ansi(false)
+                .with_filter(build_filter(options, file_level, LevelFilter::WARN)?);
+            (Some(layer), Some(guard))
+        }
+        None => (None, None),
+    };
+
+    tracing_subscriber::registry()
+        .with(console_layer)
+        .with(file_layer)
+        .init();
+    Ok(guard)
+}
+
+/// `others` is the level for all other crates. They are mostly iroh and only interesting if something goes wrong.
+fn build_filter(
+    options: &LogOptions,
+    level: LevelFilter,
+    others: LevelFilter,
+) -> Result<EnvFilter, LoggingError> {
+    let mut directives = vec![others.to_string(), format!("simple_wc3={level}")];
+    if let Some(filter) = &options.filter {
+        directives.extend(
+            filter
+                .split(',')
+                .filter(|directive| !directive.trim().is_empty())
+                .map(|directive| expand_alias(directive.trim())),
+        );
+    }
+    if options.debug_packets {
+        directives.push(format!("{PACKETS_TARGET}=trace"));
+    }
+    EnvFilter::builder()
+        .parse(directives.join(","))
+        .map_err(LoggingError::Filter)
+}
+
+fn expand_alias(directive: &str) -> String {
+    let (module, level) = match directive.split_once('=') {
+        Some((module, level)) => (module, Some(level)),
+        None => (directive, None),
+    };
+    let target = MODULE_ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == module)
+        .map_or(module, |(_, target)| target);
+    match level {
+        Some(level) => format!("{target}={level}"),
+        None => target.to_string(),
+    }
+}
+
+fn packet_logging_enabled() -> bool {
+    tracing::enabled!(target: PACKETS_TARGET, Level::TRACE)
+}
+
+/// Formats `data` as classic hex dump with 16 bytes per line.
+pub fn hex_dump(data: &[u8]) -> String {
+    data.chunks(16)
+        .enumerate()
+        .map(|(line, chunk)| {
+            let hex: Vec<String> = chunk.iter().map(|byte| format!("{byte:02x}")).collect();
+            let ascii: String = chunk
+                .iter()
+                .map(|&byte| {
+                    if byte.is_ascii_graphic() || byte == b' ' {
+                        byte as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            format!("{:04x}: {:<47}  |{}|", line * 16, hex.join(" "), ascii)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Dumps a lobby packet. `route` describes where the packet goes, like `game -> host`.
+pub fn log_udp_packet(route: &str, data: &[u8]) {
+    if !packet_logging_enabled() {
+        return;
+    }
+    tracing::trace!(
+        target: PACKETS_TARGET,
+        "UDP {route} ({} bytes) {:?}\n{}",
+        data.len(),
+        Wc3UdpMessageType::detect(data),
+        hex_dump(data)
+    );
+}
+
+/// Dumps the game traffic of a forwarded TCP connection.
+/// Reads are data from the local game, writes are data to it.
+pub struct PacketLogger {
+    peer: String,
+    from_game: Vec<u8>,
+    to_game: Vec<u8>,
+}
+
+impl PacketLogger {
+    /// `peer` names the other side of the tunnel, like `host` or `client <id>`.
+    pub fn new(peer: impl Into<String>) -> Self {
+        PacketLogger {
+            peer: peer.into(),
+            from_game: Vec::new(),
+            to_game: Vec::new(),
+        }
+    }
+
+    fn log_frames(route: &str, buffer: &mut Vec<u8>, data: &[u8]) {
+        buffer.extend_from_slice(data);
+        loop {
+            match take_packet(buffer) {
+                Ok(Some(frame)) => tracing::trace!(
+                    target: PACKETS_TARGET,
+                    "TCP {route} {} 0x{:02x} ({} bytes)\n{}",
+                    if frame[0] == GPS_HEADER { "GPS" } else { "W3GS" },
+                    frame[1],
+                    frame.len(),
+                    hex_dump(&frame)
+                ),
+                Ok(None) => break,
+                Err(_) => {
+                    //Not W3GS framed, dump the raw bytes instead
+                    tracing::trace!(
+                        target: PACKETS_TARGET,
+                        "TCP {route} raw ({} bytes)\n{}",
+                        buffer.len(),
+                        hex_dump(buffer)
+                    );
+                    buffer.clear();
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl StreamObserver for PacketLogger {
+    fn on_read(&mut self, data: &[u8]) {
+        if packet_logging_enabled() {
+            let route = format!("game -> {}", self.peer);
+            Self::log_frames(&route, &mut self.from_game, data);
+        }
+    }
+
+    fn on_write(&mut self, data: &[u8]) {
+        if packet_logging_enabled() {
+            let route = format!("{} -> game", self.peer);
+            Self::log_frames(&route, &mut self.to_game, data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{expand_alias, hex_dump};
+
+    #[test]
+    fn expands_module_aliases() {
+        assert_eq!(
+            expand_alias("scanner=debug"),
+            "simple_wc3::game_scanner=debug"
+        );
+        assert_eq!(expand_alias("packets"), "simple_wc3::packets");
+        assert_eq!(expand_alias("iroh=info"), "iroh=info");
+    }
+
+    #[test]
+    fn dumps_hex_with_ascii() {
+        assert_eq!(
+            hex_dump(b"\xF7\x2FWAR3"),
+            "0000: f7 2f 57 41 52 33                                |./WAR3|"
+        );
+        assert_eq!(hex_dump(&[0u8; 17]).lines().count(), 2);
+    }
+}
@@ -1,38 +1,532 @@
-use std::str::FromStr;
+use std::{collections::HashMap, fmt, io, path::PathBuf, process::ExitCode, str::FromStr};
 
-use iroh::{EndpointAddr, PublicKey};
-
-use crate::{
-    client::run_client,
-    host::run_host,
-    utils::{APP_NAME, APP_VERSION},
+use clap::{CommandFactory, Parser, error::ErrorKind};
+use iroh::{EndpointAddr, KeyParsingError, PublicKey};
+use simple_wc3::{
+    Client, ClientConfig, ClientEvent, Error, Host, HostConfig, HostEvent, LobbyEnd, Spectator,
+    SpectatorConfig, SpectatorEvent,
+    error::RejectReason,
+    logging::{LogOptions, LoggingError, init_logging},
+    maps::{self, MapCheck},
+    simulation::SimulatedLobby,
+    utils::{APP_NAME, APP_VERSION, SUPPORTED_GAME_VERSIONS},
+    virtual_game::VirtualGameConfig,
 };
+use tokio::sync::broadcast::error::RecvError;
+use tracing::level_filters::LevelFilter;
+
+mod console;
+mod dashboard;
+
+#[derive(Parser)]
+#[command(version, about = "Play Warcraft 3 LAN games over the internet")]
+struct Cli {
+    /// Console log level: off, error, warn, info, debug or trace
+    #[arg(long, default_value = "warn")]
+    log_level: LevelFilter,
+    /// Per-module log levels like `scanner=debug,host=trace`.
+    /// Modules: scanner, host, client, packets
+    #[arg(long)]
+    log_filter: Option<String>,
+    /// Write a daily rotated debug log into this directory
+    #[arg(long)]
+    log_dir: Option<PathBuf>,
+    /// Hex-dump and decode every WC3 packet crossing the tunnel
+    #[arg(long)]
+    debug_packets: bool,
+    /// Show a live dashboard instead of plain messages. Disables console logging
+    #[arg(long)]
+    tui: bool,
+    /// As host: only let clients with this password join.
+    /// As client: the password of the host
+    #[arg(long)]
+    password: Option<String>,
+    /// Save a .w3g replay of every game into this directory. As host or as spectator,
+    /// a spectator keeps it up to date while the game runs
+    #[arg(long)]
+    replay_dir: Option<PathBuf>,
+    /// As host: let spectators watch the running game
+    #[arg(long)]
+    allow_spectators: bool,
+    /// As host: tell the players in the lobby the real ping of every client
+    #[arg(long)]
+    announce_pings: bool,
+    /// Watch the games of the host instead of joining them
+    #[arg(long)]
+    spectate: bool,
+    /// The Maps folder of WC3. As host: offer the map of the lobby to the clients.
+    /// As client: check whether you have the map of the lobby and download it from the host
+    #[arg(long)]
+    maps_dir: Option<PathBuf>,
+    /// As client: keep downloaded maps in this directory, so a map is only downloaded once
+    #[arg(long)]
+    map_cache: Option<PathBuf>,
+    /// As host: host a game of this map without WC3. Start it with the `start` command
+    /// or `!start` from the first player in the lobby
+    #[arg(long)]
+    virtual_map: Option<PathBuf>,
+    /// As host of a virtual game: the folder with common.j and blizzard.j or the WC3 1.26 folder,
+    /// for maps that don't contain these scripts
+    #[arg(long)]
+    scripts_dir: Option<PathBuf>,
+    /// As host of a virtual or simulated game: the name of the game in the LAN game list
+    #[arg(long)]
+    game_name: Option<String>,
+    /// As host of a virtual or simulated game: the WC3 version of the players, like 26 for 1.26
+    #[arg(long, default_value_t = 26, value_parser = game_version_parser())]
+    game_version: u32,
+    /// As host: send a made-up lobby to the clients instead of the one of WC3, to test them
+    /// without WC3. It can't be joined
+    #[arg(long, conflicts_with = "virtual_map")]
+    simulate: bool,
+    /// As host with --simulate: the map path of the lobby
+    #[arg(long, requires = "simulate")]
+    map_name: Option<String>,
+    /// As host with --simulate: how many players are in the lobby
+    #[arg(long, default_value_t = 1, requires = "simulate", value_parser = clap::value_parser!(u32).range(0..=12))]
+    players: u32,
+    /// As host with --simulate: how many slots the lobby has
+    #[arg(long, default_value_t = 4, requires = "simulate", value_parser = clap::value_parser!(u32).range(1..=12))]
+    slots: u32,
+}
+
+/// Only the versions the game scanner asks the local game for.
+fn game_version_parser() -> clap::builder::RangedI64ValueParser<u32> {
+    let versions = SUPPORTED_GAME_VERSIONS;
+    clap::value_parser!(u32).range(i64::from(*versions.start())..=i64::from(*versions.end()))
+}
+
+/// Everything that makes the program exit early.
+enum AppError {
+    Logging(LoggingError),
+    ReadInput(io::Error),
+    InvalidAddress(KeyParsingError),
+    Signal(io::Error),
+    Terminal(io::Error),
+    Tunnel(Error),
+}
 
-mod client;
-mod game_scanner;
-mod host;
-mod packets;
-mod utils;
+impl AppError {
+    fn exit_code(&self) -> u8 {
+        match self {
+            AppError::Logging(_)
+            | AppError::ReadInput(_)
+            | AppError::Signal(_)
+            | AppError::Terminal(_) => 1,
+            AppError::InvalidAddress(_) => 2,
+            AppError::Tunnel(Error::EndpointBind(_)) => 3,
+            AppError::Tunnel(Error::Connect(_)) => 4,
+            AppError::Tunnel(Error::ScannerStart(_)) => 5,
+            AppError::Tunnel(Error::Socket(_)) => 6,
+            AppError::Tunnel(Error::ConnectionLost(_)) => 7,
+            AppError::Tunnel(Error::Protocol(_)) => 8,
+            AppError::Tunnel(Error::Rejected(_)) => 9,
+            AppError::Tunnel(Error::MapFile(_)) => 10,
+            AppError::Tunnel(Error::Map(_)) => 11,
+            AppError::Tunnel(Error::GameConnect(..)) => 12,
+        }
+    }
+
+    /// What the user can do about it.
+    fn hint(&self) -> Option<&'static str> {
+        match self {
+            AppError::InvalidAddress(_) => Some(
+                "Copy the complete address shown by the host. It is 64 characters long and contains only 0-9 and a-f.",
+            ),
+            AppError::Tunnel(Error::EndpointBind(_)) => {
+                Some("Check your network connection and firewall settings.")
+            }
+            AppError::Tunnel(Error::Connect(_)) => Some(
+                "Check that the address is correct, the host is still running and both of you use the same version of Simple-WC3.",
+            ),
+            AppError::Tunnel(Error::ScannerStart(_) | Error::Socket(_)) => {
+                Some("Another program might be blocking the network ports. Try restarting.")
+            }
+            AppError::Tunnel(Error::GameConnect(..)) => {
+                Some("Check that WC3 is still hosting the game and its port is not blocked.")
+            }
+            AppError::Tunnel(Error::ConnectionLost(_)) => {
+                Some("The connection to the other player dropped. Reconnect to continue.")
+            }
+            AppError::Tunnel(Error::Protocol(_)) => {
+                Some("Make sure host and clients use the same version of Simple-WC3.")
+            }
+            AppError::Tunnel(Error::Rejected(RejectReason::WrongPassword)) => {
+                Some("Ask the host for the password and pass it with --password <PASSWORD>.")
+            }
+            AppError::Tunnel(Error::Rejected(RejectReason::Kicked | RejectReason::Banned)) => {
+                Some("The host removed you from the session.")
+            }
+            AppError::Terminal(_) => Some("Run without --tui if your terminal is not supported."),
+            AppError::Tunnel(Error::MapFile(_)) => {
+                Some("Check that the Maps folder exists and can be written.")
+            }
+            AppError::Tunnel(Error::Map(_)) => Some(
+                "Check that the file is a WC3 map. If it lacks common.j and blizzard.j, pass their folder with --scripts-dir <DIR>.",
+            ),
+            AppError::Logging(_) | AppError::ReadInput(_) | AppError::Signal(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Logging(e) => write!(f, "{e}"),
+            AppError::ReadInput(e) => write!(f, "Failed to read input: {e}"),
+            AppError::InvalidAddress(e) => write!(f, "Invalid address: {e}"),
+            AppError::Signal(e) => write!(f, "Failed to listen for Ctrl+C: {e}"),
+            AppError::Terminal(e) => write!(f, "Dashboard failed: {e}"),
+            AppError::Tunnel(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<Error> for AppError {
+    fn from(e: Error) -> Self {
+        AppError::Tunnel(e)
+    }
+}
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    if cli.players > cli.slots {
+        Cli::command()
+            .error(
+                ErrorKind::ValueValidation,
+                "--players can't be more than --slots",
+            )
+            .exit();
+    }
+    let log_options = LogOptions {
+        //Log lines would tear up the dashboard
+        console_level: if cli.tui {
+            LevelFilter::OFF
+        } else {
+            cli.log_level
+        },
+        filter: cli.log_filter.clone(),
+        log_dir: cli.log_dir.clone(),
+        debug_packets: cli.debug_packets,
+    };
+    //Keep the guard until the end, it flushes the log file on drop
+    let _log_guard = match init_logging(&log_options) {
+        Ok(guard) => guard,
+        Err(e) => return report(AppError::Logging(e)),
+    };
+
+    match run(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => report(e),
+    }
+}
+
+fn report(e: AppError) -> ExitCode {
+    //The console gets the plain message, the log file gets it for the record
+    tracing::debug!("Exiting with error: {e}");
+    eprintln!("{e}");
+    if let Some(hint) = e.hint() {
+        eprintln!("{hint}");
+    }
+    ExitCode::from(e.exit_code())
+}
+
+async fn run(cli: Cli) -> Result<(), AppError> {
     println!("{} v{}", APP_NAME, APP_VERSION);
     println!("Visit https://github.com/Kaladum/Simple-WC3 for more information.");
     println!();
     println!("Enter remote address to connect or press Enter to host:");
     let mut connect_to_remote = String::new();
-    handle_error!(
-        std::io::stdin().read_line(&mut connect_to_remote),
-        "Failed to read address"
-    );
+    std::io::stdin()
+        .read_line(&mut connect_to_remote)
+        .map_err(AppError::ReadInput)?;
     connect_to_remote = connect_to_remote.trim().to_string();
 
     if connect_to_remote.is_empty() {
         println!("Starting as host");
-        run_host().await;
+        let simulate = cli.simulate.then(|| {
+            let defaults = SimulatedLobby::default();
+            SimulatedLobby {
+                game_name: cli.game_name.clone().unwrap_or(defaults.game_name),
+                game_version: cli.game_version,
+                map_name: cli.map_name.unwrap_or(defaults.map_name),
+                players: cli.players,
+                slots: cli.slots,
+                ..SimulatedLobby::default()
+            }
+        });
+        let virtual_game = cli.virtual_map.map(|map| VirtualGameConfig {
+            scripts_dir: cli.scripts_dir,
+            game_name: cli.game_name.unwrap_or_else(|| APP_NAME.to_string()),
+            game_version: cli.game_version,
+            ..VirtualGameConfig::new(map)
+        });
+        let config = HostConfig {
+            password: cli.password,
+            replay_dir: cli.replay_dir,
+            spectators: cli.allow_spectators,
+            announce_pings: cli.announce_pings,
+            maps_dir: cli.maps_dir,
+            virtual_game,
+            simulate,
+            ..HostConfig::default()
+        };
+        run_host(cli.tui, config).await
     } else {
-        println!("Connecting to host");
-        let address = handle_error!(PublicKey::from_str(&connect_to_remote), "Invalid address");
-        run_client(EndpointAddr::new(address)).await;
+        let address = PublicKey::from_str(&connect_to_remote).map_err(AppError::InvalidAddress)?;
+        if cli.spectate {
+            println!("Connecting to host as spectator");
+            run_spectator(EndpointAddr::new(address), cli.password, cli.replay_dir).await
+        } else {
+            println!("Connecting to host");
+            let config = ClientConfig {
+                password: cli.password,
+                maps_dir: cli.maps_dir,
+                map_cache_dir: cli.map_cache,
+                ..ClientConfig::new(EndpointAddr::new(address))
+            };
+            run_client(config, cli.tui).await
+        }
+    }
+}
+
+async fn run_host(tui: bool, config: HostConfig) -> Result<(), AppError> {
+    let host = Host::start(config).await?;
+    if tui {
+        let result = dashboard::run_host(&host).await;
+        host.stop().await;
+        return result.map_err(AppError::Terminal);
+    }
+
+    let mut events = host.subscribe();
+    tokio::spawn(async move {
+        let mut names = PlayerNames::default();
+        while let Ok(event) = events.recv().await {
+            match event {
+                HostEvent::GameUnreachable(_) => {
+                    eprintln!("{}", host_event_message(&event, &mut names))
+                }
+                event => println!("{}", host_event_message(&event, &mut names)),
+            }
+        }
+    });
+
+    host.online().await;
+    println!("Host is running with address:");
+    println!("{}", host.id());
+    println!();
+    println!(
+        "Copy this address (by selecting it and right-clicking) and share it with all players to let them connect"
+    );
+    println!("Type `help` to manage the session, press Ctrl+C or close the window to shut down");
+    println!();
+
+    tokio::select! {
+        signal = tokio::signal::ctrl_c() => signal.map_err(AppError::Signal)?,
+        input = console::run(&host) => input.map_err(AppError::ReadInput)?,
+    }
+    println!("Shutting down host...");
+    host.stop().await;
+    Ok(())
+}
+
+const RELAY_HINT: &str = "Expect higher latency. Allowing UDP traffic through your firewall or router may enable a direct connection.";
+
+/// The player names of the clients, learned from [`HostEvent::ClientIdentified`].
+#[derive(Default)]
+struct PlayerNames(HashMap<PublicKey, String>);
+
+impl PlayerNames {
+    /// The client id, followed by the player name once it is known.
+    fn label(&self, id: &PublicKey) -> String {
+        match self.0.get(id) {
+            Some(name) => format!("{id} ({name})"),
+            None => id.to_string(),
+        }
+    }
+}
+
+fn host_event_message(event: &HostEvent, names: &mut PlayerNames) -> String {
+    let message = match event {
+        HostEvent::GameReachable => "Successfully sent game query to WC3".to_string(),
+        HostEvent::GameUnreachable(e) => {
+            format!("Can't send game query to WC3. Is the game running? Error: {e}")
+        }
+        HostEvent::LobbyOpened(lobby) => format!("Discovered new game server: {lobby}"),
+        HostEvent::LobbyUpdated(lobby) => format!(
+            "Lobby changed: {lobby} {}/{} players",
+            lobby.players, lobby.player_slots
+        ),
+        HostEvent::LobbyClosed(lobby) => format!("Server closed: {lobby}"),
+        HostEvent::ClientConnected(client_id) => format!("New client connected: {client_id}"),
+        HostEvent::ClientConnectionLost(client_id) => format!(
+            "Lost the connection to client {}, waiting for it to come back",
+            names.label(client_id)
+        ),
+        HostEvent::ClientReconnected(client_id) => {
+            format!("Client {} is back", names.label(client_id))
+        }
+        HostEvent::ClientDisconnected(client_id) => {
+            format!("Client disconnected: {}", names.label(client_id))
+        }
+        HostEvent::ClientRejected(client_id, reason) => {
+            format!("Rejected client {}: {reason}", names.label(client_id))
+        }
+        HostEvent::ClientPathChanged(client_id, path) => {
+            format!(
+                "Connection to client {} is now {path}",
+                names.label(client_id)
+            )
+        }
+        HostEvent::ClientStillRelayed(client_id) => format!(
+            "Connection to client {} is still relayed. {RELAY_HINT}",
+            names.label(client_id)
+        ),
+        HostEvent::ClientIdentified(client_id, player) => {
+            names.0.insert(*client_id, player.name.clone());
+            format!("Client {client_id} joined as {player}")
+        }
+        HostEvent::Game(event) => event.to_string(),
+        HostEvent::ReplaySaved(path) => format!("Saved the replay to {}", path.display()),
+        HostEvent::SpectatorConnected(id) => format!("New spectator connected: {id}"),
+        HostEvent::SpectatorDisconnected(id) => format!("Spectator disconnected: {id}"),
+        HostEvent::MapSent(client_id, map_name) => format!(
+            "Sent map {} to client {}",
+            maps::file_name(map_name),
+            names.label(client_id)
+        ),
+    };
+    if let HostEvent::ClientDisconnected(client_id) = event {
+        names.0.remove(client_id);
+    }
+    message
+}
+
+async fn run_client(config: ClientConfig, tui: bool) -> Result<(), AppError> {
+    let client = Client::connect(config).await?;
+    if tui {
+        match dashboard::run_client(&client).await {
+            Ok(dashboard::ClientExit::Disconnected) => {}
+            Ok(dashboard::ClientExit::Quit) => {
+                client.stop().await;
+                return Ok(());
+            }
+            Err(e) => {
+                client.stop().await;
+                return Err(AppError::Terminal(e));
+            }
+        }
+        client.closed().await?;
+        println!("The server has closed the connection");
+        return Ok(());
+    }
+    println!("Connection established");
+
+    let mut events = client.subscribe();
+    //Disconnected is the last event, everything before it gets printed
+    loop {
+        match events.recv().await {
+            Ok(ClientEvent::Disconnected) | Err(RecvError::Closed) => break,
+            Ok(event) => {
+                if let Some(message) = client_event_message(&event) {
+                    println!("{message}");
+                }
+            }
+            Err(RecvError::Lagged(_)) => {}
+        }
+    }
+
+    client.closed().await?;
+    println!("The server has closed the connection");
+    Ok(())
+}
+
+async fn run_spectator(
+    address: EndpointAddr,
+    password: Option<String>,
+    replay_dir: Option<PathBuf>,
+) -> Result<(), AppError> {
+    let spectator = Spectator::connect(SpectatorConfig {
+        password,
+        replay_dir,
+        ..SpectatorConfig::new(address)
+    })
+    .await?;
+    println!("Connection established, waiting for a game to start");
+
+    let mut events = spectator.subscribe();
+    let print_events = tokio::spawn(async move {
+        while let Ok(event) = events.recv().await {
+            println!("{}", spectator_event_message(&event));
+        }
+    });
+    tokio::select! {
+        signal = tokio::signal::ctrl_c() => {
+            signal.map_err(AppError::Signal)?;
+            spectator.stop().await;
+        }
+        closed = spectator.closed() => {
+            closed?;
+            println!("The server has closed the connection");
+        }
+    }
+    print_events.abort();
+    Ok(())
+}
+
+fn spectator_event_message(event: &SpectatorEvent) -> String {
+    match event {
+        SpectatorEvent::Watching(game_name) => format!("Watching the game {game_name}"),
+        SpectatorEvent::Game(event) => event.to_string(),
+        SpectatorEvent::Recording(path) => {
+            format!(
+                "Writing the replay to {} while the game runs",
+                path.display()
+            )
+        }
+        SpectatorEvent::ReplaySaved(path) => format!("Saved the replay to {}", path.display()),
+    }
+}
+
+/// `None` for events that don't need to be shown.
+fn client_event_message(event: &ClientEvent) -> Option<String> {
+    match event {
+        ClientEvent::LobbyFound(lobby) => Some(format!("Found game on host: {lobby}")),
+        ClientEvent::LobbyUpdated(lobby) => Some(format!(
+            "Lobby changed: {lobby} {}/{} players",
+            lobby.players, lobby.player_slots
+        )),
+        ClientEvent::LobbyClosed(LobbyEnd::Started) => {
+            Some("The lobby is no longer available, the game was started.".to_string())
+        }
+        ClientEvent::LobbyClosed(LobbyEnd::Canceled) => {
+            Some("The lobby is no longer available, the host canceled it.".to_string())
+        }
+        ClientEvent::LobbyClosed(LobbyEnd::Unknown) => Some(
+            "The lobby is no longer available. The game was started or canceled by the host."
+                .to_string(),
+        ),
+        ClientEvent::MapChecked(map) => Some(match &map.check {
+            MapCheck::Found(_) => format!("{map}"),
+            MapCheck::Missing => format!(
+                "{map}. Trying to download it from the host, otherwise WC3 downloads it after you join, which can take a while."
+            ),
+            MapCheck::Mismatch(_) => format!(
+                "{map}. WC3 will download the map of the host after you join, which can take a while."
+            ),
+        }),
+        ClientEvent::MapDownloaded(map) => Some(format!("Downloaded from the host: {map}")),
+        ClientEvent::PathChanged(path) => Some(format!("Connection to host is now {path}")),
+        ClientEvent::StillRelayed => {
+            Some(format!("Connection to host is still relayed. {RELAY_HINT}"))
+        }
+        ClientEvent::Game(event) => Some(event.to_string()),
+        ClientEvent::ConnectionLost => {
+            Some("Lost the connection to the host, trying to get it back...".to_string())
+        }
+        ClientEvent::Reconnected => Some("The connection to the host is back".to_string()),
+        ClientEvent::HostShutDown => Some("The host shut down".to_string()),
+        ClientEvent::Disconnected => None,
     }
 }
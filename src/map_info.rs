@@ -0,0 +1,590 @@
+//! Details of WC3 maps: what the World Editor stores in `war3map.w3i` and the checksum WC3 compares
+//! in the lobby.
+
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{Cursor, Read, Seek},
+    path::Path,
+};
+
+use binrw::{BinRead, NullString, binread};
+use sha1_smol::Sha1;
+use tracing::debug;
+
+use crate::{
+    maps::follow,
+    mpq::{Archive, MpqError},
+    utils::crc32,
+    w3gs::Slot,
+};
+
+/// The game data of WC3 1.26 and older, the newest one first. The scripts of the game are taken from
+/// the first one that has them.
+const GAME_ARCHIVES: [&str; 3] = ["War3Patch.mpq", "War3x.mpq", "War3.mpq"];
+/// Mixed into the checksum and the SHA-1 of a map between the scripts of the game and the map.
+const CHECKSUM_SALT: u32 = 0x03F1_379E;
+/// The files of a map that go into its checksum after the script, in this order.
+const CHECKED_FILES: [&str; 8] = [
+    "war3map.w3e",
+    "war3map.wpm",
+    "war3map.doo",
+    "war3map.w3u",
+    "war3map.w3b",
+    "war3map.w3d",
+    "war3map.w3a",
+    "war3map.w3q",
+];
+
+/// What the author of a map says about it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapInfo {
+    /// The name shown in WC3, like `Twisted Meadows`.
+    pub name: String,
+    pub author: String,
+    /// Who the map is made for, like `2v2` or `Any`.
+    pub suggested_players: String,
+    /// Number of slots for human players.
+    pub players: u32,
+    /// The terrain, like `Lordaeron Summer`.
+    pub tileset: String,
+    /// The checksum of the map in the lobby. `None` if the scripts of WC3 that go into it are not known,
+    /// they are only found next to the Maps folder of WC3 1.26 and older.
+    pub checksum: Option<u32>,
+}
+
+impl fmt::Display for MapInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} by {}, {} players",
+            self.name, self.author, self.players
+        )?;
+        if !self.suggested_players.is_empty() {
+            write!(f, " ({})", self.suggested_players)?;
+        }
+        write!(f, ", {}", self.tileset)
+    }
+}
+
+/// A map as a game host needs it: what the lobby announces and what joining players compare their map
+/// with.
+#[derive(Debug, Clone)]
+pub struct HostedMap {
+    pub info: MapInfo,
+    /// Size of the map file in bytes.
+    pub size: u32,
+    /// CRC-32 of the whole map file.
+    pub crc32: u32,
+    /// The checksum of the map in the lobby, see [`MapInfo::checksum`].
+    pub checksum: u32,
+    /// SHA-1 of the same data as the checksum, compared by WC3 1.23 and newer.
+    pub sha1: [u8; 20],
+    /// The playable area.
+    pub width: u16,
+    pub height: u16,
+    /// The slots of the lobby before anyone joined, computer players included.
+    pub slots: Vec<Slot>,
+    /// How WC3 shows the slots: 0 = melee, 1 = custom forces, 3 = custom forces with fixed player
+    /// settings.
+    pub layout_style: u8,
+}
+
+impl HostedMap {
+    /// Whether the players keep the teams and races of the map.
+    pub fn fixed_player_settings(&self) -> bool {
+        self.layout_style == 3
+    }
+
+    /// Whether the teams of the map are predefined, players change their team by changing their slot.
+    pub fn custom_forces(&self) -> bool {
+        self.layout_style & 1 != 0
+    }
+}
+
+#[binread]
+#[derive(Debug)]
+#[br(little)]
+struct W3i {
+    #[br(temp)]
+    version: u32,
+    _saves: u32,
+    _editor_version: u32,
+    #[br(if(version >= 28))]
+    _game_version: Option<[u32; 4]>,
+    name: NullString,
+    author: NullString,
+    _description: NullString,
+    suggested_players: NullString,
+    _camera_bounds: [f32; 8],
+    _camera_bounds_complements: [u32; 4],
+    playable_size: [u32; 2],
+    flags: u32,
+    tileset: u8,
+    #[br(args(version))]
+    _screens: Screens,
+    #[br(temp)]
+    player_count: u32,
+    #[br(count = player_count, args { inner: (version,) })]
+    players: Vec<W3iPlayer>,
+    #[br(temp)]
+    force_count: u32,
+    #[br(count = force_count)]
+    forces: Vec<W3iForce>,
+}
+
+/// The loading and prologue screens, they changed with The Frozen Throne.
+#[derive(BinRead, Debug)]
+#[br(little, import(version: u32))]
+enum Screens {
+    #[br(pre_assert(version < 25))]
+    ReignOfChaos {
+        _campaign_background: u32,
+        _loading_screen: [NullString; 3],
+        _loading_screen_number: u32,
+        _prologue: [NullString; 3],
+    },
+    #[br(pre_assert(version >= 25))]
+    FrozenThrone {
+        _loading_screen_number: u32,
+        _loading_screen: [NullString; 4],
+        _game_data_set: u32,
+        _prologue: [NullString; 4],
+        _fog_style: u32,
+        _fog: [f32; 3],
+        _fog_color: [u8; 4],
+        _weather: u32,
+        _sound_environment: NullString,
+        _light_environment: u8,
+        _water_color: [u8; 4],
+        #[br(if(version >= 28))]
+        _script_language: Option<u32>,
+        #[br(if(version >= 31))]
+        _modes: Option<[u32; 2]>,
+    },
+}
+
+#[derive(BinRead, Debug)]
+#[br(little, import(version: u32))]
+struct W3iPlayer {
+    number: u32,
+    kind: u32,
+    race: u32,
+    _fixed_start: u32,
+    _name: NullString,
+    _start: [f32; 2],
+    _allies: [u32; 2],
+    #[br(if(version >= 31))]
+    _enemies: Option<[u32; 2]>,
+}
+
+#[derive(BinRead, Debug)]
+#[br(little)]
+struct W3iForce {
+    _flags: u32,
+    /// Bit n is set for player number n.
+    players: u32,
+    _name: NullString,
+}
+
+const HUMAN_PLAYER: u32 = 1;
+const COMPUTER_PLAYER: u32 = 2;
+const MAP_FIXED_PLAYER_SETTINGS: u32 = 0x20;
+const MAP_CUSTOM_FORCES: u32 = 0x40;
+const RACE_RANDOM: u8 = 0x20;
+const RACE_SELECTABLE: u8 = 0x40;
+
+/// Reads the details of the map at `path`. The scripts of the game for the checksum are looked for in
+/// `game_dir`, the folder of WC3.
+pub(crate) fn read_map_info(path: &Path, game_dir: Option<&Path>) -> Result<MapInfo, MpqError> {
+    let mut map = Archive::open(path)?;
+    let w3i = read_w3i(&mut map)?;
+    let checksum = match CheckedData::read(&mut map, game_dir) {
+        Ok(data) => Some(data.checksum()),
+        Err(e) => {
+            debug!("Can't compute the checksum of map {}: {e}", path.display());
+            None
+        }
+    };
+    map_info(&mut map, &w3i, checksum)
+}
+
+/// Reads everything needed to host the map at `path`. Unlike [`read_map_info`] this fails without the
+/// scripts of the game, they are taken from the map itself or from `scripts_dir`: loose `common.j` and
+/// `blizzard.j` files or the game data of WC3 1.26 and older.
+pub(crate) fn read_hosted_map(
+    path: &Path,
+    scripts_dir: Option<&Path>,
+) -> Result<HostedMap, MpqError> {
+    let file = fs::read(path)?;
+    let size = u32::try_from(file.len()).map_err(|_| MpqError::Corrupt)?;
+    let mut map = Archive::new(Cursor::new(file.as_slice()))?;
+    let w3i = read_w3i(&mut map)?;
+    let checked = CheckedData::read(&mut map, scripts_dir)?;
+    let checksum = checked.checksum();
+    Ok(HostedMap {
+        info: map_info(&mut map, &w3i, Some(checksum))?,
+        size,
+        crc32: crc32(&file),
+        checksum,
+        sha1: checked.sha1(),
+        width: w3i.playable_size[0] as u16,
+        height: w3i.playable_size[1] as u16,
+        slots: slots(&w3i),
+        layout_style: match (
+            w3i.flags & MAP_CUSTOM_FORCES != 0,
+            w3i.flags & MAP_FIXED_PLAYER_SETTINGS != 0,
+        ) {
+            (false, _) => 0,
+            (true, false) => 1,
+            (true, true) => 3,
+        },
+    })
+}
+
+fn read_w3i<R: Read + Seek>(map: &mut Archive<R>) -> Result<W3i, MpqError> {
+    let w3i = map
+        .read("war3map.w3i")?
+        .ok_or(MpqError::MissingFile("war3map.w3i"))?;
+    W3i::read(&mut Cursor::new(w3i)).map_err(|_| MpqError::Corrupt)
+}
+
+fn map_info<R: Read + Seek>(
+    map: &mut Archive<R>,
+    w3i: &W3i,
+    checksum: Option<u32>,
+) -> Result<MapInfo, MpqError> {
+    let strings = map
+        .read("war3map.wts")?
+        .map(|wts| trigger_strings(&wts))
+        .unwrap_or_default();
+    let text = |text: &NullString| resolve(&text.to_string(), &strings);
+    Ok(MapInfo {
+        name: text(&w3i.name),
+        author: text(&w3i.author),
+        suggested_players: text(&w3i.suggested_players),
+        players: w3i
+            .players
+            .iter()
+            .filter(|player| player.kind == HUMAN_PLAYER)
+            .count() as u32,
+        tileset: tileset_name(w3i.tileset)
+            .map_or_else(|| char::from(w3i.tileset).to_string(), ToString::to_string),
+        checksum,
+    })
+}
+
+/// The slots like GHost++ sets them up: open ones for human players, occupied ones for computers.
+/// Melee maps let every player pick team and race, other maps may prescribe them.
+fn slots(w3i: &W3i) -> Vec<Slot> {
+    let custom_forces = w3i.flags & MAP_CUSTOM_FORCES != 0;
+    let fixed = w3i.flags & MAP_FIXED_PLAYER_SETTINGS != 0;
+    w3i.players
+        .iter()
+        .filter(|player| matches!(player.kind, HUMAN_PLAYER | COMPUTER_PLAYER))
+        .enumerate()
+        .map(|(index, player)| {
+            let computer = player.kind == COMPUTER_PLAYER;
+            let race = match player.race {
+                1 => 0x01, //Human
+                2 => 0x02, //Orc
+                3 => 0x08, //Undead
+                4 => 0x04, //Night Elf
+                _ => RACE_RANDOM,
+            };
+            let team = if custom_forces {
+                w3i.forces
+                    .iter()
+                    .position(|force| force.players & (1 << player.number.min(31)) != 0)
+                    .unwrap_or(0)
+            } else {
+                index
+            };
+            Slot {
+                player_id: 0,
+                download_status: 255,
+                slot_status: if computer { 2 } else { 0 },
+                computer: u8::from(computer),
+                team: team as u8,
+                color: player.number as u8,
+                race: match (custom_forces, fixed) {
+                    (false, _) => RACE_RANDOM | RACE_SELECTABLE,
+                    (true, false) => race | RACE_SELECTABLE,
+                    (true, true) => race,
+                },
+                computer_type: 1, //Normal
+                handicap: 100,
+            }
+        })
+        .collect()
+}
+
+/// The scripts of the game and the important files of the map, in the order WC3 hashes them.
+struct CheckedData {
+    common: Vec<u8>,
+    blizzard: Vec<u8>,
+    files: Vec<Vec<u8>>,
+}
+
+impl CheckedData {
+    fn read<R: Read + Seek>(
+        map: &mut Archive<R>,
+        game_dir: Option<&Path>,
+    ) -> Result<Self, MpqError> {
+        let common = game_script(map, game_dir, "Scripts\\common.j")?
+            .ok_or(MpqError::MissingFile("Scripts\\common.j"))?;
+        let blizzard = game_script(map, game_dir, "Scripts\\blizzard.j")?
+            .ok_or(MpqError::MissingFile("Scripts\\blizzard.j"))?;
+        let script = match map.read("war3map.j")? {
+            Some(script) => script,
+            None => map
+                .read("Scripts\\war3map.j")?
+                .ok_or(MpqError::MissingFile("war3map.j"))?,
+        };
+        let mut files = vec![script];
+        for name in CHECKED_FILES {
+            files.extend(map.read(name)?);
+        }
+        Ok(CheckedData {
+            common,
+            blizzard,
+            files,
+        })
+    }
+
+    /// The checksum WC3 puts into the lobby: everything hashed and mixed together.
+    fn checksum(&self) -> u32 {
+        let mut value = (xoro(&self.common) ^ xoro(&self.blizzard)).rotate_left(3);
+        value = (value ^ CHECKSUM_SALT).rotate_left(3);
+        for file in &self.files {
+            value = (value ^ xoro(file)).rotate_left(3);
+        }
+        value
+    }
+
+    fn sha1(&self) -> [u8; 20] {
+        let mut sha1 = Sha1::new();
+        sha1.update(&self.common);
+        sha1.update(&self.blizzard);
+        sha1.update(&CHECKSUM_SALT.to_le_bytes());
+        for file in &self.files {
+            sha1.update(file);
+        }
+        sha1.digest().bytes()
+    }
+}
+
+/// A script of the game. Maps may bring their own, otherwise it is a loose file or comes from the game
+/// data.
+fn game_script<R: Read + Seek>(
+    map: &mut Archive<R>,
+    game_dir: Option<&Path>,
+    name: &str,
+) -> Result<Option<Vec<u8>>, MpqError> {
+    if let Some(script) = map.read(name)? {
+        return Ok(Some(script));
+    }
+    let Some(game_dir) = game_dir else {
+        return Ok(None);
+    };
+    let file_name = name.rsplit('\\').next().unwrap_or(name);
+    if let Some(path) = follow(game_dir, &[file_name])? {
+        return Ok(Some(fs::read(path)?));
+    }
+    for archive in GAME_ARCHIVES {
+        if let Some(path) = follow(game_dir, &[archive])?
+            && let Some(script) = Archive::open(&path)?.read(name)?
+        {
+            return Ok(Some(script));
+        }
+    }
+    Ok(None)
+}
+
+/// XORs the data word by word, rotating after each one.
+fn xoro(data: &[u8]) -> u32 {
+    let mut words = data.chunks_exact(4);
+    let mut value = 0u32;
+    for word in &mut words {
+        value = (value ^ u32::from_le_bytes(word.try_into().unwrap())).rotate_left(3);
+    }
+    for &byte in words.remainder() {
+        value = (value ^ u32::from(byte)).rotate_left(3);
+    }
+    value
+}
+
+/// The texts of `war3map.wts` by their number, the w3i refers to them as `TRIGSTR_001`.
+fn trigger_strings(wts: &[u8]) -> HashMap<u32, String> {
+    let wts = String::from_utf8_lossy(wts);
+    let mut lines = wts.trim_start_matches('\u{feff}').lines();
+    let mut strings = HashMap::new();
+    while let Some(line) = lines.next() {
+        let Some(id) = line
+            .trim()
+            .strip_prefix("STRING ")
+            .and_then(|id| id.trim().parse().ok())
+        else {
+            continue;
+        };
+        //Comments may come between the number and the text
+        if !lines.by_ref().any(|line| line.trim() == "{") {
+            break;
+        }
+        let text: Vec<&str> = lines
+            .by_ref()
+            .take_while(|line| line.trim() != "}")
+            .collect();
+        strings.insert(id, text.join("\n"));
+    }
+    strings
+}
+
+/// The text itself for references to trigger strings, without color codes.
+fn resolve(text: &str, strings: &HashMap<u32, String>) -> String {
+    let text = text
+        .strip_prefix("TRIGSTR_")
+        .and_then(|id| id.trim().parse().ok())
+        .and_then(|id| strings.get(&id))
+        .map_or(text, String::as_str);
+    strip_colors(text)
+}
+
+/// Removes `|cAARRGGBB` and `|r`, which color the text in WC3.
+fn strip_colors(text: &str) -> String {
+    let mut plain = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(pos) = rest.find('|') {
+        plain.push_str(&rest[..pos]);
+        let code = &rest[pos + 1..];
+        rest = match code.as_bytes().first() {
+            Some(b'c' | b'C')
+                if code
+                    .get(1..9)
+                    .is_some_and(|color| color.bytes().all(|byte| byte.is_ascii_hexdigit())) =>
+            {
+                &code[9..]
+            }
+            Some(b'r' | b'R') => &code[1..],
+            _ => {
+                plain.push('|');
+                code
+            }
+        };
+    }
+    plain.push_str(rest);
+    plain
+}
+
+fn tileset_name(code: u8) -> Option<&'static str> {
+    Some(match code {
+        b'A' => "Ashenvale",
+        b'B' => "Barrens",
+        b'C' => "Felwood",
+        b'D' => "Dungeon",
+        b'F' => "Lordaeron Fall",
+        b'G' => "Underground",
+        b'I' => "Icecrown Glacier",
+        b'J' => "Dalaran Ruins",
+        b'K' => "Black Citadel",
+        b'L' => "Lordaeron Summer",
+        b'N' => "Northrend",
+        b'O' => "Outland",
+        b'Q' => "Village Fall",
+        b'V' => "Village",
+        b'W' => "Lordaeron Winter",
+        b'X' => "Dalaran",
+        b'Y' => "Cityscape",
+        b'Z' => "Sunken Ruins",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{MapInfo, read_hosted_map, read_map_info, strip_colors, xoro};
+    use crate::test_utils::mpq::{WTS, build_archive, w3i};
+
+    #[test]
+    fn reads_details_and_checksum_of_maps() {
+        let map = build_archive(&[
+            ("war3map.w3i", &w3i()),
+            ("war3map.wts", WTS.as_bytes()),
+            ("war3map.j", b"function main"),
+            ("Scripts\\common.j", b"native Foo"),
+            ("Scripts\\blizzard.j", b"function Bar"),
+            ("war3map.w3e", b"W3E!"),
+        ]);
+        let path = std::env::temp_dir().join(format!("simple-wc3-info-{}.w3x", std::process::id()));
+        fs::write(&path, map).unwrap();
+
+        let info = read_map_info(&path, None).unwrap();
+        let mut checksum = (xoro(b"native Foo") ^ xoro(b"function Bar")).rotate_left(3);
+        checksum = (checksum ^ 0x03F1_379E).rotate_left(3);
+        checksum = (checksum ^ xoro(b"function main")).rotate_left(3);
+        checksum = (checksum ^ xoro(b"W3E!")).rotate_left(3);
+        assert_eq!(
+            info,
+            MapInfo {
+                name: "Twisted Meadows".to_string(),
+                author: "Blizzard".to_string(),
+                suggested_players: "1v1".to_string(),
+                players: 2,
+                tileset: "Lordaeron Summer".to_string(),
+                checksum: Some(checksum),
+            }
+        );
+        assert_eq!(
+            info.to_string(),
+            "Twisted Meadows by Blizzard, 2 players (1v1), Lordaeron Summer"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reads_what_a_host_needs() {
+        let map = build_archive(&[("war3map.w3i", &w3i()), ("war3map.j", b"function main")]);
+        let dir = std::env::temp_dir().join(format!("simple-wc3-hosted-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("map.w3x");
+        fs::write(&path, &map).unwrap();
+        assert!(read_hosted_map(&path, Some(&dir)).is_err());
+
+        fs::write(dir.join("common.j"), b"native Foo").unwrap();
+        fs::write(dir.join("blizzard.j"), b"function Bar").unwrap();
+        let hosted = read_hosted_map(&path, Some(&dir)).unwrap();
+        assert_eq!(hosted.size, map.len() as u32);
+        assert_eq!(hosted.crc32, crate::utils::crc32(&map));
+        assert_eq!(hosted.info.checksum, Some(hosted.checksum));
+        assert_eq!((hosted.width, hosted.height), (116, 84));
+        assert_eq!(hosted.layout_style, 0);
+        //Two open slots for the human players, an occupied one for the computer
+        let slots: Vec<_> = hosted
+            .slots
+            .iter()
+            .map(|slot| {
+                (
+                    slot.slot_status,
+                    slot.computer,
+                    slot.team,
+                    slot.color,
+                    slot.race,
+                )
+            })
+            .collect();
+        assert_eq!(
+            slots,
+            vec![(0, 0, 0, 0, 0x60), (0, 0, 1, 1, 0x60), (2, 1, 2, 2, 0x60)]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hashes_words_then_bytes() {
+        assert_eq!(xoro(&[1, 0, 0, 0]), 8);
+        assert_eq!(xoro(&[1, 0, 0, 0, 1]), 72);
+        assert_eq!(strip_colors("|CFF00FF00Green|r|x"), "Green|x");
+    }
+}
@@ -0,0 +1,478 @@
+//! Sends the map of the lobby to the clients over iroh, much faster than WC3 downloads it through the tunnel.
+//!
+//! The host offers the map file of its lobby, addressed by its BLAKE3 hash. A client without the map
+//! asks for the offer, takes the map from its cache if the hash is there and downloads it otherwise.
+//! Every map is checked against the hash before it is put into the Maps folder.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use binrw::{BinRead, BinWrite, NullString};
+use iroh::{
+    Endpoint, EndpointAddr,
+    endpoint::{Connection, ReadToEndError, RecvStream, SendStream},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::watch,
+    task::spawn_blocking,
+};
+use tracing::debug;
+
+use crate::{
+    client::send_password,
+    error::{Error, ProtocolError},
+    maps::{MapCheck, check_map, found_map, path_parts},
+    packets::QueryForGamesResponse,
+    utils::{MAP_ALPN, try_parse, try_serialize},
+};
+
+/// Largest map that is sent. WC3 itself allows much smaller ones.
+const MAX_MAP_SIZE: u64 = 256 * 1024 * 1024;
+const MAX_REQUEST_LEN: usize = 64;
+const MAX_OFFER_LEN: usize = 1024;
+/// Length of a `FetchReply::Map`.
+const FETCH_REPLY_LEN: usize = 9;
+/// How much of a map is read from the stream at once.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(BinRead, BinWrite, Debug, Clone, Copy, PartialEq, Eq)]
+#[brw(little)]
+enum MapRequest {
+    /// Which map the host offers.
+    #[brw(magic = 0u8)]
+    Offer,
+    /// The map with this hash.
+    #[brw(magic = 1u8)]
+    Fetch([u8; 32]),
+}
+
+#[derive(BinRead, BinWrite, Debug, Clone)]
+#[brw(little)]
+enum OfferReply {
+    #[brw(magic = 0u8)]
+    NoMap,
+    #[brw(magic = 1u8)]
+    Map(MapOffer),
+}
+
+/// The map of the lobby of the host.
+#[derive(BinRead, BinWrite, Debug, Clone, PartialEq, Eq)]
+#[brw(little)]
+pub(crate) struct MapOffer {
+    /// Path of the map inside the WC3 folder, as in the lobby.
+    map_name: NullString,
+    size: u64,
+    hash: [u8; 32],
+}
+
+/// Followed by the map itself.
+#[derive(BinRead, BinWrite, Debug, Clone, Copy)]
+#[brw(little)]
+enum FetchReply {
+    /// The host does not offer a map with this hash (anymore).
+    #[brw(magic = 0u8)]
+    NotOffered,
+    #[brw(magic = 1u8)]
+    Map { size: u64 },
+}
+
+/// Offers the map of the lobby from the Maps folder of the host.
+#[derive(Debug)]
+pub(crate) struct MapServer {
+    maps_dir: PathBuf,
+    lobby: watch::Receiver<Option<QueryForGamesResponse>>,
+    /// The last offer, hashing a map takes a moment.
+    hashed: Mutex<Option<HashedMap>>,
+}
+
+#[derive(Debug, Clone)]
+struct HashedMap {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    offer: MapOffer,
+}
+
+impl MapServer {
+    pub fn new(maps_dir: PathBuf, lobby: watch::Receiver<Option<QueryForGamesResponse>>) -> Self {
+        MapServer {
+            maps_dir,
+            lobby,
+            hashed: Mutex::new(None),
+        }
+    }
+
+    /// The map of the current lobby and where it is, `None` if there is no lobby or the map is not in the Maps folder.
+    async fn offer(&self) -> io::Result<Option<(MapOffer, PathBuf)>> {
+        let map = self
+            .lobby
+            .borrow()
+            .as_ref()
+            .and_then(|lobby| lobby.stat_string())
+            .map(|stat_string| (stat_string.map_name.to_string(), stat_string.map_checksum));
+        let Some((map_name, checksum)) = map else {
+            return Ok(None);
+        };
+        let maps_dir = self.maps_dir.clone();
+        let known = self.hashed.lock().unwrap().clone();
+        let hashed = spawn_blocking(move || -> io::Result<Option<HashedMap>> {
+            let MapCheck::Found(path) = check_map(&maps_dir, &map_name)? else {
+                return Ok(None);
+            };
+            let metadata = fs::metadata(&path)?;
+            let modified = metadata.modified().ok();
+            if let Some(known) = known.filter(|known| {
+                known.path == path
+                    && known.modified == modified
+                    && known.offer.size == metadata.len()
+                    && known.offer.map_name.to_string() == map_name
+            }) {
+                return Ok(Some(known));
+            }
+            if metadata.len() > MAX_MAP_SIZE {
+                return Ok(None);
+            }
+            //Another version than the one of the lobby would be rejected by WC3
+            let path = match found_map(&maps_dir, &map_name, path, Some(checksum)).check {
+                MapCheck::Found(path) => path,
+                _ => {
+                    debug!("Not offering map {map_name}, it is not the map of the lobby");
+                    return Ok(None);
+                }
+            };
+            let hash = file_hash(&path)?;
+            debug!("Offering map {}", path.display());
+            Ok(Some(HashedMap {
+                offer: MapOffer {
+                    map_name: NullString::from(map_name),
+                    size: metadata.len(),
+                    hash,
+                },
+                path,
+                modified,
+            }))
+        })
+        .await
+        .map_err(io::Error::other)??;
+        *self.hashed.lock().unwrap() = hashed.clone();
+        Ok(hashed.map(|hashed| (hashed.offer, hashed.path)))
+    }
+
+    /// Answers one request of a client. Returns the name of the map if it was sent.
+    pub async fn serve(
+        &self,
+        mut send: SendStream,
+        mut recv: RecvStream,
+    ) -> Result<Option<String>, Error> {
+        let request = recv
+            .read_to_end(MAX_REQUEST_LEN)
+            .await
+            .map_err(read_error)?;
+        let request = try_parse::<MapRequest>(&request).ok_or(ProtocolError::MalformedPacket)?;
+        let offer = self.offer().await.map_err(Error::MapFile)?;
+        let (reply, sent) = match (request, offer) {
+            (MapRequest::Offer, Some((offer, _))) => (
+                try_serialize(&OfferReply::Map(offer))
+                    .ok_or(ProtocolError::Serialize("MapOffer"))?,
+                None,
+            ),
+            (MapRequest::Offer, None) => (
+                try_serialize(&OfferReply::NoMap).ok_or(ProtocolError::Serialize("MapOffer"))?,
+                None,
+            ),
+            (MapRequest::Fetch(hash), Some((offer, path))) if offer.hash == hash => {
+                let reply = fetch_reply(FetchReply::Map { size: offer.size })?;
+                send.write_all(&reply).await.map_err(ProtocolError::Write)?;
+                if !send_map(&mut send, &path, &offer).await? {
+                    //The client throws away what it got, a reset stream never ends cleanly
+                    debug!("Map {} changed while it was sent", path.display());
+                    let _ = send.reset(0u32.into());
+                    return Ok(None);
+                }
+                (Vec::new(), Some(offer.map_name.to_string()))
+            }
+            (MapRequest::Fetch(_), _) => (fetch_reply(FetchReply::NotOffered)?, None),
+        };
+        send.write_all(&reply).await.map_err(ProtocolError::Write)?;
+        let _ = send.finish();
+        let _ = send.stopped().await;
+        Ok(sent)
+    }
+}
+
+/// Sends the map file piece by piece. Returns `false` if the file no longer matches the offer,
+/// it may have changed since it was hashed.
+async fn send_map(send: &mut SendStream, path: &Path, offer: &MapOffer) -> Result<bool, Error> {
+    let mut file = tokio::fs::File::open(path).await.map_err(Error::MapFile)?;
+    let mut hasher = blake3::Hasher::new();
+    let mut sent = 0;
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+        let read = file.read(&mut buf).await.map_err(Error::MapFile)?;
+        if read == 0 {
+            break;
+        }
+        sent += read as u64;
+        if sent > offer.size {
+            return Ok(false);
+        }
+        hasher.update(&buf[..read]);
+        send.write_all(&buf[..read])
+            .await
+            .map_err(ProtocolError::Write)?;
+    }
+    Ok(sent == offer.size && *hasher.finalize().as_bytes() == offer.hash)
+}
+
+fn fetch_reply(reply: FetchReply) -> Result<Vec<u8>, Error> {
+    Ok(try_serialize(&reply).ok_or(ProtocolError::Serialize("FetchReply"))?)
+}
+
+fn read_error(e: ReadToEndError) -> ProtocolError {
+    match e {
+        ReadToEndError::Read(e) => ProtocolError::Read(e),
+        ReadToEndError::TooLong => ProtocolError::MalformedPacket,
+    }
+}
+
+/// Where a client gets missing maps from.
+#[derive(Debug, Clone)]
+pub(crate) struct MapFetcher {
+    pub endpoint: Endpoint,
+    pub host: EndpointAddr,
+    pub password: String,
+    /// Downloaded maps are kept here, named by their hash.
+    pub cache_dir: Option<PathBuf>,
+}
+
+impl MapFetcher {
+    /// Gets the map of the lobby from the host and puts it at its path in `maps_dir`.
+    /// Returns `None` if the host does not offer this map.
+    pub async fn fetch(&self, maps_dir: &Path, map_name: &str) -> Result<Option<PathBuf>, Error> {
+        let Some(target) = map_path(maps_dir, map_name) else {
+            debug!("Not storing map {map_name}, its path leaves the Maps folder");
+            return Ok(None);
+        };
+        let connection = self
+            .endpoint
+            .connect(self.host.clone(), MAP_ALPN)
+            .await
+            .map_err(Error::Connect)?;
+        send_password(&connection, &self.password).await?;
+        let result = self.fetch_on(&connection, map_name, target).await;
+        connection.close(0u32.into(), b"done");
+        result
+    }
+
+    async fn fetch_on(
+        &self,
+        connection: &Connection,
+        map_name: &str,
+        target: PathBuf,
+    ) -> Result<Option<PathBuf>, Error> {
+        let reply = request(connection, MapRequest::Offer, MAX_OFFER_LEN).await?;
+        let offer = match try_parse::<OfferReply>(&reply).ok_or(ProtocolError::MalformedPacket)? {
+            OfferReply::Map(offer) if offer.map_name.to_string() == map_name => offer,
+            //The lobby changed in the meantime
+            _ => return Ok(None),
+        };
+        if offer.size > MAX_MAP_SIZE {
+            return Ok(None);
+        }
+
+        let cached = self
+            .cache_dir
+            .as_ref()
+            .map(|dir| dir.join(cache_name(&offer.hash, &target)));
+        if let Some(cached) = cached.clone()
+            && spawn_blocking({
+                let cached = cached.clone();
+                move || file_hash(&cached).ok()
+            })
+            .await
+            .ok()
+            .flatten()
+                == Some(offer.hash)
+        {
+            debug!("Taking map {map_name} from the cache");
+            copy_file(&cached, &target).await.map_err(Error::MapFile)?;
+            return Ok(Some(target));
+        }
+
+        let mut recv = open_request(connection, MapRequest::Fetch(offer.hash)).await?;
+        let mut buf = vec![0; CHUNK_SIZE];
+        let mut head = Vec::new();
+        let size = loop {
+            match try_parse::<FetchReply>(&head) {
+                Some(FetchReply::Map { size }) => break size,
+                Some(FetchReply::NotOffered) => return Ok(None),
+                None if head.len() >= FETCH_REPLY_LEN => {
+                    return Err(ProtocolError::MalformedPacket.into());
+                }
+                None => {}
+            }
+            let read = recv
+                .read(&mut buf)
+                .await
+                .map_err(ProtocolError::Read)?
+                .ok_or(ProtocolError::MalformedPacket)?;
+            head.extend_from_slice(&buf[..read]);
+        };
+        if size != offer.size {
+            return Err(ProtocolError::BadMap.into());
+        }
+
+        let partial = partial_path(&target);
+        let received = receive(&mut recv, &head[FETCH_REPLY_LEN..], &partial, &offer).await;
+        if received.is_err() {
+            let _ = tokio::fs::remove_file(&partial).await;
+        }
+        received?;
+        tokio::fs::rename(&partial, &target)
+            .await
+            .map_err(Error::MapFile)?;
+        if let Some(cached) = cached {
+            copy_file(&target, &cached).await.map_err(Error::MapFile)?;
+        }
+        Ok(Some(target))
+    }
+}
+
+/// Sends a request on a new stream and reads the whole reply.
+async fn request(
+    connection: &Connection,
+    request: MapRequest,
+    max_reply_len: usize,
+) -> Result<Vec<u8>, ProtocolError> {
+    let mut recv = open_request(connection, request).await?;
+    recv.read_to_end(max_reply_len).await.map_err(read_error)
+}
+
+/// Sends a request on a new stream, the reply comes on the returned one.
+async fn open_request(
+    connection: &Connection,
+    request: MapRequest,
+) -> Result<RecvStream, ProtocolError> {
+    let (mut send, recv) = connection
+        .open_bi()
+        .await
+        .map_err(ProtocolError::OpenStream)?;
+    let request = try_serialize(&request).ok_or(ProtocolError::Serialize("MapRequest"))?;
+    send.write_all(&request)
+        .await
+        .map_err(ProtocolError::Write)?;
+    let _ = send.finish();
+    Ok(recv)
+}
+
+/// Writes the map to `partial` as it arrives, `first` being what came with the reply.
+/// Fails if it does not match the offer.
+async fn receive(
+    recv: &mut RecvStream,
+    first: &[u8],
+    partial: &Path,
+    offer: &MapOffer,
+) -> Result<(), Error> {
+    if let Some(dir) = partial.parent() {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .map_err(Error::MapFile)?;
+    }
+    let mut file = tokio::fs::File::create(partial)
+        .await
+        .map_err(Error::MapFile)?;
+    let mut hasher = blake3::Hasher::new();
+    let mut received = 0;
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut chunk = first;
+    loop {
+        received += chunk.len() as u64;
+        if received > offer.size {
+            return Err(ProtocolError::BadMap.into());
+        }
+        hasher.update(chunk);
+        file.write_all(chunk).await.map_err(Error::MapFile)?;
+        match recv.read(&mut buf).await.map_err(ProtocolError::Read)? {
+            Some(read) => chunk = &buf[..read],
+            None => break,
+        }
+    }
+    file.flush().await.map_err(Error::MapFile)?;
+    if received != offer.size || *hasher.finalize().as_bytes() != offer.hash {
+        return Err(ProtocolError::BadMap.into());
+    }
+    Ok(())
+}
+
+fn file_hash(path: &Path) -> io::Result<[u8; 32]> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(fs::File::open(path)?)?;
+    Ok(*hasher.finalize().as_bytes())
+}
+
+/// Copies next to the file first, so WC3 never sees half a map.
+async fn copy_file(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(dir) = to.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let partial = partial_path(to);
+    tokio::fs::copy(from, &partial).await?;
+    tokio::fs::rename(&partial, to).await
+}
+
+fn partial_path(path: &Path) -> PathBuf {
+    path.with_extension("part")
+}
+
+/// Named by the hash, with the extension of the map so WC3 versions can tell them apart.
+fn cache_name(hash: &[u8; 32], map: &Path) -> String {
+    let hex: String = hash.iter().map(|byte| format!("{byte:02x}")).collect();
+    let extension = map
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("w3x");
+    format!("{hex}.{extension}")
+}
+
+/// Where the map of a lobby belongs in `maps_dir`. `None` for paths that would leave it.
+fn map_path(maps_dir: &Path, map_name: &str) -> Option<PathBuf> {
+    let parts = path_parts(map_name);
+    if parts.is_empty() || parts.iter().any(|part| *part == ".." || part.contains(':')) {
+        return None;
+    }
+    Some(
+        parts
+            .iter()
+            .fold(maps_dir.to_path_buf(), |path, part| path.join(part)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{cache_name, map_path};
+
+    #[test]
+    fn keeps_maps_inside_the_maps_folder() {
+        let maps = Path::new("maps");
+        assert_eq!(
+            map_path(maps, "Maps\\FrozenThrone\\(4)TwistedMeadows.w3x"),
+            Some(maps.join("FrozenThrone").join("(4)TwistedMeadows.w3x"))
+        );
+        assert_eq!(map_path(maps, "Maps\\..\\war3.exe"), None);
+        assert_eq!(map_path(maps, "C:\\war3.exe"), None);
+        assert_eq!(map_path(maps, "Maps\\"), None);
+    }
+
+    #[test]
+    fn keeps_the_extension_of_cached_maps() {
+        let hash = [0xab; 32];
+        assert!(cache_name(&hash, Path::new("(2)Plains.w3m")).ends_with("abab.w3m"));
+        assert!(cache_name(&hash, Path::new("(4)TwistedMeadows.w3x")).ends_with("abab.w3x"));
+    }
+}
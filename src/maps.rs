@@ -0,0 +1,199 @@
+//! Finds the maps of lobbies in the local Maps folder of WC3.
+
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use tracing::debug;
+
+use crate::map_info::{MapInfo, read_map_info};
+
+/// Whether the local game has the map of a lobby.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MapCheck {
+    /// The map is at this path.
+    Found(PathBuf),
+    /// No map with this file name is in the Maps folder. WC3 downloads it from the game host after the join.
+    Missing,
+    /// The map at this path has another checksum than the map of the lobby, it is another version.
+    /// WC3 downloads the map of the game host after the join.
+    Mismatch(PathBuf),
+}
+
+/// The map of the current lobby, looked up in the Maps folder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapStatus {
+    /// Path of the map inside the WC3 folder, as in [`crate::LobbyInfo::map_name`].
+    pub map_name: String,
+    pub check: MapCheck,
+    /// The details of the local map, `None` if it is missing or can't be read.
+    pub info: Option<MapInfo>,
+}
+
+impl fmt::Display for MapStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let file_name = file_name(&self.map_name);
+        match &self.check {
+            MapCheck::Found(path) => write!(f, "Map {file_name} found at {}", path.display())?,
+            MapCheck::Missing => write!(f, "Map {file_name} is missing")?,
+            MapCheck::Mismatch(path) => write!(
+                f,
+                "Map {file_name} at {} is another version than the map of the host",
+                path.display()
+            )?,
+        }
+        match &self.info {
+            Some(info) => write!(f, ": {info}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The file name of a map path like `Maps\FrozenThrone\(4)TwistedMeadows.w3x`.
+pub fn file_name(map_name: &str) -> &str {
+    map_name.rsplit(['\\', '/']).next().unwrap_or(map_name)
+}
+
+/// Looks for the map of a lobby in `maps_dir`, the `Maps` folder of WC3.
+/// Tries the path from the lobby first, then any file with the same name, like the downloaded maps
+/// in `Maps\Download`. Names are compared case-insensitively, like WC3 does on Windows.
+pub fn check_map(maps_dir: &Path, map_name: &str) -> io::Result<MapCheck> {
+    if let Some(path) = follow(maps_dir, &path_parts(map_name))? {
+        return Ok(MapCheck::Found(path));
+    }
+    let found = search(maps_dir, file_name(map_name))?;
+    Ok(found.map_or(MapCheck::Missing, MapCheck::Found))
+}
+
+/// Looks for the map like [`check_map`] and reads its details. A map with another checksum than
+/// `checksum`, the one of the lobby, is reported as [`MapCheck::Mismatch`].
+pub fn map_status(maps_dir: &Path, map_name: &str, checksum: Option<u32>) -> io::Result<MapStatus> {
+    Ok(match check_map(maps_dir, map_name)? {
+        MapCheck::Found(path) => found_map(maps_dir, map_name, path, checksum),
+        check => MapStatus {
+            map_name: map_name.to_string(),
+            check,
+            info: None,
+        },
+    })
+}
+
+/// The status of the map of a lobby that is at `path`. The scripts of the game for the checksum are
+/// looked for in the WC3 folder above `maps_dir`.
+pub(crate) fn found_map(
+    maps_dir: &Path,
+    map_name: &str,
+    path: PathBuf,
+    checksum: Option<u32>,
+) -> MapStatus {
+    let info = match read_map_info(&path, maps_dir.parent()) {
+        Ok(info) => Some(info),
+        Err(e) => {
+            debug!("Can't read the details of map {}: {e}", path.display());
+            None
+        }
+    };
+    let local = info.as_ref().and_then(|info| info.checksum);
+    let check = match (checksum, local) {
+        (Some(checksum), Some(local)) if checksum != local => MapCheck::Mismatch(path),
+        _ => MapCheck::Found(path),
+    };
+    MapStatus {
+        map_name: map_name.to_string(),
+        check,
+        info,
+    }
+}
+
+/// The folders and the file name of a map path, without the leading `Maps` folder.
+pub(crate) fn path_parts(map_name: &str) -> Vec<&str> {
+    let mut parts: Vec<_> = map_name
+        .split(['\\', '/'])
+        .filter(|part| !part.is_empty())
+        .collect();
+    if parts
+        .first()
+        .is_some_and(|first| first.eq_ignore_ascii_case("Maps"))
+    {
+        parts.remove(0);
+    }
+    parts
+}
+
+/// Walks down `parts` from `dir`, matching every part case-insensitively.
+pub(crate) fn follow(dir: &Path, parts: &[&str]) -> io::Result<Option<PathBuf>> {
+    let Some((part, rest)) = parts.split_first() else {
+        return Ok(dir.is_file().then(|| dir.to_path_buf()));
+    };
+    for entry in read_dir(dir)? {
+        if entry
+            .file_name()
+            .to_string_lossy()
+            .eq_ignore_ascii_case(part)
+            && let Some(path) = follow(&entry.path(), rest)?
+        {
+            return Ok(Some(path));
+        }
+    }
+    Ok(None)
+}
+
+/// Searches `dir` and all folders below it for a file called `name`.
+fn search(dir: &Path, name: &str) -> io::Result<Option<PathBuf>> {
+    let mut folders = vec![dir.to_path_buf()];
+    while let Some(folder) = folders.pop() {
+        for entry in read_dir(&folder)? {
+            let path = entry.path();
+            if path.is_dir() {
+                folders.push(path);
+            } else if entry
+                .file_name()
+                .to_string_lossy()
+                .eq_ignore_ascii_case(name)
+            {
+                return Ok(Some(path));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// The entries of a folder. A folder that is gone has none.
+fn read_dir(dir: &Path) -> io::Result<Vec<fs::DirEntry>> {
+    match fs::read_dir(dir) {
+        Ok(entries) => entries.collect(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{MapCheck, check_map};
+
+    #[test]
+    fn finds_maps_by_path_and_by_name() {
+        let dir = std::env::temp_dir().join(format!("simple-wc3-maps-{}", std::process::id()));
+        fs::create_dir_all(dir.join("FrozenThrone")).unwrap();
+        fs::create_dir_all(dir.join("Download")).unwrap();
+        let twisted = dir.join("FrozenThrone").join("(4)TwistedMeadows.w3x");
+        let downloaded = dir.join("Download").join("Legion TD.w3x");
+        fs::write(&twisted, b"map").unwrap();
+        fs::write(&downloaded, b"map").unwrap();
+
+        let check = |name| check_map(&dir, name).unwrap();
+        assert_eq!(
+            check("Maps\\frozenthrone\\(4)twistedmeadows.w3x"),
+            MapCheck::Found(twisted)
+        );
+        assert_eq!(
+            check("Maps\\Custom\\Legion TD.w3x"),
+            MapCheck::Found(downloaded)
+        );
+        assert_eq!(check("Maps\\(2)EchoIsles.w3x"), MapCheck::Missing);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,458 @@
+//! Reads files from MPQ archives, the format of WC3 maps and of the game data like `War3Patch.mpq`.
+//!
+//! Only what WC3 maps need: the tables of format version 0 and files that are stored plainly or
+//! compressed with zlib, optionally encrypted.
+
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use binrw::{BinRead, BinReaderExt};
+use miniz_oxide::inflate::decompress_to_vec_zlib_with_limit;
+
+/// Largest file that is read from an archive.
+const MAX_FILE_SIZE: u32 = 64 * 1024 * 1024;
+
+//Kinds of hashes of a file name
+pub(crate) const TABLE_OFFSET: u32 = 0;
+pub(crate) const NAME_A: u32 = 1;
+pub(crate) const NAME_B: u32 = 2;
+pub(crate) const FILE_KEY: u32 = 3;
+
+//Hash table entries that are not files
+const EMPTY: u32 = 0xFFFF_FFFF;
+const DELETED: u32 = 0xFFFF_FFFE;
+
+//Flags of a block
+pub(crate) const IMPLODED: u32 = 0x0000_0100;
+pub(crate) const COMPRESSED: u32 = 0x0000_0200;
+pub(crate) const ENCRYPTED: u32 = 0x0001_0000;
+pub(crate) const FIX_KEY: u32 = 0x0002_0000;
+pub(crate) const SINGLE_UNIT: u32 = 0x0100_0000;
+pub(crate) const EXISTS: u32 = 0x8000_0000;
+
+/// The first byte of a compressed sector names the compression.
+pub(crate) const ZLIB: u8 = 0x02;
+const PKWARE: u8 = 0x08;
+
+static CRYPT_TABLE: [u32; 0x500] = crypt_table();
+
+#[derive(Debug)]
+pub enum MpqError {
+    Io(io::Error),
+    /// No MPQ header was found.
+    NotAnArchive,
+    /// A table or file points outside of the archive or does not unpack to its size.
+    Corrupt,
+    /// The file is compressed with something else than zlib.
+    Compression(u8),
+    /// The archive is not a complete WC3 map.
+    MissingFile(&'static str),
+}
+
+impl fmt::Display for MpqError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MpqError::Io(e) => write!(f, "Can't read the archive: {e}"),
+            MpqError::NotAnArchive => write!(f, "Not an MPQ archive"),
+            MpqError::Corrupt => write!(f, "The archive is damaged"),
+            MpqError::Compression(method) => write!(f, "Unsupported compression {method:#04x}"),
+            MpqError::MissingFile(name) => write!(f, "The archive has no {name}"),
+        }
+    }
+}
+
+impl std::error::Error for MpqError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MpqError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MpqError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => MpqError::Corrupt,
+            _ => MpqError::Io(e),
+        }
+    }
+}
+
+#[derive(BinRead, Debug)]
+#[br(little, magic = b"MPQ\x1A")]
+struct Header {
+    _header_size: u32,
+    _archive_size: u32,
+    _format_version: u16,
+    /// Sectors are 512 bytes shifted left by this.
+    sector_shift: u16,
+    hash_table_pos: u32,
+    block_table_pos: u32,
+    hash_table_len: u32,
+    block_table_len: u32,
+}
+
+/// Put in front of the header by some tools.
+#[derive(BinRead, Debug)]
+#[br(little, magic = b"MPQ\x1B")]
+struct UserData {
+    _size: u32,
+    header_offset: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct HashEntry {
+    name_a: u32,
+    name_b: u32,
+    block: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BlockEntry {
+    offset: u32,
+    compressed_size: u32,
+    file_size: u32,
+    flags: u32,
+}
+
+/// An opened MPQ archive.
+pub(crate) struct Archive<R> {
+    reader: R,
+    /// Where the archive starts, maps have a header of their own in front of it.
+    start: u64,
+    sector_size: usize,
+    hashes: Vec<HashEntry>,
+    blocks: Vec<BlockEntry>,
+}
+
+impl Archive<BufReader<File>> {
+    pub fn open(path: &Path) -> Result<Self, MpqError> {
+        Archive::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read + Seek> Archive<R> {
+    pub fn new(mut reader: R) -> Result<Self, MpqError> {
+        let len = reader.seek(SeekFrom::End(0))?;
+        let (start, header) = find_header(&mut reader, len)?;
+        if header.sector_shift > 16 {
+            return Err(MpqError::Corrupt);
+        }
+        let hashes = read_table(
+            &mut reader,
+            start + u64::from(header.hash_table_pos),
+            header.hash_table_len,
+            len,
+            hash("(hash table)", FILE_KEY),
+        )?
+        .into_iter()
+        .map(|[name_a, name_b, _locale, block]| HashEntry {
+            name_a,
+            name_b,
+            block,
+        })
+        .collect();
+        let blocks = read_table(
+            &mut reader,
+            start + u64::from(header.block_table_pos),
+            header.block_table_len,
+            len,
+            hash("(block table)", FILE_KEY),
+        )?
+        .into_iter()
+        .map(|[offset, compressed_size, file_size, flags]| BlockEntry {
+            offset,
+            compressed_size,
+            file_size,
+            flags,
+        })
+        .collect();
+        Ok(Archive {
+            reader,
+            start,
+            sector_size: 512 << header.sector_shift,
+            hashes,
+            blocks,
+        })
+    }
+
+    /// The content of the file with this name, like `Scripts\common.j`. `None` if it is not in the archive.
+    /// Names are compared case-insensitively and `/` is the same as `\`.
+    pub fn read(&mut self, name: &str) -> Result<Option<Vec<u8>>, MpqError> {
+        let Some(block) = self.find(name) else {
+            return Ok(None);
+        };
+        if block.file_size > MAX_FILE_SIZE || block.compressed_size > MAX_FILE_SIZE {
+            return Err(MpqError::Corrupt);
+        }
+        let file_size = block.file_size as usize;
+        let mut raw = vec![0; block.compressed_size as usize];
+        self.reader
+            .seek(SeekFrom::Start(self.start + u64::from(block.offset)))?;
+        self.reader.read_exact(&mut raw)?;
+        let key = (block.flags & ENCRYPTED != 0).then(|| file_key(name, &block));
+
+        if block.flags & SINGLE_UNIT != 0 {
+            if let Some(key) = key {
+                decrypt(&mut raw, key);
+            }
+            return unpack(raw, file_size, block.flags).map(Some);
+        }
+        if block.flags & (COMPRESSED | IMPLODED) == 0 {
+            if let Some(key) = key {
+                for (index, sector) in raw.chunks_mut(self.sector_size).enumerate() {
+                    decrypt(sector, key.wrapping_add(index as u32));
+                }
+            }
+            if raw.len() < file_size {
+                return Err(MpqError::Corrupt);
+            }
+            raw.truncate(file_size);
+            return Ok(Some(raw));
+        }
+
+        //Compressed files start with the offsets of their sectors
+        let sectors = file_size.div_ceil(self.sector_size);
+        let mut table = raw
+            .get(..(sectors + 1) * 4)
+            .ok_or(MpqError::Corrupt)?
+            .to_vec();
+        if let Some(key) = key {
+            decrypt(&mut table, key.wrapping_sub(1));
+        }
+        let offsets: Vec<usize> = table
+            .chunks_exact(4)
+            .map(|offset| u32::from_le_bytes(offset.try_into().unwrap()) as usize)
+            .collect();
+        let mut data = Vec::with_capacity(file_size);
+        for (index, bounds) in offsets.windows(2).enumerate() {
+            let mut sector = raw
+                .get(bounds[0]..bounds[1])
+                .ok_or(MpqError::Corrupt)?
+                .to_vec();
+            if let Some(key) = key {
+                decrypt(&mut sector, key.wrapping_add(index as u32));
+            }
+            let size = self.sector_size.min(file_size.saturating_sub(data.len()));
+            data.extend(unpack(sector, size, block.flags)?);
+        }
+        if data.len() != file_size {
+            return Err(MpqError::Corrupt);
+        }
+        Ok(Some(data))
+    }
+
+    fn find(&self, name: &str) -> Option<BlockEntry> {
+        let len = self.hashes.len();
+        if len == 0 {
+            return None;
+        }
+        let (name_a, name_b) = (hash(name, NAME_A), hash(name, NAME_B));
+        let first = hash(name, TABLE_OFFSET) as usize % len;
+        for index in 0..len {
+            let entry = self.hashes[(first + index) % len];
+            match entry.block {
+                EMPTY => return None,
+                DELETED => continue,
+                _ => {}
+            }
+            //Protected maps add entries that point nowhere
+            if entry.name_a == name_a
+                && entry.name_b == name_b
+                && let Some(block) = self
+                    .blocks
+                    .get(entry.block as usize)
+                    .filter(|block| block.flags & EXISTS != 0)
+            {
+                return Some(*block);
+            }
+        }
+        None
+    }
+}
+
+/// The header is at a multiple of 512 bytes, maps have their own header in front of it.
+fn find_header<R: Read + Seek>(reader: &mut R, len: u64) -> Result<(u64, Header), MpqError> {
+    for offset in (0..len).step_by(0x200) {
+        reader.seek(SeekFrom::Start(offset))?;
+        if let Ok(header) = reader.read_le::<Header>() {
+            return Ok((offset, header));
+        }
+        reader.seek(SeekFrom::Start(offset))?;
+        if let Ok(user_data) = reader.read_le::<UserData>() {
+            let start = offset + u64::from(user_data.header_offset);
+            reader.seek(SeekFrom::Start(start))?;
+            if let Ok(header) = reader.read_le::<Header>() {
+                return Ok((start, header));
+            }
+        }
+    }
+    Err(MpqError::NotAnArchive)
+}
+
+/// Reads and decrypts a table of 16 byte entries.
+fn read_table<R: Read + Seek>(
+    reader: &mut R,
+    pos: u64,
+    entries: u32,
+    len: u64,
+    key: u32,
+) -> Result<Vec<[u32; 4]>, MpqError> {
+    //Protected maps claim more entries than the file holds
+    let entries = u64::from(entries).min(len.saturating_sub(pos) / 16) as usize;
+    let mut data = vec![0; entries * 16];
+    reader.seek(SeekFrom::Start(pos))?;
+    reader.read_exact(&mut data)?;
+    decrypt(&mut data, key);
+    Ok(data
+        .chunks_exact(16)
+        .map(|entry| {
+            let word = |index: usize| {
+                u32::from_le_bytes(entry[index * 4..index * 4 + 4].try_into().unwrap())
+            };
+            [word(0), word(1), word(2), word(3)]
+        })
+        .collect())
+}
+
+/// Decompresses a sector or a single unit file of `size` bytes.
+fn unpack(mut data: Vec<u8>, size: usize, flags: u32) -> Result<Vec<u8>, MpqError> {
+    //Data that would not get smaller is stored as is
+    if data.len() >= size {
+        data.truncate(size);
+        return Ok(data);
+    }
+    if flags & IMPLODED != 0 {
+        return Err(MpqError::Compression(PKWARE));
+    }
+    if flags & COMPRESSED == 0 {
+        return Err(MpqError::Corrupt);
+    }
+    match data.split_first() {
+        Some((&ZLIB, compressed)) => decompress_to_vec_zlib_with_limit(compressed, size)
+            .ok()
+            .filter(|unpacked| unpacked.len() == size)
+            .ok_or(MpqError::Corrupt),
+        Some((&method, _)) => Err(MpqError::Compression(method)),
+        None => Err(MpqError::Corrupt),
+    }
+}
+
+/// The key of an encrypted file, made from its name without folders.
+fn file_key(name: &str, block: &BlockEntry) -> u32 {
+    let file_name = name.rsplit(['\\', '/']).next().unwrap_or(name);
+    let key = hash(file_name, FILE_KEY);
+    if block.flags & FIX_KEY != 0 {
+        key.wrapping_add(block.offset) ^ block.file_size
+    } else {
+        key
+    }
+}
+
+/// One of the hashes of a file name, see the kinds above.
+pub(crate) fn hash(name: &str, kind: u32) -> u32 {
+    let mut seed1: u32 = 0x7FED_7FED;
+    let mut seed2: u32 = 0xEEEE_EEEE;
+    for byte in name.bytes() {
+        let byte = match byte.to_ascii_uppercase() {
+            b'/' => b'\\',
+            byte => byte,
+        };
+        let byte = u32::from(byte);
+        seed1 = CRYPT_TABLE[(kind * 0x100 + byte) as usize] ^ seed1.wrapping_add(seed2);
+        seed2 = byte
+            .wrapping_add(seed1)
+            .wrapping_add(seed2)
+            .wrapping_add(seed2 << 5)
+            .wrapping_add(3);
+    }
+    seed1
+}
+
+fn decrypt(data: &mut [u8], key: u32) {
+    crypt(data, key, false);
+}
+
+#[cfg(test)]
+pub(crate) fn encrypt(data: &mut [u8], key: u32) {
+    crypt(data, key, true);
+}
+
+/// Works on whole 32 bit words, bytes at the end stay as they are.
+fn crypt(data: &mut [u8], mut key: u32, encrypt: bool) {
+    let mut seed: u32 = 0xEEEE_EEEE;
+    for word in data.chunks_exact_mut(4) {
+        seed = seed.wrapping_add(CRYPT_TABLE[0x400 + (key & 0xFF) as usize]);
+        let input = u32::from_le_bytes((&*word).try_into().unwrap());
+        let output = input ^ key.wrapping_add(seed);
+        let plain = if encrypt { input } else { output };
+        key = (!key << 0x15).wrapping_add(0x1111_1111) | (key >> 0x0B);
+        seed = plain
+            .wrapping_add(seed)
+            .wrapping_add(seed << 5)
+            .wrapping_add(3);
+        word.copy_from_slice(&output.to_le_bytes());
+    }
+}
+
+const fn crypt_table() -> [u32; 0x500] {
+    let mut table = [0; 0x500];
+    let mut seed: u32 = 0x0010_0001;
+    let mut index = 0;
+    while index < 0x100 {
+        let mut round = 0;
+        while round < 5 {
+            seed = (seed * 125 + 3) % 0x2A_AAAB;
+            let high = (seed & 0xFFFF) << 0x10;
+            seed = (seed * 125 + 3) % 0x2A_AAAB;
+            let low = seed & 0xFFFF;
+            table[index + round * 0x100] = high | low;
+            round += 1;
+        }
+        index += 1;
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{Archive, FILE_KEY, MpqError, hash};
+    use crate::test_utils::mpq::build_archive;
+
+    #[test]
+    fn hashes_names_like_storm() {
+        //Well known keys of the tables
+        assert_eq!(hash("(hash table)", FILE_KEY), 0xC3AF_3770);
+        assert_eq!(hash("(block table)", FILE_KEY), 0xEC83_B3A3);
+    }
+
+    #[test]
+    fn reads_compressed_and_encrypted_files() {
+        let script: Vec<u8> = b"function main takes nothing returns nothing\n"
+            .iter()
+            .copied()
+            .cycle()
+            .take(10_000)
+            .collect();
+        let noise: Vec<u8> = (0..5000u32).map(|i| (i * 7919 % 251) as u8).collect();
+        let data = build_archive(&[("war3map.j", &script), ("Scripts\\common.j", &noise)]);
+        let mut archive = Archive::new(Cursor::new(data)).unwrap();
+
+        assert_eq!(archive.read("war3map.j").unwrap(), Some(script));
+        assert_eq!(archive.read("scripts/COMMON.J").unwrap(), Some(noise));
+        assert_eq!(archive.read("war3map.w3i").unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let result = Archive::new(Cursor::new(vec![0; 2048]));
+        assert!(matches!(result, Err(MpqError::NotAnArchive)));
+    }
+}
@@ -0,0 +1,107 @@
+//! Keeps the direct connections between WC3 players inside the tunnel.
+//!
+//! The game host tells every player where to reach the other players (PLAYERINFO), and the players
+//! then try to connect to each other directly. Behind the tunnel all of them are on 127.0.0.1 of
+//! the host, so these attempts can only fail after a timeout. Without an address the game does not
+//! try and sends everything through the game host, which the tunnel already forwards.
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use tracing::debug;
+
+use crate::{
+    tap::PacketRewriter,
+    utils::{try_parse, try_serialize},
+    w3gs::{PlayerInfo, SockAddr},
+};
+
+/// Removes the player addresses from the packets of the game host.
+#[derive(Debug, Default)]
+pub(crate) struct PeerAddressFilter;
+
+impl PacketRewriter for PeerAddressFilter {
+    fn rewrite(&mut self, packet: &mut Vec<u8>) {
+        if packet.get(1) != Some(&0x06) {
+            return;
+        }
+        let Some(mut info) = try_parse::<PlayerInfo>(packet) else {
+            return;
+        };
+        let hidden = SockAddr::new(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+        info.external_addr = hidden;
+        info.internal_addr = hidden;
+        match try_serialize(&info) {
+            //Trailing data unknown to the parser would be lost otherwise
+            Some(rewritten) if rewritten.len() == packet.len() => {
+                debug!(
+                    "Hid the address of player {} from the other players",
+                    info.player_name
+                );
+                *packet = rewritten;
+            }
+            _ => debug!("Passing on a PLAYERINFO packet that can't be rewritten"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use tokio::io::AsyncReadExt;
+
+    use super::PeerAddressFilter;
+    use crate::{
+        tap::Rewritten,
+        test_utils::w3gs_frames::{frame, slot_info_join},
+        w3gs::W3gsPacket,
+    };
+
+    fn player_info(name: &str, addr: [u8; 4]) -> Vec<u8> {
+        let mut body = 1u32.to_le_bytes().to_vec();
+        body.push(3);
+        body.extend_from_slice(name.as_bytes());
+        body.extend_from_slice(&[0, 1, 0]);
+        for _ in 0..2 {
+            body.extend_from_slice(&[2, 0, 0x17, 0xE0]);
+            body.extend_from_slice(&addr);
+            body.extend_from_slice(&[0; 8]);
+        }
+        frame(0x06, &body)
+    }
+
+    #[tokio::test]
+    async fn hides_player_addresses() {
+        let mut stream = slot_info_join(2);
+        stream.extend_from_slice(&player_info("Moon", [127, 0, 0, 1]));
+        let mut read = Vec::new();
+        Rewritten::new(stream.as_slice(), PeerAddressFilter)
+            .read_to_end(&mut read)
+            .await
+            .unwrap();
+
+        assert_eq!(read.len(), stream.len());
+        let split = slot_info_join(2).len();
+        assert_eq!(read[..split], stream[..split]);
+        match W3gsPacket::detect(&read[split..]) {
+            Some(W3gsPacket::PlayerInfo(info)) => {
+                assert_eq!(info.player_name.to_string(), "Moon");
+                let hidden = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+                assert_eq!(info.external_addr.addr(), hidden);
+                assert_eq!(info.internal_addr.addr(), hidden);
+            }
+            other => panic!("Expected PlayerInfo, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn passes_other_data_on() {
+        let data = b"hello, this is not W3GS".to_vec();
+        let mut read = Vec::new();
+        Rewritten::new(data.as_slice(), PeerAddressFilter)
+            .read_to_end(&mut read)
+            .await
+            .unwrap();
+        assert_eq!(read, data);
+    }
+}
@@ -1,6 +1,6 @@
 use binrw::{BinRead, BinWrite, NullString};
 
-use crate::utils::try_parse;
+use crate::{error::ProtocolError, utils::try_parse};
 
 #[derive(Debug, Clone)]
 pub enum Wc3UdpMessageType {
@@ -29,7 +29,7 @@ pub enum GenerableWc3UdpMessageType {
 
 impl Wc3UdpMessageType {
     pub fn detect(packet: &[u8]) -> Option<Self> {
-        let b0 = packet.get(0)?;
+        let b0 = packet.first()?;
         let b1 = packet.get(1)?;
         match (b0, b1) {
             (0xF7, 0x2F) => Some(Wc3UdpMessageType::QueryForGamesRequest),
@@ -46,6 +46,43 @@ impl Wc3UdpMessageType {
     }
 }
 
+/// The first byte of every GProxy packet, like `0xF7` for W3GS.
+pub(crate) const GPS_HEADER: u8 = 0xF8;
+
+/// Takes the first complete WC3 packet from `buffer`.
+/// Returns `None` if more data is needed. Every WC3 packet starts with `0xF7`, its type and its length.
+/// GProxy packets are framed the same way with `0xF8`.
+pub fn take_packet(buffer: &mut Vec<u8>) -> Result<Option<Vec<u8>>, ProtocolError> {
+    if buffer.len() < 4 {
+        return Ok(None);
+    }
+    let len = u16::from_le_bytes([buffer[2], buffer[3]]) as usize;
+    if !matches!(buffer[0], 0xF7 | GPS_HEADER) || len < 4 {
+        return Err(ProtocolError::MalformedPacket);
+    }
+    if buffer.len() < len {
+        return Ok(None);
+    }
+    let rest = buffer.split_off(len);
+    Ok(Some(std::mem::replace(buffer, rest)))
+}
+
+/// Takes all complete packets from `buffer`. Data that is not framed like WC3 packets is dropped.
+pub fn take_packets(buffer: &mut Vec<u8>) -> Vec<Vec<u8>> {
+    let mut packets = Vec::new();
+    loop {
+        match take_packet(buffer) {
+            Ok(Some(packet)) => packets.push(packet),
+            Ok(None) => break,
+            Err(_) => {
+                buffer.clear();
+                break;
+            }
+        }
+    }
+    packets
+}
+
 //Based on the implementation found at https://github.com/Qyperion/WC3LanGame
 //There is also a Doc in that repo that describes the packet structure but it looks like the doc is wrong in some places.
 #[derive(BinRead, BinWrite, Debug, Clone)]
@@ -71,6 +108,13 @@ pub struct QueryForGamesResponse {
     pub tcp_port: u16,
 }
 
+impl QueryForGamesResponse {
+    /// Decodes the stat string that contains the map and the host's name.
+    pub fn stat_string(&self) -> Option<QueryForGamesResponseInner> {
+        try_parse(&decode_encoded_string(&self.encoded))
+    }
+}
+
 #[derive(BinRead, BinWrite, Debug, Clone)]
 #[brw(little)]
 #[brw(magic = b"\xF7\x2F\x10\x00")] //byte 0-3 (Packet size is always 16)
@@ -108,7 +152,7 @@ pub struct ServerClosed {
 }
 
 //The magic values are reversed for some reason.
-#[derive(BinRead, BinWrite, Debug, Clone, Copy)]
+#[derive(BinRead, BinWrite, Debug, Clone, Copy, PartialEq, Eq)]
 #[brw(little)]
 pub enum GameType {
     #[brw(magic = b"3RAW")] //WAR3
@@ -178,3 +222,63 @@ pub fn decode_encoded_string(encoded: &[u8]) -> Vec<u8> {
     }
     decoded
 }
+
+/// Inverse of [`decode_encoded_string`].
+pub fn encode_encoded_string(decoded: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(decoded.len() + decoded.len() / 7 + 1);
+    for chunk in decoded.chunks(7) {
+        let mut mask = 1u8;
+        for (i, &byte) in chunk.iter().enumerate() {
+            if byte % 2 == 1 {
+                mask |= 1 << (i + 1);
+            }
+        }
+        encoded.push(mask);
+        for &byte in chunk {
+            encoded.push(if byte % 2 == 1 { byte } else { byte + 1 });
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_encoded_string, encode_encoded_string, take_packet};
+
+    #[test]
+    fn splits_coalesced_packets() {
+        let mut buffer = vec![
+            0xF7, 0x33, 0x08, 0x00, 0x01, 0x00, 0x00, 0x00, //ServerClosed
+            0xF7, 0x33, 0x08, 0x00, 0x02, //Incomplete ServerClosed
+        ];
+        assert_eq!(take_packet(&mut buffer).unwrap().unwrap().len(), 8);
+        assert_eq!(take_packet(&mut buffer).unwrap(), None);
+        buffer.extend_from_slice(&[0x00, 0x00, 0x00]);
+        assert_eq!(
+            take_packet(&mut buffer).unwrap().unwrap(),
+            [0xF7, 0x33, 0x08, 0x00, 0x02, 0x00, 0x00, 0x00]
+        );
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn rejects_non_wc3_data() {
+        assert!(take_packet(&mut vec![0x00, 0x33, 0x08, 0x00]).is_err());
+        assert!(take_packet(&mut vec![0xF7, 0x33, 0x02, 0x00]).is_err());
+    }
+
+    #[test]
+    fn encodes_stat_strings_reversibly() {
+        let decoded: Vec<u8> = (1..=40).collect();
+        assert_eq!(
+            decode_encoded_string(&encode_encoded_string(&decoded)),
+            decoded
+        );
+    }
+
+    #[test]
+    fn takes_gproxy_packets() {
+        let mut buffer = vec![0xF8, 0x03, 0x08, 0x00, 0x0A, 0x00, 0x00, 0x00];
+        assert_eq!(take_packet(&mut buffer).unwrap().unwrap().len(), 8);
+    }
+}
@@ -0,0 +1,124 @@
+use std::{fmt, time::Duration};
+
+use iroh::{
+    Watcher,
+    endpoint::{Connection, PathInfoList},
+};
+use tokio::time::{Instant, sleep_until};
+use tracing::{info, warn};
+
+/// How long a connection may stay relayed before a warning is given.
+const RELAY_WARNING_DELAY: Duration = Duration::from_secs(20);
+
+/// How the packets of a connection travel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionKind {
+    /// Straight to the peer, usually after hole punching.
+    Direct,
+    /// Through an iroh relay server. Works everywhere, but adds latency.
+    Relayed,
+    /// No path is selected right now.
+    Unknown,
+}
+
+impl fmt::Display for ConnectionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionKind::Direct => write!(f, "direct"),
+            ConnectionKind::Relayed => write!(f, "relayed"),
+            ConnectionKind::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// The path currently used by a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathStatus {
+    pub kind: ConnectionKind,
+    pub rtt: Option<Duration>,
+}
+
+impl fmt::Display for PathStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.rtt {
+            Some(rtt) => write!(f, "{}, RTT {} ms", self.kind, rtt.as_millis()),
+            None => write!(f, "{}", self.kind),
+        }
+    }
+}
+
+/// Something about the path of a connection that is worth telling the user.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum PathEvent {
+    /// The connection switched between direct and relayed.
+    Changed(PathStatus),
+    /// The connection is relayed since [`RELAY_WARNING_DELAY`].
+    StillRelayed,
+}
+
+pub(crate) fn current_path(connection: &Connection) -> PathStatus {
+    path_status(&connection.paths().get())
+}
+
+fn path_status(paths: &PathInfoList) -> PathStatus {
+    match paths.iter().find(|path| path.is_selected()) {
+        Some(path) => PathStatus {
+            kind: if path.is_relay() {
+                ConnectionKind::Relayed
+            } else {
+                ConnectionKind::Direct
+            },
+            rtt: Some(path.rtt()),
+        },
+        None => PathStatus {
+            kind: ConnectionKind::Unknown,
+            rtt: None,
+        },
+    }
+}
+
+/// Reports path changes of `connection` until it is closed. `peer` names the other side in logs.
+pub(crate) async fn monitor_path(
+    connection: Connection,
+    peer: String,
+    mut report: impl FnMut(PathEvent),
+) {
+    let mut paths = connection.paths();
+    let mut current = path_status(&paths.get());
+    info!("Connection to {peer} is {current}");
+    //Connections start relayed until hole punching succeeds
+    let mut relayed_since = (current.kind != ConnectionKind::Direct).then(Instant::now);
+
+    loop {
+        let warn_at = relayed_since.map(|since| since + RELAY_WARNING_DELAY);
+        tokio::select! {
+            updated = paths.updated() => {
+                let Ok(paths) = updated else {
+                    return;
+                };
+                let new = path_status(&paths);
+                if new.kind != current.kind && new.kind != ConnectionKind::Unknown {
+                    info!("Connection to {peer} changed from {} to {new}", current.kind);
+                    report(PathEvent::Changed(new));
+                    relayed_since = match new.kind {
+                        ConnectionKind::Relayed => Some(Instant::now()),
+                        _ => None,
+                    };
+                }
+                current = new;
+            }
+            _ = sleep_until(warn_at.unwrap_or_else(Instant::now)), if warn_at.is_some() => {
+                //Only warn once per relayed period
+                relayed_since = None;
+                if current.kind != ConnectionKind::Direct {
+                    warn!(
+                        "Connection to {peer} is still relayed after {}s, expect higher latency",
+                        RELAY_WARNING_DELAY.as_secs()
+                    );
+                    report(PathEvent::StillRelayed);
+                }
+            }
+            _ = connection.closed() => return,
+        }
+    }
+}
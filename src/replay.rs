@@ -0,0 +1,753 @@
+//! Rebuilds the games of the host from the forwarded game traffic and saves them as .w3g replays.
+//!
+//! The file layout follows the w3g format description of the replay parser community and the
+//! replays GHost++ saves. The host sees every action of every player, because the WC3 host sends
+//! all of them to each player. Chat is only seen from the players that joined through the tunnel.
+//! Spectators get the same records while the game runs, see [`FeedMessage`].
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use binrw::{BinRead, BinWrite, NullString, binrw};
+use miniz_oxide::deflate::compress_to_vec_zlib;
+use tokio::sync::{broadcast, watch};
+use tracing::{error, info, warn};
+
+use crate::{
+    error::ProtocolError,
+    packets::{GameType, QueryForGamesResponse, take_packets},
+    tap::StreamObserver,
+    utils::{crc32, try_parse, try_serialize},
+    w3gs::{ChatBody, SlotTable, W3gsPacket},
+};
+
+/// Replays are split into blocks of this size before they are compressed.
+const BLOCK_SIZE: usize = 8192;
+/// zlib level of the blocks, the default of zlib.
+const COMPRESSION_LEVEL: u8 = 6;
+/// The header size of replays with a version 1 header, as written since WC3 1.07.
+const HEADER_SIZE: u32 = 0x44;
+/// Marks a replay of a multiplayer game.
+const MULTIPLAYER: u16 = 0x8000;
+/// The language id GHost++ writes, WC3 does not check it.
+const LANGUAGE_ID: u32 = 0x0018_F8B0;
+/// Leave reason for players whose connection to the host closed.
+const LEFT_REMOTE: u32 = 0x01;
+/// Leave result of a player that disconnected, as in [`crate::w3gs::PlayerLeft::reason`].
+const LEFT_DISCONNECT: u32 = 0x01;
+/// How many feed messages a spectator may fall behind before it gets the whole game again.
+const FEED_CAPACITY: usize = 256;
+/// Largest feed message a spectator accepts. A start message carries the whole game so far.
+const MAX_FEED_MESSAGE: usize = 64 * 1024 * 1024;
+
+/// A game that is being recorded. Kept by the host and by every spectator.
+#[binrw]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[brw(little)]
+pub(crate) struct Replay {
+    pub game_type: GameType,
+    pub game_version: u32,
+    pub game_name: NullString,
+    #[br(temp)]
+    #[bw(calc = players.len() as u8)]
+    player_count: u8,
+    #[br(count = player_count)]
+    pub players: Vec<ReplayPlayer>,
+    /// Game time in milliseconds.
+    pub length: u32,
+    #[br(temp)]
+    #[bw(calc = data.len() as u32)]
+    data_len: u32,
+    /// The decompressed replay: the game header, the start of the game and all records since.
+    #[br(count = data_len)]
+    data: Vec<u8>,
+}
+
+#[derive(BinRead, BinWrite, Debug, Clone, PartialEq, Eq)]
+#[brw(little)]
+pub(crate) struct ReplayPlayer {
+    pub player_id: u8,
+    pub name: NullString,
+}
+
+/// What a spectator gets of the recorded game, in the order the host recorded it.
+#[binrw]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[brw(little)]
+pub(crate) enum FeedMessage {
+    /// The game started, or it was already running when the spectator came.
+    #[brw(magic = 0u8)]
+    Start(Replay),
+    /// The actions of all players in one game tick.
+    #[brw(magic = 1u8)]
+    Tick {
+        send_interval: u16,
+        #[br(temp)]
+        #[bw(calc = actions.len() as u16)]
+        len: u16,
+        #[br(count = len)]
+        actions: Vec<u8>,
+    },
+    #[brw(magic = 2u8)]
+    Chat {
+        player_id: u8,
+        /// All, allies, observers or a single player.
+        mode: u32,
+        message: NullString,
+    },
+    #[brw(magic = 3u8)]
+    Left { player_id: u8, result: u32 },
+    /// The last connection to the game closed.
+    #[brw(magic = 4u8)]
+    End,
+}
+
+impl Replay {
+    /// Starts the replay of a game. `None` if the lobby or the slots were missed.
+    fn start(
+        lobby: Option<&QueryForGamesResponse>,
+        players: &BTreeMap<u8, String>,
+        slots: Option<&SlotTable>,
+    ) -> Option<Replay> {
+        let lobby = lobby?;
+        let slots = try_serialize(slots?)?;
+        //The player with the lowest id is usually the WC3 host
+        let (&host_id, host_name) = players.first_key_value()?;
+
+        let mut data = vec![0x10, 0x01, 0x00, 0x00];
+        push_player(&mut data, 0x00, host_id, host_name);
+        data.extend_from_slice(&lobby.game_name);
+        data.extend_from_slice(&[0, 0]);
+        data.extend_from_slice(&lobby.encoded);
+        data.push(0);
+        data.extend_from_slice(&lobby.number_of_slots.to_le_bytes());
+        data.extend_from_slice(&lobby.game_flags.to_le_bytes());
+        data.extend_from_slice(&LANGUAGE_ID.to_le_bytes());
+        for (&id, name) in players.iter().skip(1) {
+            push_player(&mut data, 0x16, id, name);
+            data.extend_from_slice(&0u32.to_le_bytes());
+        }
+        data.push(0x19);
+        data.extend_from_slice(&(slots.len() as u16).to_le_bytes());
+        data.extend_from_slice(&slots);
+        for record in [0x1A, 0x1B, 0x1C] {
+            data.push(record);
+            data.extend_from_slice(&1u32.to_le_bytes());
+        }
+
+        Some(Replay {
+            game_type: lobby.game_type,
+            game_version: lobby.game_version,
+            game_name: lobby.game_name.clone(),
+            players: players
+                .iter()
+                .map(|(&player_id, name)| ReplayPlayer {
+                    player_id,
+                    name: NullString::from(name.as_str()),
+                })
+                .collect(),
+            length: 0,
+            data,
+        })
+    }
+
+    /// Adds the record of a message. [`FeedMessage::Start`] and [`FeedMessage::End`] add nothing.
+    pub fn apply(&mut self, message: &FeedMessage) {
+        let data = &mut self.data;
+        match message {
+            FeedMessage::Tick {
+                send_interval,
+                actions,
+            } => {
+                data.push(0x1F);
+                data.extend_from_slice(&(actions.len() as u16 + 2).to_le_bytes());
+                data.extend_from_slice(&send_interval.to_le_bytes());
+                data.extend_from_slice(actions);
+                self.length += *send_interval as u32;
+            }
+            FeedMessage::Chat {
+                player_id,
+                mode,
+                message,
+            } => {
+                data.extend_from_slice(&[0x20, *player_id]);
+                data.extend_from_slice(&(message.len() as u16 + 6).to_le_bytes());
+                data.push(0x20);
+                data.extend_from_slice(&mode.to_le_bytes());
+                data.extend_from_slice(message);
+                data.push(0);
+            }
+            FeedMessage::Left { player_id, result } => {
+                data.push(0x17);
+                data.extend_from_slice(&LEFT_REMOTE.to_le_bytes());
+                data.push(*player_id);
+                data.extend_from_slice(&result.to_le_bytes());
+                data.extend_from_slice(&1u32.to_le_bytes());
+            }
+            FeedMessage::Start(_) | FeedMessage::End => {}
+        }
+    }
+
+    pub fn player_name(&self, player_id: u8) -> Option<String> {
+        self.players
+            .iter()
+            .find(|player| player.player_id == player_id)
+            .map(|player| player.name.to_string())
+    }
+
+    /// Writes the replay into a new file in `dir`, named after the time and the game.
+    pub fn save(&self, dir: &Path) -> io::Result<PathBuf> {
+        let path = self.new_path(dir);
+        self.write(&path)?;
+        Ok(path)
+    }
+
+    /// A file in `dir` for this replay, named after the current time and the game.
+    pub fn new_path(&self, dir: &Path) -> PathBuf {
+        dir.join(file_name(&self.game_name.to_string()))
+    }
+
+    /// Writes the replay so far to `path`, replacing what an earlier call wrote.
+    /// The file is swapped in at once, so readers never see half a replay.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let replay = pack(&self.data, self.game_type, self.game_version, self.length)
+            .ok_or_else(|| io::Error::other("can't serialize the replay header"))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let partial = path.with_extension("part");
+        fs::write(&partial, replay)?;
+        fs::rename(&partial, path)
+    }
+}
+
+/// Prefixes a feed message with its length for the stream to a spectator.
+pub(crate) fn encode_message(message: &FeedMessage) -> Option<Vec<u8>> {
+    let body = try_serialize(message)?;
+    let mut encoded = (body.len() as u32).to_le_bytes().to_vec();
+    encoded.extend_from_slice(&body);
+    Some(encoded)
+}
+
+/// Takes the first complete feed message from `buffer`. Returns `None` if more data is needed.
+pub(crate) fn take_message(buffer: &mut Vec<u8>) -> Result<Option<FeedMessage>, ProtocolError> {
+    let Some(len) = buffer.first_chunk::<4>() else {
+        return Ok(None);
+    };
+    let len = u32::from_le_bytes(*len) as usize;
+    if len > MAX_FEED_MESSAGE {
+        return Err(ProtocolError::MalformedPacket);
+    }
+    if buffer.len() < 4 + len {
+        return Ok(None);
+    }
+    let message = try_parse(&buffer[4..4 + len]).ok_or(ProtocolError::MalformedPacket)?;
+    buffer.drain(..4 + len);
+    Ok(Some(message))
+}
+
+/// Records the game seen through all forwarded connections of a [`crate::Host`], saves its replay
+/// once the last connection of a started game closed and sends it to spectators while it runs.
+pub(crate) struct ReplayRecorder {
+    /// Where replays are saved, `None` if they are only sent to spectators.
+    dir: Option<PathBuf>,
+    lobby: watch::Receiver<Option<QueryForGamesResponse>>,
+    state: Mutex<Recording>,
+    feed: broadcast::Sender<FeedMessage>,
+    report: Box<dyn Fn(PathBuf) + Send + Sync>,
+}
+
+#[derive(Default)]
+struct Recording {
+    /// The lobby as the local game advertised it before the game started.
+    lobby: Option<QueryForGamesResponse>,
+    players: BTreeMap<u8, String>,
+    slots: Option<SlotTable>,
+    /// The countdown ended.
+    started: bool,
+    /// `None` until the game started, or if too little was known about it.
+    replay: Option<Replay>,
+    /// Game ticks recorded so far.
+    ticks: usize,
+    left: HashSet<u8>,
+    /// Open connections. The recording is saved and forgotten when the last one closes.
+    taps: usize,
+}
+
+impl fmt::Debug for ReplayRecorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        f.debug_struct("ReplayRecorder")
+            .field("dir", &self.dir)
+            .field("ticks", &state.ticks)
+            .field("taps", &state.taps)
+            .finish()
+    }
+}
+
+impl ReplayRecorder {
+    /// Saves replays into `dir` if given, it is created when the first replay is saved.
+    /// `lobby` is the lobby the local game advertises, it carries the map of the game.
+    pub fn new(
+        dir: Option<PathBuf>,
+        lobby: watch::Receiver<Option<QueryForGamesResponse>>,
+        report: impl Fn(PathBuf) + Send + Sync + 'static,
+    ) -> Arc<Self> {
+        Arc::new(ReplayRecorder {
+            dir,
+            lobby,
+            state: Mutex::new(Recording::default()),
+            feed: broadcast::channel(FEED_CAPACITY).0,
+            report: Box::new(report),
+        })
+    }
+
+    /// A tap for a new forwarded connection.
+    pub fn tap(self: &Arc<Self>) -> ReplayTap {
+        self.state.lock().unwrap().taps += 1;
+        ReplayTap {
+            recorder: self.clone(),
+            own: TapState::default(),
+            from_game: Vec::new(),
+            to_game: Vec::new(),
+        }
+    }
+
+    /// The running game as a [`FeedMessage::Start`] and all messages that follow it.
+    pub fn subscribe(&self) -> (Option<FeedMessage>, broadcast::Receiver<FeedMessage>) {
+        let recording = self.state.lock().unwrap();
+        let start = recording.replay.clone().map(FeedMessage::Start);
+        (start, self.feed.subscribe())
+    }
+
+    fn handle(&self, tap: &mut TapState, from_host: bool, packet: W3gsPacket) {
+        let mut recording = self.state.lock().unwrap();
+        match (from_host, packet) {
+            (false, W3gsPacket::ReqJoin(join)) => {
+                tap.own_name = Some(join.player_name.to_string());
+            }
+            (false, W3gsPacket::ChatToHost(chat)) => {
+                if let ChatBody::GameMessage { flags, message } = chat.body {
+                    let message = FeedMessage::Chat {
+                        player_id: chat.sender,
+                        mode: flags,
+                        message,
+                    };
+                    self.record(&mut recording, message);
+                }
+            }
+            (true, W3gsPacket::SlotInfoJoin(join)) => {
+                tap.own_id = Some(join.player_id);
+                if let Some(name) = &tap.own_name {
+                    recording.players.insert(join.player_id, name.clone());
+                }
+                recording.slots = Some(join.slots);
+                self.note_lobby(&mut recording);
+            }
+            (true, W3gsPacket::SlotInfo(info)) => {
+                recording.slots = Some(info.slots);
+                self.note_lobby(&mut recording);
+            }
+            (true, W3gsPacket::PlayerInfo(info)) => {
+                recording
+                    .players
+                    .insert(info.player_id, info.player_name.to_string());
+            }
+            (true, W3gsPacket::PlayerLeft(left)) => {
+                self.leave(&mut recording, left.player_id, left.reason);
+            }
+            (true, W3gsPacket::CountdownEnd) if !recording.started => {
+                self.note_lobby(&mut recording);
+                recording.started = true;
+                recording.replay = Replay::start(
+                    recording.lobby.as_ref(),
+                    &recording.players,
+                    recording.slots.as_ref(),
+                );
+                match &recording.replay {
+                    Some(replay) => {
+                        let _ = self.feed.send(FeedMessage::Start(replay.clone()));
+                    }
+                    None => warn!("Not enough is known about the game to record it"),
+                }
+            }
+            //Every connection carries the same ticks, the first one to see a tick records it
+            (true, W3gsPacket::IncomingAction(action)) if recording.started => {
+                if tap.ticks == recording.ticks {
+                    recording.ticks += 1;
+                    //The actions follow a checksum that the replay does not have
+                    let actions = action.data.get(2..).unwrap_or_default().to_vec();
+                    let message = FeedMessage::Tick {
+                        send_interval: action.send_interval,
+                        actions,
+                    };
+                    self.record(&mut recording, message);
+                }
+                tap.ticks += 1;
+            }
+            _ => {}
+        }
+    }
+
+    /// The lobby is only advertised until the game starts, so it is kept while players are in it.
+    fn note_lobby(&self, recording: &mut Recording) {
+        if !recording.started
+            && let Some(lobby) = self.lobby.borrow().clone()
+        {
+            recording.lobby = Some(lobby);
+        }
+    }
+
+    /// Players leave once, whether the host tells about it or their connection closes.
+    fn leave(&self, recording: &mut Recording, player_id: u8, result: u32) {
+        if !recording.started {
+            recording.players.remove(&player_id);
+        } else if recording.left.insert(player_id) {
+            self.record(recording, FeedMessage::Left { player_id, result });
+        }
+    }
+
+    fn record(&self, recording: &mut Recording, message: FeedMessage) {
+        if let Some(replay) = &mut recording.replay {
+            replay.apply(&message);
+            //Nobody may be watching
+            let _ = self.feed.send(message);
+        }
+    }
+
+    fn close_tap(&self, tap: &TapState) {
+        let mut recording = self.state.lock().unwrap();
+        if let Some(id) = tap.own_id {
+            self.leave(&mut recording, id, LEFT_DISCONNECT);
+        }
+        recording.taps -= 1;
+        if recording.taps > 0 {
+            return;
+        }
+        let ended = std::mem::take(&mut *recording);
+        drop(recording);
+        let Some(replay) = ended.replay else {
+            return;
+        };
+        let _ = self.feed.send(FeedMessage::End);
+        if let Some(dir) = &self.dir {
+            match replay.save(dir) {
+                Ok(path) => {
+                    info!("Saved the replay to {}", path.display());
+                    (self.report)(path);
+                }
+                Err(e) => error!("Failed to save the replay to {}: {e}", dir.display()),
+            }
+        }
+    }
+}
+
+fn push_player(data: &mut Vec<u8>, record: u8, player_id: u8, name: &str) {
+    data.extend_from_slice(&[record, player_id]);
+    data.extend_from_slice(name.as_bytes());
+    //No additional data in custom games
+    data.extend_from_slice(&[0, 1, 0]);
+}
+
+#[derive(BinRead, BinWrite, Debug)]
+#[brw(little, magic = b"Warcraft III recorded game\x1A\0")]
+struct ReplayHeader {
+    header_size: u32,
+    file_size: u32,
+    header_version: u32,
+    data_size: u32,
+    blocks: u32,
+    game_type: GameType,
+    game_version: u32,
+    build: u16,
+    flags: u16,
+    /// Game time in milliseconds.
+    length: u32,
+    /// CRC32 of the header while this field is 0.
+    checksum: u32,
+}
+
+/// Splits the replay data into blocks and adds the header.
+fn pack(data: &[u8], game_type: GameType, game_version: u32, length: u32) -> Option<Vec<u8>> {
+    let mut blocks = Vec::new();
+    for chunk in data.chunks(BLOCK_SIZE) {
+        let mut block = chunk.to_vec();
+        block.resize(BLOCK_SIZE, 0);
+        let compressed = compress_to_vec_zlib(&block, COMPRESSION_LEVEL);
+        let mut header = Vec::new();
+        header.extend_from_slice(&(compressed.len() as u16).to_le_bytes());
+        header.extend_from_slice(&(BLOCK_SIZE as u16).to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        let checksum = fold(crc32(&header)) | fold(crc32(&compressed)) << 16;
+        header[4..].copy_from_slice(&checksum.to_le_bytes());
+        blocks.extend_from_slice(&header);
+        blocks.extend_from_slice(&compressed);
+    }
+
+    let mut header = ReplayHeader {
+        header_size: HEADER_SIZE,
+        file_size: HEADER_SIZE + blocks.len() as u32,
+        header_version: 1,
+        data_size: data.len() as u32,
+        blocks: data.len().div_ceil(BLOCK_SIZE) as u32,
+        game_type,
+        game_version,
+        build: build_number(game_version),
+        flags: MULTIPLAYER,
+        length,
+        checksum: 0,
+    };
+    header.checksum = crc32(&try_serialize(&header)?);
+    let mut replay = try_serialize(&header)?;
+    replay.append(&mut blocks);
+    Some(replay)
+}
+
+/// The build WC3 writes into its replays. Other patches get the build of 1.26a.
+fn build_number(game_version: u32) -> u16 {
+    match game_version {
+        27 => 52240,
+        _ => 6059,
+    }
+}
+
+/// Block checksums keep 16 bits of a CRC32.
+fn fold(crc: u32) -> u32 {
+    (crc ^ crc >> 16) & 0xFFFF
+}
+
+/// The current time and the name of the game, without characters that file systems refuse.
+fn file_name(game_name: &str) -> String {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default();
+    let game_name: String = game_name
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{time}-{game_name}.w3g")
+}
+
+/// What one forwarded connection knows about its own player.
+#[derive(Default)]
+struct TapState {
+    own_name: Option<String>,
+    own_id: Option<u8>,
+    /// Game ticks this connection carried.
+    ticks: usize,
+}
+
+/// Feeds the W3GS packets of one forwarded connection of the host to a [`ReplayRecorder`].
+/// Reads are data from the local game, writes are data to it.
+pub(crate) struct ReplayTap {
+    recorder: Arc<ReplayRecorder>,
+    own: TapState,
+    from_game: Vec<u8>,
+    to_game: Vec<u8>,
+}
+
+impl ReplayTap {
+    fn decode(&mut self, from_game: bool, data: &[u8]) {
+        let buffer = if from_game {
+            &mut self.from_game
+        } else {
+            &mut self.to_game
+        };
+        buffer.extend_from_slice(data);
+        for packet in take_packets(buffer)
+            .iter()
+            .filter_map(|frame| W3gsPacket::detect(frame))
+        {
+            self.recorder.handle(&mut self.own, from_game, packet);
+        }
+    }
+}
+
+impl StreamObserver for ReplayTap {
+    fn on_read(&mut self, data: &[u8]) {
+        self.decode(true, data);
+    }
+
+    fn on_write(&mut self, data: &[u8]) {
+        self.decode(false, data);
+    }
+}
+
+impl Drop for ReplayTap {
+    fn drop(&mut self) {
+        self.recorder.close_tap(&self.own);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        sync::{Arc, Mutex},
+    };
+
+    use miniz_oxide::inflate::decompress_to_vec_zlib;
+    use tokio::sync::watch;
+
+    use super::{
+        BLOCK_SIZE, FeedMessage, HEADER_SIZE, ReplayHeader, ReplayRecorder, encode_message,
+        take_message,
+    };
+    use crate::{
+        packets::GameType,
+        tap::StreamObserver,
+        test_utils::{
+            fake_wc3_server::FakeGame,
+            w3gs_frames::{frame, req_join, slot_info_join},
+        },
+        utils::{crc32, try_parse, try_serialize},
+    };
+
+    #[test]
+    fn saves_the_game_of_two_players() {
+        let dir = std::env::temp_dir().join(format!("simple-wc3-replays-{}", std::process::id()));
+        let (_lobby_tx, lobby) = watch::channel(Some(FakeGame::default().query_response(6112)));
+        let saved = Arc::new(Mutex::new(Vec::new()));
+        let reported = saved.clone();
+        let recorder = ReplayRecorder::new(Some(dir.clone()), lobby, move |path| {
+            reported.lock().unwrap().push(path)
+        });
+
+        let mut first = recorder.tap();
+        let mut second = recorder.tap();
+        first.on_write(&req_join("Grubby"));
+        first.on_read(&slot_info_join(2));
+        second.on_write(&req_join("Moon"));
+        second.on_read(&slot_info_join(3));
+        first.on_read(&frame(0x0B, &[]));
+        second.on_read(&frame(0x0B, &[]));
+        //Both players get the same tick, it is recorded once
+        let tick = frame(0x0C, &[0x64, 0x00, 0xAB, 0xCD, 0x02, 0x01, 0x00, 0x42]);
+        first.on_read(&tick);
+        second.on_read(&tick);
+        first.on_write(&frame(0x28, b"\x01\x01\x02\x20\x00\x00\x00\x00gg\0"));
+        drop(first);
+        drop(second);
+
+        let saved = saved.lock().unwrap().clone();
+        assert_eq!(saved.len(), 1);
+        let replay = fs::read(&saved[0]).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let header: ReplayHeader = try_parse(&replay).unwrap();
+        assert_eq!(header.header_size, HEADER_SIZE);
+        assert_eq!(header.file_size as usize, replay.len());
+        assert_eq!(header.blocks, 1);
+        assert_eq!(header.game_type, GameType::TheFrozenThrone);
+        assert_eq!(header.game_version, 26);
+        assert_eq!(header.length, 100);
+        let checksum = header.checksum;
+        let header = ReplayHeader {
+            checksum: 0,
+            ..header
+        };
+        assert_eq!(crc32(&try_serialize(&header).unwrap()), checksum);
+
+        let block = &replay[HEADER_SIZE as usize..];
+        let compressed_size = u16::from_le_bytes([block[0], block[1]]) as usize;
+        assert_eq!(8 + compressed_size, block.len());
+        assert_eq!(
+            u16::from_le_bytes([block[2], block[3]]) as usize,
+            BLOCK_SIZE
+        );
+        let data = decompress_to_vec_zlib(&block[8..]).unwrap();
+        assert_eq!(data.len(), BLOCK_SIZE);
+        let contains = |part: &[u8]| data.windows(part.len()).any(|window| window == part);
+        assert!(contains(b"\x00\x02Grubby\0\x01\x00Fake Lobby\0"));
+        assert!(contains(b"\x16\x03Moon\0\x01\x00\x00\x00\x00\x00"));
+        assert_eq!(
+            data.windows(3)
+                .filter(|window| *window == b"\x1F\x06\x00")
+                .count(),
+            1
+        );
+        assert!(contains(b"\x1F\x06\x00\x64\x00\x02\x01\x00\x42"));
+        assert!(contains(b"\x20\x02\x08\x00\x20\x00\x00\x00\x00gg\0"));
+        assert!(contains(b"\x17\x01\x00\x00\x00\x02\x01\x00\x00\x00"));
+        assert!(contains(b"\x17\x01\x00\x00\x00\x03\x01\x00\x00\x00"));
+    }
+
+    #[test]
+    fn skips_games_that_did_not_start() {
+        let dir = std::env::temp_dir().join("simple-wc3-unstarted-replays");
+        let (_lobby_tx, lobby) = watch::channel(Some(FakeGame::default().query_response(6112)));
+        let recorder = ReplayRecorder::new(Some(dir.clone()), lobby, |_| panic!("Nothing to save"));
+        let mut tap = recorder.tap();
+        tap.on_write(&req_join("Grubby"));
+        tap.on_read(&slot_info_join(2));
+        drop(tap);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn spectators_rebuild_the_same_replay() {
+        let (_lobby_tx, lobby) = watch::channel(Some(FakeGame::default().query_response(6112)));
+        let recorder = ReplayRecorder::new(None, lobby, |_| {});
+        let mut tap = recorder.tap();
+        tap.on_write(&req_join("Grubby"));
+        tap.on_read(&slot_info_join(2));
+        let (start, _) = recorder.subscribe();
+        assert!(start.is_none());
+
+        tap.on_read(&frame(0x0B, &[]));
+        tap.on_read(&frame(
+            0x0C,
+            &[0x64, 0x00, 0xAB, 0xCD, 0x02, 0x01, 0x00, 0x42],
+        ));
+        //A spectator that comes late gets the game so far
+        let (start, mut feed) = recorder.subscribe();
+        let Some(FeedMessage::Start(mut watched)) = start else {
+            panic!("Expected the start of the running game");
+        };
+        assert_eq!(watched.length, 100);
+        tap.on_read(&frame(0x0C, &[0x64, 0x00]));
+        tap.on_write(&frame(0x28, b"\x01\x01\x02\x20\x01\x00\x00\x00gg\0"));
+        let expected = recorder.subscribe().0;
+        drop(tap);
+
+        //Messages go through the stream framing, in pieces
+        let mut stream = Vec::new();
+        while let Ok(message) = feed.try_recv() {
+            stream.extend_from_slice(&encode_message(&message).unwrap());
+        }
+        let mut pending = stream[..3].to_vec();
+        assert_eq!(take_message(&mut pending).unwrap(), None);
+        pending.extend_from_slice(&stream[3..]);
+        let mut messages = Vec::new();
+        while let Some(message) = take_message(&mut pending).unwrap() {
+            messages.push(message);
+        }
+        assert_eq!(messages.len(), 4);
+        assert_eq!(
+            messages[2..],
+            [
+                FeedMessage::Left {
+                    player_id: 2,
+                    result: 1
+                },
+                FeedMessage::End
+            ]
+        );
+        for message in &messages[..2] {
+            watched.apply(message);
+        }
+        assert_eq!(Some(FeedMessage::Start(watched)), expected);
+    }
+}
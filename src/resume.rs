@@ -0,0 +1,779 @@
+//! Tunnels that survive short outages of the iroh connection.
+//!
+//! Every forwarded TCP connection of the game is a tunnel with an id. Its data is sent in
+//! sequence-numbered frames, and every frame is kept until the other side acknowledged it. If the
+//! stream of a tunnel breaks, the local TCP connection stays open and the tunnel waits for a new
+//! stream. The side that opened the tunnel opens a new stream once the connection is rebuilt. Both
+//! sides tell each other the last frame they received and send again what got lost.
+
+use std::{
+    collections::{HashMap, VecDeque, hash_map::Entry},
+    io,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use iroh::{
+    PublicKey,
+    endpoint::{Connection, ConnectionError, RecvStream, SendStream},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, WriteHalf},
+    sync::{mpsc, watch},
+    task::JoinHandle,
+    time::{Instant, sleep_until, timeout},
+};
+use tracing::{debug, info};
+
+use crate::error::ProtocolError;
+
+/// How long a tunnel waits for a new stream before it closes the local TCP connection.
+pub(crate) const RESUME_TIMEOUT: Duration = Duration::from_secs(60);
+/// Data of the local connection that was not acknowledged yet. Reading pauses above this.
+const MAX_UNACKED_BYTES: usize = 1024 * 1024;
+const MAX_FRAME_DATA: usize = 16 * 1024;
+/// How long a tunnel that gives up tries to tell the other side.
+const ABORT_TIMEOUT: Duration = Duration::from_secs(1);
+
+const FRAME_DATA: u8 = 0;
+const FRAME_ACK: u8 = 1;
+const FRAME_ABORT: u8 = 2;
+
+const HELLO_OPEN: u8 = 0;
+const HELLO_RESUME: u8 = 1;
+const HELLO_REJOIN: u8 = 2;
+const WELCOME_OK: u8 = 0;
+const WELCOME_UNKNOWN: u8 = 1;
+
+/// The first message on every tunnel stream, sent by the side that opened the tunnel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Hello {
+    Open(u64),
+    /// Continues a tunnel that already received frames up to `received`.
+    Resume {
+        tunnel: u64,
+        received: u64,
+    },
+    /// A new tunnel to the reconnect port of the game host, see [`crate::gproxy`].
+    Rejoin(u64),
+}
+
+impl Hello {
+    fn encode(self) -> [u8; 17] {
+        let (kind, tunnel, received) = match self {
+            Hello::Open(tunnel) => (HELLO_OPEN, tunnel, 0),
+            Hello::Resume { tunnel, received } => (HELLO_RESUME, tunnel, received),
+            Hello::Rejoin(tunnel) => (HELLO_REJOIN, tunnel, 0),
+        };
+        let mut encoded = [0; 17];
+        encoded[0] = kind;
+        encoded[1..9].copy_from_slice(&tunnel.to_le_bytes());
+        encoded[9..].copy_from_slice(&received.to_le_bytes());
+        encoded
+    }
+
+    pub fn tunnel(self) -> u64 {
+        match self {
+            Hello::Open(tunnel) | Hello::Resume { tunnel, .. } | Hello::Rejoin(tunnel) => tunnel,
+        }
+    }
+}
+
+/// The connection broke without either side closing it, so it may come back.
+pub(crate) fn resumable(reason: &ConnectionError) -> bool {
+    matches!(reason, ConnectionError::TimedOut | ConnectionError::Reset)
+}
+
+/// One stream a tunnel can use.
+pub(crate) struct Transport {
+    reader: Box<dyn AsyncRead + Send + Unpin>,
+    writer: Box<dyn AsyncWrite + Send + Unpin>,
+    /// The last frame the other side received.
+    peer_received: u64,
+}
+
+impl Transport {
+    pub fn new(
+        reader: impl AsyncRead + Send + Unpin + 'static,
+        writer: impl AsyncWrite + Send + Unpin + 'static,
+        peer_received: u64,
+    ) -> Self {
+        Transport {
+            reader: Box::new(reader),
+            writer: Box::new(writer),
+            peer_received,
+        }
+    }
+}
+
+/// Opens a stream for a tunnel and waits until the other side takes it.
+pub(crate) async fn open_stream(
+    connection: &Connection,
+    hello: Hello,
+) -> Result<Transport, ProtocolError> {
+    let (mut send, mut recv) = connection
+        .open_bi()
+        .await
+        .map_err(ProtocolError::OpenStream)?;
+    AsyncWriteExt::write_all(&mut send, &hello.encode())
+        .await
+        .map_err(ProtocolError::Handshake)?;
+    let mut welcome = [0; 9];
+    AsyncReadExt::read_exact(&mut recv, &mut welcome)
+        .await
+        .map_err(ProtocolError::Handshake)?;
+    match welcome[0] {
+        WELCOME_OK => {
+            let peer_received = u64::from_le_bytes(welcome[1..].try_into().unwrap());
+            Ok(Transport::new(recv, send, peer_received))
+        }
+        _ => Err(ProtocolError::UnknownTunnel),
+    }
+}
+
+/// Reads what the other side wants from a stream it opened.
+pub(crate) async fn read_hello(recv: &mut RecvStream) -> Result<Hello, ProtocolError> {
+    let mut hello = [0; 17];
+    AsyncReadExt::read_exact(recv, &mut hello)
+        .await
+        .map_err(ProtocolError::Handshake)?;
+    let tunnel = u64::from_le_bytes(hello[1..9].try_into().unwrap());
+    let received = u64::from_le_bytes(hello[9..].try_into().unwrap());
+    match hello[0] {
+        HELLO_OPEN => Ok(Hello::Open(tunnel)),
+        HELLO_RESUME => Ok(Hello::Resume { tunnel, received }),
+        HELLO_REJOIN => Ok(Hello::Rejoin(tunnel)),
+        kind => Err(ProtocolError::Handshake(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown stream kind {kind}"),
+        ))),
+    }
+}
+
+/// Takes a stream the other side opened for a tunnel that received frames up to `received`.
+pub(crate) async fn accept_stream(
+    mut send: SendStream,
+    recv: RecvStream,
+    hello: Hello,
+    received: u64,
+) -> Result<Transport, ProtocolError> {
+    let mut welcome = [WELCOME_OK; 9];
+    welcome[1..].copy_from_slice(&received.to_le_bytes());
+    AsyncWriteExt::write_all(&mut send, &welcome)
+        .await
+        .map_err(ProtocolError::Handshake)?;
+    let peer_received = match hello {
+        Hello::Open(_) | Hello::Rejoin(_) => 0,
+        Hello::Resume { received, .. } => received,
+    };
+    Ok(Transport::new(recv, send, peer_received))
+}
+
+/// Tells the other side that the tunnel it wants to resume or rejoin is gone.
+pub(crate) async fn refuse_stream(mut send: SendStream) {
+    let _ = AsyncWriteExt::write_all(&mut send, &[WELCOME_UNKNOWN; 9]).await;
+    let _ = send.finish();
+}
+
+/// Where a running tunnel takes new streams.
+#[derive(Debug, Clone)]
+pub(crate) struct TunnelLink {
+    attach: mpsc::Sender<Transport>,
+    received: Arc<AtomicU64>,
+}
+
+impl TunnelLink {
+    /// The last frame the tunnel received. Does not change while the tunnel has no stream.
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Acquire)
+    }
+
+    /// Hands a new stream to the tunnel. Returns `false` if the tunnel is gone.
+    pub async fn attach(&self, transport: Transport) -> bool {
+        self.attach.send(transport).await.is_ok()
+    }
+}
+
+/// The tunnels that can be resumed, by peer and tunnel id.
+/// A tunnel without a stream ends once it is no longer listed here.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TunnelLinks {
+    links: Arc<Mutex<HashMap<(PublicKey, u64), TunnelLink>>>,
+    /// Signalled whenever tunnels are removed.
+    removed: watch::Sender<()>,
+}
+
+impl TunnelLinks {
+    /// Lists a new tunnel. It is removed again when the returned [`Tunnel`] is dropped.
+    /// `None` if a tunnel with this id is still listed.
+    pub fn register(&self, peer: PublicKey, id: u64) -> Option<Tunnel> {
+        let (attach, streams) = mpsc::channel(4);
+        let received = Arc::new(AtomicU64::new(0));
+        match self.links.lock().unwrap().entry((peer, id)) {
+            Entry::Occupied(_) => return None,
+            Entry::Vacant(entry) => entry.insert(TunnelLink {
+                attach,
+                received: received.clone(),
+            }),
+        };
+        Some(Tunnel {
+            peer,
+            id,
+            links: self.clone(),
+            streams,
+            received,
+        })
+    }
+
+    pub fn get(&self, peer: PublicKey, id: u64) -> Option<TunnelLink> {
+        self.links.lock().unwrap().get(&(peer, id)).cloned()
+    }
+
+    /// The tunnels with a peer, by id.
+    pub fn of_peer(&self, peer: PublicKey) -> Vec<(u64, TunnelLink)> {
+        self.links
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((link_peer, _), _)| *link_peer == peer)
+            .map(|((_, id), link)| (*id, link.clone()))
+            .collect()
+    }
+
+    /// Waits until no tunnel with the peer is listed anymore.
+    pub async fn drained(&self, peer: PublicKey) {
+        let mut removed = self.removed.subscribe();
+        while !self.of_peer(peer).is_empty() {
+            //Can't fail, the sender is part of self
+            let _ = removed.changed().await;
+        }
+    }
+
+    pub fn remove(&self, peer: PublicKey, id: u64) {
+        self.links.lock().unwrap().remove(&(peer, id));
+        self.removed.send_replace(());
+    }
+
+    /// The tunnels with this peer end instead of waiting for a new stream.
+    pub fn remove_peer(&self, peer: PublicKey) {
+        self.links
+            .lock()
+            .unwrap()
+            .retain(|(link_peer, _), _| *link_peer != peer);
+        self.removed.send_replace(());
+    }
+
+    pub fn clear(&self) {
+        self.links.lock().unwrap().clear();
+        self.removed.send_replace(());
+    }
+}
+
+/// A registered tunnel, ready to forward a local TCP connection.
+pub(crate) struct Tunnel {
+    peer: PublicKey,
+    id: u64,
+    links: TunnelLinks,
+    streams: mpsc::Receiver<Transport>,
+    received: Arc<AtomicU64>,
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        let mut links = self.links.links.lock().unwrap();
+        //The id might belong to another tunnel by now
+        if links
+            .get(&(self.peer, self.id))
+            .is_some_and(|link| Arc::ptr_eq(&link.received, &self.received))
+        {
+            links.remove(&(self.peer, self.id));
+            drop(links);
+            self.links.removed.send_replace(());
+        }
+    }
+}
+
+impl Tunnel {
+    /// Forwards `local` over the tunnel until both sides are done.
+    /// Returns the number of bytes written to and read from `local`, like [`tokio::io::copy_bidirectional`].
+    pub async fn run<L>(
+        mut self,
+        local: L,
+        transport: Transport,
+    ) -> Result<(u64, u64), ProtocolError>
+    where
+        L: AsyncRead + AsyncWrite + Send + Unpin,
+    {
+        let (mut local_read, local_write) = tokio::io::split(local);
+        let mut state = TunnelState::new(local_write, self.received.clone());
+        let mut link = state.connect(transport).await;
+        let mut detached_at = link.is_none().then(Instant::now);
+        let mut streams_open = true;
+        let mut buf = vec![0; MAX_FRAME_DATA];
+
+        let result = loop {
+            if state.is_done() {
+                break Ok(());
+            }
+            if link.is_none() && !streams_open {
+                break Err(ProtocolError::TunnelAborted);
+            }
+            let can_read = !state.local_closed && state.unacked_bytes < MAX_UNACKED_BYTES;
+            let deadline = detached_at.map(|at| at + RESUME_TIMEOUT);
+            tokio::select! {
+                read = local_read.read(&mut buf), if can_read => {
+                    let len = match read {
+                        Ok(len) => len,
+                        Err(e) => break Err(ProtocolError::Forwarding(e)),
+                    };
+                    let frame = state.push(&buf[..len]);
+                    if let Some(active) = &mut link
+                        && let Err(e) = active.writer.write_all(&frame).await
+                    {
+                        debug!("Tunnel {} lost its stream: {e}", self.id);
+                        link = None;
+                        detached_at = Some(Instant::now());
+                    }
+                }
+                frame = next_frame(&mut link) => match frame {
+                    Ok(Some(Frame::Data { seq, data })) => {
+                        if let Err(e) = state.deliver(seq, data).await {
+                            break Err(e);
+                        }
+                        //Acknowledged once all frames that arrived together are handled
+                        if let Some(active) = &mut link
+                            && active.frames.is_empty()
+                        {
+                            let _ = active.writer.write_all(&Frame::Ack(state.received).encode()).await;
+                        }
+                    }
+                    Ok(Some(Frame::Ack(seq))) => state.acknowledged(seq),
+                    Ok(Some(Frame::Abort)) => break Err(ProtocolError::TunnelAborted),
+                    //The other side only finishes the stream when it received everything
+                    Ok(None) if state.local_closed && state.remote_closed => break Ok(()),
+                    Ok(None) => break Err(ProtocolError::TunnelAborted),
+                    Err(e) => {
+                        debug!("Tunnel {} lost its stream: {e}", self.id);
+                        link = None;
+                        detached_at = Some(Instant::now());
+                    }
+                },
+                transport = self.streams.recv(), if streams_open => match transport {
+                    Some(transport) => {
+                        if detached_at.is_some() {
+                            info!("Tunnel {} with {} resumed", self.id, self.peer);
+                        }
+                        link = state.connect(transport).await;
+                        detached_at = link.is_none().then(Instant::now);
+                    }
+                    None => streams_open = false,
+                },
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    break Err(ProtocolError::ResumeTimedOut);
+                }
+            }
+        };
+
+        if let Some(mut active) = link {
+            match &result {
+                Ok(()) => {
+                    //The other side may still wait for this
+                    let _ = active
+                        .writer
+                        .write_all(&Frame::Ack(state.received).encode())
+                        .await;
+                    let _ = active.writer.shutdown().await;
+                }
+                Err(_) => {
+                    let _ = timeout(
+                        ABORT_TIMEOUT,
+                        active.writer.write_all(&Frame::Abort.encode()),
+                    )
+                    .await;
+                }
+            }
+        }
+        result.map(|()| (state.to_game, state.from_game))
+    }
+}
+
+/// The current stream of a tunnel.
+struct Link {
+    writer: Box<dyn AsyncWrite + Send + Unpin>,
+    frames: mpsc::Receiver<io::Result<Option<Frame>>>,
+    reader: JoinHandle<()>,
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// Waits for the next frame of the current stream. Never finishes without a stream.
+async fn next_frame(link: &mut Option<Link>) -> io::Result<Option<Frame>> {
+    match link {
+        Some(link) => link
+            .frames
+            .recv()
+            .await
+            .unwrap_or_else(|| Err(io::Error::other("stream reader stopped"))),
+        None => std::future::pending().await,
+    }
+}
+
+/// Reads frames until the stream ends. The last message is the end or the error.
+async fn read_frames(
+    mut reader: Box<dyn AsyncRead + Send + Unpin>,
+    frames: mpsc::Sender<io::Result<Option<Frame>>>,
+) {
+    loop {
+        let frame = Frame::read(&mut reader).await;
+        let last = !matches!(frame, Ok(Some(_)));
+        if frames.send(frame).await.is_err() || last {
+            break;
+        }
+    }
+}
+
+struct TunnelState<L> {
+    local_write: WriteHalf<L>,
+    /// Sent frames that were not acknowledged yet, encoded.
+    unacked: VecDeque<(u64, Vec<u8>)>,
+    unacked_bytes: usize,
+    next_seq: u64,
+    /// The last frame that was written to the local connection.
+    received: u64,
+    shared_received: Arc<AtomicU64>,
+    local_closed: bool,
+    remote_closed: bool,
+    to_game: u64,
+    from_game: u64,
+}
+
+impl<L: AsyncWrite> TunnelState<L> {
+    fn new(local_write: WriteHalf<L>, shared_received: Arc<AtomicU64>) -> Self {
+        TunnelState {
+            local_write,
+            unacked: VecDeque::new(),
+            unacked_bytes: 0,
+            next_seq: 1,
+            received: 0,
+            shared_received,
+            local_closed: false,
+            remote_closed: false,
+            to_game: 0,
+            from_game: 0,
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.local_closed && self.remote_closed && self.unacked.is_empty()
+    }
+
+    /// Turns data of the local connection into a frame and keeps it until it is acknowledged.
+    /// No data means the local connection was closed.
+    fn push(&mut self, data: &[u8]) -> Vec<u8> {
+        if data.is_empty() {
+            self.local_closed = true;
+        }
+        self.from_game += data.len() as u64;
+        let frame = Frame::Data {
+            seq: self.next_seq,
+            data: data.to_vec(),
+        }
+        .encode();
+        self.unacked.push_back((self.next_seq, frame.clone()));
+        self.unacked_bytes += frame.len();
+        self.next_seq += 1;
+        frame
+    }
+
+    fn acknowledged(&mut self, seq: u64) {
+        while let Some((_, frame)) = self.unacked.front().filter(|(sent, _)| *sent <= seq) {
+            self.unacked_bytes -= frame.len();
+            self.unacked.pop_front();
+        }
+    }
+
+    async fn deliver(&mut self, seq: u64, data: Vec<u8>) -> Result<(), ProtocolError> {
+        if seq <= self.received {
+            //Sent again after a new stream, already delivered
+            return Ok(());
+        }
+        if seq != self.received + 1 {
+            debug!("Tunnel frame {seq} arrived, expected {}", self.received + 1);
+            return Err(ProtocolError::TunnelAborted);
+        }
+        if data.is_empty() {
+            self.remote_closed = true;
+            let _ = self.local_write.shutdown().await;
+        } else {
+            self.local_write
+                .write_all(&data)
+                .await
+                .map_err(ProtocolError::Forwarding)?;
+            //Wrappers of the local connection may hold data back until flushed
+            self.local_write
+                .flush()
+                .await
+                .map_err(ProtocolError::Forwarding)?;
+            self.to_game += data.len() as u64;
+        }
+        self.received = seq;
+        self.shared_received.store(seq, Ordering::Release);
+        Ok(())
+    }
+
+    /// Starts using a new stream. Sends everything the other side did not receive yet.
+    async fn connect(&mut self, transport: Transport) -> Option<Link> {
+        self.acknowledged(transport.peer_received);
+        let (sender, frames) = mpsc::channel(64);
+        let reader = tokio::spawn(read_frames(transport.reader, sender));
+        let mut link = Link {
+            writer: transport.writer,
+            frames,
+            reader,
+        };
+        for (_, frame) in &self.unacked {
+            link.writer.write_all(frame).await.ok()?;
+        }
+        link.writer
+            .write_all(&Frame::Ack(self.received).encode())
+            .await
+            .ok()?;
+        Some(link)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Frame {
+    /// Data of the local connection, empty when it was closed.
+    Data { seq: u64, data: Vec<u8> },
+    /// All frames up to this one arrived.
+    Ack(u64),
+    /// The tunnel is given up and won't be resumed.
+    Abort,
+}
+
+impl Frame {
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            Frame::Data { seq, data } => {
+                let mut encoded = Vec::with_capacity(11 + data.len());
+                encoded.push(FRAME_DATA);
+                encoded.extend_from_slice(&seq.to_le_bytes());
+                encoded.extend_from_slice(&(data.len() as u16).to_le_bytes());
+                encoded.extend_from_slice(data);
+                encoded
+            }
+            Frame::Ack(seq) => {
+                let mut encoded = vec![FRAME_ACK];
+                encoded.extend_from_slice(&seq.to_le_bytes());
+                encoded
+            }
+            Frame::Abort => vec![FRAME_ABORT],
+        }
+    }
+
+    /// `None` if the stream ended between two frames.
+    async fn read(reader: &mut (impl AsyncRead + Unpin)) -> io::Result<Option<Frame>> {
+        let kind = match reader.read_u8().await {
+            Ok(kind) => kind,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let frame = match kind {
+            FRAME_DATA => {
+                let seq = reader.read_u64_le().await?;
+                let len = reader.read_u16_le().await?;
+                let mut data = vec![0; len as usize];
+                reader.read_exact(&mut data).await?;
+                Frame::Data { seq, data }
+            }
+            FRAME_ACK => Frame::Ack(reader.read_u64_le().await?),
+            FRAME_ABORT => Frame::Abort,
+            kind => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown tunnel frame {kind}"),
+                ));
+            }
+        };
+        Ok(Some(frame))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        pin::Pin,
+        sync::{
+            Arc,
+            atomic::{AtomicBool, Ordering},
+        },
+        task::{Context, Poll},
+        time::Duration,
+    };
+
+    use iroh::SecretKey;
+    use tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWriteExt, DuplexStream, ReadBuf, duplex},
+        task::JoinHandle,
+        time::timeout,
+    };
+
+    use super::{Transport, TunnelLinks};
+    use crate::error::ProtocolError;
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    /// Fails instead of ending once the stream was cut, like a stream of a lost connection.
+    struct Cuttable {
+        inner: tokio::io::ReadHalf<DuplexStream>,
+        cut: Arc<AtomicBool>,
+    }
+
+    impl AsyncRead for Cuttable {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let filled = buf.filled().len();
+            let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+            if matches!(result, Poll::Ready(Ok(())))
+                && buf.filled().len() == filled
+                && self.cut.load(Ordering::Acquire)
+            {
+                return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+            }
+            result
+        }
+    }
+
+    /// A stream between two tunnels. Aborting the returned task cuts it.
+    fn stream_pair(
+        peer_received: (u64, u64),
+    ) -> (Transport, Transport, Arc<AtomicBool>, JoinHandle<()>) {
+        let cut = Arc::new(AtomicBool::new(false));
+        let (a, mut relay_a) = duplex(1024);
+        let (b, mut relay_b) = duplex(1024);
+        let relay = tokio::spawn(async move {
+            let _ = tokio::io::copy_bidirectional(&mut relay_a, &mut relay_b).await;
+        });
+        let transport = |stream, peer_received| {
+            let (inner, writer) = tokio::io::split(stream);
+            let reader = Cuttable {
+                inner,
+                cut: cut.clone(),
+            };
+            Transport::new(reader, writer, peer_received)
+        };
+        let transport_a = transport(a, peer_received.0);
+        let transport_b = transport(b, peer_received.1);
+        (transport_a, transport_b, cut, relay)
+    }
+
+    fn cut_stream(cut: &AtomicBool, relay: JoinHandle<()>) {
+        cut.store(true, Ordering::Release);
+        relay.abort();
+    }
+
+    async fn read_exactly(game: &mut DuplexStream, len: usize) -> Vec<u8> {
+        let mut received = vec![0; len];
+        timeout(WAIT, game.read_exact(&mut received))
+            .await
+            .expect("data did not arrive")
+            .unwrap();
+        received
+    }
+
+    #[tokio::test]
+    async fn resumes_after_the_stream_breaks() {
+        let peer = SecretKey::from_bytes(&[7; 32]).public();
+        let (links_a, links_b) = (TunnelLinks::default(), TunnelLinks::default());
+        let (mut game_a, local_a) = duplex(1024);
+        let (mut game_b, local_b) = duplex(1024);
+        let (transport_a, transport_b, cut, relay) = stream_pair((0, 0));
+        let run_a = tokio::spawn(links_a.register(peer, 1).unwrap().run(local_a, transport_a));
+        let run_b = tokio::spawn(links_b.register(peer, 1).unwrap().run(local_b, transport_b));
+
+        game_a.write_all(b"before").await.unwrap();
+        assert_eq!(read_exactly(&mut game_b, 6).await, b"before");
+
+        cut_stream(&cut, relay);
+        game_a.write_all(b"during").await.unwrap();
+        game_b.write_all(b"answer").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let (link_a, link_b) = (links_a.get(peer, 1).unwrap(), links_b.get(peer, 1).unwrap());
+        let (transport_a, transport_b, _, _relay) =
+            stream_pair((link_b.received(), link_a.received()));
+        assert!(link_a.attach(transport_a).await);
+        assert!(link_b.attach(transport_b).await);
+
+        assert_eq!(read_exactly(&mut game_b, 6).await, b"during");
+        assert_eq!(read_exactly(&mut game_a, 6).await, b"answer");
+        game_a.write_all(b"after").await.unwrap();
+        assert_eq!(read_exactly(&mut game_b, 5).await, b"after");
+
+        game_a.shutdown().await.unwrap();
+        game_b.shutdown().await.unwrap();
+        let rest_a = timeout(WAIT, game_a.read_to_end(&mut Vec::new()))
+            .await
+            .unwrap()
+            .unwrap();
+        let rest_b = timeout(WAIT, game_b.read_to_end(&mut Vec::new()))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((rest_a, rest_b), (0, 0));
+        assert_eq!(
+            timeout(WAIT, run_a).await.unwrap().unwrap().unwrap(),
+            (6, 17)
+        );
+        assert_eq!(
+            timeout(WAIT, run_b).await.unwrap().unwrap().unwrap(),
+            (17, 6)
+        );
+        assert!(links_a.get(peer, 1).is_none());
+    }
+
+    #[tokio::test]
+    async fn ends_when_the_tunnel_is_given_up() {
+        let peer = SecretKey::from_bytes(&[7; 32]).public();
+        let links = TunnelLinks::default();
+        let (mut game, local) = duplex(1024);
+        let (transport, _other, cut, relay) = stream_pair((0, 0));
+        let run = tokio::spawn(links.register(peer, 1).unwrap().run(local, transport));
+
+        cut_stream(&cut, relay);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        links.remove_peer(peer);
+
+        let result = timeout(WAIT, run).await.unwrap().unwrap();
+        assert!(matches!(result, Err(ProtocolError::TunnelAborted)));
+        let closed = timeout(WAIT, game.read_to_end(&mut Vec::new()))
+            .await
+            .unwrap();
+        assert!(matches!(closed, Ok(0)));
+    }
+
+    #[test]
+    fn stale_tunnel_leaves_its_successor_listed() {
+        let peer = SecretKey::from_bytes(&[3; 32]).public();
+        let links = TunnelLinks::default();
+        let stale = links.register(peer, 1).unwrap();
+        assert!(links.register(peer, 1).is_none());
+
+        links.remove_peer(peer);
+        let _successor = links.register(peer, 1).unwrap();
+        drop(stale);
+        assert!(links.get(peer, 1).is_some());
+    }
+}
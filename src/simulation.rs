@@ -0,0 +1,205 @@
+//! Made-up lobbies for testing clients on machines without WC3.
+//!
+//! Answers the game queries like a local WC3 with an open lobby, so the [`crate::Host`] sends it to the
+//! clients as usual. Nobody can join, the game is always full.
+
+use std::net::SocketAddr;
+
+use binrw::NullString;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+    task::JoinHandle,
+};
+use tracing::{debug, info};
+
+use crate::{
+    error::Error,
+    packets::{GameType, QueryForGamesResponse, QueryForGamesResponseInner, encode_encoded_string},
+    utils::{APP_NAME, try_serialize},
+    virtual_game::{answer_queries, bind_same_port},
+    w3gs::{RejectJoin, serialize_packet},
+};
+
+/// The lobby a host simulates instead of polling WC3.
+#[derive(Debug, Clone)]
+pub struct SimulatedLobby {
+    pub game_name: String,
+    /// Only clients whose WC3 has this version see the lobby, like 26 for 1.26.
+    pub game_version: u32,
+    pub game_type: GameType,
+    /// The path of the map, like `Maps\FrozenThrone\(4)TwistedMeadows.w3x`.
+    pub map_name: String,
+    pub host_name: String,
+    /// Occupied slots, including the host.
+    pub players: u32,
+    pub slots: u32,
+}
+
+impl Default for SimulatedLobby {
+    fn default() -> Self {
+        SimulatedLobby {
+            game_name: "Simulated Lobby".to_string(),
+            game_version: 26,
+            game_type: GameType::TheFrozenThrone,
+            map_name: "Maps\\FrozenThrone\\(4)TwistedMeadows.w3x".to_string(),
+            host_name: APP_NAME.to_string(),
+            players: 1,
+            slots: 4,
+        }
+    }
+}
+
+impl SimulatedLobby {
+    fn query_response(&self, tcp_port: u16) -> QueryForGamesResponse {
+        let stat_string = QueryForGamesResponseInner {
+            game_settings: 0x0000_4802,
+            unknown1: 0,
+            map_width: 116,
+            map_height: 116,
+            //No real map, clients don't compare a checksum of 0
+            map_checksum: 0,
+            map_name: NullString::from(self.map_name.as_str()),
+            host_username: NullString::from(self.host_name.as_str()),
+            unknown2: 0,
+        };
+        let encoded = try_serialize(&stat_string)
+            .map(|stat_string| encode_encoded_string(&stat_string))
+            .unwrap_or_default();
+        let mut response = QueryForGamesResponse {
+            packet_size: 0,
+            game_type: self.game_type,
+            game_version: self.game_version,
+            game_id: 1,
+            unknown1: 0,
+            game_name: NullString::from(self.game_name.as_str()),
+            unknown2: 0,
+            encoded: NullString(encoded),
+            number_of_slots: 12,
+            game_flags: 1,
+            number_of_players: self.players,
+            number_of_player_slots: self.slots,
+            game_age: 0,
+            tcp_port,
+        };
+        response.packet_size = try_serialize(&response).map_or(0, |packet| packet.len() as u16);
+        response
+    }
+}
+
+/// A running simulation. The lobby closes when this is dropped.
+pub(crate) struct Simulation {
+    addr: SocketAddr,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Simulation {
+    /// Opens the lobby on a free port of localhost.
+    pub async fn start(lobby: SimulatedLobby) -> Result<Simulation, Error> {
+        let (udp_socket, tcp_listener) = bind_same_port().await.map_err(Error::Socket)?;
+        let addr = udp_socket.local_addr().map_err(Error::Socket)?;
+        info!(
+            "Simulating lobby \"{}\" with {}/{} players on {} for WC3 1.{}",
+            lobby.game_name, lobby.players, lobby.slots, lobby.map_name, lobby.game_version
+        );
+        let (_, advert) = watch::channel(Some(lobby.query_response(addr.port())));
+        let tasks = vec![
+            tokio::spawn(answer_queries(udp_socket, advert)),
+            tokio::spawn(turn_away_players(tcp_listener)),
+        ];
+        Ok(Simulation { addr, tasks })
+    }
+
+    /// Where the lobby answers queries, use it like the address of a local WC3.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for Simulation {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// Tells every player that tries to join that the game is full, so WC3 does not wait for an answer.
+async fn turn_away_players(listener: TcpListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(turn_away(stream));
+            }
+            Err(e) => debug!("Can't accept a player of the simulated lobby: {e}"),
+        }
+    }
+}
+
+async fn turn_away(mut stream: TcpStream) {
+    //The join request comes first, the answer would be ignored before it
+    let mut buffer = [0u8; 1024];
+    if matches!(stream.read(&mut buffer).await, Ok(0) | Err(_)) {
+        return;
+    }
+    debug!("Turning away a player, the simulated lobby can't be joined");
+    if let Some(reject) = serialize_packet(&RejectJoin {
+        packet_size: 0,
+        reason: 9, //Full
+    }) {
+        let _ = stream.write_all(&reject).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{net::UdpSocket, time::timeout};
+
+    use super::{SimulatedLobby, Simulation};
+    use crate::{
+        packets::{GameType, QueryForGamesRequest, QueryForGamesResponse},
+        utils::{try_parse, try_serialize},
+    };
+
+    async fn query(simulation: &Simulation, version: u32) -> Option<QueryForGamesResponse> {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let request = QueryForGamesRequest::new(GameType::TheFrozenThrone, version);
+        socket
+            .send_to(&try_serialize(&request).unwrap(), simulation.addr())
+            .await
+            .unwrap();
+        let mut buffer = [0; 1024];
+        let len = timeout(Duration::from_millis(300), socket.recv(&mut buffer))
+            .await
+            .ok()?
+            .unwrap();
+        try_parse(&buffer[..len])
+    }
+
+    #[tokio::test]
+    async fn answers_queries_of_its_version() {
+        let simulation = Simulation::start(SimulatedLobby {
+            game_version: 27,
+            players: 3,
+            slots: 8,
+            ..SimulatedLobby::default()
+        })
+        .await
+        .unwrap();
+
+        assert!(query(&simulation, 26).await.is_none());
+        let lobby = query(&simulation, 27).await.expect("lobby");
+        assert_eq!(lobby.game_name.to_string(), "Simulated Lobby");
+        assert_eq!(
+            (lobby.number_of_players, lobby.number_of_player_slots),
+            (3, 8)
+        );
+        assert_eq!(
+            lobby.stat_string().unwrap().map_name.to_string(),
+            "Maps\\FrozenThrone\\(4)TwistedMeadows.w3x"
+        );
+    }
+}
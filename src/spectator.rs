@@ -0,0 +1,263 @@
+//! Watching the games of a host without taking a slot.
+//!
+//! The host rebuilds its games from the forwarded traffic like replays. A spectator gets the game so
+//! far when it connects, then every new record. With a replay folder, it keeps a .w3g replay of the
+//! running game up to date, so WC3 can show the game while it is still played.
+
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use iroh::{
+    Endpoint, EndpointAddr, PublicKey,
+    endpoint::{Connection, RecvStream},
+};
+use tokio::{
+    sync::broadcast::{self, Receiver, Sender},
+    task::JoinHandle,
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    client::send_password,
+    error::{CLOSE_PROTOCOL_ERROR, Error, ProtocolError},
+    events::{GameEvent, GamePhase, SpectatorEvent},
+    replay::{FeedMessage, Replay, take_message},
+    utils::SPECTATOR_ALPN,
+};
+
+/// How often the replay of a running game is written again. Packing it takes a moment for long games.
+const LIVE_REPLAY_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Settings for a [`Spectator`].
+#[derive(Debug, Clone)]
+pub struct SpectatorConfig {
+    /// The host to watch.
+    pub host: EndpointAddr,
+    /// The password of the host, if it has one.
+    pub password: Option<String>,
+    /// Save a .w3g replay of every watched game into this directory, updated while the game runs.
+    pub replay_dir: Option<PathBuf>,
+}
+
+impl SpectatorConfig {
+    pub fn new(host: EndpointAddr) -> Self {
+        SpectatorConfig {
+            host,
+            password: None,
+            replay_dir: None,
+        }
+    }
+}
+
+/// Watches the games of a host without taking a slot. The host has to allow spectators.
+pub struct Spectator {
+    endpoint: Endpoint,
+    connection: Connection,
+    events: Sender<SpectatorEvent>,
+    /// The error that made the spectator close the connection, if any.
+    failure: Arc<Mutex<Option<Error>>>,
+    task: JoinHandle<()>,
+}
+
+impl Spectator {
+    /// Binds a new endpoint and connects to the host with it.
+    pub async fn connect(config: SpectatorConfig) -> Result<Spectator, Error> {
+        let endpoint = Endpoint::bind().await.map_err(Error::EndpointBind)?;
+        Self::connect_on(endpoint, config).await
+    }
+
+    /// Connects to the host with an existing endpoint.
+    pub async fn connect_on(
+        endpoint: Endpoint,
+        config: SpectatorConfig,
+    ) -> Result<Spectator, Error> {
+        let connection = endpoint
+            .connect(config.host, SPECTATOR_ALPN)
+            .await
+            .map_err(Error::Connect)?;
+        send_password(&connection, &config.password.unwrap_or_default()).await?;
+
+        let (events, _) = broadcast::channel(64);
+        let failure = Arc::new(Mutex::new(None));
+        let task = tokio::spawn(watch_games(
+            connection.clone(),
+            events.clone(),
+            failure.clone(),
+            config.replay_dir,
+        ));
+        Ok(Spectator {
+            endpoint,
+            connection,
+            events,
+            failure,
+            task,
+        })
+    }
+
+    /// The id of this spectator, as seen by the host.
+    pub fn id(&self) -> PublicKey {
+        self.endpoint.id()
+    }
+
+    /// Subscribes to the events of this spectator. Only events sent after subscribing are received.
+    pub fn subscribe(&self) -> Receiver<SpectatorEvent> {
+        self.events.subscribe()
+    }
+
+    /// Waits until the connection to the host is closed.
+    /// Returns an error if the connection was not closed on purpose by either side.
+    pub async fn closed(&self) -> Result<(), Error> {
+        let reason = self.connection.closed().await;
+        if let Some(failure) = self.failure.lock().unwrap().take() {
+            return Err(failure);
+        }
+        Error::from_close(reason)
+    }
+
+    /// Disconnects from the host.
+    pub async fn stop(self) {
+        self.connection.close(0u32.into(), b"spectator stopped");
+        self.endpoint.close().await;
+    }
+}
+
+impl Drop for Spectator {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn watch_games(
+    connection: Connection,
+    events: Sender<SpectatorEvent>,
+    failure: Arc<Mutex<Option<Error>>>,
+    replay_dir: Option<PathBuf>,
+) {
+    let result = match connection.accept_uni().await {
+        Ok(mut feed) => follow_feed(&mut feed, &events, replay_dir).await,
+        Err(_) if connection.close_reason().is_some() => Ok(()),
+        Err(e) => Err(ProtocolError::AcceptStream(e).into()),
+    };
+    if let Err(e) = result {
+        if connection.close_reason().is_some() {
+            return;
+        }
+        warn!("Stopped watching the host: {e}");
+        connection.close(CLOSE_PROTOCOL_ERROR, b"protocol error");
+        *failure.lock().unwrap() = Some(e);
+    }
+}
+
+/// The replay file of the watched game.
+struct LiveReplay {
+    path: PathBuf,
+    written: Instant,
+}
+
+/// Rebuilds the games from the feed of the host until it ends.
+async fn follow_feed(
+    feed: &mut RecvStream,
+    events: &Sender<SpectatorEvent>,
+    replay_dir: Option<PathBuf>,
+) -> Result<(), Error> {
+    let mut game = Option::<Replay>::None;
+    let mut live = Option::<LiveReplay>::None;
+    let mut buf = [0; 8192];
+    let mut pending = Vec::new();
+    loop {
+        match feed.read(&mut buf).await {
+            Ok(Some(len)) => pending.extend_from_slice(&buf[..len]),
+            Ok(None) => return Ok(()),
+            Err(e) => return Err(ProtocolError::Read(e).into()),
+        }
+        while let Some(message) = take_message(&mut pending)? {
+            let event = match (&mut game, message) {
+                //The host sends the whole game again if the spectator fell behind
+                (_, FeedMessage::Start(replay)) => {
+                    let watching = game.replace(replay).is_none();
+                    let replay = game.as_ref().expect("game was just set");
+                    if !watching {
+                        continue;
+                    }
+                    let name = replay.game_name.to_string();
+                    info!("Watching game {name}");
+                    let _ = events.send(SpectatorEvent::Watching(name));
+                    let path = replay_dir.as_ref().map(|dir| replay.new_path(dir));
+                    match path.map(|path| replay.write(&path).map(|()| path)) {
+                        Some(Ok(path)) => {
+                            info!("Writing the replay to {}", path.display());
+                            live = Some(LiveReplay {
+                                path: path.clone(),
+                                written: Instant::now(),
+                            });
+                            Some(SpectatorEvent::Recording(path))
+                        }
+                        Some(Err(e)) => {
+                            error!("Failed to write the replay: {e}");
+                            None
+                        }
+                        None => None,
+                    }
+                }
+                (Some(replay), FeedMessage::End) => {
+                    info!("The watched game ended");
+                    let _ = events.send(SpectatorEvent::Game(GameEvent::PhaseChanged(
+                        GamePhase::Ended,
+                    )));
+                    let saved = match live.take() {
+                        Some(live) => Some(replay.write(&live.path).map(|()| live.path)),
+                        None => replay_dir.as_ref().map(|dir| replay.save(dir)),
+                    };
+                    game = None;
+                    match saved {
+                        Some(Ok(path)) => {
+                            info!("Saved the replay to {}", path.display());
+                            Some(SpectatorEvent::ReplaySaved(path))
+                        }
+                        Some(Err(e)) => {
+                            error!("Failed to save the replay: {e}");
+                            None
+                        }
+                        None => None,
+                    }
+                }
+                (Some(replay), message) => {
+                    replay.apply(&message);
+                    match message {
+                        FeedMessage::Chat {
+                            player_id, message, ..
+                        } => Some(GameEvent::Chat {
+                            player_id,
+                            name: replay.player_name(player_id),
+                            message: message.to_string(),
+                        }),
+                        FeedMessage::Left { player_id, .. } => Some(GameEvent::PlayerLeft {
+                            player_id,
+                            name: replay
+                                .player_name(player_id)
+                                .unwrap_or_else(|| format!("Player {player_id}")),
+                        }),
+                        _ => None,
+                    }
+                    .map(SpectatorEvent::Game)
+                }
+                //Nothing to follow without the start of the game
+                (None, _) => None,
+            };
+            if let Some(event) = event {
+                let _ = events.send(event);
+            }
+        }
+        if let (Some(replay), Some(live)) = (&game, &mut live)
+            && live.written.elapsed() >= LIVE_REPLAY_INTERVAL
+        {
+            if let Err(e) = replay.write(&live.path) {
+                warn!("Failed to update the replay: {e}");
+            }
+            live.written = Instant::now();
+        }
+    }
+}
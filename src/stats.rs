@@ -0,0 +1,237 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Instant,
+};
+
+use iroh::PublicKey;
+
+use tracing::info;
+
+use crate::{
+    packets::take_packet,
+    status::{Traffic, TunnelStatus},
+    tap::StreamObserver,
+};
+
+/// Keeps track of all forwarded TCP connections that are currently open
+/// and of the traffic of the closed ones per peer.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TunnelRegistry {
+    tunnels: Arc<Mutex<HashMap<u64, Arc<TunnelCounters>>>>,
+    closed: Arc<Mutex<HashMap<PublicKey, Traffic>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl TunnelRegistry {
+    /// Registers a new tunnel to or from `peer`. It is removed again when the handle is dropped.
+    pub fn open(&self, peer: PublicKey) -> TunnelHandle {
+        let counters = Arc::new(TunnelCounters {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            peer,
+            opened_at: Instant::now(),
+            bytes_to_game: AtomicU64::new(0),
+            bytes_from_game: AtomicU64::new(0),
+            packets_to_game: AtomicU64::new(0),
+            packets_from_game: AtomicU64::new(0),
+        });
+        self.tunnels
+            .lock()
+            .unwrap()
+            .insert(counters.id, counters.clone());
+        TunnelHandle {
+            registry: self.clone(),
+            counters,
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<TunnelStatus> {
+        let mut tunnels: Vec<_> = self
+            .tunnels
+            .lock()
+            .unwrap()
+            .values()
+            .map(|counters| counters.status())
+            .collect();
+        tunnels.sort_by_key(|tunnel| tunnel.id);
+        tunnels
+    }
+
+    /// All traffic with `peer`, of open and closed tunnels.
+    pub fn traffic(&self, peer: PublicKey) -> Traffic {
+        let mut traffic = self
+            .closed
+            .lock()
+            .unwrap()
+            .get(&peer)
+            .copied()
+            .unwrap_or_default();
+        for tunnel in self.tunnels.lock().unwrap().values() {
+            if tunnel.peer == peer {
+                traffic.add(&tunnel.status().traffic());
+            }
+        }
+        traffic
+    }
+
+    /// Like [`TunnelRegistry::traffic`], but forgets the closed tunnels of `peer` afterwards.
+    pub fn forget(&self, peer: PublicKey) -> Traffic {
+        let traffic = self.traffic(peer);
+        self.closed.lock().unwrap().remove(&peer);
+        traffic
+    }
+}
+
+#[derive(Debug)]
+struct TunnelCounters {
+    id: u64,
+    peer: PublicKey,
+    opened_at: Instant,
+    bytes_to_game: AtomicU64,
+    bytes_from_game: AtomicU64,
+    packets_to_game: AtomicU64,
+    packets_from_game: AtomicU64,
+}
+
+impl TunnelCounters {
+    fn status(&self) -> TunnelStatus {
+        TunnelStatus {
+            id: self.id,
+            peer: self.peer,
+            opened_at: self.opened_at,
+            bytes_to_game: self.bytes_to_game.load(Ordering::Relaxed),
+            bytes_from_game: self.bytes_from_game.load(Ordering::Relaxed),
+            packets_to_game: self.packets_to_game.load(Ordering::Relaxed),
+            packets_from_game: self.packets_from_game.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub(crate) struct TunnelHandle {
+    registry: TunnelRegistry,
+    counters: Arc<TunnelCounters>,
+}
+
+impl TunnelHandle {
+    /// Counts the bytes and W3GS packets of the local game side of the tunnel.
+    pub fn byte_counter(&self) -> ByteCounter {
+        ByteCounter {
+            counters: self.counters.clone(),
+            from_game: Vec::new(),
+            to_game: Vec::new(),
+        }
+    }
+}
+
+impl Drop for TunnelHandle {
+    fn drop(&mut self) {
+        let status = self.counters.status();
+        info!(
+            "Game connection #{} with {} closed after {:.1?}: {}",
+            status.id,
+            status.peer.fmt_short(),
+            status.opened_at.elapsed(),
+            status.traffic()
+        );
+        self.registry
+            .tunnels
+            .lock()
+            .unwrap()
+            .remove(&self.counters.id);
+        self.registry
+            .closed
+            .lock()
+            .unwrap()
+            .entry(status.peer)
+            .or_default()
+            .add(&status.traffic());
+    }
+}
+
+/// Reads are data from the local game, writes are data to it.
+pub(crate) struct ByteCounter {
+    counters: Arc<TunnelCounters>,
+    from_game: Vec<u8>,
+    to_game: Vec<u8>,
+}
+
+impl ByteCounter {
+    /// Counts the complete packets in `buffer` after adding `data`.
+    fn count_packets(buffer: &mut Vec<u8>, data: &[u8]) -> u64 {
+        buffer.extend_from_slice(data);
+        let mut packets = 0;
+        loop {
+            match take_packet(buffer) {
+                Ok(Some(_)) => packets += 1,
+                Ok(None) => return packets,
+                Err(_) => {
+                    //Not W3GS framed, only count the bytes
+                    buffer.clear();
+                    return packets;
+                }
+            }
+        }
+    }
+}
+
+impl StreamObserver for ByteCounter {
+    fn on_read(&mut self, data: &[u8]) {
+        let counters = &self.counters;
+        counters
+            .bytes_from_game
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        counters.packets_from_game.fetch_add(
+            Self::count_packets(&mut self.from_game, data),
+            Ordering::Relaxed,
+        );
+    }
+
+    fn on_write(&mut self, data: &[u8]) {
+        let counters = &self.counters;
+        counters
+            .bytes_to_game
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        counters.packets_to_game.fetch_add(
+            Self::count_packets(&mut self.to_game, data),
+            Ordering::Relaxed,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh::SecretKey;
+
+    use super::TunnelRegistry;
+    use crate::tap::StreamObserver;
+
+    #[test]
+    fn counts_traffic_per_tunnel_and_peer() {
+        let peer = SecretKey::from_bytes(&[1; 32]).public();
+        let registry = TunnelRegistry::default();
+        let tunnel = registry.open(peer);
+        let mut counter = tunnel.byte_counter();
+
+        //A W3GS ping split over two writes, raw bytes that are no W3GS packet from the game
+        counter.on_write(&[0xF7, 0x01, 0x08]);
+        counter.on_write(&[0x00, 0x01, 0x02, 0x03, 0x04]);
+        counter.on_read(b"hello");
+
+        let status = &registry.snapshot()[0];
+        assert_eq!(status.bytes_to_game, 8);
+        assert_eq!(status.packets_to_game, 1);
+        assert_eq!(status.bytes_from_game, 5);
+        assert_eq!(status.packets_from_game, 0);
+
+        drop(tunnel);
+        assert!(registry.snapshot().is_empty());
+        let traffic = registry.traffic(peer);
+        assert_eq!(traffic.streams, 1);
+        assert_eq!(traffic.bytes_to_game, 8);
+        assert_eq!(registry.forget(peer), traffic);
+        assert_eq!(registry.traffic(peer).streams, 0);
+    }
+}
@@ -0,0 +1,119 @@
+use std::{fmt, time::Instant};
+
+use iroh::PublicKey;
+
+use crate::{
+    events::{GamePhase, LobbyInfo},
+    maps::MapStatus,
+    path::PathStatus,
+};
+
+/// A peer on the other side of the tunnel.
+#[derive(Debug, Clone)]
+pub struct PeerStatus {
+    pub id: PublicKey,
+    pub path: PathStatus,
+    pub connected_at: Instant,
+    /// All game traffic with this peer since it connected.
+    pub traffic: Traffic,
+    /// The player that joined the game from this peer, as long as it is connected.
+    /// Only known on the host side, once the game accepted the join.
+    pub player: Option<InGamePlayer>,
+}
+
+/// A player in the WC3 game, taken from the join of a forwarded connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InGamePlayer {
+    pub player_id: u8,
+    pub name: String,
+    /// Counted from 0.
+    pub slot: Option<u8>,
+}
+
+impl fmt::Display for InGamePlayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.slot {
+            Some(slot) => write!(f, "{} (slot {})", self.name, slot + 1),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// Game traffic summed up over several forwarded TCP connections.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Traffic {
+    /// Number of forwarded TCP connections.
+    pub streams: u64,
+    pub bytes_to_game: u64,
+    pub bytes_from_game: u64,
+    /// W3GS packets, not counted for data that is not W3GS framed.
+    pub packets_to_game: u64,
+    pub packets_from_game: u64,
+}
+
+impl Traffic {
+    pub fn add(&mut self, other: &Traffic) {
+        self.streams += other.streams;
+        self.bytes_to_game += other.bytes_to_game;
+        self.bytes_from_game += other.bytes_from_game;
+        self.packets_to_game += other.packets_to_game;
+        self.packets_from_game += other.packets_from_game;
+    }
+}
+
+impl fmt::Display for Traffic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes ({} packets) to game, {} bytes ({} packets) from game",
+            self.bytes_to_game, self.packets_to_game, self.bytes_from_game, self.packets_from_game
+        )
+    }
+}
+
+/// A forwarded TCP connection of the game.
+#[derive(Debug, Clone)]
+pub struct TunnelStatus {
+    pub id: u64,
+    /// The client on the host side, the host on the client side.
+    pub peer: PublicKey,
+    pub opened_at: Instant,
+    pub bytes_to_game: u64,
+    pub bytes_from_game: u64,
+    pub packets_to_game: u64,
+    pub packets_from_game: u64,
+}
+
+impl TunnelStatus {
+    pub fn traffic(&self) -> Traffic {
+        Traffic {
+            streams: 1,
+            bytes_to_game: self.bytes_to_game,
+            bytes_from_game: self.bytes_from_game,
+            packets_to_game: self.packets_to_game,
+            packets_from_game: self.packets_from_game,
+        }
+    }
+}
+
+/// A snapshot of a running [`crate::Host`].
+#[derive(Debug, Clone)]
+pub struct HostStatus {
+    pub lobby: Option<LobbyInfo>,
+    pub clients: Vec<PeerStatus>,
+    pub tunnels: Vec<TunnelStatus>,
+    /// The phase of the game the clients are in, `None` if none of them joined.
+    pub game: Option<GamePhase>,
+}
+
+/// A snapshot of a connected [`crate::Client`].
+#[derive(Debug, Clone)]
+pub struct ClientStatus {
+    pub lobby: Option<LobbyInfo>,
+    /// Whether the local game has the map of the lobby. `None` without a Maps folder to look in.
+    pub map: Option<MapStatus>,
+    pub host: PeerStatus,
+    pub tunnels: Vec<TunnelStatus>,
+    /// The phase of the game the local player is in, `None` if it did not join.
+    pub game: Option<GamePhase>,
+}
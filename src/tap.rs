@@ -0,0 +1,238 @@
+use std::{
+    io, mem,
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::packets::take_packet;
+
+/// How much a [`Rewritten`] stream reads at once.
+const READ_CHUNK: usize = 8192;
+
+/// Sees every byte that flows through a [`Tapped`] stream without changing it.
+pub trait StreamObserver: Send {
+    /// Data that was read from the stream.
+    fn on_read(&mut self, data: &[u8]);
+    /// Data that was written to the stream.
+    fn on_write(&mut self, data: &[u8]);
+}
+
+/// Both observers see all data, the first one first.
+impl<A: StreamObserver, B: StreamObserver> StreamObserver for (A, B) {
+    fn on_read(&mut self, data: &[u8]) {
+        self.0.on_read(data);
+        self.1.on_read(data);
+    }
+
+    fn on_write(&mut self, data: &[u8]) {
+        self.0.on_write(data);
+        self.1.on_write(data);
+    }
+}
+
+/// Observers that are turned off are `None`.
+impl<O: StreamObserver> StreamObserver for Option<O> {
+    fn on_read(&mut self, data: &[u8]) {
+        if let Some(observer) = self {
+            observer.on_read(data);
+        }
+    }
+
+    fn on_write(&mut self, data: &[u8]) {
+        if let Some(observer) = self {
+            observer.on_write(data);
+        }
+    }
+}
+
+/// Wraps a stream and reports all data passing through it to an observer.
+pub struct Tapped<S, O> {
+    inner: S,
+    observer: O,
+}
+
+impl<S, O> Tapped<S, O> {
+    pub fn new(inner: S, observer: O) -> Self {
+        Tapped { inner, observer }
+    }
+}
+
+impl<S: AsyncRead + Unpin, O: StreamObserver + Unpin> AsyncRead for Tapped<S, O> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            let read = &buf.filled()[before..];
+            if !read.is_empty() {
+                this.observer.on_read(read);
+            }
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin, O: StreamObserver + Unpin> AsyncWrite for Tapped<S, O> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            this.observer.on_write(&buf[..written]);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Changes the W3GS packets read from a [`Rewritten`] stream.
+pub trait PacketRewriter: Send {
+    /// Gets every complete packet, including its header. Must keep the length field correct.
+    fn rewrite(&mut self, packet: &mut Vec<u8>);
+
+    /// A packet to add to the stream. Only asked for between two complete packets.
+    fn poll_inject(&mut self, _cx: &mut Context<'_>) -> Poll<Option<Vec<u8>>> {
+        Poll::Ready(None)
+    }
+}
+
+/// Both rewriters see every packet, the first one first.
+impl<A: PacketRewriter, B: PacketRewriter> PacketRewriter for (A, B) {
+    fn rewrite(&mut self, packet: &mut Vec<u8>) {
+        self.0.rewrite(packet);
+        self.1.rewrite(packet);
+    }
+
+    fn poll_inject(&mut self, cx: &mut Context<'_>) -> Poll<Option<Vec<u8>>> {
+        match self.0.poll_inject(cx) {
+            Poll::Ready(None) => self.1.poll_inject(cx),
+            Poll::Pending => match self.1.poll_inject(cx) {
+                Poll::Ready(None) => Poll::Pending,
+                second => second,
+            },
+            first => first,
+        }
+    }
+}
+
+/// Wraps a stream and lets a rewriter change the W3GS packets read from it, or add its own between them.
+/// Writes are passed on as they are. Packets are held back until they are complete.
+/// Streams that are not W3GS framed are passed on unchanged.
+pub struct Rewritten<S, R> {
+    inner: S,
+    rewriter: R,
+    /// Read data that does not make up a complete packet yet.
+    incoming: Vec<u8>,
+    /// Rewritten data that was not read yet.
+    outgoing: Vec<u8>,
+    sent: usize,
+    passthrough: bool,
+}
+
+impl<S, R> Rewritten<S, R> {
+    pub fn new(inner: S, rewriter: R) -> Self {
+        Rewritten {
+            inner,
+            rewriter,
+            incoming: Vec::new(),
+            outgoing: Vec::new(),
+            sent: 0,
+            passthrough: false,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin, R: PacketRewriter + Unpin> AsyncRead for Rewritten<S, R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.sent < this.outgoing.len() {
+                let len = buf.remaining().min(this.outgoing.len() - this.sent);
+                buf.put_slice(&this.outgoing[this.sent..this.sent + len]);
+                this.sent += len;
+                if this.sent == this.outgoing.len() {
+                    this.outgoing.clear();
+                    this.sent = 0;
+                }
+                return Poll::Ready(Ok(()));
+            }
+            if !this.passthrough
+                && let Poll::Ready(Some(packet)) = this.rewriter.poll_inject(cx)
+            {
+                this.outgoing = packet;
+                continue;
+            }
+
+            let mut chunk = [0; READ_CHUNK];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            let read = chunk.filled();
+            if read.is_empty() {
+                //End of the stream, an incomplete packet is passed on as it is
+                if this.incoming.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                this.outgoing = mem::take(&mut this.incoming);
+                continue;
+            }
+            if this.passthrough {
+                this.outgoing.extend_from_slice(read);
+                continue;
+            }
+            this.incoming.extend_from_slice(read);
+            loop {
+                match take_packet(&mut this.incoming) {
+                    Ok(Some(mut packet)) => {
+                        this.rewriter.rewrite(&mut packet);
+                        this.outgoing.extend_from_slice(&packet);
+                    }
+                    Ok(None) => break,
+                    Err(_) => {
+                        //Not W3GS, nothing to rewrite from now on
+                        this.passthrough = true;
+                        this.outgoing.append(&mut this.incoming);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin, R: Unpin> AsyncWrite for Rewritten<S, R> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,56 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use tokio::{
+    net::{TcpStream, UdpSocket},
+    time::timeout,
+};
+
+use crate::packets::Wc3UdpMessageType;
+
+/// A stand-in for a WC3 instance sitting in the LAN game list.
+///
+/// Receives the lobby packets the tunnel client forwards to the local game
+/// and joins games over TCP like WC3 does.
+pub struct FakeWc3Client {
+    socket: UdpSocket,
+}
+
+impl FakeWc3Client {
+    pub async fn start() -> Self {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .expect("bind UDP socket");
+        FakeWc3Client { socket }
+    }
+
+    /// The address the tunnel client has to forward lobby packets to.
+    pub fn addr(&self) -> SocketAddr {
+        self.socket.local_addr().expect("bound UDP socket")
+    }
+
+    /// Waits for the next lobby packet. Panics if nothing arrives within `wait`.
+    pub async fn next_packet(&self, wait: Duration) -> (Wc3UdpMessageType, Vec<u8>) {
+        let mut buffer = [0u8; 1024];
+        loop {
+            let len = timeout(wait, self.socket.recv(&mut buffer))
+                .await
+                .expect("lobby packet in time")
+                .expect("readable UDP socket");
+            let data = buffer[..len].to_vec();
+            if let Some(packet) = Wc3UdpMessageType::detect(&data) {
+                return (packet, data);
+            }
+        }
+    }
+
+    /// Joins the game on `tcp_port` as advertised in a `QueryForGamesResponse`.
+    /// WC3 connects to the address the response came from, which is always localhost here.
+    pub async fn join(&self, tcp_port: u16) -> TcpStream {
+        TcpStream::connect((Ipv4Addr::LOCALHOST, tcp_port))
+            .await
+            .expect("join game")
+    }
+}
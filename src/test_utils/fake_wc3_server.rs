@@ -0,0 +1,320 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use binrw::NullString;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{Mutex, Notify},
+    task::JoinHandle,
+};
+
+use crate::{
+    packets::{
+        GameType, NewServerHosted, QueryForGamesRequest, QueryForGamesResponse,
+        QueryForGamesResponseInner, ServerClosed, encode_encoded_string,
+    },
+    utils::{try_parse, try_serialize},
+};
+
+/// The lobby a [`FakeWc3Server`] advertises.
+#[derive(Debug, Clone)]
+pub struct FakeGame {
+    pub game_type: GameType,
+    pub game_version: u32,
+    pub game_id: u32,
+    pub game_name: String,
+    pub map_name: String,
+    pub host_username: String,
+    pub number_of_slots: u32,
+    pub number_of_players: u32,
+    /// Width and height of the map, GHost++ announces reconnects with 1984.
+    pub map_size: u16,
+}
+
+impl Default for FakeGame {
+    fn default() -> Self {
+        FakeGame {
+            game_type: GameType::TheFrozenThrone,
+            game_version: 26,
+            game_id: 1,
+            game_name: "Fake Lobby".to_string(),
+            map_name: "Maps\\FrozenThrone\\(4)TwistedMeadows.w3x".to_string(),
+            host_username: "FakeHost".to_string(),
+            number_of_slots: 4,
+            number_of_players: 1,
+            map_size: 116,
+        }
+    }
+}
+
+impl FakeGame {
+    /// Builds the response a real WC3 server sends for this lobby, including the encoded stat string.
+    pub fn query_response(&self, tcp_port: u16) -> QueryForGamesResponse {
+        let inner = QueryForGamesResponseInner {
+            game_settings: 0x0000_4802,
+            unknown1: 0,
+            map_width: self.map_size,
+            map_height: self.map_size,
+            map_checksum: 0xC0FF_EE00,
+            map_name: NullString::from(self.map_name.as_str()),
+            host_username: NullString::from(self.host_username.as_str()),
+            unknown2: 0,
+        };
+        let inner_bytes = try_serialize(&inner).expect("serializable stat string");
+
+        let mut response = QueryForGamesResponse {
+            packet_size: 0,
+            game_type: self.game_type,
+            game_version: self.game_version,
+            game_id: self.game_id,
+            unknown1: 0,
+            game_name: NullString::from(self.game_name.as_str()),
+            unknown2: 0,
+            encoded: NullString(encode_encoded_string(&inner_bytes)),
+            number_of_slots: 12,
+            game_flags: 1,
+            number_of_players: self.number_of_players,
+            number_of_player_slots: self.number_of_slots,
+            game_age: 0,
+            tcp_port,
+        };
+        response.packet_size = try_serialize(&response)
+            .expect("serializable response")
+            .len() as u16;
+        response
+    }
+}
+
+/// A stand-in for a WC3 instance hosting a LAN game.
+///
+/// Listens for UDP and TCP on the same port, like WC3 does on 6112.
+/// Answers `QueryForGamesRequest`s that match the hosted game, sends create/close broadcasts
+/// to the configured targets on command and echoes everything received on TCP connections
+/// after sending an optional greeting.
+pub struct FakeWc3Server {
+    addr: SocketAddr,
+    udp_socket: Arc<UdpSocket>,
+    game: Arc<Mutex<Option<FakeGame>>>,
+    broadcast_targets: Vec<SocketAddr>,
+    answered_queries: Arc<AtomicUsize>,
+    accepted_connections: Arc<AtomicUsize>,
+    open_connections: Arc<AtomicUsize>,
+    reset: Arc<Notify>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl FakeWc3Server {
+    /// Starts a server on a random free port.
+    pub async fn start() -> Self {
+        Self::start_with_greeting(Vec::new()).await
+    }
+
+    /// Starts a server that sends `greeting` on every new TCP connection before echoing.
+    pub async fn start_with_greeting(greeting: Vec<u8>) -> Self {
+        let (udp_socket, tcp_listener) = bind_same_port().await;
+        let addr = udp_socket.local_addr().expect("bound UDP socket");
+        let udp_socket = Arc::new(udp_socket);
+
+        let game = Arc::new(Mutex::new(Option::<FakeGame>::None));
+        let answered_queries = Arc::new(AtomicUsize::new(0));
+        let accepted_connections = Arc::new(AtomicUsize::new(0));
+        let open_connections = Arc::new(AtomicUsize::new(0));
+        let reset = Arc::new(Notify::new());
+
+        let udp_task = tokio::spawn(answer_queries(
+            udp_socket.clone(),
+            game.clone(),
+            answered_queries.clone(),
+            addr.port(),
+        ));
+        let tcp_task = tokio::spawn(accept_game_connections(
+            tcp_listener,
+            greeting,
+            accepted_connections.clone(),
+            open_connections.clone(),
+            reset.clone(),
+        ));
+
+        FakeWc3Server {
+            addr,
+            udp_socket,
+            game,
+            broadcast_targets: Vec::new(),
+            answered_queries,
+            accepted_connections,
+            open_connections,
+            reset,
+            tasks: vec![udp_task, tcp_task],
+        }
+    }
+
+    /// The UDP and TCP address of this server.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Adds an address that receives the create/close broadcasts.
+    pub fn add_broadcast_target(&mut self, target: SocketAddr) {
+        self.broadcast_targets.push(target);
+    }
+
+    /// Opens a lobby and broadcasts `NewServerHosted`.
+    pub async fn host_game(&self, game: FakeGame) {
+        let packet = NewServerHosted {
+            game_type: game.game_type,
+            game_version: game.game_version,
+            game_id: game.game_id,
+        };
+        *self.game.lock().await = Some(game);
+        self.broadcast(&try_serialize(&packet).expect("serializable packet"))
+            .await;
+    }
+
+    /// Closes the current lobby (if any) and broadcasts `ServerClosed`.
+    pub async fn close_game(&self) {
+        if let Some(game) = self.game.lock().await.take() {
+            let packet = ServerClosed {
+                game_id: game.game_id,
+            };
+            self.broadcast(&try_serialize(&packet).expect("serializable packet"))
+                .await;
+        }
+    }
+
+    /// Number of `QueryForGamesRequest`s that were answered with a lobby.
+    pub fn answered_queries(&self) -> usize {
+        self.answered_queries.load(Ordering::SeqCst)
+    }
+
+    /// Number of TCP game connections accepted so far.
+    pub fn accepted_connections(&self) -> usize {
+        self.accepted_connections.load(Ordering::SeqCst)
+    }
+
+    /// Number of TCP game connections that are still open.
+    pub fn open_connections(&self) -> usize {
+        self.open_connections.load(Ordering::SeqCst)
+    }
+
+    /// Aborts all open TCP game connections, like a game host that dropped its players.
+    pub fn reset_connections(&self) {
+        self.reset.notify_waiters();
+    }
+
+    async fn broadcast(&self, packet: &[u8]) {
+        for target in &self.broadcast_targets {
+            let _ = self.udp_socket.send_to(packet, target).await;
+        }
+    }
+}
+
+impl Drop for FakeWc3Server {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+async fn bind_same_port() -> (UdpSocket, TcpListener) {
+    loop {
+        let udp_socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .expect("bind UDP socket");
+        let port = udp_socket.local_addr().expect("bound UDP socket").port();
+        //The TCP port might already be taken, just try another one
+        if let Ok(tcp_listener) = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).await {
+            return (udp_socket, tcp_listener);
+        }
+    }
+}
+
+async fn answer_queries(
+    socket: Arc<UdpSocket>,
+    game: Arc<Mutex<Option<FakeGame>>>,
+    answered_queries: Arc<AtomicUsize>,
+    tcp_port: u16,
+) {
+    let mut buffer = [0u8; 1024];
+    loop {
+        let Ok((len, sender)) = socket.recv_from(&mut buffer).await else {
+            continue;
+        };
+        let Some(request) = try_parse::<QueryForGamesRequest>(&buffer[..len]) else {
+            continue;
+        };
+        let response = match &*game.lock().await {
+            //A real server stays silent if the version or the extension don't match
+            Some(game)
+                if game.game_type == request.game_type
+                    && game.game_version == request.game_version =>
+            {
+                game.query_response(tcp_port)
+            }
+            _ => continue,
+        };
+        if socket
+            .send_to(&try_serialize(&response).expect("serializable"), sender)
+            .await
+            .is_ok()
+        {
+            answered_queries.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+async fn accept_game_connections(
+    listener: TcpListener,
+    greeting: Vec<u8>,
+    accepted_connections: Arc<AtomicUsize>,
+    open_connections: Arc<AtomicUsize>,
+    reset: Arc<Notify>,
+) {
+    loop {
+        let Ok((mut stream, _)) = listener.accept().await else {
+            continue;
+        };
+        accepted_connections.fetch_add(1, Ordering::SeqCst);
+        open_connections.fetch_add(1, Ordering::SeqCst);
+        let greeting = greeting.clone();
+        let open_connections = open_connections.clone();
+        let reset = reset.clone();
+        tokio::spawn(async move {
+            let reset = tokio::select! {
+                _ = echo(&mut stream, greeting) => false,
+                _ = reset.notified() => true,
+            };
+            if reset {
+                //Without lingering the socket is closed with RST, a zero timeout does not block
+                #[allow(deprecated)]
+                let _ = stream.set_linger(Some(Duration::ZERO));
+            }
+            drop(stream);
+            open_connections.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+async fn echo(stream: &mut TcpStream, greeting: Vec<u8>) {
+    if !greeting.is_empty() && stream.write_all(&greeting).await.is_err() {
+        return;
+    }
+    let mut buffer = [0u8; 4096];
+    loop {
+        match stream.read(&mut buffer).await {
+            Ok(0) | Err(_) => return,
+            Ok(len) => {
+                if stream.write_all(&buffer[..len]).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
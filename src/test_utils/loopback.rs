@@ -0,0 +1,180 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use iroh::{
+    Endpoint, EndpointAddr, RelayMode,
+    endpoint::{Connection, QuicTransportConfig},
+};
+use tokio::{net::UdpSocket, sync::watch, task::JoinHandle};
+
+use crate::utils::ALPN;
+
+/// Creates an endpoint that only talks over loopback. No relays, no address lookup.
+pub async fn loopback_endpoint() -> Endpoint {
+    Endpoint::empty_builder(RelayMode::Disabled)
+        .alpns(vec![ALPN.to_vec()])
+        .bind_addr(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+        .expect("valid bind address")
+        .bind()
+        .await
+        .expect("loopback endpoint")
+}
+
+/// The direct address of a loopback endpoint, usable without any address lookup.
+pub fn loopback_addr(endpoint: &Endpoint) -> EndpointAddr {
+    endpoint
+        .bound_sockets()
+        .into_iter()
+        .fold(EndpointAddr::new(endpoint.id()), |addr, socket| {
+            addr.with_ip_addr(socket)
+        })
+}
+
+/// Two loopback endpoints with an established connection between them.
+/// The endpoints are kept here, dropping them would close the connections.
+#[allow(dead_code)]
+pub struct ConnectedPair {
+    pub host_endpoint: Endpoint,
+    pub client_endpoint: Endpoint,
+    pub host: Connection,
+    pub client: Connection,
+}
+
+pub async fn connected_pair() -> ConnectedPair {
+    let host_endpoint = loopback_endpoint().await;
+    let client_endpoint = loopback_endpoint().await;
+
+    let accepting_endpoint = host_endpoint.clone();
+    let accept = tokio::spawn(async move {
+        let incoming = accepting_endpoint
+            .accept()
+            .await
+            .expect("incoming connection");
+        incoming.await.expect("accepted connection")
+    });
+    let client = client_endpoint
+        .connect(loopback_addr(&host_endpoint), ALPN)
+        .await
+        .expect("connect");
+    let host = accept.await.expect("accept task");
+
+    ConnectedPair {
+        host_endpoint,
+        client_endpoint,
+        host,
+        client,
+    }
+}
+
+/// Passes UDP packets between a host endpoint and one peer, like a network that can go down.
+///
+/// The host endpoint only has an IPv6 socket and the peer endpoint only an IPv4 one. They can't
+/// reach each other around the relay with the addresses they learn from each other.
+pub struct UdpRelay {
+    host_addr: EndpointAddr,
+    down: Arc<AtomicBool>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl UdpRelay {
+    /// Starts the relay and the endpoint of the host behind it.
+    pub async fn start() -> (UdpRelay, Endpoint) {
+        let host = Endpoint::empty_builder(RelayMode::Disabled)
+            .alpns(vec![ALPN.to_vec()])
+            .clear_ip_transports()
+            .bind_addr(SocketAddr::from((Ipv6Addr::LOCALHOST, 0)))
+            .expect("valid bind address")
+            .bind()
+            .await
+            .expect("loopback endpoint");
+        let host_socket = host.bound_sockets()[0];
+        let peer_side = Arc::new(
+            UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+                .await
+                .expect("relay socket"),
+        );
+        let host_side = Arc::new(
+            UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+                .await
+                .expect("relay socket"),
+        );
+        let host_addr = EndpointAddr::new(host.id())
+            .with_ip_addr(peer_side.local_addr().expect("relay address"));
+        let down = Arc::new(AtomicBool::new(false));
+        let (peer_tx, mut peer_rx) = watch::channel(None);
+
+        let to_host = {
+            let (peer_side, host_side, down) = (peer_side.clone(), host_side.clone(), down.clone());
+            tokio::spawn(async move {
+                let mut buffer = vec![0; 65536];
+                while let Ok((len, peer)) = peer_side.recv_from(&mut buffer).await {
+                    peer_tx.send_replace(Some(peer));
+                    if !down.load(Ordering::Relaxed) {
+                        let _ = host_side.send_to(&buffer[..len], host_socket).await;
+                    }
+                }
+            })
+        };
+        let to_peer = {
+            let down = down.clone();
+            tokio::spawn(async move {
+                let mut buffer = vec![0; 65536];
+                while let Ok(len) = host_side.recv(&mut buffer).await {
+                    let peer = *peer_rx.borrow_and_update();
+                    if let Some(peer) = peer.filter(|_| !down.load(Ordering::Relaxed)) {
+                        let _ = peer_side.send_to(&buffer[..len], peer).await;
+                    }
+                }
+            })
+        };
+        let relay = UdpRelay {
+            host_addr,
+            down,
+            tasks: vec![to_host, to_peer],
+        };
+        (relay, host)
+    }
+
+    /// An endpoint for the peer that notices within a second when the network goes down.
+    pub async fn peer_endpoint(&self) -> Endpoint {
+        let transport = QuicTransportConfig::builder()
+            .max_idle_timeout(Some(
+                Duration::from_secs(1).try_into().expect("valid timeout"),
+            ))
+            .keep_alive_interval(Duration::from_millis(200))
+            .build();
+        Endpoint::empty_builder(RelayMode::Disabled)
+            .alpns(vec![ALPN.to_vec()])
+            .transport_config(transport)
+            .clear_ip_transports()
+            .bind_addr(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+            .expect("valid bind address")
+            .bind()
+            .await
+            .expect("loopback endpoint")
+    }
+
+    /// How the peer reaches the host.
+    pub fn host_addr(&self) -> EndpointAddr {
+        self.host_addr.clone()
+    }
+
+    /// Drops every packet while the network is down.
+    pub fn set_down(&self, down: bool) {
+        self.down.store(down, Ordering::Relaxed);
+    }
+}
+
+impl Drop for UdpRelay {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
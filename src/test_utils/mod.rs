@@ -0,0 +1,7 @@
+//! Reusable components for tests that need a WC3 installation or a second peer.
+
+pub mod fake_wc3_client;
+pub mod fake_wc3_server;
+pub mod loopback;
+pub mod mpq;
+pub mod w3gs_frames;
@@ -0,0 +1,151 @@
+//! Builds small WC3 maps: MPQ archives with encrypted, zlib compressed files behind a map header.
+
+use miniz_oxide::deflate::compress_to_vec_zlib;
+
+use crate::mpq::{
+    COMPRESSED, ENCRYPTED, EXISTS, FILE_KEY, NAME_A, NAME_B, TABLE_OFFSET, ZLIB, encrypt, hash,
+};
+
+const SECTOR_SIZE: usize = 4096;
+/// Room for the `HM3W` header of the map in front of the archive.
+const MAP_HEADER_SIZE: usize = 512;
+const MPQ_HEADER_SIZE: usize = 32;
+
+/// The trigger strings for [`w3i`].
+pub const WTS: &str =
+    "\u{feff}STRING 1\n{\n|cffffcc00Twisted|r Meadows\n}\n\nSTRING 2\n// Author\n{\nBlizzard\n}\n";
+
+/// A `war3map.w3i` of The Frozen Throne with two human players and a computer.
+pub fn w3i() -> Vec<u8> {
+    let mut w3i = Vec::new();
+    let int = |w3i: &mut Vec<u8>, value: u32| w3i.extend_from_slice(&value.to_le_bytes());
+    let text = |w3i: &mut Vec<u8>, text: &str| {
+        w3i.extend_from_slice(text.as_bytes());
+        w3i.push(0);
+    };
+    for value in [25, 3, 6059] {
+        int(&mut w3i, value);
+    }
+    text(&mut w3i, "TRIGSTR_001");
+    text(&mut w3i, "TRIGSTR_002");
+    text(&mut w3i, "Trees everywhere");
+    text(&mut w3i, "1v1");
+    w3i.extend_from_slice(&[0; 8 * 4 + 4 * 4]);
+    int(&mut w3i, 116); //Playable width
+    int(&mut w3i, 84); //Playable height
+    int(&mut w3i, 0); //Flags, a melee map
+    w3i.push(b'L');
+    int(&mut w3i, 0); //Loading screen
+    (0..4).for_each(|_| text(&mut w3i, ""));
+    int(&mut w3i, 0); //Game data set
+    (0..4).for_each(|_| text(&mut w3i, ""));
+    w3i.extend_from_slice(&[0; 4 + 3 * 4 + 4 + 4]);
+    text(&mut w3i, "");
+    w3i.extend_from_slice(&[0; 1 + 4]);
+    int(&mut w3i, 3);
+    for (number, kind) in [(0, 1), (1, 1), (2, 2)] {
+        for value in [number, kind, 1, 0] {
+            int(&mut w3i, value);
+        }
+        text(&mut w3i, "Player");
+        w3i.extend_from_slice(&[0; 2 * 4 + 2 * 4]);
+    }
+    int(&mut w3i, 1);
+    int(&mut w3i, 0); //Force flags
+    int(&mut w3i, 0b111);
+    text(&mut w3i, "Force 1");
+    w3i
+}
+
+pub fn build_archive(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut body = Vec::new();
+    let mut blocks = Vec::new();
+    for (name, data) in files {
+        let offset = (MPQ_HEADER_SIZE + body.len()) as u32;
+        let stored = store(name, data);
+        blocks.push([
+            offset,
+            stored.len() as u32,
+            data.len() as u32,
+            EXISTS | COMPRESSED | ENCRYPTED,
+        ]);
+        body.extend(stored);
+    }
+
+    let hash_len = (files.len() * 2).next_power_of_two().max(4);
+    let mut hashes = vec![[u32::MAX; 4]; hash_len];
+    for (block, (name, _)) in files.iter().enumerate() {
+        let mut index = hash(name, TABLE_OFFSET) as usize % hash_len;
+        while hashes[index][3] != u32::MAX {
+            index = (index + 1) % hash_len;
+        }
+        hashes[index] = [hash(name, NAME_A), hash(name, NAME_B), 0, block as u32];
+    }
+    let hash_pos = MPQ_HEADER_SIZE + body.len();
+    let block_pos = hash_pos + hash_len * 16;
+
+    let mut archive = vec![0; MAP_HEADER_SIZE];
+    archive[..4].copy_from_slice(b"HM3W");
+    archive.extend_from_slice(b"MPQ\x1A");
+    for word in [
+        MPQ_HEADER_SIZE as u32,
+        (block_pos + blocks.len() * 16) as u32,
+    ] {
+        archive.extend_from_slice(&word.to_le_bytes());
+    }
+    archive.extend_from_slice(&0u16.to_le_bytes()); //Format version
+    archive.extend_from_slice(&3u16.to_le_bytes()); //4096 byte sectors
+    for word in [
+        hash_pos as u32,
+        block_pos as u32,
+        hash_len as u32,
+        blocks.len() as u32,
+    ] {
+        archive.extend_from_slice(&word.to_le_bytes());
+    }
+    archive.extend(body);
+    archive.extend(table(&hashes, "(hash table)"));
+    archive.extend(table(&blocks, "(block table)"));
+    archive
+}
+
+/// Sector offsets, then every sector compressed on its own, all encrypted.
+fn store(name: &str, data: &[u8]) -> Vec<u8> {
+    let key = hash(name.rsplit('\\').next().unwrap(), FILE_KEY);
+    let sectors: Vec<Vec<u8>> = data
+        .chunks(SECTOR_SIZE)
+        .enumerate()
+        .map(|(index, sector)| {
+            let mut compressed = vec![ZLIB];
+            compressed.extend(compress_to_vec_zlib(sector, 6));
+            let mut stored = if compressed.len() < sector.len() {
+                compressed
+            } else {
+                sector.to_vec()
+            };
+            encrypt(&mut stored, key.wrapping_add(index as u32));
+            stored
+        })
+        .collect();
+    let mut offsets = vec![(sectors.len() + 1) * 4];
+    for sector in &sectors {
+        offsets.push(offsets.last().unwrap() + sector.len());
+    }
+    let mut stored: Vec<u8> = offsets
+        .iter()
+        .flat_map(|offset| (*offset as u32).to_le_bytes())
+        .collect();
+    encrypt(&mut stored, key.wrapping_sub(1));
+    stored.extend(sectors.concat());
+    stored
+}
+
+fn table(entries: &[[u32; 4]], name: &str) -> Vec<u8> {
+    let mut data: Vec<u8> = entries
+        .iter()
+        .flatten()
+        .flat_map(|word| word.to_le_bytes())
+        .collect();
+    encrypt(&mut data, hash(name, FILE_KEY));
+    data
+}
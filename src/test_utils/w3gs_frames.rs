@@ -0,0 +1,96 @@
+//! Builds the W3GS packets of a join, as sent by a real game, and reads them back.
+
+use std::time::Duration;
+
+use tokio::{io::AsyncReadExt, net::TcpStream, time::timeout};
+
+use crate::packets::take_packet;
+
+/// Adds the W3GS header to a packet body.
+pub fn frame(id: u8, body: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0xF7, id];
+    frame.extend_from_slice(&(body.len() as u16 + 4).to_le_bytes());
+    frame.extend_from_slice(body);
+    frame
+}
+
+/// Adds the GProxy header to a packet body.
+pub fn gps_frame(id: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = frame(id, body);
+    packet[0] = 0xF8;
+    packet
+}
+
+/// GPS_INIT of GHost++: the player can reconnect on `port` with `reconnect_key`, no empty actions.
+pub fn gps_init(port: u16, player_id: u8, reconnect_key: u32) -> Vec<u8> {
+    let mut body = port.to_le_bytes().to_vec();
+    body.push(player_id);
+    body.extend_from_slice(&reconnect_key.to_le_bytes());
+    body.push(0);
+    gps_frame(0x01, &body)
+}
+
+/// The join request of a player.
+pub fn req_join(name: &str) -> Vec<u8> {
+    req_join_lobby(name, 0)
+}
+
+/// The join request of a player for the lobby with this game id.
+pub fn req_join_lobby(name: &str, host_counter: u32) -> Vec<u8> {
+    let mut body = host_counter.to_le_bytes().to_vec();
+    body.extend_from_slice(&[0; 11]);
+    body.extend_from_slice(name.as_bytes());
+    body.push(0);
+    body.extend_from_slice(&[0; 10]);
+    frame(0x1E, &body)
+}
+
+/// Accepts a join, with the host in the first and the player in the second slot.
+pub fn slot_info_join(player_id: u8) -> Vec<u8> {
+    let mut table = vec![2];
+    table.extend_from_slice(&[1, 255, 2, 0, 0, 0, 0x20, 1, 100]);
+    table.extend_from_slice(&[player_id, 255, 2, 0, 1, 1, 0x20, 1, 100]);
+    table.extend_from_slice(&[0, 0, 0, 0, 2, 2]); //Seed, layout and player slots
+    let mut body = (table.len() as u16).to_le_bytes().to_vec();
+    body.extend_from_slice(&table);
+    body.push(player_id);
+    body.extend_from_slice(&[2, 0, 0x17, 0xE0, 127, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+    frame(0x04, &body)
+}
+
+/// Tells the host whether the player has the map.
+pub fn map_size(size: u32) -> Vec<u8> {
+    let mut body = 1u32.to_le_bytes().to_vec();
+    body.push(1);
+    body.extend_from_slice(&size.to_le_bytes());
+    frame(0x42, &body)
+}
+
+/// A lobby message of a player.
+pub fn chat_to_host(receivers: &[u8], sender: u8, message: &str) -> Vec<u8> {
+    let mut body = vec![receivers.len() as u8];
+    body.extend_from_slice(receivers);
+    body.extend_from_slice(&[sender, 0x10]);
+    body.extend_from_slice(message.as_bytes());
+    body.push(0);
+    frame(0x28, &body)
+}
+
+/// Reads the next packet with this id from the game host, skipping all others.
+pub async fn next_frame(stream: &mut TcpStream, buffer: &mut Vec<u8>, id: u8) -> Vec<u8> {
+    timeout(Duration::from_secs(5), async {
+        loop {
+            while let Some(packet) = take_packet(buffer).expect("W3GS packet") {
+                if packet[1] == id {
+                    return packet;
+                }
+            }
+            let mut chunk = [0; 4096];
+            let len = stream.read(&mut chunk).await.expect("open game connection");
+            assert_ne!(len, 0, "game connection closed");
+            buffer.extend_from_slice(&chunk[..len]);
+        }
+    })
+    .await
+    .expect("packet in time")
+}
@@ -0,0 +1,993 @@
+use std::time::Duration;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    time::{sleep, timeout},
+};
+
+use iroh::{EndpointAddr, PublicKey};
+
+use crate::{
+    Client, ClientConfig, ClientEvent, Error, GameEvent, GamePhase, Host, HostConfig, HostEvent,
+    LobbyEnd, Spectator, SpectatorConfig, SpectatorEvent,
+    error::RejectReason,
+    maps::MapCheck,
+    packets::Wc3UdpMessageType,
+    path::ConnectionKind,
+    simulation::SimulatedLobby,
+    status::InGamePlayer,
+    test_utils::{
+        fake_wc3_client::FakeWc3Client,
+        fake_wc3_server::{FakeGame, FakeWc3Server},
+        loopback::{UdpRelay, loopback_addr, loopback_endpoint},
+        mpq::{WTS, build_archive, w3i},
+        w3gs_frames::{
+            frame, gps_frame, gps_init, map_size, next_frame, req_join, req_join_lobby,
+            slot_info_join,
+        },
+    },
+    utils::APP_NAME,
+    virtual_game::VirtualGameConfig,
+    w3gs::W3gsPacket,
+};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+async fn wait_until(mut condition: impl FnMut() -> bool) {
+    timeout(WAIT, async {
+        while !condition() {
+            sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("condition met in time");
+}
+
+/// Waits for the next packet that is not one of the periodic `QueryForGamesResponse` refreshes.
+async fn next_lobby_change(client: &FakeWc3Client) -> Wc3UdpMessageType {
+    loop {
+        match client.next_packet(WAIT).await {
+            (Wc3UdpMessageType::QueryForGamesResponse(_), _) => continue,
+            (packet, _) => return packet,
+        }
+    }
+}
+
+struct Session {
+    server: FakeWc3Server,
+    game_client: FakeWc3Client,
+    host: Host,
+    client: Client,
+}
+
+async fn start_host(server: &FakeWc3Server, password: Option<&str>) -> (Host, EndpointAddr) {
+    let host_endpoint = loopback_endpoint().await;
+    let host_addr = loopback_addr(&host_endpoint);
+    let host = Host::start_on(
+        host_endpoint,
+        HostConfig {
+            game_addr: server.addr(),
+            password: password.map(str::to_string),
+            ..HostConfig::default()
+        },
+    )
+    .await
+    .unwrap();
+    (host, host_addr)
+}
+
+async fn connect_client(
+    host_addr: EndpointAddr,
+    game_client: &FakeWc3Client,
+    password: Option<&str>,
+) -> Client {
+    Client::connect_on(
+        loopback_endpoint().await,
+        ClientConfig {
+            game_addr: game_client.addr(),
+            password: password.map(str::to_string),
+            ..ClientConfig::new(host_addr)
+        },
+    )
+    .await
+    .unwrap()
+}
+
+async fn start_session() -> Session {
+    let server = FakeWc3Server::start_with_greeting(b"hello".to_vec()).await;
+    let game_client = FakeWc3Client::start().await;
+    let (host, host_addr) = start_host(&server, None).await;
+    let client = connect_client(host_addr, &game_client, None).await;
+
+    Session {
+        server,
+        game_client,
+        host,
+        client,
+    }
+}
+
+/// Like [`start_session`], but the client reaches the host through a [`UdpRelay`] and notices
+/// within a second when the network goes down.
+async fn start_session_over_relay(server: FakeWc3Server) -> (Session, UdpRelay) {
+    let game_client = FakeWc3Client::start().await;
+    let (relay, host_endpoint) = UdpRelay::start().await;
+    let host = Host::start_on(
+        host_endpoint,
+        HostConfig {
+            game_addr: server.addr(),
+            ..HostConfig::default()
+        },
+    )
+    .await
+    .unwrap();
+    let client = Client::connect_on(
+        relay.peer_endpoint().await,
+        ClientConfig {
+            game_addr: game_client.addr(),
+            ..ClientConfig::new(relay.host_addr())
+        },
+    )
+    .await
+    .unwrap();
+
+    let session = Session {
+        server,
+        game_client,
+        host,
+        client,
+    };
+    (session, relay)
+}
+
+#[tokio::test]
+async fn lobby_join_and_close() {
+    let session = start_session().await;
+    let mut host_events = session.host.subscribe();
+    let mut client_events = session.client.subscribe();
+    session
+        .server
+        .host_game(FakeGame {
+            game_name: "Loopback Lobby".to_string(),
+            game_id: 7,
+            ..FakeGame::default()
+        })
+        .await;
+
+    match next_lobby_change(&session.game_client).await {
+        Wc3UdpMessageType::NewServerHosted => {}
+        other => panic!("Expected NewServerHosted, got {other:?}"),
+    }
+    let response = match session.game_client.next_packet(WAIT).await {
+        (Wc3UdpMessageType::QueryForGamesResponse(response), raw) => {
+            assert_eq!(response.packet_size as usize, raw.len());
+            response
+        }
+        other => panic!("Expected QueryForGamesResponse, got {other:?}"),
+    };
+    assert_eq!(
+        response.game_name.to_string(),
+        format!("[{APP_NAME}] Loopback Lobby")
+    );
+    assert_ne!(response.tcp_port, session.server.addr().port());
+
+    match timeout(WAIT, client_events.recv()).await.unwrap().unwrap() {
+        ClientEvent::LobbyFound(lobby) => {
+            assert_eq!(lobby.game_name, "Loopback Lobby");
+            assert_eq!(lobby.game_id, 7);
+        }
+        other => panic!("Expected LobbyFound, got {other:?}"),
+    }
+    let mut lobby_opened = false;
+    while let Ok(event) = host_events.try_recv() {
+        lobby_opened |= matches!(event, HostEvent::LobbyOpened(lobby) if lobby.game_id == 7);
+    }
+    assert!(lobby_opened);
+
+    let mut game_stream = session.game_client.join(response.tcp_port).await;
+    game_stream.write_all(b"ping").await.unwrap();
+    let mut received = [0u8; 9];
+    timeout(WAIT, game_stream.read_exact(&mut received))
+        .await
+        .expect("echo in time")
+        .unwrap();
+    assert_eq!(&received, b"helloping");
+    assert_eq!(session.server.accepted_connections(), 1);
+
+    let host_status = session.host.status();
+    assert_eq!(host_status.lobby.unwrap().game_id, 7);
+    assert_eq!(host_status.clients.len(), 1);
+    assert_eq!(host_status.clients[0].id, session.client.id());
+    assert_eq!(host_status.tunnels[0].bytes_to_game, 4);
+    //Loopback endpoints have no relay, so the path must be direct
+    assert_eq!(host_status.clients[0].path.kind, ConnectionKind::Direct);
+    assert!(host_status.clients[0].path.rtt.is_some());
+    let client_status = session.client.status();
+    assert_eq!(client_status.host.path.kind, ConnectionKind::Direct);
+    assert_eq!(client_status.lobby.unwrap().game_name, "Loopback Lobby");
+    assert_eq!(client_status.host.id, session.host.id());
+    assert_eq!(client_status.tunnels[0].bytes_from_game, 4);
+
+    drop(game_stream);
+    wait_until(|| session.server.open_connections() == 0).await;
+    wait_until(|| session.host.status().tunnels.is_empty()).await;
+    let traffic = session.host.status().clients[0].traffic;
+    assert_eq!(traffic.streams, 1);
+    assert_eq!(traffic.bytes_to_game, 4);
+    assert_eq!(traffic.bytes_from_game, 9);
+
+    session.server.close_game().await;
+    match next_lobby_change(&session.game_client).await {
+        Wc3UdpMessageType::ServerCanceled => {}
+        other => panic!("Expected ServerCanceled, got {other:?}"),
+    }
+    assert!(matches!(
+        timeout(WAIT, client_events.recv()).await.unwrap().unwrap(),
+        ClientEvent::LobbyClosed(LobbyEnd::Unknown)
+    ));
+}
+
+#[tokio::test]
+async fn client_tells_canceled_lobby_after_its_game_stream_closed() {
+    let join = slot_info_join(2);
+    let server = FakeWc3Server::start_with_greeting(join.clone()).await;
+    let game_client = FakeWc3Client::start().await;
+    let (_host, host_addr) = start_host(&server, None).await;
+    let client = connect_client(host_addr, &game_client, None).await;
+    let mut client_events = client.subscribe();
+    server.host_game(FakeGame::default()).await;
+    let tcp_port = loop {
+        if let (Wc3UdpMessageType::QueryForGamesResponse(response), _) =
+            game_client.next_packet(WAIT).await
+        {
+            break response.tcp_port;
+        }
+    };
+    let mut game_stream = game_client.join(tcp_port).await;
+    let mut received = vec![0; join.len()];
+    timeout(WAIT, game_stream.read_exact(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+
+    //WC3 drops the players before the lobby is gone from the LAN list
+    server.reset_connections();
+    wait_until(|| client.status().tunnels.is_empty()).await;
+    server.close_game().await;
+    loop {
+        match timeout(WAIT, client_events.recv()).await.unwrap().unwrap() {
+            ClientEvent::LobbyClosed(end) => {
+                assert_eq!(end, LobbyEnd::Canceled);
+                break;
+            }
+            _ => continue,
+        }
+    }
+}
+
+#[tokio::test]
+async fn client_disconnect_closes_game_streams() {
+    let session = start_session().await;
+    session.server.host_game(FakeGame::default()).await;
+
+    let tcp_port = loop {
+        if let (Wc3UdpMessageType::QueryForGamesResponse(response), _) =
+            session.game_client.next_packet(WAIT).await
+        {
+            break response.tcp_port;
+        }
+    };
+    let mut game_stream = session.game_client.join(tcp_port).await;
+    game_stream.write_all(b"ping").await.unwrap();
+    wait_until(|| session.server.open_connections() == 1).await;
+
+    let mut host_events = session.host.subscribe();
+    let client_id = session.client.id();
+    session.client.stop().await;
+    wait_until(|| session.server.open_connections() == 0).await;
+    loop {
+        match timeout(WAIT, host_events.recv()).await.unwrap().unwrap() {
+            HostEvent::ClientDisconnected(id) => {
+                assert_eq!(id, client_id);
+                break;
+            }
+            _ => continue,
+        }
+    }
+}
+
+#[tokio::test]
+async fn host_forgets_lost_client_once_its_game_streams_ended() {
+    let (session, relay) = start_session_over_relay(FakeWc3Server::start().await).await;
+    session.server.host_game(FakeGame::default()).await;
+    let tcp_port = loop {
+        if let (Wc3UdpMessageType::QueryForGamesResponse(response), _) =
+            session.game_client.next_packet(WAIT).await
+        {
+            break response.tcp_port;
+        }
+    };
+    let mut game_stream = session.game_client.join(tcp_port).await;
+    game_stream.write_all(b"ping").await.unwrap();
+    wait_until(|| session.server.open_connections() == 1).await;
+
+    let mut host_events = session.host.subscribe();
+    relay.set_down(true);
+    while !matches!(
+        timeout(WAIT, host_events.recv()).await.unwrap().unwrap(),
+        HostEvent::ClientConnectionLost(_)
+    ) {}
+    //Nothing is left to resume, the host doesn't wait for the client anymore
+    session.server.reset_connections();
+    loop {
+        match timeout(WAIT, host_events.recv()).await.unwrap().unwrap() {
+            HostEvent::ClientDisconnected(id) => {
+                assert_eq!(id, session.client.id());
+                break;
+            }
+            _ => continue,
+        }
+    }
+}
+
+#[tokio::test]
+async fn client_rejoins_game_after_the_connection_broke() {
+    //The game host of GHost++ takes the player back on another port
+    let reconnects = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let reconnect_port = reconnects.local_addr().unwrap().port();
+    let join = slot_info_join(2);
+    let greeting = [join.clone(), gps_init(reconnect_port, 2, 0xC0FFEE)].concat();
+    let server = FakeWc3Server::start_with_greeting(greeting).await;
+    let (session, relay) = start_session_over_relay(server).await;
+    session
+        .server
+        .host_game(FakeGame {
+            map_size: 1984,
+            ..FakeGame::default()
+        })
+        .await;
+    let tcp_port = loop {
+        if let (Wc3UdpMessageType::QueryForGamesResponse(response), _) =
+            session.game_client.next_packet(WAIT).await
+        {
+            break response.tcp_port;
+        }
+    };
+    let mut game_stream = session.game_client.join(tcp_port).await;
+    let mut received = vec![0; join.len()];
+    timeout(WAIT, game_stream.read_exact(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received, join);
+    let actions: Vec<_> = (1..=3).map(|n| frame(0x26, &[n; 4])).collect();
+    game_stream.write_all(&actions.concat()).await.unwrap();
+    //Echoed by the game host, so everything before it passed the tunnel
+    let mut echoed = vec![0; actions.concat().len()];
+    timeout(WAIT, game_stream.read_exact(&mut echoed))
+        .await
+        .unwrap()
+        .unwrap();
+
+    let mut host_events = session.host.subscribe();
+    relay.set_down(true);
+    while !matches!(
+        timeout(WAIT, host_events.recv()).await.unwrap().unwrap(),
+        HostEvent::ClientConnectionLost(_)
+    ) {}
+    //The game host drops the player, the tunnel can't be resumed
+    session.server.reset_connections();
+    relay.set_down(false);
+
+    let (mut reconnect, _) = timeout(3 * WAIT, reconnects.accept())
+        .await
+        .expect("rejoin in time")
+        .unwrap();
+    let mut request = [0; 13];
+    timeout(WAIT, reconnect.read_exact(&mut request))
+        .await
+        .unwrap()
+        .unwrap();
+    let mut expected = vec![2];
+    expected.extend_from_slice(&0xC0FFEEu32.to_le_bytes());
+    expected.extend_from_slice(&4u32.to_le_bytes()); //The join and the echoed actions
+    assert_eq!(request, *gps_frame(0x02, &expected));
+    //Only the first action arrived before the connection broke
+    reconnect
+        .write_all(&gps_frame(0x02, &1u32.to_le_bytes()))
+        .await
+        .unwrap();
+    let mut resent = vec![0; actions[1..].concat().len()];
+    timeout(WAIT, reconnect.read_exact(&mut resent))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(resent, actions[1..].concat());
+}
+
+#[tokio::test]
+async fn client_stops_when_host_shuts_down() {
+    let session = start_session().await;
+    let mut client_events = session.client.subscribe();
+    session.server.host_game(FakeGame::default()).await;
+    assert!(matches!(
+        timeout(WAIT, client_events.recv()).await.unwrap().unwrap(),
+        ClientEvent::LobbyFound(_)
+    ));
+    wait_until(|| session.host.status().lobby.is_some()).await;
+    assert!(matches!(
+        next_lobby_change(&session.game_client).await,
+        Wc3UdpMessageType::NewServerHosted
+    ));
+
+    session.host.stop().await;
+    timeout(WAIT, session.client.closed())
+        .await
+        .expect("client stops in time")
+        .expect("host closed the connection on purpose");
+    match next_lobby_change(&session.game_client).await {
+        Wc3UdpMessageType::ServerCanceled => {}
+        other => panic!("Expected ServerCanceled, got {other:?}"),
+    }
+
+    let mut shut_down = false;
+    loop {
+        match timeout(WAIT, client_events.recv()).await.unwrap().unwrap() {
+            ClientEvent::HostShutDown => shut_down = true,
+            ClientEvent::Disconnected => break,
+            _ => continue,
+        }
+    }
+    assert!(shut_down);
+}
+
+async fn wait_for_client(host: &Host, id: PublicKey) {
+    wait_until(|| host.status().clients.iter().any(|client| client.id == id)).await;
+}
+
+#[tokio::test]
+async fn kicked_client_can_reconnect_but_banned_client_cannot() {
+    let server = FakeWc3Server::start().await;
+    let game_client = FakeWc3Client::start().await;
+    let (host, host_addr) = start_host(&server, None).await;
+
+    let client = connect_client(host_addr.clone(), &game_client, None).await;
+    wait_for_client(&host, client.id()).await;
+    assert!(host.kick(client.id()));
+    assert!(matches!(
+        timeout(WAIT, client.closed()).await.unwrap(),
+        Err(Error::Rejected(RejectReason::Kicked))
+    ));
+
+    let client = connect_client(host_addr.clone(), &game_client, None).await;
+    wait_for_client(&host, client.id()).await;
+    assert!(host.ban(client.id()));
+    assert!(matches!(
+        timeout(WAIT, client.closed()).await.unwrap(),
+        Err(Error::Rejected(RejectReason::Banned))
+    ));
+
+    //Bans also work for clients that are not connected yet
+    let endpoint = loopback_endpoint().await;
+    assert!(!host.ban(endpoint.id()));
+    let mut host_events = host.subscribe();
+    let client = Client::connect_on(
+        endpoint,
+        ClientConfig {
+            game_addr: game_client.addr(),
+            ..ClientConfig::new(host_addr)
+        },
+    )
+    .await
+    .unwrap();
+    assert!(matches!(
+        timeout(WAIT, client.closed()).await.unwrap(),
+        Err(Error::Rejected(RejectReason::Banned))
+    ));
+    loop {
+        match timeout(WAIT, host_events.recv()).await.unwrap().unwrap() {
+            HostEvent::ClientRejected(_, reason) => {
+                assert_eq!(reason, RejectReason::Banned);
+                break;
+            }
+            _ => continue,
+        }
+    }
+}
+
+#[tokio::test]
+async fn host_checks_password() {
+    let server = FakeWc3Server::start().await;
+    let game_client = FakeWc3Client::start().await;
+    let (host, host_addr) = start_host(&server, Some("secret")).await;
+    let mut host_events = host.subscribe();
+
+    let client = connect_client(host_addr.clone(), &game_client, Some("wrong")).await;
+    assert!(matches!(
+        timeout(WAIT, client.closed()).await.unwrap(),
+        Err(Error::Rejected(RejectReason::WrongPassword))
+    ));
+    loop {
+        match timeout(WAIT, host_events.recv()).await.unwrap().unwrap() {
+            HostEvent::ClientRejected(id, reason) => {
+                assert_eq!(id, client.id());
+                assert_eq!(reason, RejectReason::WrongPassword);
+                break;
+            }
+            _ => continue,
+        }
+    }
+
+    let client = connect_client(host_addr.clone(), &game_client, Some("secret")).await;
+    wait_for_client(&host, client.id()).await;
+
+    host.set_password(None);
+    let client = connect_client(host_addr, &game_client, None).await;
+    wait_for_client(&host, client.id()).await;
+}
+
+#[tokio::test]
+async fn host_learns_player_name_of_client() {
+    //The echo of the fake game makes the join look accepted
+    let server = FakeWc3Server::start().await;
+    let game_client = FakeWc3Client::start().await;
+    let (host, host_addr) = start_host(&server, None).await;
+    let mut host_events = host.subscribe();
+    let client = connect_client(host_addr, &game_client, None).await;
+    server.host_game(FakeGame::default()).await;
+    let tcp_port = loop {
+        if let (Wc3UdpMessageType::QueryForGamesResponse(response), _) =
+            game_client.next_packet(WAIT).await
+        {
+            break response.tcp_port;
+        }
+    };
+
+    let mut game_stream = game_client.join(tcp_port).await;
+    let join = req_join("Grubby");
+    game_stream.write_all(&join).await.unwrap();
+    let mut echo = vec![0; join.len()];
+    timeout(WAIT, game_stream.read_exact(&mut echo))
+        .await
+        .expect("echo in time")
+        .unwrap();
+    game_stream.write_all(&slot_info_join(2)).await.unwrap();
+
+    let expected = InGamePlayer {
+        player_id: 2,
+        name: "Grubby".to_string(),
+        slot: Some(1),
+    };
+    loop {
+        match timeout(WAIT, host_events.recv()).await.unwrap().unwrap() {
+            HostEvent::ClientIdentified(id, player) => {
+                assert_eq!(id, client.id());
+                assert_eq!(player, expected);
+                break;
+            }
+            _ => continue,
+        }
+    }
+    assert_eq!(host.status().clients[0].player, Some(expected));
+    assert!(host.kick(client.id()));
+}
+
+#[tokio::test]
+async fn client_checks_map_of_lobby() {
+    let server = FakeWc3Server::start().await;
+    let game_client = FakeWc3Client::start().await;
+    let (_host, host_addr) = start_host(&server, None).await;
+    let maps_dir = std::env::temp_dir().join(format!("simple-wc3-lobby-maps-{}", host_addr.id));
+    let map = maps_dir.join("FrozenThrone").join("(4)TwistedMeadows.w3x");
+    std::fs::create_dir_all(map.parent().unwrap()).unwrap();
+    std::fs::write(&map, b"map").unwrap();
+    let client = Client::connect_on(
+        loopback_endpoint().await,
+        ClientConfig {
+            game_addr: game_client.addr(),
+            maps_dir: Some(maps_dir.clone()),
+            ..ClientConfig::new(host_addr)
+        },
+    )
+    .await
+    .unwrap();
+    let mut events = client.subscribe();
+    server.host_game(FakeGame::default()).await;
+
+    let status = loop {
+        if let ClientEvent::MapChecked(status) =
+            timeout(WAIT, events.recv()).await.unwrap().unwrap()
+        {
+            break status;
+        }
+    };
+    assert_eq!(status.map_name, "Maps\\FrozenThrone\\(4)TwistedMeadows.w3x");
+    assert_eq!(status.check, MapCheck::Found(map));
+    assert_eq!(client.status().map, Some(status));
+    std::fs::remove_dir_all(&maps_dir).unwrap();
+}
+
+#[tokio::test]
+async fn client_reports_other_version_of_map() {
+    let server = FakeWc3Server::start().await;
+    let game_client = FakeWc3Client::start().await;
+    let (_host, host_addr) = start_host(&server, None).await;
+    let maps_dir = std::env::temp_dir().join(format!("simple-wc3-other-map-{}", host_addr.id));
+    let map = maps_dir.join("FrozenThrone").join("(4)TwistedMeadows.w3x");
+    std::fs::create_dir_all(map.parent().unwrap()).unwrap();
+    //The map brings the scripts of the game, so its checksum is known
+    let archive = build_archive(&[
+        ("war3map.w3i", &w3i()),
+        ("war3map.wts", WTS.as_bytes()),
+        ("war3map.j", b"function main"),
+        ("Scripts\\common.j", b"native Foo"),
+        ("Scripts\\blizzard.j", b"function Bar"),
+    ]);
+    std::fs::write(&map, archive).unwrap();
+    let client = Client::connect_on(
+        loopback_endpoint().await,
+        ClientConfig {
+            game_addr: game_client.addr(),
+            maps_dir: Some(maps_dir.clone()),
+            ..ClientConfig::new(host_addr)
+        },
+    )
+    .await
+    .unwrap();
+    let mut events = client.subscribe();
+    server.host_game(FakeGame::default()).await;
+
+    let status = loop {
+        if let ClientEvent::MapChecked(status) =
+            timeout(WAIT, events.recv()).await.unwrap().unwrap()
+        {
+            break status;
+        }
+    };
+    assert_eq!(status.check, MapCheck::Mismatch(map));
+    let info = status.info.unwrap();
+    assert_eq!(info.name, "Twisted Meadows");
+    assert_ne!(info.checksum, Some(0xC0FF_EE00));
+    std::fs::remove_dir_all(&maps_dir).unwrap();
+}
+
+#[tokio::test]
+async fn client_downloads_missing_map_from_host() {
+    let server = FakeWc3Server::start().await;
+    let game_client = FakeWc3Client::start().await;
+    let host_endpoint = loopback_endpoint().await;
+    let host_addr = loopback_addr(&host_endpoint);
+    let dir = std::env::temp_dir().join(format!("simple-wc3-map-transfer-{}", host_addr.id));
+    let host_maps = dir.join("host");
+    let client_maps = dir.join("client");
+    let cache = dir.join("cache");
+    let map = host_maps.join("FrozenThrone").join("(4)TwistedMeadows.w3x");
+    std::fs::create_dir_all(map.parent().unwrap()).unwrap();
+    std::fs::write(&map, b"twisted meadows").unwrap();
+    let host = Host::start_on(
+        host_endpoint,
+        HostConfig {
+            game_addr: server.addr(),
+            maps_dir: Some(host_maps),
+            ..HostConfig::default()
+        },
+    )
+    .await
+    .unwrap();
+    let mut host_events = host.subscribe();
+    let client = Client::connect_on(
+        loopback_endpoint().await,
+        ClientConfig {
+            game_addr: game_client.addr(),
+            maps_dir: Some(client_maps.clone()),
+            map_cache_dir: Some(cache.clone()),
+            ..ClientConfig::new(host_addr)
+        },
+    )
+    .await
+    .unwrap();
+    let mut events = client.subscribe();
+    server.host_game(FakeGame::default()).await;
+
+    let status = loop {
+        if let ClientEvent::MapDownloaded(status) =
+            timeout(WAIT, events.recv()).await.unwrap().unwrap()
+        {
+            break status;
+        }
+    };
+    let downloaded = client_maps
+        .join("FrozenThrone")
+        .join("(4)TwistedMeadows.w3x");
+    assert_eq!(status.check, MapCheck::Found(downloaded.clone()));
+    assert_eq!(std::fs::read(&downloaded).unwrap(), b"twisted meadows");
+    assert_eq!(client.status().map, Some(status));
+    assert_eq!(std::fs::read_dir(&cache).unwrap().count(), 1);
+    loop {
+        if let HostEvent::MapSent(_, map_name) =
+            timeout(WAIT, host_events.recv()).await.unwrap().unwrap()
+        {
+            assert_eq!(map_name, "Maps\\FrozenThrone\\(4)TwistedMeadows.w3x");
+            break;
+        }
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn host_announces_ping_of_joining_player() {
+    let server = FakeWc3Server::start().await;
+    let game_client = FakeWc3Client::start().await;
+    let host_endpoint = loopback_endpoint().await;
+    let host_addr = loopback_addr(&host_endpoint);
+    let host = Host::start_on(
+        host_endpoint,
+        HostConfig {
+            game_addr: server.addr(),
+            announce_pings: true,
+            ..HostConfig::default()
+        },
+    )
+    .await
+    .unwrap();
+    let _client = connect_client(host_addr, &game_client, None).await;
+    server.host_game(FakeGame::default()).await;
+    let tcp_port = loop {
+        if let (Wc3UdpMessageType::QueryForGamesResponse(response), _) =
+            game_client.next_packet(WAIT).await
+        {
+            break response.tcp_port;
+        }
+    };
+
+    let mut game_stream = game_client.join(tcp_port).await;
+    let mut join = req_join("Grubby");
+    join.extend_from_slice(&slot_info_join(2));
+    game_stream.write_all(&join).await.unwrap();
+    let mut echo = vec![0; join.len()];
+    timeout(WAIT, game_stream.read_exact(&mut echo))
+        .await
+        .expect("echo in time")
+        .unwrap();
+
+    //The message comes as chat of the player at the game host
+    let mut header = [0; 4];
+    timeout(WAIT, game_stream.read_exact(&mut header))
+        .await
+        .expect("announcement in time")
+        .unwrap();
+    let mut chat = header.to_vec();
+    chat.resize(u16::from_le_bytes([header[2], header[3]]) as usize, 0);
+    game_stream.read_exact(&mut chat[4..]).await.unwrap();
+    match W3gsPacket::detect(&chat) {
+        Some(W3gsPacket::ChatFromHost(chat)) => {
+            assert_eq!(chat.receivers, vec![2]);
+            assert_eq!(chat.sender, 1);
+            let message = chat.body.message().unwrap();
+            assert!(message.starts_with("Grubby is connected"), "{message}");
+            assert!(message.ends_with(" ms"), "{message}");
+        }
+        other => panic!("Expected ChatFromHost, got {other:?}"),
+    }
+    assert!(host.pings().unwrap().starts_with("Ping: Grubby "));
+}
+
+#[tokio::test]
+async fn client_joins_virtual_game_of_host() {
+    let game_client = FakeWc3Client::start().await;
+    let host_endpoint = loopback_endpoint().await;
+    let host_addr = loopback_addr(&host_endpoint);
+    let dir = std::env::temp_dir().join(format!("simple-wc3-virtual-host-{}", host_addr.id));
+    let map = dir.join("(2)TwistedMeadows.w3x");
+    std::fs::create_dir_all(&dir).unwrap();
+    let archive = build_archive(&[
+        ("war3map.w3i", &w3i()),
+        ("war3map.wts", WTS.as_bytes()),
+        ("war3map.j", b"function main"),
+        ("Scripts\\common.j", b"native Foo"),
+        ("Scripts\\blizzard.j", b"function Bar"),
+    ]);
+    std::fs::write(&map, &archive).unwrap();
+    let host = Host::start_on(
+        host_endpoint,
+        HostConfig {
+            virtual_game: Some(VirtualGameConfig {
+                game_name: "Virtual Lobby".to_string(),
+                ..VirtualGameConfig::new(map)
+            }),
+            ..HostConfig::default()
+        },
+    )
+    .await
+    .unwrap();
+    let mut host_events = host.subscribe();
+    let client = connect_client(host_addr, &game_client, None).await;
+    let lobby = loop {
+        if let (Wc3UdpMessageType::QueryForGamesResponse(response), _) =
+            game_client.next_packet(WAIT).await
+        {
+            break response;
+        }
+    };
+    assert_eq!(
+        lobby.game_name.to_string(),
+        format!("[{APP_NAME}] Virtual Lobby")
+    );
+
+    let mut game_stream = game_client.join(lobby.tcp_port).await;
+    game_stream
+        .write_all(&req_join_lobby("Grubby", lobby.game_id))
+        .await
+        .unwrap();
+    let mut buffer = Vec::new();
+    next_frame(&mut game_stream, &mut buffer, 0x3D).await;
+    loop {
+        if let HostEvent::ClientIdentified(id, player) =
+            timeout(WAIT, host_events.recv()).await.unwrap().unwrap()
+        {
+            assert_eq!(id, client.id());
+            assert_eq!(player.name, "Grubby");
+            break;
+        }
+    }
+    game_stream
+        .write_all(&map_size(archive.len() as u32))
+        .await
+        .unwrap();
+    //The lobby learns about the map of the player a moment later
+    timeout(WAIT, async {
+        while !host.start_game().await {
+            sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("game started in time");
+    next_frame(&mut game_stream, &mut buffer, 0x0B).await;
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn client_sees_simulated_lobby() {
+    let game_client = FakeWc3Client::start().await;
+    let host_endpoint = loopback_endpoint().await;
+    let host_addr = loopback_addr(&host_endpoint);
+    let _host = Host::start_on(
+        host_endpoint,
+        HostConfig {
+            simulate: Some(SimulatedLobby {
+                game_name: "Test Lobby".to_string(),
+                players: 3,
+                slots: 6,
+                ..SimulatedLobby::default()
+            }),
+            ..HostConfig::default()
+        },
+    )
+    .await
+    .unwrap();
+    let client = connect_client(host_addr, &game_client, None).await;
+    let lobby = loop {
+        if let (Wc3UdpMessageType::QueryForGamesResponse(response), _) =
+            game_client.next_packet(WAIT).await
+        {
+            break response;
+        }
+    };
+    assert_eq!(
+        lobby.game_name.to_string(),
+        format!("[{APP_NAME}] Test Lobby")
+    );
+    assert_eq!(
+        (lobby.number_of_players, lobby.number_of_player_slots),
+        (3, 6)
+    );
+    //A map of the same name would otherwise count as another version
+    assert_eq!(client.status().lobby.unwrap().map_checksum, None);
+
+    //Joins reach the simulation through the tunnel and are turned away
+    let mut game_stream = game_client.join(lobby.tcp_port).await;
+    game_stream.write_all(&req_join("Grubby")).await.unwrap();
+    match W3gsPacket::detect(&next_frame(&mut game_stream, &mut Vec::new(), 0x05).await) {
+        Some(W3gsPacket::RejectJoin(reject)) => assert_eq!(reject.reason, 9),
+        other => panic!("Expected RejectJoin, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn spectator_watches_game_of_client() {
+    let server = FakeWc3Server::start().await;
+    let game_client = FakeWc3Client::start().await;
+    let host_endpoint = loopback_endpoint().await;
+    let host_addr = loopback_addr(&host_endpoint);
+    let host = Host::start_on(
+        host_endpoint,
+        HostConfig {
+            game_addr: server.addr(),
+            spectators: true,
+            ..HostConfig::default()
+        },
+    )
+    .await
+    .unwrap();
+    let client = connect_client(host_addr.clone(), &game_client, None).await;
+    let replay_dir = std::env::temp_dir().join(format!("simple-wc3-spectated-{}", client.id()));
+    let spectator = Spectator::connect_on(
+        loopback_endpoint().await,
+        SpectatorConfig {
+            replay_dir: Some(replay_dir.clone()),
+            ..SpectatorConfig::new(host_addr)
+        },
+    )
+    .await
+    .unwrap();
+    let mut events = spectator.subscribe();
+    server.host_game(FakeGame::default()).await;
+    let tcp_port = loop {
+        if let (Wc3UdpMessageType::QueryForGamesResponse(response), _) =
+            game_client.next_packet(WAIT).await
+        {
+            break response.tcp_port;
+        }
+    };
+
+    //The fake game echoes everything, so every packet also comes back from the game host
+    let mut game_stream = game_client.join(tcp_port).await;
+    let join = req_join("Grubby");
+    game_stream.write_all(&join).await.unwrap();
+    let mut echo = vec![0; join.len()];
+    timeout(WAIT, game_stream.read_exact(&mut echo))
+        .await
+        .expect("echo in time")
+        .unwrap();
+    game_stream.write_all(&slot_info_join(2)).await.unwrap();
+    game_stream.write_all(&frame(0x0B, &[])).await.unwrap();
+    let mut next_event = async || timeout(WAIT, events.recv()).await.unwrap().unwrap();
+    match next_event().await {
+        SpectatorEvent::Watching(name) => assert_eq!(name, "Fake Lobby"),
+        other => panic!("Expected the game to be watched, got {other:?}"),
+    }
+    let recording = match next_event().await {
+        SpectatorEvent::Recording(path) => path,
+        other => panic!("Expected the replay to be written, got {other:?}"),
+    };
+    //Already a replay WC3 can open, before the game ended
+    let replay = std::fs::read(&recording).unwrap();
+    assert!(replay.starts_with(b"Warcraft III recorded game"));
+
+    game_stream
+        .write_all(&frame(0x0C, &[0x64, 0x00]))
+        .await
+        .unwrap();
+    game_stream
+        .write_all(&frame(0x28, b"\x01\x01\x02\x20\x00\x00\x00\x00gg\0"))
+        .await
+        .unwrap();
+    match next_event().await {
+        SpectatorEvent::Game(GameEvent::Chat { name, message, .. }) => {
+            assert_eq!(name.as_deref(), Some("Grubby"));
+            assert_eq!(message, "gg");
+        }
+        other => panic!("Expected chat, got {other:?}"),
+    }
+
+    drop(game_stream);
+    assert!(matches!(
+        next_event().await,
+        SpectatorEvent::Game(GameEvent::PlayerLeft { player_id: 2, .. })
+    ));
+    assert!(matches!(
+        next_event().await,
+        SpectatorEvent::Game(GameEvent::PhaseChanged(GamePhase::Ended))
+    ));
+    match next_event().await {
+        SpectatorEvent::ReplaySaved(path) => {
+            assert_eq!(path, recording);
+            assert_ne!(std::fs::read(&path).unwrap(), replay);
+        }
+        other => panic!("Expected the replay, got {other:?}"),
+    }
+    std::fs::remove_dir_all(&replay_dir).unwrap();
+    spectator.stop().await;
+    host.stop().await;
+}
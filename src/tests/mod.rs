@@ -0,0 +1,3 @@
+//! Tests that run a host and a client in one process on loopback.
+
+mod loopback;
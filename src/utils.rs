@@ -14,7 +14,11 @@ use crate::packets::GameType;
 pub const APP_NAME: &str = "Simple-WC3";
 pub const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
 
-pub const ALPN: &[u8] = concat!("simple-wc3-", env!("CARGO_PKG_VERSION")).as_bytes();
+//The suffix is the revision of the stream format, bump it when peers can no longer understand each other
+pub const ALPN: &[u8] = concat!("simple-wc3-", env!("CARGO_PKG_VERSION"), "-2").as_bytes();
+pub const SPECTATOR_ALPN: &[u8] =
+    concat!("simple-wc3-spectator-", env!("CARGO_PKG_VERSION")).as_bytes();
+pub const MAP_ALPN: &[u8] = concat!("simple-wc3-map-", env!("CARGO_PKG_VERSION")).as_bytes();
 
 pub const WC3_DEFAULT_PORT: u16 = 6112;
 pub const ZERO_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
@@ -41,31 +45,44 @@ where
     Some(serialized)
 }
 
-pub const SUPPORTED_GAME_VERSIONS: RangeInclusive<u32> = 25..=31;
-pub const SUPPORTED_GAME_TYPES: [GameType; 2] = [GameType::Warcraft3, GameType::TheFrozenThrone];
+/// Cuts `text` to at most `max` bytes without splitting a character.
+pub fn truncate(text: &str, max: usize) -> &str {
+    let mut end = text.len().min(max);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
 
-#[macro_export]
-macro_rules! handle_error {
-    ($x:expr,$error_message:literal) => {{
-        match $x {
-            Ok(val) => val,
-            Err(_e) => {
-                eprintln!($error_message);
-                return;
-            }
+/// The CRC-32 of zlib, WC3 uses it for map files and game actions.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
         }
-    }};
+    }
+    !crc
 }
 
-#[macro_export]
-macro_rules! handle_error_displayed {
-    ($x:expr,$error_message:literal) => {{
-        match $x {
-            Ok(val) => val,
-            Err(e) => {
-                eprintln!($error_message, e);
-                return;
-            }
-        }
-    }};
+pub const SUPPORTED_GAME_VERSIONS: RangeInclusive<u32> = 25..=31;
+pub const SUPPORTED_GAME_TYPES: [GameType; 2] = [GameType::Warcraft3, GameType::TheFrozenThrone];
+
+#[cfg(test)]
+mod tests {
+    use super::{crc32, truncate};
+
+    #[test]
+    fn computes_crc32_of_zlib() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn truncates_between_characters() {
+        assert_eq!(truncate("[Simple-WC3] Grubby", 31), "[Simple-WC3] Grubby");
+        //"ä" takes two bytes
+        assert_eq!(truncate("Orc räumt", 6), "Orc r");
+    }
 }
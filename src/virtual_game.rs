@@ -0,0 +1,1034 @@
+//! A game host without WC3. Opens a LAN lobby for a map and runs the game like GHost++ does, so a
+//! server without WC3 can host games for the clients.
+//!
+//! Answers the game queries on UDP and speaks W3GS with the players on TCP, both on the same port like
+//! WC3 does. The [`crate::Host`] treats it like a local WC3. The players reach each other only through
+//! this host, it relays their chat and their actions.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use binrw::{BinWrite, NullString, meta::WriteEndian};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot, watch,
+    },
+    task::{JoinHandle, spawn_blocking},
+    time::{Instant, MissedTickBehavior, interval},
+};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    error::Error,
+    map_info::{HostedMap, read_hosted_map},
+    maps::file_name,
+    mpq::MpqError,
+    packets::{
+        GameType, QueryForGamesRequest, QueryForGamesResponse, QueryForGamesResponseInner,
+        encode_encoded_string, take_packet,
+    },
+    utils::{APP_NAME, crc32, try_parse, try_serialize},
+    w3gs::{
+        Chat, ChatBody, IncomingAction, MapCheck, MapSize, PingFromHost, PlayerAction, PlayerInfo,
+        PlayerLeft, PlayerLoaded, RejectJoin, ReqJoin, Slot, SlotInfo, SlotInfoJoin, SlotTable,
+        SockAddr, W3gsPacket, chat_from_host, serialize_packet,
+    },
+};
+
+/// Settings for the virtual game of a [`crate::Host`].
+#[derive(Debug, Clone)]
+pub struct VirtualGameConfig {
+    /// The map to host.
+    pub map: PathBuf,
+    /// Where `common.j` and `blizzard.j` are, the map checksum needs them if the map does not bring its
+    /// own. Either the loose files or the folder of WC3 1.26 and older with its game data.
+    pub scripts_dir: Option<PathBuf>,
+    /// The name of the game in the LAN game list.
+    pub game_name: String,
+    /// The WC3 version of the lobby, like 26 for 1.26. Only players with this version see it.
+    pub game_version: u32,
+    /// The name of the host in the lobby. It leaves when the game starts.
+    pub host_name: String,
+}
+
+impl VirtualGameConfig {
+    pub fn new(map: PathBuf) -> Self {
+        VirtualGameConfig {
+            map,
+            scripts_dir: None,
+            game_name: APP_NAME.to_string(),
+            game_version: 26,
+            host_name: APP_NAME.to_string(),
+        }
+    }
+}
+
+/// Game time per action packet. WC3 hosts use 100 ms on LAN.
+const SEND_INTERVAL: Duration = Duration::from_millis(100);
+/// How often the players are pinged, they drop the game without it.
+const PING_INTERVAL: Duration = Duration::from_secs(5);
+/// Most action bytes per packet, the rest waits for the next one.
+const MAX_ACTIONS_SIZE: usize = 1452;
+/// The virtual host is a player without a slot, the others get the ids after it.
+const HOST_PLAYER_ID: u8 = 1;
+const MAX_PLAYER_ID: u8 = 15;
+/// Fast speed, default visibility, teams together and fixed teams.
+const GAME_SETTINGS: u32 = 0x0006_4802;
+const SLOT_OPEN: u8 = 0;
+const SLOT_OCCUPIED: u8 = 2;
+const REJECT_FULL: u32 = 9;
+const REJECT_STARTED: u32 = 10;
+const LEAVE_LOST: u32 = 0x07;
+const LEAVE_LOBBY: u32 = 0x0D;
+/// Download status of a player that has the map.
+const HAS_MAP: u8 = 100;
+const VALID_RACES: [u8; 5] = [0x01, 0x02, 0x04, 0x08, 0x20];
+const RACE_SELECTABLE: u8 = 0x40;
+const START_COMMAND: &str = "!start";
+
+/// A running virtual game. The lobby opens right away and closes when this is dropped.
+pub(crate) struct VirtualGame {
+    addr: SocketAddr,
+    commands: UnboundedSender<Command>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl VirtualGame {
+    /// Reads the map and opens the lobby on a free port of localhost.
+    pub async fn start(config: VirtualGameConfig) -> Result<VirtualGame, Error> {
+        let map = {
+            let path = config.map.clone();
+            let scripts_dir = config.scripts_dir.clone();
+            spawn_blocking(move || read_hosted_map(&path, scripts_dir.as_deref()))
+                .await
+                .unwrap_or_else(|e| Err(MpqError::Io(e.into())))
+                .map_err(Error::Map)?
+        };
+        let (udp_socket, tcp_listener) = bind_same_port().await.map_err(Error::Socket)?;
+        let addr = udp_socket.local_addr().map_err(Error::Socket)?;
+        let (advert, advert_rx) = watch::channel(None);
+        let (events, events_rx) = mpsc::unbounded_channel();
+        let (commands, commands_rx) = mpsc::unbounded_channel();
+
+        info!(
+            "Hosting {} as virtual game \"{}\" for WC3 1.{}",
+            map.info, config.game_name, config.game_version
+        );
+        let mut lobby = Lobby::new(config, map, addr.port(), advert);
+        lobby.publish();
+        let tasks = vec![
+            tokio::spawn(answer_queries(udp_socket, advert_rx)),
+            tokio::spawn(accept_players(tcp_listener, events)),
+            tokio::spawn(lobby.run(events_rx, commands_rx)),
+        ];
+        Ok(VirtualGame {
+            addr,
+            commands,
+            tasks,
+        })
+    }
+
+    /// Where the game answers queries and accepts players, use it like the address of a local WC3.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Starts the game. Returns `false` if the lobby is empty, a player does not have the map yet or
+    /// the game is already running.
+    pub async fn start_game(&self) -> bool {
+        let (reply, started) = oneshot::channel();
+        if self.commands.send(Command::Start(reply)).is_err() {
+            return false;
+        }
+        started.await.unwrap_or(false)
+    }
+}
+
+impl Drop for VirtualGame {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+enum Command {
+    Start(oneshot::Sender<bool>),
+}
+
+/// What the connections of the players report to the [`Lobby`].
+enum Event {
+    Connected(u64, UnboundedSender<Vec<u8>>),
+    Packet(u64, Vec<u8>),
+    Closed(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Lobby,
+    Loading,
+    Playing,
+}
+
+struct Player {
+    connection: u64,
+    name: String,
+    /// Counts the joins, the player that is in the lobby longest may start the game.
+    joined: u32,
+    loaded: bool,
+}
+
+/// The state of the game, owned by a single task.
+struct Lobby {
+    config: VirtualGameConfig,
+    map: HostedMap,
+    /// The map path in the lobby, WC3 looks for the map there and in its download folder.
+    map_path: String,
+    port: u16,
+    /// Counts up with every lobby, WC3 calls it host counter.
+    game_id: u32,
+    opened: Instant,
+    slots: Vec<Slot>,
+    random_seed: u32,
+    phase: Phase,
+    connections: HashMap<u64, UnboundedSender<Vec<u8>>>,
+    players: BTreeMap<u8, Player>,
+    joins: u32,
+    actions: VecDeque<PlayerAction>,
+    advert: watch::Sender<Option<QueryForGamesResponse>>,
+}
+
+impl Lobby {
+    fn new(
+        config: VirtualGameConfig,
+        map: HostedMap,
+        port: u16,
+        advert: watch::Sender<Option<QueryForGamesResponse>>,
+    ) -> Self {
+        let map_path = format!("Maps\\Download\\{}", map_file_name(&config.map));
+        Lobby {
+            config,
+            slots: map.slots.clone(),
+            map,
+            map_path,
+            port,
+            game_id: 1,
+            opened: Instant::now(),
+            random_seed: random_seed(),
+            phase: Phase::Lobby,
+            connections: HashMap::new(),
+            players: BTreeMap::new(),
+            joins: 0,
+            actions: VecDeque::new(),
+            advert,
+        }
+    }
+
+    async fn run(
+        mut self,
+        mut events: UnboundedReceiver<Event>,
+        mut commands: UnboundedReceiver<Command>,
+    ) {
+        let mut actions = interval(SEND_INTERVAL);
+        actions.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut pings = interval(PING_INTERVAL);
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Some(event) => self.handle(event),
+                    None => return,
+                },
+                command = commands.recv() => match command {
+                    Some(Command::Start(reply)) => {
+                        let _ = reply.send(self.start());
+                    }
+                    None => return,
+                },
+                _ = actions.tick() => self.send_actions(),
+                _ = pings.tick() => self.ping(),
+            }
+        }
+    }
+
+    fn handle(&mut self, event: Event) {
+        match event {
+            Event::Connected(connection, sender) => {
+                self.connections.insert(connection, sender);
+            }
+            Event::Closed(connection) => {
+                self.connections.remove(&connection);
+                if let Some(player_id) = self.player_of(connection) {
+                    self.remove_player(player_id);
+                }
+            }
+            Event::Packet(connection, frame) => {
+                let Some(packet) = W3gsPacket::detect(&frame) else {
+                    return;
+                };
+                let Some(player_id) = self.player_of(connection) else {
+                    if let W3gsPacket::ReqJoin(join) = packet {
+                        self.join(connection, join);
+                    }
+                    return;
+                };
+                match packet {
+                    W3gsPacket::LeaveGame(_) => self.remove_player(player_id),
+                    W3gsPacket::ChatToHost(chat) => self.chat(player_id, chat),
+                    W3gsPacket::MapSize(size) => self.map_size(player_id, size),
+                    W3gsPacket::GameLoadedSelf => self.loaded(player_id),
+                    W3gsPacket::OutgoingAction(action) if self.phase == Phase::Playing => {
+                        self.actions.push_back(PlayerAction {
+                            player_id,
+                            data: action.data,
+                        });
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn join(&mut self, connection: u64, join: ReqJoin) {
+        let name = join.player_name.to_string();
+        if self.phase != Phase::Lobby || join.host_counter != self.game_id {
+            debug!("Turning away {name}, the lobby is gone");
+            self.reject(connection, REJECT_STARTED);
+            return;
+        }
+        let slot = self
+            .slots
+            .iter()
+            .position(|slot| slot.slot_status == SLOT_OPEN);
+        let player_id =
+            (HOST_PLAYER_ID + 1..=MAX_PLAYER_ID).find(|id| !self.players.contains_key(id));
+        let (Some(slot), Some(player_id)) = (slot, player_id) else {
+            debug!("Turning away {name}, the lobby is full");
+            self.reject(connection, REJECT_FULL);
+            return;
+        };
+        info!("{name} joined the virtual game");
+        self.slots[slot] = Slot {
+            player_id,
+            download_status: 255,
+            slot_status: SLOT_OCCUPIED,
+            ..self.slots[slot]
+        };
+        self.joins += 1;
+        self.players.insert(
+            player_id,
+            Player {
+                connection,
+                name: name.clone(),
+                joined: self.joins,
+                loaded: false,
+            },
+        );
+
+        let slot_table = self.slot_table();
+        self.send(
+            [player_id],
+            &SlotInfoJoin {
+                packet_size: 0,
+                slot_table_size: slot_table_size(&slot_table),
+                slots: slot_table,
+                player_id,
+                external_addr: hidden_addr(),
+            },
+        );
+        let others: Vec<u8> = self
+            .players
+            .keys()
+            .copied()
+            .filter(|id| *id != player_id)
+            .collect();
+        self.send(
+            [player_id],
+            &player_info(HOST_PLAYER_ID, &self.config.host_name),
+        );
+        for other in &others {
+            self.send([player_id], &player_info(*other, &self.players[other].name));
+        }
+        self.send(
+            [player_id],
+            &MapCheck {
+                packet_size: 0,
+                unknown: 1,
+                map_path: NullString::from(self.map_path.as_str()),
+                map_size: self.map.size,
+                map_crc32: self.map.crc32,
+                map_checksum: self.map.checksum,
+                map_sha1: self.map.sha1,
+            },
+        );
+        self.send(others, &player_info(player_id, &name));
+        self.send_slot_info();
+    }
+
+    fn reject(&mut self, connection: u64, reason: u32) {
+        if let Some(sender) = self.connections.remove(&connection)
+            && let Some(packet) = serialize_packet(&RejectJoin {
+                packet_size: 0,
+                reason,
+            })
+        {
+            let _ = sender.send(packet);
+        }
+    }
+
+    fn remove_player(&mut self, player_id: u8) {
+        let Some(player) = self.players.remove(&player_id) else {
+            return;
+        };
+        info!("{} left the virtual game", player.name);
+        //Closes the connection once everything queued is sent
+        self.connections.remove(&player.connection);
+        self.send(
+            self.players.keys().copied().collect::<Vec<_>>(),
+            &PlayerLeft {
+                packet_size: 0,
+                player_id,
+                reason: if self.phase == Phase::Lobby {
+                    LEAVE_LOBBY
+                } else {
+                    LEAVE_LOST
+                },
+            },
+        );
+        match self.phase {
+            Phase::Lobby => {
+                if let Some(slot) = self.slot_of(player_id) {
+                    self.slots[slot] = Slot {
+                        player_id: 0,
+                        download_status: 255,
+                        slot_status: SLOT_OPEN,
+                        handicap: 100,
+                        ..self.slots[slot]
+                    };
+                }
+                self.send_slot_info();
+            }
+            _ if self.players.is_empty() => self.reopen(),
+            Phase::Loading => self.check_loaded(),
+            Phase::Playing => {}
+        }
+    }
+
+    /// Opens a new lobby after everyone left the game.
+    fn reopen(&mut self) {
+        self.game_id += 1;
+        self.opened = Instant::now();
+        self.slots = self.map.slots.clone();
+        self.random_seed = random_seed();
+        self.phase = Phase::Lobby;
+        self.actions.clear();
+        info!("The virtual game is over, opening a new lobby");
+        self.publish();
+    }
+
+    fn chat(&mut self, player_id: u8, chat: Chat) {
+        let Some(slot) = self.slot_of(player_id) else {
+            return;
+        };
+        match chat.body {
+            ChatBody::Message(_) | ChatBody::GameMessage { .. } => {
+                let message = chat.body.message().unwrap_or_default();
+                let receivers: Vec<u8> = chat
+                    .receivers
+                    .iter()
+                    .copied()
+                    .filter(|id| self.players.contains_key(id))
+                    .collect();
+                match chat_from_host(&chat.receivers, player_id, chat.body) {
+                    Some(packet) => self.send_raw(receivers, packet),
+                    None => error!("Failed to serialize chat of player {player_id}"),
+                }
+                if message.trim().eq_ignore_ascii_case(START_COMMAND)
+                    && self.owner() == Some(player_id)
+                    && !self.start()
+                {
+                    debug!("Can't start the virtual game yet");
+                }
+                return;
+            }
+            _ if self.phase != Phase::Lobby => return,
+            ChatBody::TeamChange(team) => self.change_team(slot, team),
+            ChatBody::ColorChange(color) => {
+                let taken = self
+                    .slots
+                    .iter()
+                    .any(|slot| slot.slot_status == SLOT_OCCUPIED && slot.color == color);
+                if !self.map.fixed_player_settings() && color < 12 && !taken {
+                    self.slots[slot].color = color;
+                }
+            }
+            ChatBody::RaceChange(race) => {
+                if !self.map.fixed_player_settings() && VALID_RACES.contains(&race) {
+                    self.slots[slot].race = race | RACE_SELECTABLE;
+                }
+            }
+            ChatBody::HandicapChange(handicap) => {
+                if (50..=100).contains(&handicap) && handicap % 10 == 0 {
+                    self.slots[slot].handicap = handicap;
+                }
+            }
+        }
+        self.send_slot_info();
+    }
+
+    /// Melee maps have a team per slot, the player joins another one. Maps with custom forces have
+    /// teams of slots, the player moves into an open slot of the team.
+    fn change_team(&mut self, slot: usize, team: u8) {
+        if self.map.fixed_player_settings() {
+            return;
+        }
+        if !self.map.custom_forces() {
+            if usize::from(team) < self.slots.len() {
+                self.slots[slot].team = team;
+            }
+            return;
+        }
+        let Some(open) = self
+            .slots
+            .iter()
+            .position(|slot| slot.slot_status == SLOT_OPEN && slot.team == team)
+        else {
+            return;
+        };
+        let moving = self.slots[slot];
+        self.slots[open] = Slot {
+            player_id: moving.player_id,
+            download_status: moving.download_status,
+            slot_status: moving.slot_status,
+            handicap: moving.handicap,
+            ..self.slots[open]
+        };
+        self.slots[slot] = Slot {
+            player_id: 0,
+            download_status: 255,
+            slot_status: SLOT_OPEN,
+            handicap: 100,
+            ..moving
+        };
+    }
+
+    fn map_size(&mut self, player_id: u8, size: MapSize) {
+        let Some(slot) = self.slot_of(player_id) else {
+            return;
+        };
+        if size.map_size == self.map.size {
+            if self.slots[slot].download_status != HAS_MAP {
+                self.slots[slot].download_status = HAS_MAP;
+                self.send_slot_info();
+            }
+        } else if self.players.contains_key(&player_id) {
+            //The map can't be sent through the game, clients download it over iroh before joining
+            warn!(
+                "{} does not have the map {}, removing the player",
+                self.players[&player_id].name, self.map_path
+            );
+            self.remove_player(player_id);
+        }
+    }
+
+    /// Counts down right away. The virtual host leaves first, it does not play.
+    fn start(&mut self) -> bool {
+        let ready = self
+            .slots
+            .iter()
+            .filter(|slot| slot.slot_status == SLOT_OCCUPIED && slot.computer == 0)
+            .all(|slot| slot.download_status == HAS_MAP);
+        if self.phase != Phase::Lobby || self.players.is_empty() || !ready {
+            return false;
+        }
+        info!(
+            "Starting the virtual game with {} players",
+            self.players.len()
+        );
+        self.phase = Phase::Loading;
+        self.publish();
+        let players: Vec<u8> = self.players.keys().copied().collect();
+        self.send(
+            players.clone(),
+            &PlayerLeft {
+                packet_size: 0,
+                player_id: HOST_PLAYER_ID,
+                reason: LEAVE_LOBBY,
+            },
+        );
+        self.send_raw(players.clone(), vec![0xF7, 0x0A, 4, 0]); //Countdown start
+        self.send_raw(players, vec![0xF7, 0x0B, 4, 0]); //Countdown end
+        true
+    }
+
+    fn loaded(&mut self, player_id: u8) {
+        if self.phase != Phase::Loading {
+            return;
+        }
+        if let Some(player) = self.players.get_mut(&player_id) {
+            player.loaded = true;
+        }
+        self.send(
+            self.players.keys().copied().collect::<Vec<_>>(),
+            &PlayerLoaded {
+                packet_size: 0,
+                player_id,
+            },
+        );
+        self.check_loaded();
+    }
+
+    fn check_loaded(&mut self) {
+        if self.players.values().all(|player| player.loaded) {
+            info!("All players loaded the virtual game");
+            self.phase = Phase::Playing;
+        }
+    }
+
+    /// Sends the actions of the last interval to everyone. The game only advances with these packets,
+    /// so they go out even without actions.
+    fn send_actions(&mut self) {
+        if self.phase != Phase::Playing {
+            return;
+        }
+        let mut actions = Vec::new();
+        while let Some(action) = self.actions.front() {
+            if !actions.is_empty() && actions.len() + 3 + action.data.len() > MAX_ACTIONS_SIZE {
+                break;
+            }
+            let action = self.actions.pop_front().expect("action in queue");
+            actions.push(action.player_id);
+            actions.extend_from_slice(&(action.data.len() as u16).to_le_bytes());
+            actions.extend(action.data);
+        }
+        let mut data = Vec::new();
+        if !actions.is_empty() {
+            data.extend_from_slice(&(crc32(&actions) as u16).to_le_bytes());
+            data.extend(actions);
+        }
+        self.send(
+            self.players.keys().copied().collect::<Vec<_>>(),
+            &IncomingAction {
+                packet_size: 0,
+                send_interval: SEND_INTERVAL.as_millis() as u16,
+                data,
+            },
+        );
+    }
+
+    fn ping(&self) {
+        self.send(
+            self.players.keys().copied().collect::<Vec<_>>(),
+            &PingFromHost {
+                packet_size: 0,
+                ticks: self.opened.elapsed().as_millis() as u32,
+            },
+        );
+    }
+
+    fn send_slot_info(&mut self) {
+        let slot_table = self.slot_table();
+        self.send(
+            self.players.keys().copied().collect::<Vec<_>>(),
+            &SlotInfo {
+                packet_size: 0,
+                slot_table_size: slot_table_size(&slot_table),
+                slots: slot_table,
+            },
+        );
+        self.publish();
+    }
+
+    /// Answers the game queries with the current lobby, or not at all once the game started.
+    fn publish(&mut self) {
+        let response = (self.phase == Phase::Lobby).then(|| self.query_response());
+        self.advert.send_replace(response);
+    }
+
+    fn query_response(&self) -> QueryForGamesResponse {
+        let stat_string = QueryForGamesResponseInner {
+            game_settings: GAME_SETTINGS,
+            unknown1: 0,
+            map_width: self.map.width,
+            map_height: self.map.height,
+            map_checksum: self.map.checksum,
+            map_name: NullString::from(self.map_path.as_str()),
+            host_username: NullString::from(self.config.host_name.as_str()),
+            unknown2: 0,
+        };
+        let encoded = try_serialize(&stat_string)
+            .map(|stat_string| encode_encoded_string(&stat_string))
+            .unwrap_or_default();
+        let mut response = QueryForGamesResponse {
+            packet_size: 0,
+            game_type: GameType::TheFrozenThrone,
+            game_version: self.config.game_version,
+            game_id: self.game_id,
+            unknown1: 0,
+            game_name: NullString::from(self.config.game_name.as_str()),
+            unknown2: 0,
+            encoded: NullString(encoded),
+            number_of_slots: 12,
+            game_flags: 1,
+            number_of_players: self
+                .slots
+                .iter()
+                .filter(|slot| slot.slot_status == SLOT_OCCUPIED)
+                .count() as u32,
+            number_of_player_slots: self.slots.len() as u32,
+            game_age: 0,
+            tcp_port: self.port,
+        };
+        response.packet_size = try_serialize(&response).map_or(0, |packet| packet.len() as u16);
+        response
+    }
+
+    fn slot_table(&self) -> SlotTable {
+        SlotTable {
+            slot_count: self.slots.len() as u8,
+            slots: self.slots.clone(),
+            random_seed: self.random_seed,
+            layout_style: self.map.layout_style,
+            player_slots: self.slots.len() as u8,
+        }
+    }
+
+    fn player_of(&self, connection: u64) -> Option<u8> {
+        self.players
+            .iter()
+            .find(|(_, player)| player.connection == connection)
+            .map(|(id, _)| *id)
+    }
+
+    fn slot_of(&self, player_id: u8) -> Option<usize> {
+        self.slots
+            .iter()
+            .position(|slot| slot.slot_status == SLOT_OCCUPIED && slot.player_id == player_id)
+    }
+
+    /// The player that is in the lobby longest.
+    fn owner(&self) -> Option<u8> {
+        self.players
+            .iter()
+            .min_by_key(|(_, player)| player.joined)
+            .map(|(id, _)| *id)
+    }
+
+    fn send<T: BinWrite + WriteEndian>(&self, players: impl IntoIterator<Item = u8>, packet: &T)
+    where
+        for<'a> <T as BinWrite>::Args<'a>: Default,
+    {
+        match serialize_packet(packet) {
+            Some(packet) => self.send_raw(players, packet),
+            None => error!("Failed to serialize a packet of the virtual game"),
+        }
+    }
+
+    fn send_raw(&self, players: impl IntoIterator<Item = u8>, packet: Vec<u8>) {
+        for player_id in players {
+            if let Some(sender) = self
+                .players
+                .get(&player_id)
+                .and_then(|player| self.connections.get(&player.connection))
+            {
+                let _ = sender.send(packet.clone());
+            }
+        }
+    }
+}
+
+fn player_info(player_id: u8, name: &str) -> PlayerInfo {
+    PlayerInfo {
+        packet_size: 0,
+        player_counter: 2,
+        player_id,
+        player_name: NullString::from(name),
+        unknown: 1,
+        external_addr: hidden_addr(),
+        internal_addr: hidden_addr(),
+    }
+}
+
+/// Without an address the players don't try to connect to each other, see [`crate::p2p`].
+fn hidden_addr() -> SockAddr {
+    SockAddr::new(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))
+}
+
+fn slot_table_size(slots: &SlotTable) -> u16 {
+    try_serialize(slots).map_or(0, |table| table.len() as u16)
+}
+
+fn map_file_name(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    file_name(&path).to_string()
+}
+
+fn random_seed() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.subsec_nanos())
+}
+
+/// WC3 takes the TCP port of the game from the query answer, so both use the same port.
+pub(crate) async fn bind_same_port() -> std::io::Result<(UdpSocket, TcpListener)> {
+    loop {
+        let udp_socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let port = udp_socket.local_addr()?.port();
+        //The TCP port might already be taken, just try another one
+        if let Ok(tcp_listener) = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).await {
+            return Ok((udp_socket, tcp_listener));
+        }
+    }
+}
+
+/// Answers the game queries with the advertised lobby, if it is for the same WC3 version.
+pub(crate) async fn answer_queries(
+    socket: UdpSocket,
+    advert: watch::Receiver<Option<QueryForGamesResponse>>,
+) {
+    let mut buffer = [0u8; 1024];
+    loop {
+        let Ok((len, sender)) = socket.recv_from(&mut buffer).await else {
+            continue;
+        };
+        let Some(request) = try_parse::<QueryForGamesRequest>(&buffer[..len]) else {
+            continue;
+        };
+        //Like WC3, stay silent for other versions
+        let response = match &*advert.borrow() {
+            Some(response)
+                if response.game_type == request.game_type
+                    && response.game_version == request.game_version =>
+            {
+                try_serialize(response)
+            }
+            _ => None,
+        };
+        if let Some(response) = response {
+            let _ = socket.send_to(&response, sender).await;
+        }
+    }
+}
+
+async fn accept_players(listener: TcpListener, events: UnboundedSender<Event>) {
+    let mut next_connection = 0;
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                debug!("Can't accept a player of the virtual game: {e}");
+                continue;
+            }
+        };
+        next_connection += 1;
+        let _ = stream.set_nodelay(true);
+        tokio::spawn(serve_player(next_connection, stream, events.clone()));
+    }
+}
+
+/// Passes the packets of a player to the lobby and sends what the lobby queues for it.
+async fn serve_player(connection: u64, stream: TcpStream, events: UnboundedSender<Event>) {
+    let (mut reader, mut writer) = stream.into_split();
+    let (sender, mut packets) = mpsc::unbounded_channel::<Vec<u8>>();
+    if events.send(Event::Connected(connection, sender)).is_err() {
+        return;
+    }
+    tokio::spawn(async move {
+        while let Some(packet) = packets.recv().await {
+            if writer.write_all(&packet).await.is_err() {
+                break;
+            }
+        }
+    });
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+    'read: loop {
+        match reader.read(&mut chunk).await {
+            Ok(0) | Err(_) => break,
+            Ok(len) => buffer.extend_from_slice(&chunk[..len]),
+        }
+        loop {
+            match take_packet(&mut buffer) {
+                Ok(Some(packet)) => {
+                    if events.send(Event::Packet(connection, packet)).is_err() {
+                        break 'read;
+                    }
+                }
+                Ok(None) => break,
+                Err(_) => break 'read,
+            }
+        }
+    }
+    let _ = events.send(Event::Closed(connection));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, time::Duration};
+
+    use tokio::{
+        io::AsyncWriteExt,
+        net::{TcpStream, UdpSocket},
+        time::timeout,
+    };
+
+    use super::{VirtualGame, VirtualGameConfig};
+    use crate::{
+        packets::{GameType, QueryForGamesRequest, QueryForGamesResponse},
+        test_utils::{
+            mpq::{WTS, build_archive, w3i},
+            w3gs_frames::{chat_to_host, frame, map_size, next_frame, req_join_lobby},
+        },
+        utils::{try_parse, try_serialize},
+        w3gs::W3gsPacket,
+    };
+
+    async fn query(game: &VirtualGame) -> Option<QueryForGamesResponse> {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let request = QueryForGamesRequest::new(GameType::TheFrozenThrone, 26);
+        socket
+            .send_to(&try_serialize(&request).unwrap(), game.addr())
+            .await
+            .unwrap();
+        let mut buffer = [0; 1024];
+        let len = timeout(Duration::from_millis(500), socket.recv(&mut buffer))
+            .await
+            .ok()?
+            .unwrap();
+        try_parse(&buffer[..len])
+    }
+
+    async fn join(game: &VirtualGame, name: &str, game_id: u32) -> (TcpStream, Vec<u8>, u8) {
+        let mut stream = TcpStream::connect(game.addr()).await.unwrap();
+        stream
+            .write_all(&req_join_lobby(name, game_id))
+            .await
+            .unwrap();
+        let mut buffer = Vec::new();
+        let player_id = match W3gsPacket::detect(&next_frame(&mut stream, &mut buffer, 0x04).await)
+        {
+            Some(W3gsPacket::SlotInfoJoin(join)) => join.player_id,
+            other => panic!("Expected SlotInfoJoin, got {other:?}"),
+        };
+        (stream, buffer, player_id)
+    }
+
+    #[tokio::test]
+    async fn runs_lobby_and_game_without_wc3() {
+        let map = build_archive(&[
+            ("war3map.w3i", &w3i()),
+            ("war3map.wts", WTS.as_bytes()),
+            ("war3map.j", b"function main"),
+            ("Scripts\\common.j", b"native Foo"),
+            ("Scripts\\blizzard.j", b"function Bar"),
+        ]);
+        let path =
+            std::env::temp_dir().join(format!("simple-wc3-virtual-{}.w3x", std::process::id()));
+        fs::write(&path, &map).unwrap();
+        let game = VirtualGame::start(VirtualGameConfig::new(path.clone()))
+            .await
+            .unwrap();
+
+        let lobby = query(&game).await.expect("lobby");
+        assert_eq!(lobby.game_id, 1);
+        assert_eq!(lobby.tcp_port, game.addr().port());
+        //The computer player of the map
+        assert_eq!(
+            (lobby.number_of_players, lobby.number_of_player_slots),
+            (1, 3)
+        );
+        let stat_string = lobby.stat_string().unwrap();
+        assert_eq!(
+            stat_string.map_name.to_string(),
+            format!(
+                "Maps\\Download\\{}",
+                path.file_name().unwrap().to_string_lossy()
+            )
+        );
+        assert_eq!((stat_string.map_width, stat_string.map_height), (116, 84));
+
+        let (mut grubby, mut grubby_buffer, grubby_id) = join(&game, "Grubby", 1).await;
+        assert_eq!(grubby_id, 2);
+        match W3gsPacket::detect(&next_frame(&mut grubby, &mut grubby_buffer, 0x3D).await) {
+            Some(W3gsPacket::MapCheck(check)) => {
+                assert_eq!(check.map_size, map.len() as u32);
+                assert_eq!(check.map_checksum, stat_string.map_checksum);
+            }
+            other => panic!("Expected MapCheck, got {other:?}"),
+        }
+        assert!(!game.start_game().await, "Grubby has not confirmed the map");
+        grubby.write_all(&map_size(map.len() as u32)).await.unwrap();
+
+        let (mut moon, mut moon_buffer, moon_id) = join(&game, "Moon", 1).await;
+        assert_eq!(moon_id, 3);
+        next_frame(&mut grubby, &mut grubby_buffer, 0x06).await;
+        moon.write_all(&map_size(map.len() as u32)).await.unwrap();
+        let (mut full, mut full_buffer) =
+            (TcpStream::connect(game.addr()).await.unwrap(), Vec::new());
+        full.write_all(&req_join_lobby("Happy", 1)).await.unwrap();
+        match W3gsPacket::detect(&next_frame(&mut full, &mut full_buffer, 0x05).await) {
+            Some(W3gsPacket::RejectJoin(reject)) => assert_eq!(reject.reason, 9),
+            other => panic!("Expected RejectJoin, got {other:?}"),
+        }
+
+        //Only the player that joined first may start
+        moon.write_all(&chat_to_host(&[1, 2], moon_id, "!start"))
+            .await
+            .unwrap();
+        match W3gsPacket::detect(&next_frame(&mut grubby, &mut grubby_buffer, 0x0F).await) {
+            Some(W3gsPacket::ChatFromHost(chat)) => {
+                assert_eq!(chat.sender, moon_id);
+                assert_eq!(chat.body.message().as_deref(), Some("!start"));
+            }
+            other => panic!("Expected ChatFromHost, got {other:?}"),
+        }
+        assert!(query(&game).await.is_some());
+        grubby
+            .write_all(&chat_to_host(&[1, 3], grubby_id, "!start"))
+            .await
+            .unwrap();
+        for (stream, buffer) in [
+            (&mut grubby, &mut grubby_buffer),
+            (&mut moon, &mut moon_buffer),
+        ] {
+            next_frame(stream, buffer, 0x0B).await;
+            stream.write_all(&frame(0x23, &[])).await.unwrap();
+        }
+        assert!(query(&game).await.is_none(), "the lobby closes on start");
+
+        let mut action = 0u32.to_le_bytes().to_vec();
+        action.extend_from_slice(&[0x11, 0x22]);
+        moon.write_all(&frame(0x26, &action)).await.unwrap();
+        let actions = loop {
+            match W3gsPacket::detect(&next_frame(&mut grubby, &mut grubby_buffer, 0x0C).await) {
+                Some(W3gsPacket::IncomingAction(action)) if !action.data.is_empty() => {
+                    break action.actions();
+                }
+                _ => continue,
+            }
+        };
+        assert_eq!(actions.len(), 1);
+        assert_eq!(actions[0].player_id, moon_id);
+        assert_eq!(actions[0].data, vec![0x11, 0x22]);
+
+        //A new lobby opens once everyone left
+        drop((grubby, moon));
+        let lobby = timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(lobby) = query(&game).await {
+                    break lobby;
+                }
+            }
+        })
+        .await
+        .expect("new lobby in time");
+        assert_eq!(lobby.game_id, 2);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,495 @@
+//! Packets of the W3GS TCP protocol that WC3 speaks between the game host and the players.
+//!
+//! Based on the packet descriptions of the BNETDocs project and the GHost++ source.
+//! Only the packets that are interesting for the tunnel are decoded, everything else is [`W3gsPacket::Other`].
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use binrw::{BinRead, BinWrite, NullString, meta::WriteEndian};
+
+use crate::utils::{try_parse, try_serialize};
+
+#[derive(Debug, Clone)]
+pub enum W3gsPacket {
+    /// 0x1E, player -> host
+    ReqJoin(ReqJoin),
+    /// 0x05, host -> player
+    RejectJoin(RejectJoin),
+    /// 0x04, host -> player, answer to [`W3gsPacket::ReqJoin`]
+    SlotInfoJoin(SlotInfoJoin),
+    /// 0x09, host -> player
+    SlotInfo(SlotInfo),
+    /// 0x06, host -> player, tells a player about another one
+    PlayerInfo(PlayerInfo),
+    /// 0x07, host -> player
+    PlayerLeft(PlayerLeft),
+    /// 0x21, player -> host
+    LeaveGame(LeaveGame),
+    /// 0x0A, host -> player
+    CountdownStart,
+    /// 0x0B, host -> player, the game starts loading
+    CountdownEnd,
+    /// 0x23, player -> host
+    GameLoadedSelf,
+    /// 0x08, host -> player
+    PlayerLoaded(PlayerLoaded),
+    /// 0x0F, host -> player
+    ChatFromHost(Chat),
+    /// 0x28, player -> host
+    ChatToHost(Chat),
+    /// 0x0C, host -> player
+    IncomingAction(IncomingAction),
+    /// 0x26, player -> host
+    OutgoingAction(OutgoingAction),
+    /// 0x3D, host -> player, describes the map of the lobby
+    MapCheck(MapCheck),
+    /// 0x42, player -> host, answer to [`W3gsPacket::MapCheck`]
+    MapSize(MapSize),
+    /// Any other packet. Contains the packet id.
+    Other(u8),
+}
+
+impl W3gsPacket {
+    /// Decodes a complete frame as returned by [`crate::packets::take_packet`].
+    /// Returns `None` if it is not a W3GS packet or it is malformed.
+    pub fn detect(frame: &[u8]) -> Option<Self> {
+        if *frame.first()? != 0xF7 {
+            return None;
+        }
+        let packet = match *frame.get(1)? {
+            0x1E => W3gsPacket::ReqJoin(try_parse(frame)?),
+            0x05 => W3gsPacket::RejectJoin(try_parse(frame)?),
+            0x04 => W3gsPacket::SlotInfoJoin(try_parse(frame)?),
+            0x09 => W3gsPacket::SlotInfo(try_parse(frame)?),
+            0x06 => W3gsPacket::PlayerInfo(try_parse(frame)?),
+            0x07 => W3gsPacket::PlayerLeft(try_parse(frame)?),
+            0x21 => W3gsPacket::LeaveGame(try_parse(frame)?),
+            0x0A => W3gsPacket::CountdownStart,
+            0x0B => W3gsPacket::CountdownEnd,
+            0x23 => W3gsPacket::GameLoadedSelf,
+            0x08 => W3gsPacket::PlayerLoaded(try_parse(frame)?),
+            0x0F => W3gsPacket::ChatFromHost(try_parse::<ChatFromHost>(frame)?.0),
+            0x28 => W3gsPacket::ChatToHost(try_parse::<ChatToHost>(frame)?.0),
+            0x0C => W3gsPacket::IncomingAction(try_parse(frame)?),
+            0x26 => W3gsPacket::OutgoingAction(try_parse(frame)?),
+            0x3D => W3gsPacket::MapCheck(try_parse(frame)?),
+            0x42 => W3gsPacket::MapSize(try_parse(frame)?),
+            id => W3gsPacket::Other(id),
+        };
+        Some(packet)
+    }
+}
+
+/// An IPv4 `sockaddr_in` as WC3 sends it. The port is big endian, everything else little endian.
+#[derive(BinRead, BinWrite, Debug, Clone, Copy, PartialEq, Eq)]
+#[brw(little)]
+pub struct SockAddr {
+    pub family: u16,
+    #[brw(big)]
+    pub port: u16,
+    pub ip: [u8; 4],
+    pub zero: [u8; 8],
+}
+
+impl SockAddr {
+    pub fn new(addr: SocketAddrV4) -> Self {
+        SockAddr {
+            family: 2, //AF_INET
+            port: addr.port(),
+            ip: addr.ip().octets(),
+            zero: [0; 8],
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::from(self.ip), self.port)
+    }
+}
+
+#[derive(BinRead, BinWrite, Debug, Clone)]
+#[brw(little)]
+#[brw(magic = b"\xF7\x1E")]
+pub struct ReqJoin {
+    pub packet_size: u16,
+    /// The game id of the lobby, as in [`crate::packets::QueryForGamesResponse::game_id`].
+    pub host_counter: u32,
+    pub entry_key: u32,
+    pub unknown1: u8,
+    pub listen_port: u16,
+    pub peer_key: u32,
+    pub player_name: NullString,
+    pub unknown2: u32,
+    pub internal_port: u16,
+    pub internal_ip: [u8; 4],
+}
+
+#[derive(BinRead, BinWrite, Debug, Clone)]
+#[brw(little)]
+#[brw(magic = b"\xF7\x05")]
+pub struct RejectJoin {
+    pub packet_size: u16,
+    pub reason: u32,
+}
+
+#[derive(BinRead, BinWrite, Debug, Clone, Copy, PartialEq, Eq)]
+#[brw(little)]
+pub struct Slot {
+    /// 0 for open, closed and computer slots.
+    pub player_id: u8,
+    /// 255 if the player has the map, otherwise the download progress in percent.
+    pub download_status: u8,
+    /// 0 = open, 1 = closed, 2 = occupied
+    pub slot_status: u8,
+    pub computer: u8,
+    pub team: u8,
+    pub color: u8,
+    pub race: u8,
+    pub computer_type: u8,
+    pub handicap: u8,
+}
+
+impl Slot {
+    pub fn is_occupied(&self) -> bool {
+        self.slot_status == 2
+    }
+}
+
+#[derive(BinRead, BinWrite, Debug, Clone)]
+#[brw(little)]
+pub struct SlotTable {
+    pub slot_count: u8,
+    #[br(count = slot_count)]
+    pub slots: Vec<Slot>,
+    pub random_seed: u32,
+    pub layout_style: u8,
+    pub player_slots: u8,
+}
+
+impl SlotTable {
+    /// The index of the slot of a player.
+    pub fn slot_of(&self, player_id: u8) -> Option<u8> {
+        self.slots
+            .iter()
+            .position(|slot| slot.is_occupied() && slot.player_id == player_id)
+            .map(|index| index as u8)
+    }
+}
+
+#[derive(BinRead, BinWrite, Debug, Clone)]
+#[brw(little)]
+#[brw(magic = b"\xF7\x04")]
+pub struct SlotInfoJoin {
+    pub packet_size: u16,
+    pub slot_table_size: u16,
+    pub slots: SlotTable,
+    /// The id the host gave to the joining player.
+    pub player_id: u8,
+    pub external_addr: SockAddr,
+}
+
+#[derive(BinRead, BinWrite, Debug, Clone)]
+#[brw(little)]
+#[brw(magic = b"\xF7\x09")]
+pub struct SlotInfo {
+    pub packet_size: u16,
+    pub slot_table_size: u16,
+    pub slots: SlotTable,
+}
+
+#[derive(BinRead, BinWrite, Debug, Clone)]
+#[brw(little)]
+#[brw(magic = b"\xF7\x06")]
+pub struct PlayerInfo {
+    pub packet_size: u16,
+    pub player_counter: u32,
+    pub player_id: u8,
+    pub player_name: NullString,
+    pub unknown: u16,
+    /// Where the other players can reach this player directly.
+    pub external_addr: SockAddr,
+    pub internal_addr: SockAddr,
+}
+
+#[derive(BinRead, BinWrite, Debug, Clone)]
+#[brw(little)]
+#[brw(magic = b"\xF7\x07")]
+pub struct PlayerLeft {
+    pub packet_size: u16,
+    pub player_id: u8,
+    pub reason: u32,
+}
+
+#[derive(BinRead, BinWrite, Debug, Clone)]
+#[brw(little)]
+#[brw(magic = b"\xF7\x21")]
+pub struct LeaveGame {
+    pub packet_size: u16,
+    pub reason: u32,
+}
+
+#[derive(BinRead, BinWrite, Debug, Clone)]
+#[brw(little)]
+#[brw(magic = b"\xF7\x08")]
+pub struct PlayerLoaded {
+    pub packet_size: u16,
+    pub player_id: u8,
+}
+
+/// The body of both chat packets.
+#[derive(BinRead, BinWrite, Debug, Clone)]
+#[brw(little)]
+pub struct Chat {
+    pub packet_size: u16,
+    pub receiver_count: u8,
+    #[br(count = receiver_count)]
+    pub receivers: Vec<u8>,
+    pub sender: u8,
+    pub body: ChatBody,
+}
+
+#[derive(BinRead, BinWrite, Debug, Clone)]
+#[brw(little)]
+pub enum ChatBody {
+    /// A message in the lobby.
+    #[brw(magic = 0x10u8)]
+    Message(NullString),
+    #[brw(magic = 0x11u8)]
+    TeamChange(u8),
+    #[brw(magic = 0x12u8)]
+    ColorChange(u8),
+    #[brw(magic = 0x13u8)]
+    RaceChange(u8),
+    #[brw(magic = 0x14u8)]
+    HandicapChange(u8),
+    /// A message in the running game. The flags select all, allies, observers or a single player.
+    #[brw(magic = 0x20u8)]
+    GameMessage { flags: u32, message: NullString },
+}
+
+impl ChatBody {
+    /// The text, if this is a message and not a lobby setting change.
+    pub fn message(&self) -> Option<String> {
+        match self {
+            ChatBody::Message(message) | ChatBody::GameMessage { message, .. } => {
+                Some(message.to_string())
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(BinRead, BinWrite, Debug, Clone)]
+#[brw(little)]
+#[brw(magic = b"\xF7\x0F")]
+struct ChatFromHost(Chat);
+
+/// Builds the packet of the game host that shows a chat message of `sender` to `receivers`.
+pub fn chat_from_host(receivers: &[u8], sender: u8, body: ChatBody) -> Option<Vec<u8>> {
+    serialize_packet(&ChatFromHost(Chat {
+        packet_size: 0,
+        receiver_count: u8::try_from(receivers.len()).ok()?,
+        receivers: receivers.to_vec(),
+        sender,
+        body,
+    }))
+}
+
+/// Serializes a packet and fills in its length, whatever its `packet_size` field says.
+pub fn serialize_packet<T: BinWrite + WriteEndian>(packet: &T) -> Option<Vec<u8>>
+where
+    for<'a> <T as BinWrite>::Args<'a>: Default,
+{
+    let mut packet = try_serialize(packet)?;
+    let len = u16::try_from(packet.len()).ok()?;
+    packet.get_mut(2..4)?.copy_from_slice(&len.to_le_bytes());
+    Some(packet)
+}
+
+#[derive(BinRead, BinWrite, Debug, Clone)]
+#[brw(little)]
+#[brw(magic = b"\xF7\x28")]
+struct ChatToHost(Chat);
+
+/// The actions of all players for one game tick.
+#[derive(BinRead, BinWrite, Debug, Clone)]
+#[brw(little)]
+#[brw(magic = b"\xF7\x0C")]
+pub struct IncomingAction {
+    pub packet_size: u16,
+    /// Milliseconds of game time this tick covers.
+    pub send_interval: u16,
+    /// Checksum and actions, empty if nobody did anything.
+    #[br(count = packet_size.saturating_sub(6))]
+    pub data: Vec<u8>,
+}
+
+impl IncomingAction {
+    /// The actions of the players in this tick.
+    pub fn actions(&self) -> Vec<PlayerAction> {
+        let mut actions = Vec::new();
+        //The first two bytes are a checksum
+        let mut rest = self.data.get(2..).unwrap_or_default();
+        while rest.len() >= 3 {
+            let len = u16::from_le_bytes([rest[1], rest[2]]) as usize;
+            let Some(data) = rest.get(3..3 + len) else {
+                break;
+            };
+            actions.push(PlayerAction {
+                player_id: rest[0],
+                data: data.to_vec(),
+            });
+            rest = &rest[3 + len..];
+        }
+        actions
+    }
+}
+
+/// The actions of one player in one game tick.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlayerAction {
+    pub player_id: u8,
+    pub data: Vec<u8>,
+}
+
+#[derive(BinRead, BinWrite, Debug, Clone)]
+#[brw(little)]
+#[brw(magic = b"\xF7\x26")]
+pub struct OutgoingAction {
+    pub packet_size: u16,
+    pub checksum: u32,
+    #[br(count = packet_size.saturating_sub(8))]
+    pub data: Vec<u8>,
+}
+
+/// Tells a joining player which map the lobby is about.
+#[derive(BinRead, BinWrite, Debug, Clone)]
+#[brw(little)]
+#[brw(magic = b"\xF7\x3D")]
+pub struct MapCheck {
+    pub packet_size: u16,
+    pub unknown: u32,
+    /// Path of the map relative to the folder of WC3, like `Maps\Download\Map.w3x`.
+    pub map_path: NullString,
+    pub map_size: u32,
+    /// CRC-32 of the map file.
+    pub map_crc32: u32,
+    /// The checksum of the lobby, see [`crate::map_info::MapInfo::checksum`].
+    pub map_checksum: u32,
+    pub map_sha1: [u8; 20],
+}
+
+/// How much of the map a player has.
+#[derive(BinRead, BinWrite, Debug, Clone)]
+#[brw(little)]
+#[brw(magic = b"\xF7\x42")]
+pub struct MapSize {
+    pub packet_size: u16,
+    pub unknown: u32,
+    /// 1 while the player checks or downloads the map, 3 if it can't be downloaded.
+    pub size_flag: u8,
+    pub map_size: u32,
+}
+
+/// Sent every few seconds, the players answer with `0x46`.
+#[derive(BinRead, BinWrite, Debug, Clone)]
+#[brw(little)]
+#[brw(magic = b"\xF7\x01")]
+pub struct PingFromHost {
+    pub packet_size: u16,
+    pub ticks: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use super::{ChatBody, W3gsPacket, chat_from_host};
+
+    #[test]
+    fn decodes_join_request() {
+        let mut frame = vec![0xF7, 0x1E, 0x00, 0x00];
+        frame.extend_from_slice(&7u32.to_le_bytes()); //Host counter
+        frame.extend_from_slice(&0u32.to_le_bytes()); //Entry key
+        frame.push(0);
+        frame.extend_from_slice(&6112u16.to_le_bytes());
+        frame.extend_from_slice(&0u32.to_le_bytes()); //Peer key
+        frame.extend_from_slice(b"Grubby\0");
+        frame.extend_from_slice(&0u32.to_le_bytes());
+        frame.extend_from_slice(&6112u16.to_le_bytes());
+        frame.extend_from_slice(&[192, 168, 0, 2]);
+        let len = frame.len() as u16;
+        frame[2..4].copy_from_slice(&len.to_le_bytes());
+
+        match W3gsPacket::detect(&frame) {
+            Some(W3gsPacket::ReqJoin(join)) => {
+                assert_eq!(join.host_counter, 7);
+                assert_eq!(join.player_name.to_string(), "Grubby");
+            }
+            other => panic!("Expected ReqJoin, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn decodes_player_info_and_chat() {
+        let mut frame = vec![0xF7, 0x06, 0x00, 0x00];
+        frame.extend_from_slice(&1u32.to_le_bytes());
+        frame.push(3);
+        frame.extend_from_slice(b"Moon\0");
+        frame.extend_from_slice(&[1, 0]);
+        frame.extend_from_slice(&[2, 0, 0x17, 0xE0, 10, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 0]);
+        frame.extend_from_slice(&[0; 16]);
+        let len = frame.len() as u16;
+        frame[2..4].copy_from_slice(&len.to_le_bytes());
+        match W3gsPacket::detect(&frame) {
+            Some(W3gsPacket::PlayerInfo(info)) => {
+                assert_eq!(info.player_id, 3);
+                assert_eq!(info.player_name.to_string(), "Moon");
+                assert_eq!(
+                    info.external_addr.addr(),
+                    SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 5), 6112)
+                );
+            }
+            other => panic!("Expected PlayerInfo, got {other:?}"),
+        }
+
+        let chat = b"\xF7\x28\x0C\x00\x01\x01\x03\x10gg\0";
+        let mut chat = chat.to_vec();
+        chat[2] = chat.len() as u8;
+        match W3gsPacket::detect(&chat) {
+            Some(W3gsPacket::ChatToHost(chat)) => {
+                assert_eq!(chat.sender, 3);
+                assert!(matches!(&chat.body, ChatBody::Message(_)));
+                assert_eq!(chat.body.message().as_deref(), Some("gg"));
+            }
+            other => panic!("Expected ChatToHost, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn splits_incoming_actions() {
+        let frame = [
+            0xF7, 0x0C, 0x10, 0x00, 0x64, 0x00, //Header and send interval
+            0xAB, 0xCD, //Checksum
+            0x02, 0x02, 0x00, 0x01, 0x02, //Player 2
+            0x03, 0x00, 0x00, //Player 3 without data
+        ];
+        match W3gsPacket::detect(&frame) {
+            Some(W3gsPacket::IncomingAction(action)) => {
+                assert_eq!(action.send_interval, 100);
+                let actions = action.actions();
+                assert_eq!(actions.len(), 2);
+                assert_eq!(actions[0].player_id, 2);
+                assert_eq!(actions[0].data, vec![1, 2]);
+                assert!(actions[1].data.is_empty());
+            }
+            other => panic!("Expected IncomingAction, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn refuses_chat_to_more_receivers_than_fit() {
+        let body = || ChatBody::Message("gl hf".into());
+        assert!(chat_from_host(&[1; 255], 2, body()).is_some());
+        assert!(chat_from_host(&[1; 256], 2, body()).is_none());
+    }
+}